@@ -0,0 +1,146 @@
+// the asid and tlb flush bookkeeping of one cpu, the driver's asid.rs puts
+// it into the vmcb
+
+// ordered by what they cover, requests merge into the widest
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum tlb_flush {
+    #[default]
+    None,
+    // the current asid, global translations stay
+    NonGlobal,
+    // the current asid
+    Asid,
+    // every asid on the cpu
+    All,
+}
+
+// generation 0 never matches, a retired or unused slot
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct asid_slot {
+    pub asid: u32,
+    pub generation: u64,
+}
+
+impl asid_slot {
+    pub const fn new() -> Self {
+        Self {
+            asid: 0,
+            generation: 0,
+        }
+    }
+
+    pub fn retire(&mut self) {
+        self.generation = 0;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct asid_allocator {
+    // highest usable asid, 0 belongs to the host
+    max: u32,
+    next: u32,
+    generation: u64,
+}
+
+impl asid_allocator {
+    // count as reported by cpuid Fn8000_000A_EBX
+    pub const fn new(count: u32) -> Self {
+        Self {
+            max: if count > 2 { count - 1 } else { 1 },
+            next: 1,
+            generation: 1,
+        }
+    }
+
+    // the asid slot stands for, a fresh one if it's retired or from an older
+    // generation. the flush has to happen before the guest runs with it
+    pub fn assign(&mut self, slot: &mut asid_slot) -> (u32, tlb_flush) {
+        if slot.generation == self.generation {
+            return (slot.asid, tlb_flush::None);
+        }
+        let mut flush = tlb_flush::None;
+        if self.next > self.max {
+            self.generation += 1;
+            self.next = 1;
+            flush = tlb_flush::All;
+        }
+        slot.asid = self.next;
+        slot.generation = self.generation;
+        self.next += 1;
+        (slot.asid, flush)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_an_assigned_asid() {
+        let mut asids = asid_allocator::new(16);
+        let mut slot = asid_slot::new();
+        assert_eq!(asids.assign(&mut slot), (1, tlb_flush::None));
+        assert_eq!(asids.assign(&mut slot), (1, tlb_flush::None));
+        let mut other = asid_slot::new();
+        assert_eq!(asids.assign(&mut other), (2, tlb_flush::None));
+    }
+
+    #[test]
+    fn retiring_hands_out_a_fresh_one() {
+        let mut asids = asid_allocator::new(16);
+        let mut slot = asid_slot::new();
+        asids.assign(&mut slot);
+        slot.retire();
+        assert_eq!(asids.assign(&mut slot), (2, tlb_flush::None));
+    }
+
+    #[test]
+    fn running_out_starts_a_generation() {
+        // asid 0 is the host's, 1 to 3 are usable
+        let mut asids = asid_allocator::new(4);
+        let mut slot = asid_slot::new();
+        let mut other = asid_slot::new();
+        for asid in 1..=3 {
+            slot.retire();
+            assert_eq!(asids.assign(&mut slot), (asid, tlb_flush::None));
+        }
+        slot.retire();
+        // everything cached for the old generation has to go
+        assert_eq!(asids.assign(&mut slot), (1, tlb_flush::All));
+        // and slots of the old one can't keep their asid
+        assert_eq!(asids.assign(&mut other), (2, tlb_flush::None));
+        assert_eq!(asids.assign(&mut slot), (1, tlb_flush::None));
+    }
+
+    #[test]
+    fn stale_slots_are_reassigned() {
+        let mut asids = asid_allocator::new(3);
+        let mut stale = asid_slot::new();
+        let mut slot = asid_slot::new();
+        assert_eq!(asids.assign(&mut stale), (1, tlb_flush::None));
+        assert_eq!(asids.assign(&mut slot), (2, tlb_flush::None));
+        slot.retire();
+        assert_eq!(asids.assign(&mut slot), (1, tlb_flush::All));
+        assert_eq!(asids.assign(&mut stale), (2, tlb_flush::None));
+    }
+
+    #[test]
+    fn tiny_asid_counts() {
+        // a cpu with a single guest asid retires by flushing everything
+        for count in [0, 1, 2] {
+            let mut asids = asid_allocator::new(count);
+            let mut slot = asid_slot::new();
+            assert_eq!(asids.assign(&mut slot), (1, tlb_flush::None));
+            slot.retire();
+            assert_eq!(asids.assign(&mut slot), (1, tlb_flush::All));
+        }
+    }
+
+    #[test]
+    fn flushes_merge_into_the_widest() {
+        assert!(tlb_flush::None < tlb_flush::NonGlobal);
+        assert!(tlb_flush::NonGlobal < tlb_flush::Asid);
+        assert!(tlb_flush::Asid < tlb_flush::All);
+        assert_eq!(tlb_flush::Asid.max(tlb_flush::NonGlobal), tlb_flush::Asid);
+    }
+}
@@ -0,0 +1,68 @@
+// event injection encoding for control_area::event_inj
+// see AMD manual '15.20 Event Injection'
+
+pub const EVENT_TYPE_INTR: u64 = 0;
+pub const EVENT_TYPE_NMI: u64 = 2;
+pub const EVENT_TYPE_EXCEPTION: u64 = 3;
+pub const EVENT_TYPE_SOFT_INT: u64 = 4;
+
+pub const EVENT_ERROR_CODE_VALID: u64 = 1 << 11;
+pub const EVENT_VALID: u64 = 1 << 31;
+
+pub const EXCEPTION_DE: u8 = 0;
+pub const EXCEPTION_DB: u8 = 1;
+pub const EXCEPTION_NMI: u8 = 2;
+pub const EXCEPTION_BP: u8 = 3;
+pub const EXCEPTION_OF: u8 = 4;
+pub const EXCEPTION_BR: u8 = 5;
+pub const EXCEPTION_UD: u8 = 6;
+pub const EXCEPTION_NM: u8 = 7;
+pub const EXCEPTION_DF: u8 = 8;
+pub const EXCEPTION_TS: u8 = 10;
+pub const EXCEPTION_NP: u8 = 11;
+pub const EXCEPTION_SS: u8 = 12;
+pub const EXCEPTION_GP: u8 = 13;
+pub const EXCEPTION_PF: u8 = 14;
+pub const EXCEPTION_MF: u8 = 16;
+pub const EXCEPTION_AC: u8 = 17;
+pub const EXCEPTION_MC: u8 = 18;
+pub const EXCEPTION_XM: u8 = 19;
+pub const EXCEPTION_VE: u8 = 20;
+pub const EXCEPTION_CP: u8 = 21;
+pub const EXCEPTION_SX: u8 = 30;
+
+pub fn event(vector: u8, event_type: u64, error_code: Option<u32>) -> u64 {
+    let mut event = vector as u64 | (event_type << 8) | EVENT_VALID;
+    if let Some(error_code) = error_code {
+        event |= EVENT_ERROR_CODE_VALID | ((error_code as u64) << 32);
+    }
+    event
+}
+
+pub fn exception(vector: u8, error_code: Option<u32>) -> u64 {
+    event(vector, EVENT_TYPE_EXCEPTION, error_code)
+}
+
+pub fn event_vector(event: u64) -> u8 {
+    event as u8
+}
+
+pub fn event_type(event: u64) -> u64 {
+    (event >> 8) & 7
+}
+
+// vectors for which the cpu pushes an error code
+pub fn has_error_code(vector: u8) -> bool {
+    matches!(
+        vector,
+        EXCEPTION_DF
+            | EXCEPTION_TS
+            | EXCEPTION_NP
+            | EXCEPTION_SS
+            | EXCEPTION_GP
+            | EXCEPTION_PF
+            | EXCEPTION_AC
+            | EXCEPTION_CP
+            | EXCEPTION_SX
+    )
+}
@@ -0,0 +1,394 @@
+// what exception handlers decide and what ends up in the vmcb for it, the
+// handler registry is in the driver's handler/exception.rs
+use crate::event::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct exception_info {
+    pub vector: u8,
+    pub error_code: Option<u32>,
+    // faulting address of #PF, cr2 when reflected
+    pub address: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum exception_action {
+    // not this handler's, ask the next one
+    Pass,
+    // handled, the guest resumes at rip without an exception. handlers of
+    // traps move rip themselves
+    Consume,
+    // deliver the exception as it happened
+    Reflect,
+    // deliver this one instead
+    Inject(exception_info),
+}
+
+// the first decision that is not Pass, unclaimed exceptions are reflected.
+// all actions are evaluated, handlers may clean up state even when another
+// one already decided
+pub fn decide(actions: impl Iterator<Item = exception_action>) -> exception_action {
+    let decision = actions.fold(exception_action::Pass, |decision, action| match decision {
+        exception_action::Pass => action,
+        decision => decision,
+    });
+    match decision {
+        exception_action::Pass => exception_action::Reflect,
+        decision => decision,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct delivery {
+    pub event_inj: u64,
+    pub cr2: Option<u64>,
+    // #BP and #OF are traps, the guest has to see the rip after int3 / into
+    pub skip_instruction: bool,
+}
+
+// what ends up in the vmcb for an action, None when nothing is delivered
+pub fn delivery_for(info: &exception_info, action: exception_action) -> Option<delivery> {
+    let info = match action {
+        exception_action::Pass | exception_action::Reflect => *info,
+        exception_action::Consume => return None,
+        exception_action::Inject(info) => info,
+    };
+    let error_code = match has_error_code(info.vector) {
+        true => Some(info.error_code.unwrap_or(0)),
+        false => None,
+    };
+    Some(delivery {
+        event_inj: exception(info.vector, error_code),
+        cr2: (info.vector == EXCEPTION_PF).then_some(info.address),
+        skip_instruction: matches!(info.vector, EXCEPTION_BP | EXCEPTION_OF),
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum nested_event {
+    // nothing was interrupted, or an exception or software interrupt that
+    // comes back when the guest executes the instruction again
+    None,
+    // an interrupt or nmi that was taken already, it goes in after the
+    // exception
+    Requeue(u64),
+    // the two exceptions combined, the delivery became a #DF
+    DoubleFault,
+    // an exception while delivering #DF, the cpu would shut down
+    TripleFault,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum exception_class {
+    Benign,
+    Contributory,
+    PageFault,
+    DoubleFault,
+}
+
+fn exception_class(vector: u8) -> exception_class {
+    match vector {
+        EXCEPTION_DE | EXCEPTION_TS | EXCEPTION_NP | EXCEPTION_SS | EXCEPTION_GP => {
+            exception_class::Contributory
+        }
+        EXCEPTION_PF => exception_class::PageFault,
+        EXCEPTION_DF => exception_class::DoubleFault,
+        _ => exception_class::Benign,
+    }
+}
+
+// delivery happens while interrupted, the event in exit_int_info, was being
+// delivered. see AMD manual '8.2.9 Double-Fault Exception (#DF)'
+pub fn nest(interrupted: u64, delivery: &mut delivery) -> nested_event {
+    if interrupted & EVENT_VALID == 0 {
+        return nested_event::None;
+    }
+    match event_type(interrupted) {
+        EVENT_TYPE_INTR | EVENT_TYPE_NMI => return nested_event::Requeue(interrupted),
+        EVENT_TYPE_EXCEPTION => {}
+        _ => return nested_event::None,
+    }
+    let first = exception_class(event_vector(interrupted));
+    let second = exception_class(event_vector(delivery.event_inj));
+    match (first, second) {
+        (exception_class::DoubleFault, exception_class::Contributory)
+        | (exception_class::DoubleFault, exception_class::PageFault) => nested_event::TripleFault,
+        (exception_class::Contributory, exception_class::Contributory)
+        | (exception_class::PageFault, exception_class::Contributory)
+        | (exception_class::PageFault, exception_class::PageFault) => {
+            delivery.event_inj = exception(EXCEPTION_DF, Some(0));
+            delivery.skip_instruction = false;
+            nested_event::DoubleFault
+        }
+        _ => nested_event::None,
+    }
+}
+
+pub const MAX_REQUEUED: usize = 8;
+
+// interrupts that were cut short while v_irq already held one, they go in
+// oldest first once the guest takes it
+#[derive(Clone, Copy, Debug)]
+pub struct requeued_interrupts {
+    vectors: [u8; MAX_REQUEUED],
+    len: usize,
+}
+
+impl requeued_interrupts {
+    pub const fn new() -> Self {
+        Self {
+            vectors: [0; MAX_REQUEUED],
+            len: 0,
+        }
+    }
+
+    // false if it's full. a vector that is already queued is taken once,
+    // like a second request for a vector set in the apic's irr
+    pub fn push(&mut self, vector: u8) -> bool {
+        if self.vectors[..self.len].contains(&vector) {
+            return true;
+        }
+        if self.len == MAX_REQUEUED {
+            return false;
+        }
+        self.vectors[self.len] = vector;
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let vector = self.vectors[0];
+        self.vectors.copy_within(1..self.len, 0);
+        self.len -= 1;
+        Some(vector)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guest_mem::PF_WRITE;
+    use exception_action::*;
+
+    const GP: exception_info = exception_info {
+        vector: EXCEPTION_GP,
+        error_code: Some(0x10),
+        address: 0,
+    };
+
+    #[test]
+    fn unclaimed_exceptions_are_reflected() {
+        assert_eq!(decide([].into_iter()), Reflect);
+        assert_eq!(decide([Pass, Pass].into_iter()), Reflect);
+    }
+
+    #[test]
+    fn first_decision_wins() {
+        assert_eq!(decide([Pass, Consume, Reflect].into_iter()), Consume);
+        assert_eq!(decide([Inject(GP), Consume].into_iter()), Inject(GP));
+        assert_eq!(decide([Pass, Reflect, Inject(GP)].into_iter()), Reflect);
+    }
+
+    #[test]
+    fn every_handler_runs() {
+        let mut ran = 0;
+        let actions = [Consume, Pass, Reflect].into_iter().inspect(|_| ran += 1);
+        assert_eq!(decide(actions), Consume);
+        assert_eq!(ran, 3);
+    }
+
+    #[test]
+    fn consumed_exceptions_deliver_nothing() {
+        assert_eq!(delivery_for(&GP, Consume), None);
+    }
+
+    #[test]
+    fn reflect_delivers_the_exception() {
+        let delivery = delivery_for(&GP, Reflect).unwrap();
+        assert_eq!(delivery.event_inj, exception(EXCEPTION_GP, Some(0x10)));
+        assert_eq!(delivery.cr2, None);
+        assert!(!delivery.skip_instruction);
+    }
+
+    #[test]
+    fn page_faults_carry_cr2() {
+        let pf = exception_info {
+            vector: EXCEPTION_PF,
+            error_code: Some(PF_WRITE),
+            address: 0xdead_b000,
+        };
+        let delivery = delivery_for(&pf, Reflect).unwrap();
+        assert_eq!(delivery.event_inj, exception(EXCEPTION_PF, Some(PF_WRITE)));
+        assert_eq!(delivery.cr2, Some(0xdead_b000));
+    }
+
+    #[test]
+    fn injected_exception_replaces_the_original() {
+        let ud = exception_info {
+            vector: EXCEPTION_UD,
+            error_code: None,
+            address: 0,
+        };
+        let delivery = delivery_for(&ud, Inject(GP)).unwrap();
+        assert_eq!(delivery.event_inj, exception(EXCEPTION_GP, Some(0x10)));
+    }
+
+    #[test]
+    fn error_codes_follow_the_vector() {
+        // a missing error code for a vector that pushes one is 0
+        let gp = exception_info {
+            error_code: None,
+            ..GP
+        };
+        let delivery = delivery_for(&gp, Reflect).unwrap();
+        assert_eq!(delivery.event_inj, exception(EXCEPTION_GP, Some(0)));
+        // and one for a vector that doesn't is dropped
+        let ud = exception_info {
+            vector: EXCEPTION_UD,
+            error_code: Some(5),
+            address: 0,
+        };
+        let delivery = delivery_for(&ud, Reflect).unwrap();
+        assert_eq!(delivery.event_inj, exception(EXCEPTION_UD, None));
+        assert_eq!(delivery.event_inj & EVENT_ERROR_CODE_VALID, 0);
+    }
+
+    #[test]
+    fn traps_skip_the_instruction() {
+        for vector in [EXCEPTION_BP, EXCEPTION_OF] {
+            let info = exception_info {
+                vector,
+                error_code: None,
+                address: 0,
+            };
+            assert!(delivery_for(&info, Reflect).unwrap().skip_instruction);
+        }
+        assert!(!delivery_for(&GP, Reflect).unwrap().skip_instruction);
+    }
+
+    fn nested(interrupted: u64, vector: u8) -> (nested_event, u64) {
+        let info = exception_info {
+            vector,
+            error_code: Some(0),
+            address: 0x1000,
+        };
+        let mut delivery = delivery_for(&info, Reflect).unwrap();
+        (nest(interrupted, &mut delivery), delivery.event_inj)
+    }
+
+    #[test]
+    fn nothing_interrupted() {
+        assert_eq!(
+            nested(0, EXCEPTION_GP),
+            (nested_event::None, exception(EXCEPTION_GP, Some(0)))
+        );
+    }
+
+    #[test]
+    fn interrupts_and_nmis_are_requeued() {
+        let interrupt = event(0x41, EVENT_TYPE_INTR, None);
+        let nmi = event(EXCEPTION_NMI, EVENT_TYPE_NMI, None);
+        for interrupted in [interrupt, nmi] {
+            assert_eq!(
+                nested(interrupted, EXCEPTION_PF),
+                (
+                    nested_event::Requeue(interrupted),
+                    exception(EXCEPTION_PF, Some(0))
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn software_interrupts_come_back() {
+        let int = event(0x2e, EVENT_TYPE_SOFT_INT, None);
+        assert_eq!(nested(int, EXCEPTION_GP).0, nested_event::None);
+    }
+
+    #[test]
+    fn double_fault_rules() {
+        let df = exception(EXCEPTION_DF, Some(0));
+        let first = |vector| exception(vector, has_error_code(vector).then_some(0));
+        // contributory or page fault followed by contributory
+        for vector in [
+            EXCEPTION_DE,
+            EXCEPTION_TS,
+            EXCEPTION_NP,
+            EXCEPTION_SS,
+            EXCEPTION_GP,
+        ] {
+            assert_eq!(
+                nested(first(vector), EXCEPTION_GP),
+                (nested_event::DoubleFault, df)
+            );
+            assert_eq!(
+                nested(first(EXCEPTION_PF), vector),
+                (nested_event::DoubleFault, df)
+            );
+        }
+        assert_eq!(
+            nested(first(EXCEPTION_PF), EXCEPTION_PF),
+            (nested_event::DoubleFault, df)
+        );
+        // a page fault while delivering a contributory exception is taken
+        assert_eq!(
+            nested(first(EXCEPTION_GP), EXCEPTION_PF).0,
+            nested_event::None
+        );
+        // benign exceptions on either side never combine
+        assert_eq!(
+            nested(first(EXCEPTION_UD), EXCEPTION_GP).0,
+            nested_event::None
+        );
+        assert_eq!(
+            nested(first(EXCEPTION_GP), EXCEPTION_UD).0,
+            nested_event::None
+        );
+        assert_eq!(
+            nested(first(EXCEPTION_DB), EXCEPTION_PF).0,
+            nested_event::None
+        );
+        // and #DF only escalates further on contributory faults and page faults
+        assert_eq!(nested(df, EXCEPTION_GP).0, nested_event::TripleFault);
+        assert_eq!(nested(df, EXCEPTION_PF).0, nested_event::TripleFault);
+        assert_eq!(nested(df, EXCEPTION_UD).0, nested_event::None);
+    }
+
+    #[test]
+    fn double_fault_is_not_a_trap() {
+        let info = exception_info {
+            vector: EXCEPTION_BP,
+            error_code: None,
+            address: 0,
+        };
+        let mut delivery = delivery_for(&info, Inject(GP)).unwrap();
+        nest(exception(EXCEPTION_GP, Some(0)), &mut delivery);
+        assert!(!delivery.skip_instruction);
+    }
+
+    #[test]
+    fn requeued_interrupts_in_order() {
+        let mut queue = requeued_interrupts::new();
+        assert_eq!(queue.pop(), None);
+        assert!(queue.push(0x30) && queue.push(0xd1) && queue.push(0x30));
+        assert_eq!(queue.pop(), Some(0x30));
+        assert!(queue.push(0x41));
+        assert_eq!(queue.pop(), Some(0xd1));
+        assert_eq!(queue.pop(), Some(0x41));
+        assert!(queue.is_empty());
+
+        for vector in 0..MAX_REQUEUED as u8 {
+            assert!(queue.push(0x30 + vector));
+        }
+        assert!(!queue.push(0xff));
+        assert!(queue.push(0x30));
+        assert_eq!(queue.pop(), Some(0x30));
+        assert!(queue.push(0xff));
+    }
+}
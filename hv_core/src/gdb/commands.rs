@@ -0,0 +1,721 @@
+// gdb packet dispatch against an abstract target, so whole sessions can be
+// replayed on the host. threads are processors, numbered from 1
+use super::packet::*;
+
+pub const MAX_THREADS: u32 = 64;
+pub const SIGINT: u8 = 2;
+pub const SIGTRAP: u8 = 5;
+
+// amd64 register numbering without a target description: the gprs in gdb's
+// order, rip, then eflags and the selectors as 32 bit values
+pub const GDB_REGISTERS: usize = 24;
+pub const GDB_RSP: usize = 7;
+pub const GDB_RIP: usize = 16;
+pub const GDB_EFLAGS: usize = 17;
+pub const GDB_CS: usize = 18;
+
+const ERROR_INVALID: u8 = 0x16;
+const ERROR_FAULT: u8 = 0x0e;
+const ERROR_FAILED: u8 = 0x01;
+
+fn register_size(index: usize) -> usize {
+    match index {
+        0..=GDB_RIP => 8,
+        _ => 4,
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct gdb_regs {
+    pub values: [u64; GDB_REGISTERS],
+}
+
+// Z / z packet types
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum gdb_breakpoint {
+    Software,
+    Hardware,
+    Write,
+    Read,
+    Access,
+}
+
+pub trait gdb_target {
+    fn thread_alive(&self, thread: u32) -> bool;
+    fn read_registers(&mut self, thread: u32) -> Option<gdb_regs>;
+    fn write_registers(&mut self, thread: u32, regs: &gdb_regs) -> bool;
+    // virtual addresses in the thread's address space
+    fn read_memory(&mut self, thread: u32, address: u64, buf: &mut [u8]) -> bool;
+    fn write_memory(&mut self, thread: u32, address: u64, data: &[u8]) -> bool;
+    fn set_breakpoint(&mut self, kind: gdb_breakpoint, address: u64, len: u64) -> bool;
+    fn clear_breakpoint(&mut self, kind: gdb_breakpoint, address: u64, len: u64) -> bool;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum gdb_action {
+    // send the reply and wait for the next packet
+    Reply,
+    // resume the guest, the stop reply is sent at the next stop
+    Continue,
+    Step,
+    // resume without a debugger attached
+    Detach,
+}
+
+pub struct gdb_session {
+    // thread that stopped and is resumed by c / s
+    pub stop_thread: u32,
+    // thread g / G / m / M apply to
+    pub general_thread: u32,
+    pub signal: u8,
+}
+
+impl gdb_session {
+    pub const fn new() -> Self {
+        Self {
+            stop_thread: 1,
+            general_thread: 1,
+            signal: SIGTRAP,
+        }
+    }
+
+    pub fn stopped(&mut self, thread: u32, signal: u8) {
+        self.stop_thread = thread;
+        self.general_thread = thread;
+        self.signal = signal;
+    }
+}
+
+pub struct reply_buffer {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl reply_buffer {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    // silently truncated at PACKET_SIZE, requests are sized to fit
+    pub fn push(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.len == PACKET_SIZE {
+                return;
+            }
+            self.buf[self.len] = byte;
+            self.len += 1;
+        }
+    }
+
+    pub fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(&[hex_char(byte >> 4), hex_char(byte)]);
+        }
+    }
+
+    // big endian without leading zeros
+    pub fn push_hex_u64(&mut self, value: u64) {
+        let digits = (64 - value.leading_zeros()).div_ceil(4).max(1);
+        for digit in (0..digits).rev() {
+            self.push(&[hex_char((value >> (digit * 4)) as u8)]);
+        }
+    }
+
+    pub fn ok(&mut self) {
+        self.push(b"OK");
+    }
+
+    pub fn error(&mut self, code: u8) {
+        self.push(b"E");
+        self.push_hex(&[code]);
+    }
+}
+
+fn split(args: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let at = args.iter().position(|&byte| byte == separator)?;
+    Some((&args[..at], &args[at + 1..]))
+}
+
+// "addr,len"
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let (address, len) = split(args, b',')?;
+    Some((parse_hex(address)?, parse_hex(len)?))
+}
+
+// -1 means all threads and 0 any, both resolve to the stopped one
+fn parse_thread(session: &gdb_session, args: &[u8]) -> Option<u32> {
+    match args {
+        b"-1" | b"0" => Some(session.stop_thread),
+        _ => u32::try_from(parse_hex(args)?).ok(),
+    }
+}
+
+pub fn stop_reply(session: &gdb_session, reply: &mut reply_buffer) {
+    reply.push(b"T");
+    reply.push_hex(&[session.signal]);
+    reply.push(b"thread:");
+    reply.push_hex_u64(session.stop_thread as u64);
+    reply.push(b";");
+}
+
+// handles one packet payload, reply holds the answer when Reply or Detach is
+// returned
+pub fn dispatch<T: gdb_target + ?Sized>(
+    session: &mut gdb_session,
+    target: &mut T,
+    packet: &[u8],
+    reply: &mut reply_buffer,
+) -> gdb_action {
+    reply.clear();
+    let Some((&command, args)) = packet.split_first() else {
+        return gdb_action::Reply;
+    };
+    match command {
+        b'?' => stop_reply(session, reply),
+        b'g' => read_registers(session, target, reply),
+        b'G' => write_registers(session, target, args, reply),
+        b'p' => read_register(session, target, args, reply),
+        b'P' => write_register(session, target, args, reply),
+        b'm' => read_memory(session, target, args, reply),
+        b'M' => write_memory(session, target, args, reply),
+        b'c' | b's' => {
+            if !args.is_empty() && !set_rip(session, target, args) {
+                reply.error(ERROR_INVALID);
+                return gdb_action::Reply;
+            }
+            return match command {
+                b'c' => gdb_action::Continue,
+                _ => gdb_action::Step,
+            };
+        }
+        b'Z' | b'z' => breakpoint(target, command == b'Z', args, reply),
+        b'H' => set_thread(session, target, args, reply),
+        b'T' => match parse_thread(session, args) {
+            Some(thread) if target.thread_alive(thread) => reply.ok(),
+            _ => reply.error(ERROR_FAILED),
+        },
+        b'q' => query(session, target, args, reply),
+        b'D' => {
+            reply.ok();
+            return gdb_action::Detach;
+        }
+        // nothing to kill, let the guest go
+        b'k' => return gdb_action::Detach,
+        // an empty reply tells gdb the packet is not supported
+        _ => {}
+    }
+    gdb_action::Reply
+}
+
+fn read_registers<T: gdb_target + ?Sized>(
+    session: &gdb_session,
+    target: &mut T,
+    reply: &mut reply_buffer,
+) {
+    let Some(regs) = target.read_registers(session.general_thread) else {
+        return reply.error(ERROR_FAILED);
+    };
+    for (index, value) in regs.values.iter().enumerate() {
+        reply.push_hex(&value.to_le_bytes()[..register_size(index)]);
+    }
+}
+
+// gdb sends its whole register file, anything past the ones described here
+// is ignored
+fn write_registers<T: gdb_target + ?Sized>(
+    session: &gdb_session,
+    target: &mut T,
+    args: &[u8],
+    reply: &mut reply_buffer,
+) {
+    let mut regs = gdb_regs::default();
+    let mut digits = args;
+    for index in 0..GDB_REGISTERS {
+        let size = register_size(index);
+        let mut bytes = [0u8; 8];
+        let Some(value) = digits.get(..size * 2) else {
+            return reply.error(ERROR_INVALID);
+        };
+        if decode_hex(value, &mut bytes[..size]).is_none() {
+            return reply.error(ERROR_INVALID);
+        }
+        regs.values[index] = u64::from_le_bytes(bytes);
+        digits = &digits[size * 2..];
+    }
+    match target.write_registers(session.general_thread, &regs) {
+        true => reply.ok(),
+        false => reply.error(ERROR_FAILED),
+    }
+}
+
+fn read_register<T: gdb_target + ?Sized>(
+    session: &gdb_session,
+    target: &mut T,
+    args: &[u8],
+    reply: &mut reply_buffer,
+) {
+    let Some(index) = parse_hex(args).map(|index| index as usize) else {
+        return reply.error(ERROR_INVALID);
+    };
+    let value = target
+        .read_registers(session.general_thread)
+        .and_then(|regs| regs.values.get(index).copied());
+    match value {
+        Some(value) => reply.push_hex(&value.to_le_bytes()[..register_size(index)]),
+        None => reply.error(ERROR_FAILED),
+    }
+}
+
+fn write_register<T: gdb_target + ?Sized>(
+    session: &gdb_session,
+    target: &mut T,
+    args: &[u8],
+    reply: &mut reply_buffer,
+) {
+    let Some((index, value)) = split(args, b'=') else {
+        return reply.error(ERROR_INVALID);
+    };
+    let Some(index) = parse_hex(index).map(|index| index as usize) else {
+        return reply.error(ERROR_INVALID);
+    };
+    let mut bytes = [0u8; 8];
+    if index >= GDB_REGISTERS || decode_hex(value, &mut bytes[..register_size(index)]).is_none() {
+        return reply.error(ERROR_INVALID);
+    }
+    let Some(mut regs) = target.read_registers(session.general_thread) else {
+        return reply.error(ERROR_FAILED);
+    };
+    regs.values[index] = u64::from_le_bytes(bytes);
+    match target.write_registers(session.general_thread, &regs) {
+        true => reply.ok(),
+        false => reply.error(ERROR_FAILED),
+    }
+}
+
+fn read_memory<T: gdb_target + ?Sized>(
+    session: &gdb_session,
+    target: &mut T,
+    args: &[u8],
+    reply: &mut reply_buffer,
+) {
+    let Some((address, len)) = parse_range(args) else {
+        return reply.error(ERROR_INVALID);
+    };
+    // two hex digits per byte, gdb splits larger reads by PacketSize
+    let mut buf = [0u8; PACKET_SIZE / 2];
+    let buf = &mut buf[..core::cmp::min(len, (PACKET_SIZE / 2) as u64) as usize];
+    match target.read_memory(session.general_thread, address, buf) {
+        true => reply.push_hex(buf),
+        false => reply.error(ERROR_FAULT),
+    }
+}
+
+fn write_memory<T: gdb_target + ?Sized>(
+    session: &gdb_session,
+    target: &mut T,
+    args: &[u8],
+    reply: &mut reply_buffer,
+) {
+    let Some((range, data)) = split(args, b':') else {
+        return reply.error(ERROR_INVALID);
+    };
+    let mut buf = [0u8; PACKET_SIZE / 2];
+    let decoded = decode_hex(data, &mut buf);
+    let Some((address, len)) = parse_range(range).filter(|(_, len)| decoded == Some(*len as usize))
+    else {
+        return reply.error(ERROR_INVALID);
+    };
+    match target.write_memory(session.general_thread, address, &buf[..len as usize]) {
+        true => reply.ok(),
+        false => reply.error(ERROR_FAULT),
+    }
+}
+
+// c addr / s addr resume somewhere else
+fn set_rip<T: gdb_target + ?Sized>(session: &gdb_session, target: &mut T, args: &[u8]) -> bool {
+    let Some(rip) = parse_hex(args) else {
+        return false;
+    };
+    let Some(mut regs) = target.read_registers(session.stop_thread) else {
+        return false;
+    };
+    regs.values[GDB_RIP] = rip;
+    target.write_registers(session.stop_thread, &regs)
+}
+
+// Z type,addr,kind with kind the length in bytes, conditions are not supported
+fn breakpoint<T: gdb_target + ?Sized>(
+    target: &mut T,
+    insert: bool,
+    args: &[u8],
+    reply: &mut reply_buffer,
+) {
+    let args = split(args, b';').map_or(args, |(args, _)| args);
+    let Some((kind, range)) = split(args, b',') else {
+        return reply.error(ERROR_INVALID);
+    };
+    let kind = match kind {
+        b"0" => gdb_breakpoint::Software,
+        b"1" => gdb_breakpoint::Hardware,
+        b"2" => gdb_breakpoint::Write,
+        b"3" => gdb_breakpoint::Read,
+        b"4" => gdb_breakpoint::Access,
+        _ => return,
+    };
+    let Some((address, len)) = parse_range(range) else {
+        return reply.error(ERROR_INVALID);
+    };
+    let done = match insert {
+        true => target.set_breakpoint(kind, address, len),
+        false => target.clear_breakpoint(kind, address, len),
+    };
+    match done {
+        true => reply.ok(),
+        false => reply.error(ERROR_FAILED),
+    }
+}
+
+// Hg selects the thread for register and memory access. everything resumes
+// on c, so Hc is only acknowledged
+fn set_thread<T: gdb_target + ?Sized>(
+    session: &mut gdb_session,
+    target: &mut T,
+    args: &[u8],
+    reply: &mut reply_buffer,
+) {
+    let Some((&operation, thread)) = args.split_first() else {
+        return reply.error(ERROR_INVALID);
+    };
+    match (operation, parse_thread(session, thread)) {
+        (b'g', Some(thread)) if target.thread_alive(thread) => {
+            session.general_thread = thread;
+            reply.ok();
+        }
+        (b'c', Some(_)) => reply.ok(),
+        _ => reply.error(ERROR_FAILED),
+    }
+}
+
+fn query<T: gdb_target + ?Sized>(
+    session: &gdb_session,
+    target: &mut T,
+    args: &[u8],
+    reply: &mut reply_buffer,
+) {
+    let (name, rest) = match args.iter().position(|&byte| byte == b',' || byte == b':') {
+        Some(at) => (&args[..at], &args[at + 1..]),
+        None => (args, &[][..]),
+    };
+    match name {
+        b"Supported" => {
+            reply.push(b"PacketSize=");
+            reply.push_hex_u64(PACKET_SIZE as u64);
+        }
+        b"Attached" => reply.push(b"1"),
+        b"C" => {
+            reply.push(b"QC");
+            reply.push_hex_u64(session.stop_thread as u64);
+        }
+        b"fThreadInfo" => {
+            reply.push(b"m");
+            let mut first = true;
+            for thread in (1..=MAX_THREADS).filter(|thread| target.thread_alive(*thread)) {
+                if !first {
+                    reply.push(b",");
+                }
+                reply.push_hex_u64(thread as u64);
+                first = false;
+            }
+        }
+        // everything fit into the first reply
+        b"sThreadInfo" => reply.push(b"l"),
+        b"ThreadExtraInfo" => {
+            let Some(thread) = parse_hex(rest).filter(|thread| *thread != 0) else {
+                return reply.error(ERROR_INVALID);
+            };
+            reply.push_hex(b"processor ");
+            let processor = thread - 1;
+            let mut digits = [0u8; 20];
+            let mut start = digits.len();
+            let mut value = processor;
+            loop {
+                start -= 1;
+                digits[start] = b'0' + (value % 10) as u8;
+                value /= 10;
+                if value == 0 {
+                    break;
+                }
+            }
+            reply.push_hex(&digits[start..]);
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::vec::Vec;
+
+    // two processors, memory shared between them
+    struct fake_target {
+        regs: [gdb_regs; 2],
+        memory: BTreeMap<u64, u8>,
+        breakpoints: Vec<(gdb_breakpoint, u64, u64)>,
+    }
+
+    impl fake_target {
+        fn new() -> Self {
+            let mut regs = [gdb_regs::default(); 2];
+            for (thread, regs) in regs.iter_mut().enumerate() {
+                for (index, value) in regs.values.iter_mut().enumerate() {
+                    *value = (thread as u64 + 1) << 8 | index as u64;
+                }
+            }
+            let memory = (0x1000..0x1010)
+                .map(|address| (address, address as u8))
+                .collect();
+            Self {
+                regs,
+                memory,
+                breakpoints: Vec::new(),
+            }
+        }
+    }
+
+    impl gdb_target for fake_target {
+        fn thread_alive(&self, thread: u32) -> bool {
+            (1..=2).contains(&thread)
+        }
+
+        fn read_registers(&mut self, thread: u32) -> Option<gdb_regs> {
+            self.regs.get(thread.checked_sub(1)? as usize).copied()
+        }
+
+        fn write_registers(&mut self, thread: u32, regs: &gdb_regs) -> bool {
+            match thread
+                .checked_sub(1)
+                .and_then(|index| self.regs.get_mut(index as usize))
+            {
+                Some(slot) => {
+                    *slot = *regs;
+                    true
+                }
+                None => false,
+            }
+        }
+
+        fn read_memory(&mut self, _thread: u32, address: u64, buf: &mut [u8]) -> bool {
+            for (offset, byte) in buf.iter_mut().enumerate() {
+                match self.memory.get(&(address + offset as u64)) {
+                    Some(value) => *byte = *value,
+                    None => return false,
+                }
+            }
+            true
+        }
+
+        fn write_memory(&mut self, _thread: u32, address: u64, data: &[u8]) -> bool {
+            for (offset, byte) in data.iter().enumerate() {
+                match self.memory.get_mut(&(address + offset as u64)) {
+                    Some(value) => *value = *byte,
+                    None => return false,
+                }
+            }
+            true
+        }
+
+        fn set_breakpoint(&mut self, kind: gdb_breakpoint, address: u64, len: u64) -> bool {
+            self.breakpoints.push((kind, address, len));
+            true
+        }
+
+        fn clear_breakpoint(&mut self, kind: gdb_breakpoint, address: u64, len: u64) -> bool {
+            let before = self.breakpoints.len();
+            self.breakpoints.retain(|bp| *bp != (kind, address, len));
+            self.breakpoints.len() != before
+        }
+    }
+
+    // packets and the replies the stub gives, as gdb would see them
+    fn replay(target: &mut fake_target, session: &[(&str, gdb_action, &str)]) {
+        let mut state = gdb_session::new();
+        let mut reply = reply_buffer::new();
+        for &(packet, action, expected) in session {
+            assert_eq!(
+                dispatch(&mut state, target, packet.as_bytes(), &mut reply),
+                action,
+                "{}",
+                packet
+            );
+            assert_eq!(
+                core::str::from_utf8(reply.as_bytes()).unwrap(),
+                expected,
+                "{}",
+                packet
+            );
+        }
+    }
+
+    fn registers_hex(regs: &gdb_regs) -> std::string::String {
+        let mut reply = reply_buffer::new();
+        for (index, value) in regs.values.iter().enumerate() {
+            reply.push_hex(&value.to_le_bytes()[..register_size(index)]);
+        }
+        std::string::String::from_utf8(reply.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn attach_session() {
+        let mut target = fake_target::new();
+        replay(
+            &mut target,
+            &[
+                (
+                    "qSupported:multiprocess+;swbreak+",
+                    gdb_action::Reply,
+                    "PacketSize=400",
+                ),
+                ("vMustReplyEmpty", gdb_action::Reply, ""),
+                ("Hg0", gdb_action::Reply, "OK"),
+                ("qAttached", gdb_action::Reply, "1"),
+                ("?", gdb_action::Reply, "T05thread:1;"),
+                ("qC", gdb_action::Reply, "QC1"),
+                ("qfThreadInfo", gdb_action::Reply, "m1,2"),
+                ("qsThreadInfo", gdb_action::Reply, "l"),
+                (
+                    "qThreadExtraInfo,2",
+                    gdb_action::Reply,
+                    "70726f636573736f722031",
+                ),
+                ("T2", gdb_action::Reply, "OK"),
+                ("T3", gdb_action::Reply, "E01"),
+            ],
+        );
+    }
+
+    #[test]
+    fn registers() {
+        let mut target = fake_target::new();
+        let thread2 = registers_hex(&target.regs[1]);
+        // rip of thread 2 is 0x210, eflags 0x211 as 32 bits
+        replay(
+            &mut target,
+            &[
+                ("Hg2", gdb_action::Reply, "OK"),
+                ("g", gdb_action::Reply, &thread2),
+                ("p10", gdb_action::Reply, "1002000000000000"),
+                ("p11", gdb_action::Reply, "11020000"),
+                ("P10=efbeadde00000000", gdb_action::Reply, "OK"),
+                ("p10", gdb_action::Reply, "efbeadde00000000"),
+                ("P18=00", gdb_action::Reply, "E16"),
+                ("p18", gdb_action::Reply, "E01"),
+                ("G00", gdb_action::Reply, "E16"),
+            ],
+        );
+        assert_eq!(target.regs[1].values[GDB_RIP], 0xdead_beef);
+        assert_eq!(target.regs[0].values[GDB_RIP], 0x110);
+
+        let mut regs = gdb_regs::default();
+        regs.values[GDB_RSP] = 0xffff_8000_0000_1000;
+        regs.values[GDB_CS] = 0x10;
+        let written = std::format!("G{}", registers_hex(&regs));
+        replay(&mut target, &[(&written, gdb_action::Reply, "OK")]);
+        assert_eq!(target.regs[0], regs);
+    }
+
+    #[test]
+    fn memory() {
+        let mut target = fake_target::new();
+        replay(
+            &mut target,
+            &[
+                ("m1000,4", gdb_action::Reply, "00010203"),
+                ("m100e,4", gdb_action::Reply, "E0e"),
+                ("M1002,2:aabb", gdb_action::Reply, "OK"),
+                ("m1000,4", gdb_action::Reply, "0001aabb"),
+                ("M1002,2:aa", gdb_action::Reply, "E16"),
+                ("M2000,1:00", gdb_action::Reply, "E0e"),
+                ("m1000", gdb_action::Reply, "E16"),
+            ],
+        );
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut target = fake_target::new();
+        replay(
+            &mut target,
+            &[
+                ("Z0,fffff80000001000,1", gdb_action::Reply, "OK"),
+                ("Z2,2000,8", gdb_action::Reply, "OK"),
+                ("Z1,3000,1;X2,0a", gdb_action::Reply, "OK"),
+                ("Z9,3000,1", gdb_action::Reply, ""),
+                ("Z0,1000", gdb_action::Reply, "E16"),
+                ("z2,2000,8", gdb_action::Reply, "OK"),
+                ("z2,2000,8", gdb_action::Reply, "E01"),
+            ],
+        );
+        assert_eq!(
+            target.breakpoints,
+            [
+                (gdb_breakpoint::Software, 0xffff_f800_0000_1000, 1),
+                (gdb_breakpoint::Hardware, 0x3000, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn resuming() {
+        let mut target = fake_target::new();
+        replay(
+            &mut target,
+            &[
+                ("c", gdb_action::Continue, ""),
+                ("s", gdb_action::Step, ""),
+                ("c2000", gdb_action::Continue, ""),
+                ("cxyz", gdb_action::Reply, "E16"),
+                ("D", gdb_action::Detach, "OK"),
+                ("k", gdb_action::Detach, ""),
+            ],
+        );
+        assert_eq!(target.regs[0].values[GDB_RIP], 0x2000);
+    }
+
+    #[test]
+    fn stop_replies_follow_the_stopped_thread() {
+        let mut target = fake_target::new();
+        let mut session = gdb_session::new();
+        let mut reply = reply_buffer::new();
+        session.stopped(2, SIGINT);
+        dispatch(&mut session, &mut target, b"?", &mut reply);
+        assert_eq!(reply.as_bytes(), b"T02thread:2;");
+        // register access goes to the thread that stopped
+        dispatch(&mut session, &mut target, b"p10", &mut reply);
+        assert_eq!(reply.as_bytes(), b"1002000000000000");
+    }
+
+    #[test]
+    fn replies_are_bounded() {
+        let mut reply = reply_buffer::new();
+        reply.push_hex(&[0xab; PACKET_SIZE]);
+        assert_eq!(reply.as_bytes().len(), PACKET_SIZE);
+        reply.clear();
+        reply.push_hex_u64(0);
+        reply.push(b",");
+        reply.push_hex_u64(0x1_0000);
+        assert_eq!(reply.as_bytes(), b"0,10000");
+    }
+}
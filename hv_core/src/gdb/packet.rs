@@ -0,0 +1,270 @@
+// gdb remote serial protocol framing: $payload#checksum, acknowledged with
+// + or -. nothing here touches the hypervisor, recorded sessions can be fed
+// through it byte by byte on the host
+pub const PACKET_SIZE: usize = 1024;
+pub const INTERRUPT: u8 = 0x03;
+const ESCAPE: u8 = b'}';
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum packet_event {
+    // a complete packet with a valid checksum, see packet_reader::payload
+    Packet,
+    // ctrl-c outside of a packet
+    Interrupt,
+    Ack,
+    Nack,
+    BadChecksum,
+    // longer than PACKET_SIZE, the payload is truncated
+    Overflow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum reader_state {
+    Idle,
+    Data,
+    Escape,
+    Checksum,
+    Checksum2,
+}
+
+pub struct packet_reader {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+    state: reader_state,
+    sum: u8,
+    received: u8,
+    overflow: bool,
+}
+
+impl packet_reader {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+            state: reader_state::Idle,
+            sum: 0,
+            received: 0,
+            overflow: false,
+        }
+    }
+
+    // the unescaped payload of the last packet
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn store(&mut self, byte: u8) {
+        match self.len < PACKET_SIZE {
+            true => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+            }
+            false => self.overflow = true,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<packet_event> {
+        match (self.state, byte) {
+            (reader_state::Idle, b'$') | (reader_state::Data, b'$') => {
+                // a new packet start drops whatever was incomplete
+                self.state = reader_state::Data;
+                self.len = 0;
+                self.sum = 0;
+                self.overflow = false;
+            }
+            (reader_state::Idle, b'+') => return Some(packet_event::Ack),
+            (reader_state::Idle, b'-') => return Some(packet_event::Nack),
+            (reader_state::Idle, INTERRUPT) => return Some(packet_event::Interrupt),
+            (reader_state::Idle, _) => {}
+            (reader_state::Data, b'#') => self.state = reader_state::Checksum,
+            (reader_state::Data, ESCAPE) => {
+                self.sum = self.sum.wrapping_add(byte);
+                self.state = reader_state::Escape;
+            }
+            (reader_state::Data, _) => {
+                self.sum = self.sum.wrapping_add(byte);
+                self.store(byte);
+            }
+            (reader_state::Escape, _) => {
+                self.sum = self.sum.wrapping_add(byte);
+                self.store(byte ^ 0x20);
+                self.state = reader_state::Data;
+            }
+            (reader_state::Checksum, _) => {
+                let Some(digit) = hex_digit(byte) else {
+                    self.state = reader_state::Idle;
+                    return Some(packet_event::BadChecksum);
+                };
+                self.received = digit << 4;
+                self.state = reader_state::Checksum2;
+            }
+            (reader_state::Checksum2, _) => {
+                self.state = reader_state::Idle;
+                let Some(digit) = hex_digit(byte) else {
+                    return Some(packet_event::BadChecksum);
+                };
+                return Some(match (self.overflow, self.received | digit == self.sum) {
+                    (true, _) => packet_event::Overflow,
+                    (false, true) => packet_event::Packet,
+                    (false, false) => packet_event::BadChecksum,
+                });
+            }
+        }
+        None
+    }
+}
+
+// frames payload, escaping the bytes that have a meaning in the framing
+pub fn write_packet(payload: &[u8], mut out: impl FnMut(u8)) {
+    let mut sum = 0u8;
+    out(b'$');
+    for &byte in payload {
+        let escaped = match byte {
+            b'$' | b'#' | ESCAPE | b'*' => {
+                out(ESCAPE);
+                sum = sum.wrapping_add(ESCAPE);
+                byte ^ 0x20
+            }
+            _ => byte,
+        };
+        out(escaped);
+        sum = sum.wrapping_add(escaped);
+    }
+    out(b'#');
+    out(HEX_DIGITS[(sum >> 4) as usize]);
+    out(HEX_DIGITS[(sum & 0xf) as usize]);
+}
+
+pub fn hex_digit(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+pub fn hex_char(nibble: u8) -> u8 {
+    HEX_DIGITS[(nibble & 0xf) as usize]
+}
+
+// big endian hex number as used for addresses and lengths, at most 16 digits
+pub fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |value, &byte| {
+        Some(value << 4 | hex_digit(byte)? as u64)
+    })
+}
+
+// pairs of hex digits into out, returns the number of bytes decoded
+pub fn decode_hex(digits: &[u8], out: &mut [u8]) -> Option<usize> {
+    if !digits.len().is_multiple_of(2) || digits.len() / 2 > out.len() {
+        return None;
+    }
+    for (byte, pair) in out.iter_mut().zip(digits.chunks_exact(2)) {
+        *byte = hex_digit(pair[0])? << 4 | hex_digit(pair[1])?;
+    }
+    Some(digits.len() / 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn feed(reader: &mut packet_reader, bytes: &[u8]) -> Vec<packet_event> {
+        bytes.iter().filter_map(|&byte| reader.push(byte)).collect()
+    }
+
+    fn framed(payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_packet(payload, |byte| out.push(byte));
+        out
+    }
+
+    #[test]
+    fn reads_a_packet() {
+        let mut reader = packet_reader::new();
+        assert_eq!(feed(&mut reader, b"$g#67"), [packet_event::Packet]);
+        assert_eq!(reader.payload(), b"g");
+        assert_eq!(feed(&mut reader, b"$m1000,4#8e"), [packet_event::Packet]);
+        assert_eq!(reader.payload(), b"m1000,4");
+    }
+
+    #[test]
+    fn acks_and_interrupts_between_packets() {
+        let mut reader = packet_reader::new();
+        assert_eq!(
+            feed(&mut reader, b"+-\x03 $?#3f+"),
+            [
+                packet_event::Ack,
+                packet_event::Nack,
+                packet_event::Interrupt,
+                packet_event::Packet,
+                packet_event::Ack
+            ]
+        );
+        // inside a packet they're payload
+        assert_eq!(feed(&mut reader, b"$+-#58"), [packet_event::Packet]);
+        assert_eq!(reader.payload(), b"+-");
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut reader = packet_reader::new();
+        assert_eq!(feed(&mut reader, b"$g#68"), [packet_event::BadChecksum]);
+        assert_eq!(feed(&mut reader, b"$g#x7"), [packet_event::BadChecksum]);
+        // upper case digits are accepted
+        assert_eq!(feed(&mut reader, b"$m1000,4#8E"), [packet_event::Packet]);
+    }
+
+    #[test]
+    fn restarts_on_a_new_packet() {
+        let mut reader = packet_reader::new();
+        assert_eq!(feed(&mut reader, b"$m10$g#67"), [packet_event::Packet]);
+        assert_eq!(reader.payload(), b"g");
+    }
+
+    #[test]
+    fn escapes_round_trip() {
+        let payload = b"a$b#c}d*e";
+        let out = framed(payload);
+        assert_eq!(&out[..out.len() - 2], b"$a}\x04b}\x03c}]d}\x0ae#");
+        let mut reader = packet_reader::new();
+        assert_eq!(feed(&mut reader, &out), [packet_event::Packet]);
+        assert_eq!(reader.payload(), payload);
+    }
+
+    #[test]
+    fn overflow_is_reported() {
+        let payload = [b'a'; PACKET_SIZE + 1];
+        let mut reader = packet_reader::new();
+        assert_eq!(
+            feed(&mut reader, &framed(&payload)),
+            [packet_event::Overflow]
+        );
+        assert_eq!(reader.payload().len(), PACKET_SIZE);
+        // the next one is fine again
+        assert_eq!(feed(&mut reader, b"$g#67"), [packet_event::Packet]);
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(parse_hex(b"fffff80012345678"), Some(0xffff_f800_1234_5678));
+        assert_eq!(parse_hex(b"A"), Some(0xa));
+        assert_eq!(parse_hex(b""), None);
+        assert_eq!(parse_hex(b"10000000000000000"), None);
+        assert_eq!(parse_hex(b"1g"), None);
+
+        let mut out = [0u8; 3];
+        assert_eq!(decode_hex(b"00ff7f", &mut out), Some(3));
+        assert_eq!(out, [0x00, 0xff, 0x7f]);
+        assert_eq!(decode_hex(b"0", &mut out), None);
+        assert_eq!(decode_hex(b"0011223344", &mut out), None);
+        assert_eq!(decode_hex(b"zz", &mut out), None);
+        assert_eq!(hex_char(0x1c), b'c');
+    }
+}
@@ -0,0 +1,625 @@
+// guest virtual to guest physical translation by walking the guest page tables
+// see AMD manual '5.3 Long-Mode Page Translation'
+pub const CR0_WP: u64 = 1 << 16;
+pub const CR0_PG: u64 = 1 << 31;
+pub const CR4_PAE: u64 = 1 << 5;
+pub const CR4_LA57: u64 = 1 << 12;
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_LARGE: u64 = 1 << 7;
+const PTE_NX: u64 = 1 << 63;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+const EFER_NXE: u64 = 1 << 11;
+
+// #PF error code bits
+pub const PF_PRESENT: u32 = 1 << 0;
+pub const PF_WRITE: u32 = 1 << 1;
+pub const PF_USER: u32 = 1 << 2;
+pub const PF_RSVD: u32 = 1 << 3;
+pub const PF_FETCH: u32 = 1 << 4;
+
+const PAGE_MASK: u64 = 0xfff;
+
+// anything that can access guest physical memory, accesses never cross a
+// page boundary
+pub trait phys_mem {
+    fn read(&self, pa: u64, buf: &mut [u8]) -> Option<()>;
+
+    fn write(&self, pa: u64, data: &[u8]) -> Option<()>;
+
+    fn read_u64(&self, pa: u64) -> Option<u64> {
+        let mut bytes = [0u8; 8];
+        self.read(pa, &mut bytes)?;
+        Some(u64::from_le_bytes(bytes))
+    }
+}
+
+// plain data without padding that every bit pattern is a valid value of, what
+// can be copied between guest memory and a value byte by byte. implementors
+// must be repr(C) or primitive, have no padding and no invalid bit patterns
+pub unsafe trait pod: Copy {}
+
+macro_rules! impl_pod {
+    ($($t:ty),*) => { $(unsafe impl pod for $t {})* };
+}
+impl_pod!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize
+);
+unsafe impl<T: pod, const N: usize> pod for [T; N] {}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct paging_ctx {
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub efer: u64,
+}
+
+impl paging_ctx {
+    // same address space, different root
+    pub fn with_cr3(self, cr3: u64) -> Self {
+        Self { cr3, ..self }
+    }
+
+    fn levels(&self) -> u32 {
+        if self.cr4 & CR4_LA57 != 0 { 5 } else { 4 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct access {
+    pub write: bool,
+    pub user: bool,
+    pub execute: bool,
+}
+
+impl access {
+    pub const READ: Self = Self {
+        write: false,
+        user: false,
+        execute: false,
+    };
+    pub const WRITE: Self = Self {
+        write: true,
+        user: false,
+        execute: false,
+    };
+    pub const EXECUTE: Self = Self {
+        write: false,
+        user: false,
+        execute: true,
+    };
+
+    fn error_code(&self) -> u32 {
+        let mut error_code = 0;
+        if self.write {
+            error_code |= PF_WRITE;
+        }
+        if self.user {
+            error_code |= PF_USER;
+        }
+        if self.execute {
+            error_code |= PF_FETCH;
+        }
+        error_code
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct page_fault {
+    pub address: u64,
+    pub error_code: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum walk_error {
+    PageFault(page_fault),
+    // address is not canonical for the paging mode, the guest would see #GP
+    NonCanonical(u64),
+    // a paging structure could not be read at this physical address
+    PhysRead(u64),
+}
+
+pub fn is_canonical(gva: u64, levels: u32) -> bool {
+    let bits = 12 + 9 * levels;
+    let shift = 64 - bits;
+    (((gva << shift) as i64) >> shift) as u64 == gva
+}
+
+// translate gva to gpa, checking the permissions the access would need.
+// accessed/dirty bits are not updated
+pub fn translate<M: phys_mem + ?Sized>(
+    mem: &M,
+    ctx: &paging_ctx,
+    gva: u64,
+    access: access,
+) -> Result<u64, walk_error> {
+    if ctx.cr0 & CR0_PG == 0 {
+        return Ok(gva);
+    }
+    // only long mode paging is supported, which is all a 64-bit guest runs with
+    let levels = ctx.levels();
+    if !is_canonical(gva, levels) {
+        return Err(walk_error::NonCanonical(gva));
+    }
+
+    let nx_enabled = ctx.efer & EFER_NXE != 0;
+    let fault = |extra: u32| {
+        walk_error::PageFault(page_fault {
+            address: gva,
+            error_code: access.error_code() | extra,
+        })
+    };
+
+    let mut table = ctx.cr3 & PTE_ADDR_MASK;
+    let mut writable = true;
+    let mut user = true;
+    let mut executable = true;
+
+    for level in (1..=levels).rev() {
+        let shift = 12 + 9 * (level - 1);
+        let index = (gva >> shift) & 0x1ff;
+        let entry_pa = table + index * 8;
+        let entry = mem
+            .read_u64(entry_pa)
+            .ok_or(walk_error::PhysRead(entry_pa))?;
+
+        if entry & PTE_PRESENT == 0 {
+            return Err(fault(0));
+        }
+        if entry & PTE_NX != 0 && !nx_enabled {
+            return Err(fault(PF_PRESENT | PF_RSVD));
+        }
+
+        writable &= entry & PTE_WRITE != 0;
+        user &= entry & PTE_USER != 0;
+        executable &= entry & PTE_NX == 0;
+
+        // PS is only valid in PDPTEs (1GB) and PDEs (2MB)
+        let large = entry & PTE_LARGE != 0;
+        if large && level > 3 {
+            return Err(fault(PF_PRESENT | PF_RSVD));
+        }
+
+        if level == 1 || large {
+            if access.user && !user {
+                return Err(fault(PF_PRESENT));
+            }
+            if access.write && !writable && (access.user || ctx.cr0 & CR0_WP != 0) {
+                return Err(fault(PF_PRESENT));
+            }
+            if access.execute && nx_enabled && !executable {
+                return Err(fault(PF_PRESENT));
+            }
+
+            let offset_mask = (1u64 << shift) - 1;
+            let frame = entry & PTE_ADDR_MASK & !offset_mask;
+            return Ok(frame | (gva & offset_mask));
+        }
+
+        table = entry & PTE_ADDR_MASK;
+    }
+    unreachable!()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum guest_mem_error {
+    Walk(walk_error),
+    // no terminating nul within the destination buffer
+    Unterminated,
+}
+
+impl From<walk_error> for guest_mem_error {
+    fn from(error: walk_error) -> Self {
+        guest_mem_error::Walk(error)
+    }
+}
+
+// splits [gva, gva + len) at page boundaries and calls f with the translated
+// gpa, the offset into the buffer and the chunk length
+pub fn for_each_page<M: phys_mem + ?Sized>(
+    mem: &M,
+    ctx: &paging_ctx,
+    gva: u64,
+    len: usize,
+    access: access,
+    mut f: impl FnMut(u64, usize, usize) -> Result<bool, walk_error>,
+) -> Result<(), walk_error> {
+    let mut done = 0;
+    while done < len {
+        let va = gva.wrapping_add(done as u64);
+        let chunk = core::cmp::min(len - done, (PAGE_MASK + 1 - (va & PAGE_MASK)) as usize);
+        let gpa = translate(mem, ctx, va, access)?;
+        if !f(gpa, done, chunk)? {
+            break;
+        }
+        done += chunk;
+    }
+    Ok(())
+}
+
+pub fn read_virt<M: phys_mem + ?Sized>(
+    mem: &M,
+    ctx: &paging_ctx,
+    gva: u64,
+    buf: &mut [u8],
+    access: access,
+) -> Result<(), walk_error> {
+    for_each_page(mem, ctx, gva, buf.len(), access, |gpa, offset, len| {
+        mem.read(gpa, &mut buf[offset..offset + len])
+            .ok_or(walk_error::PhysRead(gpa))?;
+        Ok(true)
+    })
+}
+
+pub fn write_virt<M: phys_mem + ?Sized>(
+    mem: &M,
+    ctx: &paging_ctx,
+    gva: u64,
+    data: &[u8],
+    access: access,
+) -> Result<(), walk_error> {
+    // translate every page first so a fault never leaves a partial write behind
+    for_each_page(mem, ctx, gva, data.len(), access, |_, _, _| Ok(true))?;
+    for_each_page(mem, ctx, gva, data.len(), access, |gpa, offset, len| {
+        mem.write(gpa, &data[offset..offset + len])
+            .ok_or(walk_error::PhysRead(gpa))?;
+        Ok(true)
+    })
+}
+
+// copies a nul terminated string into buf and returns its length without the nul
+pub fn read_virt_string<M: phys_mem + ?Sized>(
+    mem: &M,
+    ctx: &paging_ctx,
+    gva: u64,
+    buf: &mut [u8],
+    access: access,
+) -> Result<usize, guest_mem_error> {
+    let mut length = None;
+    for_each_page(mem, ctx, gva, buf.len(), access, |gpa, offset, len| {
+        let chunk = &mut buf[offset..offset + len];
+        mem.read(gpa, chunk).ok_or(walk_error::PhysRead(gpa))?;
+        if let Some(nul) = chunk.iter().position(|&b| b == 0) {
+            length = Some(offset + nul);
+            return Ok(false);
+        }
+        Ok(true)
+    })?;
+    length.ok_or(guest_mem_error::Unterminated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    const PML4: u64 = 0x1000;
+    const RW: u64 = PTE_PRESENT | PTE_WRITE;
+    const URW: u64 = PTE_PRESENT | PTE_WRITE | PTE_USER;
+
+    // physical memory with pages allocated on first write, reads of pages
+    // that were never written fail
+    struct synthetic_mem {
+        pages: RefCell<BTreeMap<u64, Box<[u8; 4096]>>>,
+        next_table: RefCell<u64>,
+    }
+
+    impl synthetic_mem {
+        fn new() -> Self {
+            let mem = Self {
+                pages: RefCell::new(BTreeMap::new()),
+                next_table: RefCell::new(PML4 + 0x1000),
+            };
+            mem.write(PML4, &[0; 8]).unwrap();
+            mem
+        }
+
+        fn entry(&self, pa: u64) -> u64 {
+            self.read_u64(pa).unwrap_or(0)
+        }
+
+        fn set_entry(&self, pa: u64, value: u64) {
+            self.write(pa, &value.to_le_bytes()).unwrap();
+        }
+
+        fn new_table(&self) -> u64 {
+            let mut next = self.next_table.borrow_mut();
+            let table = *next;
+            *next += 0x1000;
+            self.write(table, &[0; 8]).unwrap();
+            table
+        }
+
+        // maps gva to pa with a leaf at level (1 for 4k pages, 2 for 2m, 3
+        // for 1g), intermediate entries are user writable
+        fn map(&self, levels: u32, gva: u64, pa: u64, leaf_level: u32, flags: u64) {
+            let mut table = PML4;
+            for level in (leaf_level..=levels).rev() {
+                let index = (gva >> (12 + 9 * (level - 1))) & 0x1ff;
+                let entry_pa = table + index * 8;
+                if level == leaf_level {
+                    let large = if level > 1 { PTE_LARGE } else { 0 };
+                    return self.set_entry(entry_pa, pa | flags | large);
+                }
+                let entry = self.entry(entry_pa);
+                table = match entry & PTE_PRESENT {
+                    0 => {
+                        let next = self.new_table();
+                        self.set_entry(entry_pa, next | URW);
+                        next
+                    }
+                    _ => entry & PTE_ADDR_MASK,
+                };
+            }
+        }
+
+        // the leaf entry of gva in 4 level paging
+        fn leaf(&self, gva: u64) -> u64 {
+            let mut table = PML4;
+            for level in (2..=4).rev() {
+                let index = (gva >> (12 + 9 * (level - 1))) & 0x1ff;
+                table = self.entry(table + index * 8) & PTE_ADDR_MASK;
+            }
+            table + ((gva >> 12) & 0x1ff) * 8
+        }
+    }
+
+    impl phys_mem for synthetic_mem {
+        fn read(&self, pa: u64, buf: &mut [u8]) -> Option<()> {
+            let pages = self.pages.borrow();
+            let page = pages.get(&(pa & !PAGE_MASK))?;
+            let offset = (pa & PAGE_MASK) as usize;
+            buf.copy_from_slice(&page[offset..offset + buf.len()]);
+            Some(())
+        }
+
+        fn write(&self, pa: u64, data: &[u8]) -> Option<()> {
+            let mut pages = self.pages.borrow_mut();
+            let page = pages
+                .entry(pa & !PAGE_MASK)
+                .or_insert_with(|| Box::new([0; 4096]));
+            let offset = (pa & PAGE_MASK) as usize;
+            page[offset..offset + data.len()].copy_from_slice(data);
+            Some(())
+        }
+    }
+
+    fn long_mode() -> paging_ctx {
+        paging_ctx {
+            cr0: CR0_PG | CR0_WP,
+            cr3: PML4,
+            cr4: CR4_PAE,
+            efer: EFER_NXE,
+        }
+    }
+
+    fn fault(address: u64, error_code: u32) -> Result<u64, walk_error> {
+        Err(walk_error::PageFault(page_fault {
+            address,
+            error_code,
+        }))
+    }
+
+    #[test]
+    fn paging_off_is_identity() {
+        let mem = synthetic_mem::new();
+        let ctx = paging_ctx::default();
+        assert_eq!(
+            translate(&mem, &ctx, 0x1234_5678, access::WRITE),
+            Ok(0x1234_5678)
+        );
+    }
+
+    #[test]
+    fn page_sizes() {
+        let mem = synthetic_mem::new();
+        let ctx = long_mode();
+        mem.map(4, 0xffff_f800_0000_1000, 0x7_3000, 1, RW);
+        mem.map(4, 0x4000_0000, 0x8000_0000, 3, RW);
+        mem.map(4, 0x20_0000, 0x60_0000, 2, RW);
+
+        let read = |gva| translate(&mem, &ctx, gva, access::READ);
+        assert_eq!(read(0xffff_f800_0000_1abc), Ok(0x7_3abc));
+        assert_eq!(read(0x4123_4567), Ok(0x8123_4567));
+        assert_eq!(read(0x2f_fff8), Ok(0x6f_fff8));
+    }
+
+    #[test]
+    fn not_present() {
+        let mem = synthetic_mem::new();
+        let ctx = long_mode();
+        assert_eq!(
+            translate(&mem, &ctx, 0x5000, access::WRITE),
+            fault(0x5000, PF_WRITE)
+        );
+        // present directories, missing pte
+        mem.map(4, 0x6000, 0x9000, 1, RW);
+        assert_eq!(
+            translate(&mem, &ctx, 0x7000, access::READ),
+            fault(0x7000, 0)
+        );
+    }
+
+    #[test]
+    fn user_and_write_permissions() {
+        let mem = synthetic_mem::new();
+        let mut ctx = long_mode();
+        mem.map(4, 0x1000, 0x1_1000, 1, PTE_PRESENT);
+        mem.map(4, 0x2000, 0x1_2000, 1, PTE_PRESENT | PTE_USER);
+
+        let user_read = access {
+            user: true,
+            ..access::READ
+        };
+        let user_write = access {
+            user: true,
+            ..access::WRITE
+        };
+        assert_eq!(
+            translate(&mem, &ctx, 0x1000, user_read),
+            fault(0x1000, PF_PRESENT | PF_USER)
+        );
+        assert_eq!(translate(&mem, &ctx, 0x2000, user_read), Ok(0x1_2000));
+        assert_eq!(
+            translate(&mem, &ctx, 0x2000, user_write),
+            fault(0x2000, PF_PRESENT | PF_WRITE | PF_USER)
+        );
+
+        // supervisor writes to read only pages only fault with cr0.wp
+        assert_eq!(
+            translate(&mem, &ctx, 0x1000, access::WRITE),
+            fault(0x1000, PF_PRESENT | PF_WRITE)
+        );
+        ctx.cr0 &= !CR0_WP;
+        assert_eq!(translate(&mem, &ctx, 0x1000, access::WRITE), Ok(0x1_1000));
+        assert_eq!(
+            translate(&mem, &ctx, 0x2000, user_write),
+            fault(0x2000, PF_PRESENT | PF_WRITE | PF_USER)
+        );
+    }
+
+    #[test]
+    fn permissions_combine_across_levels() {
+        let mem = synthetic_mem::new();
+        let ctx = long_mode();
+        mem.map(4, 0x3000, 0x1_3000, 1, URW);
+        // take write away in the pml4e only
+        mem.set_entry(PML4, mem.entry(PML4) & !PTE_WRITE);
+        assert_eq!(
+            translate(&mem, &ctx, 0x3000, access::WRITE),
+            fault(0x3000, PF_PRESENT | PF_WRITE)
+        );
+        assert_eq!(translate(&mem, &ctx, 0x3000, access::READ), Ok(0x1_3000));
+    }
+
+    #[test]
+    fn no_execute() {
+        let mem = synthetic_mem::new();
+        let mut ctx = long_mode();
+        mem.map(4, 0x4000, 0x1_4000, 1, RW | PTE_NX);
+        assert_eq!(
+            translate(&mem, &ctx, 0x4000, access::EXECUTE),
+            fault(0x4000, PF_PRESENT | PF_FETCH)
+        );
+        assert_eq!(translate(&mem, &ctx, 0x4000, access::READ), Ok(0x1_4000));
+        // nx is a reserved bit without efer.nxe
+        ctx.efer = 0;
+        assert_eq!(
+            translate(&mem, &ctx, 0x4000, access::READ),
+            fault(0x4000, PF_PRESENT | PF_RSVD)
+        );
+    }
+
+    #[test]
+    fn large_pml4e_is_reserved() {
+        let mem = synthetic_mem::new();
+        let ctx = long_mode();
+        mem.set_entry(PML4, 0x40_0000 | RW | PTE_LARGE);
+        assert_eq!(
+            translate(&mem, &ctx, 0x1000, access::READ),
+            fault(0x1000, PF_PRESENT | PF_RSVD)
+        );
+    }
+
+    #[test]
+    fn canonical_addresses() {
+        let mem = synthetic_mem::new();
+        let mut ctx = long_mode();
+        let gva = 0x00ff_8000_0000_0000;
+        assert_eq!(
+            translate(&mem, &ctx, gva, access::READ),
+            Err(walk_error::NonCanonical(gva))
+        );
+        // five levels make it canonical
+        ctx.cr4 |= CR4_LA57;
+        mem.map(5, gva, 0x2_0000, 1, RW);
+        assert_eq!(translate(&mem, &ctx, gva + 8, access::READ), Ok(0x2_0008));
+    }
+
+    #[test]
+    fn unreadable_table() {
+        let mem = synthetic_mem::new();
+        let ctx = long_mode();
+        mem.set_entry(PML4, 0xdead_0000 | RW);
+        assert_eq!(
+            translate(&mem, &ctx, 0x1000, access::READ),
+            Err(walk_error::PhysRead(0xdead_0000))
+        );
+    }
+
+    #[test]
+    fn accesses_split_at_pages() {
+        let mem = synthetic_mem::new();
+        let ctx = long_mode();
+        // two virtually adjacent pages, physically apart
+        mem.map(4, 0x10000, 0x5_0000, 1, RW);
+        mem.map(4, 0x11000, 0x3_0000, 1, RW);
+        write_virt(&mem, &ctx, 0x10ffc, b"abcdefgh", access::WRITE).unwrap();
+
+        let mut buf = [0u8; 4];
+        mem.read(0x5_0ffc, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+        mem.read(0x3_0000, &mut buf).unwrap();
+        assert_eq!(&buf, b"efgh");
+
+        let mut buf = [0u8; 8];
+        read_virt(&mem, &ctx, 0x10ffc, &mut buf, access::READ).unwrap();
+        assert_eq!(&buf, b"abcdefgh");
+    }
+
+    #[test]
+    fn faulting_write_changes_nothing() {
+        let mem = synthetic_mem::new();
+        let ctx = long_mode();
+        mem.map(4, 0x10000, 0x5_0000, 1, RW);
+        mem.map(4, 0x11000, 0x3_0000, 1, PTE_PRESENT);
+        mem.write(0x5_0000, &[0; 8]).unwrap();
+        assert_eq!(
+            write_virt(&mem, &ctx, 0x10ffe, b"abcd", access::WRITE),
+            Err(walk_error::PageFault(page_fault {
+                address: 0x11000,
+                error_code: PF_PRESENT | PF_WRITE,
+            }))
+        );
+        let mut buf = [0xffu8; 2];
+        mem.read(0x5_0ffe, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0]);
+    }
+
+    #[test]
+    fn strings() {
+        let mem = synthetic_mem::new();
+        let ctx = long_mode();
+        mem.map(4, 0x10000, 0x5_0000, 1, RW);
+        mem.map(4, 0x11000, 0x3_0000, 1, RW);
+        write_virt(&mem, &ctx, 0x10ffd, b"hello\0", access::WRITE).unwrap();
+
+        let mut buf = [0u8; 16];
+        let len = read_virt_string(&mem, &ctx, 0x10ffd, &mut buf, access::READ);
+        assert_eq!(len, Ok(5));
+        assert_eq!(&buf[..5], b"hello");
+
+        let mut short = [0u8; 4];
+        assert_eq!(
+            read_virt_string(&mem, &ctx, 0x10ffd, &mut short, access::READ),
+            Err(guest_mem_error::Unterminated)
+        );
+    }
+
+    #[test]
+    fn accessed_and_dirty_untouched() {
+        let mem = synthetic_mem::new();
+        let ctx = long_mode();
+        mem.map(4, 0x8000, 0x1_8000, 1, RW);
+        let leaf = mem.leaf(0x8000);
+        let before = mem.entry(leaf);
+        write_virt(&mem, &ctx, 0x8000, &[1], access::WRITE).unwrap();
+        assert_eq!(mem.entry(leaf), before);
+    }
+}
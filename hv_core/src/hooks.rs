@@ -0,0 +1,167 @@
+// fixed size tables of callbacks. the exit handler walks them without a lock
+// while normal context registers and unregisters, a walk never waits
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+const FREE: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+struct hook_slot<F> {
+    state: AtomicU8,
+    // walks looking at the slot, a new hook is only written once they left
+    readers: AtomicU32,
+    hook: UnsafeCell<Option<F>>,
+}
+
+pub struct hook_table<F, const N: usize> {
+    slots: [hook_slot<F>; N],
+}
+
+unsafe impl<F: Send, const N: usize> Sync for hook_table<F, N> {}
+
+impl<F: Copy, const N: usize> hook_table<F, N> {
+    pub const fn new() -> Self {
+        Self {
+            slots: [const {
+                hook_slot {
+                    state: AtomicU8::new(FREE),
+                    readers: AtomicU32::new(0),
+                    hook: UnsafeCell::new(None),
+                }
+            }; N],
+        }
+    }
+
+    // returns the slot to unregister with, None if all slots are taken
+    pub fn register(&self, hook: F) -> Option<usize> {
+        let index = self.slots.iter().position(|slot| {
+            slot.state
+                .compare_exchange(FREE, WRITING, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+        })?;
+        let slot = &self.slots[index];
+        // a walk can still be copying out the hook that was here before
+        while slot.readers.load(Ordering::SeqCst) != 0 {
+            spin_loop();
+        }
+        unsafe { *slot.hook.get() = Some(hook) };
+        slot.state.store(READY, Ordering::Release);
+        Some(index)
+    }
+
+    // walks that already picked the hook up still call it
+    pub fn unregister(&self, index: usize) {
+        if let Some(slot) = self.slots.get(index) {
+            let _ = slot
+                .state
+                .compare_exchange(READY, FREE, Ordering::AcqRel, Ordering::Relaxed);
+        }
+    }
+
+    fn get(slot: &hook_slot<F>) -> Option<F> {
+        slot.readers.fetch_add(1, Ordering::SeqCst);
+        let hook = match slot.state.load(Ordering::SeqCst) {
+            READY => unsafe { *slot.hook.get() },
+            _ => None,
+        };
+        slot.readers.fetch_sub(1, Ordering::Release);
+        hook
+    }
+
+    // the registered hooks in slot order
+    pub fn iter(&self) -> impl Iterator<Item = F> + '_ {
+        self.slots.iter().filter_map(Self::get)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::vec::Vec;
+
+    type hook = fn(u64) -> u64;
+
+    fn double(x: u64) -> u64 {
+        x * 2
+    }
+
+    fn square(x: u64) -> u64 {
+        x * x
+    }
+
+    fn called(table: &hook_table<hook, 4>, x: u64) -> Vec<u64> {
+        table.iter().map(|hook| hook(x)).collect()
+    }
+
+    #[test]
+    fn registers_into_free_slots() {
+        let table = hook_table::<hook, 4>::new();
+        assert!(called(&table, 3).is_empty());
+        assert_eq!(table.register(double), Some(0));
+        assert_eq!(table.register(square), Some(1));
+        assert_eq!(called(&table, 3), [6, 9]);
+
+        table.unregister(0);
+        assert_eq!(called(&table, 3), [9]);
+        assert_eq!(table.register(square), Some(0));
+        assert_eq!(called(&table, 3), [9, 9]);
+    }
+
+    #[test]
+    fn full_and_unknown_slots() {
+        let table = hook_table::<hook, 4>::new();
+        for slot in 0..4 {
+            assert_eq!(table.register(double), Some(slot));
+        }
+        assert_eq!(table.register(square), None);
+        table.unregister(4);
+        table.unregister(usize::MAX);
+        assert_eq!(called(&table, 1), [2; 4]);
+    }
+
+    #[test]
+    fn unregistering_twice() {
+        let table = hook_table::<hook, 4>::new();
+        let slot = table.register(double).unwrap();
+        table.unregister(slot);
+        table.unregister(slot);
+        assert_eq!(table.register(square), Some(slot));
+        assert_eq!(called(&table, 4), [16]);
+    }
+
+    #[test]
+    fn walks_while_hooks_change() {
+        static TABLE: hook_table<hook, 4> = hook_table::new();
+        let stop = AtomicBool::new(false);
+        thread::scope(|scope| {
+            for _ in 0..3 {
+                let stop = &stop;
+                scope.spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        // only ever a whole hook
+                        for result in called(&TABLE, 5) {
+                            assert!(result == 10 || result == 25);
+                        }
+                        thread::yield_now();
+                    }
+                });
+            }
+            for round in 0..2000 {
+                let hook: hook = match round % 2 {
+                    0 => double,
+                    _ => square,
+                };
+                let slot = TABLE.register(hook).unwrap();
+                if round % 3 == 0 {
+                    thread::yield_now();
+                }
+                TABLE.unregister(slot);
+            }
+            stop.store(true, Ordering::Relaxed);
+        });
+    }
+}
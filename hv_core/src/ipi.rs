@@ -0,0 +1,284 @@
+// the rendezvous behind hypervisor ipis, how cpus are signalled and what
+// runs is up to the driver's ipi.rs. cpus are indices below MAX_PROCESSORS
+use crate::MAX_PROCESSORS;
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+pub struct rendezvous<F> {
+    busy: AtomicBool,
+    online: AtomicU64,
+    generation: AtomicU64,
+    // written before the generation is bumped and not again until every cpu
+    // ran it
+    callback: UnsafeCell<Option<F>>,
+    context: AtomicU64,
+    // last generation each cpu ran
+    served: [AtomicU64; MAX_PROCESSORS],
+    // signalled and the signal hasn't arrived yet
+    in_flight: [AtomicBool; MAX_PROCESSORS],
+}
+
+unsafe impl<F: Send> Sync for rendezvous<F> {}
+
+impl<F: Copy> rendezvous<F> {
+    pub const fn new() -> Self {
+        Self {
+            busy: AtomicBool::new(false),
+            online: AtomicU64::new(0),
+            generation: AtomicU64::new(0),
+            callback: UnsafeCell::new(None),
+            context: AtomicU64::new(0),
+            served: [const { AtomicU64::new(0) }; MAX_PROCESSORS],
+            in_flight: [const { AtomicBool::new(false) }; MAX_PROCESSORS],
+        }
+    }
+
+    fn lock(&self, cpu: usize, run: &mut impl FnMut(F, u64)) {
+        while self
+            .busy
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.serve(cpu, &mut *run);
+            spin_loop();
+        }
+    }
+
+    fn unlock(&self) {
+        self.busy.store(false, Ordering::Release);
+    }
+
+    // runs the current request if cpu hasn't yet, true if it did
+    pub fn serve(&self, cpu: usize, mut run: impl FnMut(F, u64)) -> bool {
+        let generation = self.generation.load(Ordering::Acquire);
+        if self.served[cpu].load(Ordering::Relaxed) >= generation {
+            return false;
+        }
+        if let Some(callback) = unsafe { *self.callback.get() } {
+            run(callback, self.context.load(Ordering::Relaxed));
+        }
+        self.served[cpu].store(generation, Ordering::Release);
+        true
+    }
+
+    // the signal sent to cpu arrived, false for signals that weren't ours
+    pub fn signalled(&self, cpu: usize) -> bool {
+        self.in_flight[cpu].swap(false, Ordering::AcqRel)
+    }
+
+    // a cpu only leaves between broadcasts, none waits for it afterwards
+    pub fn set_online(&self, cpu: usize, online: bool, mut run: impl FnMut(F, u64)) {
+        self.lock(cpu, &mut run);
+        // requests from before it came online aren't its business
+        self.served[cpu].store(self.generation.load(Ordering::Relaxed), Ordering::Relaxed);
+        match online {
+            true => self.online.fetch_or(1 << cpu, Ordering::AcqRel),
+            false => self.online.fetch_and(!(1 << cpu), Ordering::AcqRel),
+        };
+        self.unlock();
+    }
+
+    fn signal_others(&self, cpu: usize, signal: &mut impl FnMut(usize)) -> u64 {
+        let targets = self.online.load(Ordering::Acquire) & !(1 << cpu);
+        for target in (0..MAX_PROCESSORS).filter(|t| targets & 1 << t != 0) {
+            if !self.in_flight[target].swap(true, Ordering::AcqRel) {
+                signal(target);
+            }
+        }
+        targets
+    }
+
+    // runs callback on cpu and every online cpu, returns once all did
+    pub fn broadcast(
+        &self,
+        cpu: usize,
+        callback: F,
+        context: u64,
+        mut signal: impl FnMut(usize),
+        mut run: impl FnMut(F, u64),
+    ) {
+        self.lock(cpu, &mut run);
+        unsafe { *self.callback.get() = Some(callback) };
+        self.context.store(context, Ordering::Relaxed);
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+
+        let targets = self.signal_others(cpu, &mut signal);
+        run(callback, context);
+        self.served[cpu].store(generation, Ordering::Relaxed);
+        for target in (0..MAX_PROCESSORS).filter(|t| targets & 1 << t != 0) {
+            while self.served[target].load(Ordering::Acquire) < generation {
+                spin_loop();
+            }
+        }
+        self.unlock();
+    }
+
+    // signals every other cpu without a request, they only exit
+    pub fn kick(&self, cpu: usize, mut signal: impl FnMut(usize)) {
+        self.signal_others(cpu, &mut signal);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::vec::Vec;
+
+    // a cpu's signal line, the nmi the driver sends
+    struct signal_lines([AtomicBool; MAX_PROCESSORS]);
+
+    impl signal_lines {
+        fn new() -> Self {
+            Self([const { AtomicBool::new(false) }; MAX_PROCESSORS])
+        }
+
+        fn send(&self, cpu: usize) {
+            assert!(
+                !self.0[cpu].swap(true, Ordering::AcqRel),
+                "second signal to {}",
+                cpu
+            );
+        }
+
+        // what the nmi handler does when the signal arrives
+        fn take(&self, rendezvous: &rendezvous<usize>, cpu: usize, run: impl FnMut(usize, u64)) {
+            if self.0[cpu].swap(false, Ordering::AcqRel) {
+                assert!(rendezvous.signalled(cpu));
+                rendezvous.serve(cpu, run);
+            }
+        }
+    }
+
+    #[test]
+    fn runs_on_the_sender_alone() {
+        let rendezvous = rendezvous::<usize>::new();
+        let mut ran = Vec::new();
+        rendezvous.broadcast(
+            3,
+            7,
+            42,
+            |_| panic!("nobody is online"),
+            |callback, context| ran.push((callback, context)),
+        );
+        assert_eq!(ran, [(7, 42)]);
+    }
+
+    #[test]
+    fn waits_for_every_online_cpu() {
+        const CPUS: usize = 8;
+        let rendezvous = rendezvous::<usize>::new();
+        let lines = signal_lines::new();
+        let stop = AtomicBool::new(false);
+        let ran: [AtomicU64; CPUS] = [const { AtomicU64::new(0) }; CPUS];
+        for cpu in 0..CPUS {
+            rendezvous.set_online(cpu, true, |_, _| {});
+        }
+        thread::scope(|scope| {
+            for cpu in 1..CPUS {
+                let (rendezvous, lines, stop, ran) = (&rendezvous, &lines, &stop, &ran);
+                scope.spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        lines.take(rendezvous, cpu, |callback, context| {
+                            ran[cpu].fetch_add(callback as u64 * context, Ordering::Relaxed);
+                        });
+                        // a single core host has to get to the sender
+                        thread::yield_now();
+                    }
+                });
+            }
+            for round in 1..=100 {
+                rendezvous.broadcast(
+                    0,
+                    2,
+                    round,
+                    |target| lines.send(target),
+                    |callback, context| {
+                        ran[0].fetch_add(callback as u64 * context, Ordering::Relaxed);
+                    },
+                );
+                // everyone ran it by the time broadcast returns
+                let expected = round * (round + 1);
+                for count in &ran {
+                    assert_eq!(count.load(Ordering::Relaxed), expected);
+                }
+            }
+            stop.store(true, Ordering::Relaxed);
+        });
+    }
+
+    #[test]
+    fn concurrent_senders_serve_each_other() {
+        const CPUS: usize = 4;
+        const ROUNDS: u64 = 50;
+        let rendezvous = rendezvous::<usize>::new();
+        let lines = signal_lines::new();
+        let finished = AtomicU64::new(0);
+        // (sender, round) pairs every cpu ran
+        let ran: Vec<Mutex<Vec<u64>>> = (0..CPUS).map(|_| Mutex::new(Vec::new())).collect();
+        for cpu in 0..CPUS {
+            rendezvous.set_online(cpu, true, |_, _| {});
+        }
+        thread::scope(|scope| {
+            for cpu in 0..CPUS {
+                let (rendezvous, lines, finished, ran) = (&rendezvous, &lines, &finished, &ran);
+                scope.spawn(move || {
+                    let run = |callback: usize, context: u64| {
+                        ran[cpu]
+                            .lock()
+                            .unwrap()
+                            .push((callback as u64) << 32 | context);
+                    };
+                    for round in 0..ROUNDS {
+                        rendezvous.broadcast(cpu, cpu, round, |target| lines.send(target), run);
+                        lines.take(rendezvous, cpu, run);
+                    }
+                    finished.fetch_add(1, Ordering::AcqRel);
+                    // others may still be waiting for this cpu
+                    while finished.load(Ordering::Acquire) != CPUS as u64 {
+                        lines.take(rendezvous, cpu, run);
+                        thread::yield_now();
+                    }
+                });
+            }
+        });
+        for ran in &ran {
+            let mut ran = ran.lock().unwrap().clone();
+            ran.sort();
+            let expected: Vec<u64> = (0..CPUS as u64)
+                .flat_map(|sender| (0..ROUNDS).map(move |round| sender << 32 | round))
+                .collect();
+            assert_eq!(ran, expected);
+        }
+    }
+
+    #[test]
+    fn one_signal_in_flight() {
+        let rendezvous = rendezvous::<usize>::new();
+        rendezvous.set_online(0, true, |_, _| {});
+        rendezvous.set_online(1, true, |_, _| {});
+        let mut sent = 0;
+        rendezvous.kick(0, |_| sent += 1);
+        rendezvous.kick(0, |_| sent += 1);
+        assert_eq!(sent, 1);
+        assert!(rendezvous.signalled(1));
+        // a signal that wasn't ours
+        assert!(!rendezvous.signalled(1));
+        rendezvous.kick(0, |_| sent += 1);
+        assert_eq!(sent, 2);
+    }
+
+    #[test]
+    fn offline_cpus_are_left_alone() {
+        let rendezvous = rendezvous::<usize>::new();
+        rendezvous.set_online(0, true, |_, _| {});
+        rendezvous.set_online(1, true, |_, _| {});
+        rendezvous.set_online(1, false, |_, _| {});
+        rendezvous.broadcast(0, 1, 0, |target| panic!("signalled {}", target), |_, _| {});
+        // and don't run what happened while they were gone
+        rendezvous.set_online(1, true, |_, _| {});
+        assert!(!rendezvous.serve(1, |_, _| panic!("stale request")));
+    }
+}
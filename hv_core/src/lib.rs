@@ -0,0 +1,28 @@
+// the parts of the hypervisor that are plain logic over plain data, no
+// hardware access and no kernel calls. the driver builds on them and they
+// build for the host as well, where `cargo test` runs their tests
+#![no_std]
+#![allow(non_camel_case_types)]
+// const fn new() is what statics are built with, comments aren't doc comments
+#![allow(clippy::new_without_default, clippy::missing_safety_doc)]
+
+#[cfg(test)]
+extern crate std;
+
+// one bit per cpu in a u64
+pub const MAX_PROCESSORS: usize = 64;
+
+pub mod asid;
+pub mod event;
+pub mod exception;
+pub mod gdb;
+pub mod guest_mem;
+pub mod hooks;
+pub mod ipi;
+pub mod msr_shadow;
+pub mod msw;
+pub mod ring;
+pub mod serial;
+pub mod stats;
+pub mod syscall;
+pub mod tsc;
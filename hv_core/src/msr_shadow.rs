@@ -0,0 +1,186 @@
+// the table of shadowed msrs and what their write policies do with a guest
+// wrmsr, the global instance is in the driver's msr_shadow.rs
+pub const MAX_SHADOW_MSRS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum write_policy {
+    // the write reaches the hardware, reads keep returning the shadow
+    Allow,
+    // #GP, nothing changes
+    Deny,
+    // like Allow and logged
+    Log,
+    // the write only changes the shadow, the hardware keeps its value
+    Redirect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct shadow_entry {
+    pub msr: u32,
+    // what the guest reads, None reads the hardware
+    pub shadow: Option<u64>,
+    pub policy: write_policy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum write_outcome {
+    Hardware,
+    Logged,
+    Shadowed,
+    Fault,
+}
+
+pub struct shadow_table {
+    entries: [Option<shadow_entry>; MAX_SHADOW_MSRS],
+}
+
+impl shadow_table {
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; MAX_SHADOW_MSRS],
+        }
+    }
+
+    pub fn lookup(&self, msr: u32) -> Option<&shadow_entry> {
+        self.entries.iter().flatten().find(|entry| entry.msr == msr)
+    }
+
+    // replaces the entry of an msr that is already shadowed, false when full
+    pub fn insert(&mut self, entry: shadow_entry) -> bool {
+        let slot = match self
+            .entries
+            .iter()
+            .position(|e| e.is_some_and(|e| e.msr == entry.msr))
+        {
+            Some(slot) => Some(slot),
+            None => self.entries.iter().position(Option::is_none),
+        };
+        let Some(slot) = slot else {
+            return false;
+        };
+        self.entries[slot] = Some(entry);
+        true
+    }
+
+    pub fn remove(&mut self, msr: u32) -> Option<shadow_entry> {
+        self.entries
+            .iter_mut()
+            .find(|e| e.is_some_and(|e| e.msr == msr))?
+            .take()
+    }
+
+    // None when the msr isn't shadowed or reads the hardware
+    pub fn read(&self, msr: u32) -> Option<u64> {
+        self.lookup(msr)?.shadow
+    }
+
+    // None when the msr isn't shadowed, the caller writes the hardware itself
+    pub fn write(&mut self, msr: u32, value: u64) -> Option<write_outcome> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.msr == msr)?;
+        Some(match entry.policy {
+            write_policy::Allow => write_outcome::Hardware,
+            write_policy::Log => write_outcome::Logged,
+            write_policy::Deny => write_outcome::Fault,
+            write_policy::Redirect => {
+                entry.shadow = Some(value);
+                write_outcome::Shadowed
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LSTAR: u32 = 0xc000_0082;
+    const TSC_AUX: u32 = 0xc000_0103;
+
+    fn entry(msr: u32, shadow: Option<u64>, policy: write_policy) -> shadow_entry {
+        shadow_entry {
+            msr,
+            shadow,
+            policy,
+        }
+    }
+
+    #[test]
+    fn reads() {
+        let mut table = shadow_table::new();
+        assert!(table.insert(entry(LSTAR, Some(0x1000), write_policy::Allow)));
+        assert!(table.insert(entry(TSC_AUX, None, write_policy::Deny)));
+        assert_eq!(table.read(LSTAR), Some(0x1000));
+        // shadowed for writes only
+        assert_eq!(table.read(TSC_AUX), None);
+        assert!(table.lookup(TSC_AUX).is_some());
+        assert_eq!(table.read(0x10), None);
+        assert!(table.lookup(0x10).is_none());
+    }
+
+    #[test]
+    fn write_policies() {
+        let mut table = shadow_table::new();
+        for (msr, policy) in [
+            (1, write_policy::Allow),
+            (2, write_policy::Deny),
+            (3, write_policy::Log),
+            (4, write_policy::Redirect),
+        ] {
+            assert!(table.insert(entry(msr, Some(msr as u64), policy)));
+        }
+        assert_eq!(table.write(1, 0xa), Some(write_outcome::Hardware));
+        assert_eq!(table.write(2, 0xa), Some(write_outcome::Fault));
+        assert_eq!(table.write(3, 0xa), Some(write_outcome::Logged));
+        assert_eq!(table.write(4, 0xa), Some(write_outcome::Shadowed));
+        assert_eq!(table.write(5, 0xa), None);
+        // only a redirected write changes what the guest reads
+        assert_eq!(table.read(1), Some(1));
+        assert_eq!(table.read(2), Some(2));
+        assert_eq!(table.read(3), Some(3));
+        assert_eq!(table.read(4), Some(0xa));
+    }
+
+    #[test]
+    fn redirect_starts_shadowing_reads() {
+        let mut table = shadow_table::new();
+        table.insert(entry(LSTAR, None, write_policy::Redirect));
+        assert_eq!(table.read(LSTAR), None);
+        table.write(LSTAR, 0xffff_f800_0000_0000);
+        assert_eq!(table.read(LSTAR), Some(0xffff_f800_0000_0000));
+    }
+
+    #[test]
+    fn insert_replaces() {
+        let mut table = shadow_table::new();
+        table.insert(entry(LSTAR, Some(1), write_policy::Allow));
+        table.insert(entry(LSTAR, Some(2), write_policy::Deny));
+        assert_eq!(
+            table.lookup(LSTAR),
+            Some(&entry(LSTAR, Some(2), write_policy::Deny))
+        );
+        assert_eq!(
+            table.remove(LSTAR),
+            Some(entry(LSTAR, Some(2), write_policy::Deny))
+        );
+        assert_eq!(table.remove(LSTAR), None);
+    }
+
+    #[test]
+    fn full_table() {
+        let mut table = shadow_table::new();
+        for msr in 0..MAX_SHADOW_MSRS as u32 {
+            assert!(table.insert(entry(msr, Some(0), write_policy::Allow)));
+        }
+        assert!(!table.insert(entry(LSTAR, Some(0), write_policy::Allow)));
+        // replacing still works, and a removed slot is reused
+        assert!(table.insert(entry(3, Some(1), write_policy::Allow)));
+        table.remove(5);
+        assert!(table.insert(entry(LSTAR, Some(0), write_policy::Allow)));
+        assert_eq!(table.read(LSTAR), Some(0));
+        assert_eq!(table.read(3), Some(1));
+    }
+}
@@ -0,0 +1,383 @@
+// decoding of the cr0 accesses decode assists leave undescribed: CLTS (0f 06),
+// SMSW (0f 01 /4) and LMSW (0f 01 /6). the driver's cr.rs resolves a memory
+// operand against the guest's registers and segments
+
+// the cpu raises #GP for anything longer
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum segment {
+    Es,
+    Cs,
+    Ss,
+    Ds,
+    Fs,
+    Gs,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct mem_operand {
+    // gpr indices, rsp is 4
+    pub base: Option<u8>,
+    pub index: Option<u8>,
+    pub scale: u8,
+    pub disp: i64,
+    // the displacement is relative to the next instruction
+    pub rip_relative: bool,
+    pub segment: segment,
+    // in bytes, the offset wraps at it
+    pub address_size: u8,
+}
+
+impl mem_operand {
+    // the offset into the segment, gpr reads a register by index
+    pub fn offset(&self, gpr: impl Fn(u8) -> u64, next_rip: u64) -> u64 {
+        let mut offset = self.disp as u64;
+        if let Some(base) = self.base {
+            offset = offset.wrapping_add(gpr(base));
+        }
+        if let Some(index) = self.index {
+            offset = offset.wrapping_add(gpr(index) << self.scale);
+        }
+        if self.rip_relative {
+            offset = offset.wrapping_add(next_rip);
+        }
+        match self.address_size {
+            2 => offset & 0xffff,
+            4 => offset & 0xffff_ffff,
+            _ => offset,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum msw_operand {
+    Reg(u8),
+    Mem(mem_operand),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum msw_op {
+    Clts,
+    Smsw(msw_operand),
+    Lmsw(msw_operand),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct msw_instruction {
+    pub op: msw_op,
+    // of a register operand, the memory forms always access 16 bits
+    pub operand_size: u8,
+    pub len: u8,
+}
+
+// bytes as fetched from rip, code_size is the default operand and address
+// size of the code segment in bytes: 8 in 64-bit mode, 4 or 2 otherwise
+pub fn decode(bytes: &[u8], code_size: u8) -> Option<msw_instruction> {
+    let bytes = &bytes[..bytes.len().min(MAX_INSTRUCTION_LEN)];
+    let long_mode = code_size == 8;
+    let mut operand_size = code_size.min(4);
+    let mut address_size = code_size;
+    let mut segment = None;
+    let mut rex = 0;
+    let mut i = 0;
+    loop {
+        let byte = *bytes.get(i)?;
+        match byte {
+            0x66 => operand_size = if code_size == 2 { 4 } else { 2 },
+            0x67 => address_size = if code_size == 4 { 2 } else { 4 },
+            0x26 => segment = Some(segment::Es),
+            0x2e => segment = Some(segment::Cs),
+            0x36 => segment = Some(segment::Ss),
+            0x3e => segment = Some(segment::Ds),
+            0x64 => segment = Some(segment::Fs),
+            0x65 => segment = Some(segment::Gs),
+            0xf2 | 0xf3 => {}
+            0x40..=0x4f if long_mode => {
+                rex = byte;
+                i += 1;
+                continue;
+            }
+            _ => break,
+        }
+        // a rex prefix only counts right before the opcode
+        rex = 0;
+        i += 1;
+    }
+    if rex & 8 != 0 {
+        operand_size = 8;
+    }
+
+    let modrm = match [*bytes.get(i)?, *bytes.get(i + 1)?] {
+        [0x0f, 0x06] => {
+            return Some(msw_instruction {
+                op: msw_op::Clts,
+                operand_size,
+                len: i as u8 + 2,
+            });
+        }
+        [0x0f, 0x01] => *bytes.get(i + 2)?,
+        _ => return None,
+    };
+    let mut len = i + 3;
+    let operand = if modrm >> 6 == 3 {
+        msw_operand::Reg((modrm & 7) | (rex & 1) << 3)
+    } else {
+        let (mem, used) = decode_mem(&bytes[len..], modrm, rex, address_size, long_mode)?;
+        len += used;
+        msw_operand::Mem(mem_operand {
+            segment: segment.unwrap_or(mem.segment),
+            ..mem
+        })
+    };
+    let op = match (modrm >> 3) & 7 {
+        4 => msw_op::Smsw(operand),
+        6 => msw_op::Lmsw(operand),
+        _ => return None,
+    };
+    Some(msw_instruction {
+        op,
+        operand_size,
+        len: len as u8,
+    })
+}
+
+fn read_disp(bytes: &[u8], size: usize) -> Option<i64> {
+    Some(match size {
+        1 => *bytes.first()? as i8 as i64,
+        2 => i16::from_le_bytes(bytes.get(..2)?.try_into().ok()?) as i64,
+        _ => i32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as i64,
+    })
+}
+
+// the operand behind a modrm with mod != 3 and the bytes that follow it,
+// segment is the default one
+fn decode_mem(
+    bytes: &[u8],
+    modrm: u8,
+    rex: u8,
+    address_size: u8,
+    long_mode: bool,
+) -> Option<(mem_operand, usize)> {
+    let mode = modrm >> 6;
+    let rm = modrm & 7;
+    let mut mem = mem_operand {
+        base: None,
+        index: None,
+        scale: 0,
+        disp: 0,
+        rip_relative: false,
+        segment: segment::Ds,
+        address_size,
+    };
+
+    if address_size == 2 {
+        // bx si di bp as gpr indices
+        let (base, index) = match rm {
+            0 => (3, Some(6)),
+            1 => (3, Some(7)),
+            2 => (5, Some(6)),
+            3 => (5, Some(7)),
+            4 => (6, None),
+            5 => (7, None),
+            6 => (5, None),
+            _ => (3, None),
+        };
+        let disp_size = match mode {
+            0 if rm == 6 => 2,
+            0 => 0,
+            1 => 1,
+            _ => 2,
+        };
+        if !(mode == 0 && rm == 6) {
+            mem.base = Some(base);
+            mem.index = index;
+            if base == 5 {
+                mem.segment = segment::Ss;
+            }
+        }
+        if disp_size != 0 {
+            mem.disp = read_disp(bytes, disp_size)?;
+        }
+        return Some((mem, disp_size));
+    }
+
+    let mut used = 0;
+    let mut disp_size = match mode {
+        0 => 0,
+        1 => 1,
+        _ => 4,
+    };
+    let mut base = rm;
+    if rm == 4 {
+        let sib = *bytes.first()?;
+        used = 1;
+        mem.scale = sib >> 6;
+        let index = (sib >> 3) & 7 | (rex & 2) << 2;
+        // rsp can't be an index, r12 can
+        mem.index = (index != 4).then_some(index);
+        base = sib & 7;
+    }
+    if mode == 0 && base == 5 {
+        disp_size = 4;
+        mem.rip_relative = long_mode && rm == 5;
+    } else {
+        let base = base | (rex & 1) << 3;
+        mem.base = Some(base);
+        if base == 4 || base == 5 {
+            mem.segment = segment::Ss;
+        }
+    }
+    if disp_size != 0 {
+        mem.disp = read_disp(&bytes[used..], disp_size)?;
+    }
+    Some((mem, used + disp_size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mem(base: Option<u8>, index: Option<u8>, scale: u8, disp: i64) -> mem_operand {
+        mem_operand {
+            base,
+            index,
+            scale,
+            disp,
+            rip_relative: false,
+            segment: segment::Ds,
+            address_size: 8,
+        }
+    }
+
+    fn operand(bytes: &[u8], code_size: u8) -> Option<(msw_op, u8, u8)> {
+        decode(bytes, code_size).map(|insn| (insn.op, insn.operand_size, insn.len))
+    }
+
+    #[test]
+    fn register_forms() {
+        assert_eq!(operand(&[0x0f, 0x06], 8), Some((msw_op::Clts, 4, 2)));
+        // smsw eax, smsw r9, lmsw cx
+        assert_eq!(
+            operand(&[0x0f, 0x01, 0xe0], 8),
+            Some((msw_op::Smsw(msw_operand::Reg(0)), 4, 3))
+        );
+        assert_eq!(
+            operand(&[0x49, 0x0f, 0x01, 0xe1], 8),
+            Some((msw_op::Smsw(msw_operand::Reg(9)), 8, 4))
+        );
+        assert_eq!(
+            operand(&[0x66, 0x0f, 0x01, 0xf1], 4),
+            Some((msw_op::Lmsw(msw_operand::Reg(1)), 2, 4))
+        );
+        // a rex before a legacy prefix is dropped
+        assert_eq!(
+            operand(&[0x48, 0x66, 0x0f, 0x01, 0xe0], 8),
+            Some((msw_op::Smsw(msw_operand::Reg(0)), 2, 5))
+        );
+        // other 0f 01 instructions
+        assert_eq!(decode(&[0x0f, 0x01, 0xf8], 8), None);
+        assert_eq!(decode(&[0x0f, 0x01, 0xd9], 8), None);
+        assert_eq!(decode(&[0x0f, 0x05], 8), None);
+    }
+
+    #[test]
+    fn memory_forms() {
+        // smsw [rax]
+        assert_eq!(
+            operand(&[0x0f, 0x01, 0x20], 8),
+            Some((
+                msw_op::Smsw(msw_operand::Mem(mem(Some(0), None, 0, 0))),
+                4,
+                3
+            ))
+        );
+        // lmsw [r12 + r13 * 4 - 8]
+        let indexed = mem(Some(12), Some(13), 2, -8);
+        assert_eq!(
+            operand(&[0x43, 0x0f, 0x01, 0x74, 0xac, 0xf8], 8),
+            Some((msw_op::Lmsw(msw_operand::Mem(indexed)), 4, 6))
+        );
+        // smsw gs:[rip + 0x100]
+        let mut relative = mem(None, None, 0, 0x100);
+        relative.rip_relative = true;
+        relative.segment = segment::Gs;
+        assert_eq!(
+            operand(&[0x65, 0x0f, 0x01, 0x25, 0x00, 0x01, 0x00, 0x00], 8),
+            Some((msw_op::Smsw(msw_operand::Mem(relative)), 4, 8))
+        );
+        // smsw [esp + 0x10] in 32-bit code defaults to ss
+        let mut esp = mem(Some(4), None, 0, 0x10);
+        esp.segment = segment::Ss;
+        esp.address_size = 4;
+        assert_eq!(
+            operand(&[0x0f, 0x01, 0x64, 0x24, 0x10], 4),
+            Some((msw_op::Smsw(msw_operand::Mem(esp)), 4, 5))
+        );
+        // smsw [0x1234] through a sib without base or index
+        let mut absolute = mem(None, None, 0, 0x1234);
+        absolute.address_size = 4;
+        assert_eq!(
+            operand(&[0x0f, 0x01, 0x24, 0x25, 0x34, 0x12, 0x00, 0x00], 4),
+            Some((msw_op::Smsw(msw_operand::Mem(absolute)), 4, 8))
+        );
+    }
+
+    #[test]
+    fn sixteen_bit_addressing() {
+        // lmsw [bp + di + 2]
+        let mut bp = mem(Some(5), Some(7), 0, 2);
+        bp.segment = segment::Ss;
+        bp.address_size = 2;
+        assert_eq!(
+            operand(&[0x0f, 0x01, 0x73, 0x02], 2),
+            Some((msw_op::Lmsw(msw_operand::Mem(bp)), 2, 4))
+        );
+        // smsw es:[0x500]
+        let mut absolute = mem(None, None, 0, 0x500);
+        absolute.segment = segment::Es;
+        absolute.address_size = 2;
+        assert_eq!(
+            operand(&[0x26, 0x0f, 0x01, 0x26, 0x00, 0x05], 2),
+            Some((msw_op::Smsw(msw_operand::Mem(absolute)), 2, 6))
+        );
+        // 0x67 switches 32-bit code to it
+        assert_eq!(
+            decode(&[0x67, 0x0f, 0x01, 0x37], 4).map(|insn| insn.len),
+            Some(4)
+        );
+    }
+
+    #[test]
+    fn stays_within_the_instruction() {
+        // nothing but prefixes
+        assert_eq!(decode(&[0x66; 15], 8), None);
+        assert_eq!(decode(&[0x66; 20], 8), None);
+        // the modrm would be the 16th byte
+        let mut long = [0x66; 16];
+        long[13..].copy_from_slice(&[0x0f, 0x01, 0x20]);
+        assert_eq!(decode(&long, 8), None);
+        long[12..15].copy_from_slice(&[0x0f, 0x01, 0x20]);
+        assert_eq!(decode(&long, 8).map(|insn| insn.len), Some(15));
+        // cut off displacements and sib
+        assert_eq!(decode(&[0x0f, 0x01, 0x25, 0x00, 0x01], 8), None);
+        assert_eq!(decode(&[0x0f, 0x01, 0x24], 8), None);
+        assert_eq!(decode(&[0x0f, 0x01], 8), None);
+        assert_eq!(decode(&[], 8), None);
+    }
+
+    #[test]
+    fn offsets_wrap_at_the_address_size() {
+        let regs = |index: u8| [0xffff_fff0, 0x20][index as usize];
+        let mut operand = mem(Some(0), Some(1), 1, -4);
+        assert_eq!(operand.offset(regs, 0), 0x1_0000_002c);
+        operand.address_size = 4;
+        assert_eq!(operand.offset(regs, 0), 0x2c);
+        operand.address_size = 2;
+        assert_eq!(operand.offset(regs, 0), 0x2c);
+
+        let mut relative = mem(None, None, 0, -0x10);
+        relative.rip_relative = true;
+        assert_eq!(relative.offset(regs, 0x1000), 0xff0);
+    }
+}
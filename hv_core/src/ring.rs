@@ -0,0 +1,141 @@
+// single producer single consumer ring buffer, lock-free on both sides.
+// the producer is the exit handler of one cpu, which can never run
+// concurrently with itself, the consumer is whoever drains it
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+pub struct spsc_ring<T: Copy, const N: usize> {
+    head: AtomicUsize, // next slot to read, owned by the consumer
+    tail: AtomicUsize, // next slot to write, owned by the producer
+    dropped: AtomicU64,
+    slots: UnsafeCell<[MaybeUninit<T>; N]>,
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Sync for spsc_ring<T, N> {}
+
+impl<T: Copy, const N: usize> spsc_ring<T, N> {
+    const MASK: usize = {
+        assert!(N.is_power_of_two());
+        N - 1
+    };
+
+    pub const fn new() -> Self {
+        Self {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+            slots: UnsafeCell::new([MaybeUninit::uninit(); N]),
+        }
+    }
+
+    // producer side, returns false and counts the entry as dropped when full
+    pub fn push(&self, value: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == N {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        unsafe { (*self.slots.get())[tail & Self::MASK].write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    // consumer side
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let value = unsafe { (*self.slots.get())[head & Self::MASK].assume_init() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        tail.wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn fifo_order() {
+        let ring = spsc_ring::<u32, 4>::new();
+        assert!(ring.is_empty());
+        assert!(ring.push(1));
+        assert!(ring.push(2));
+        assert_eq!(ring.len(), 2);
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn full_ring_drops() {
+        let ring = spsc_ring::<u32, 4>::new();
+        for value in 0..6 {
+            assert_eq!(ring.push(value), value < 4);
+        }
+        assert_eq!(ring.len(), 4);
+        assert_eq!(ring.dropped(), 2);
+        // the oldest entries survive
+        assert_eq!(ring.pop(), Some(0));
+        assert!(ring.push(6));
+        let rest: Vec<_> = core::iter::from_fn(|| ring.pop()).collect();
+        assert_eq!(rest, [1, 2, 3, 6]);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let ring = spsc_ring::<u64, 8>::new();
+        for value in 0..1000 {
+            assert!(ring.push(value));
+            assert!(ring.push(value + 1));
+            assert_eq!(ring.pop(), Some(value));
+            assert_eq!(ring.pop(), Some(value + 1));
+        }
+        assert!(ring.is_empty());
+        assert_eq!(ring.dropped(), 0);
+    }
+
+    #[test]
+    fn concurrent_producer_and_consumer() {
+        const COUNT: u64 = 100_000;
+        let ring = spsc_ring::<u64, 64>::new();
+        let received = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for value in 0..COUNT {
+                    while !ring.push(value) {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            let mut received = Vec::new();
+            while received.len() < COUNT as usize {
+                match ring.pop() {
+                    Some(value) => received.push(value),
+                    None => std::thread::yield_now(),
+                }
+            }
+            received
+        });
+        // nothing lost, duplicated or reordered. failed pushes were retried
+        assert!(received.iter().copied().eq(0..COUNT));
+        assert!(ring.is_empty());
+    }
+}
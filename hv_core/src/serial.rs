@@ -0,0 +1,220 @@
+// polling 16550 uart over an abstract register interface, the driver talks
+// to it through port i/o, see serial.rs there
+use core::fmt;
+
+const UART_DATA: u16 = 0; // THR / RBR, divisor latch low with DLAB set
+const UART_IER: u16 = 1; // divisor latch high with DLAB set
+const UART_FCR: u16 = 2;
+const UART_LCR: u16 = 3;
+const UART_MCR: u16 = 4;
+const UART_LSR: u16 = 5;
+
+const LCR_8N1: u8 = 0x03;
+const LCR_DLAB: u8 = 0x80;
+const FCR_ENABLE_CLEAR_14: u8 = 0xc7;
+const MCR_DTR_RTS_OUT2: u8 = 0x0b;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+const UART_CLOCK: u32 = 115200;
+// give up on a transmitter that never drains instead of hanging the exit handler
+const TX_SPIN_LIMIT: u32 = 100_000;
+
+// register access relative to the uart's base
+pub trait uart_io {
+    fn read(&self, reg: u16) -> u8;
+    fn write(&self, reg: u16, value: u8);
+}
+
+pub struct uart<IO: uart_io> {
+    io: IO,
+}
+
+impl<IO: uart_io> uart<IO> {
+    pub const fn new(io: IO) -> Self {
+        Self { io }
+    }
+
+    pub fn init(&self, baud: u32) {
+        // the slowest rate the 16 bit divisor latch can express is ~2 baud
+        let divisor = (UART_CLOCK / baud.max(1)).clamp(1, u16::MAX as u32) as u16;
+        self.io.write(UART_IER, 0);
+        self.io.write(UART_LCR, LCR_DLAB);
+        self.io.write(UART_DATA, divisor as u8);
+        self.io.write(UART_IER, (divisor >> 8) as u8);
+        self.io.write(UART_LCR, LCR_8N1);
+        self.io.write(UART_FCR, FCR_ENABLE_CLEAR_14);
+        self.io.write(UART_MCR, MCR_DTR_RTS_OUT2);
+    }
+
+    pub fn write_byte(&self, byte: u8) {
+        for _ in 0..TX_SPIN_LIMIT {
+            if self.io.read(UART_LSR) & LSR_THR_EMPTY != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        self.io.write(UART_DATA, byte);
+    }
+
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_byte(byte);
+        }
+    }
+
+    pub fn read_byte(&self) -> Option<u8> {
+        (self.io.read(UART_LSR) & LSR_DATA_READY != 0).then(|| self.io.read(UART_DATA))
+    }
+}
+
+// text output, terminals want \r\n
+impl<IO: uart_io> fmt::Write for &uart<IO> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::{Cell, RefCell};
+    use core::fmt::Write;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    // the registers of a 16550 as far as the driver uses them. the
+    // transmitter holds a byte for busy_reads reads of lsr after each write
+    #[derive(Default)]
+    struct memory_uart {
+        lcr: Cell<u8>,
+        ier: Cell<u8>,
+        fcr: Cell<u8>,
+        mcr: Cell<u8>,
+        divisor: Cell<u16>,
+        busy_reads: Cell<u32>,
+        busy: Cell<u32>,
+        sent: RefCell<Vec<u8>>,
+        received: RefCell<VecDeque<u8>>,
+    }
+
+    impl memory_uart {
+        fn dlab(&self) -> bool {
+            self.lcr.get() & LCR_DLAB != 0
+        }
+    }
+
+    impl uart_io for &memory_uart {
+        fn read(&self, reg: u16) -> u8 {
+            match reg {
+                UART_DATA => self.received.borrow_mut().pop_front().unwrap_or(0),
+                UART_LSR => {
+                    let mut lsr = 0;
+                    if !self.received.borrow().is_empty() {
+                        lsr |= LSR_DATA_READY;
+                    }
+                    match self.busy.get() {
+                        0 => lsr |= LSR_THR_EMPTY,
+                        busy => self.busy.set(busy - 1),
+                    }
+                    lsr
+                }
+                UART_LCR => self.lcr.get(),
+                _ => 0,
+            }
+        }
+
+        fn write(&self, reg: u16, value: u8) {
+            let divisor = self.divisor.get();
+            match (reg, self.dlab()) {
+                (UART_DATA, true) => self.divisor.set(divisor & 0xff00 | value as u16),
+                (UART_IER, true) => self.divisor.set(divisor & 0xff | (value as u16) << 8),
+                (UART_DATA, false) => {
+                    self.sent.borrow_mut().push(value);
+                    self.busy.set(self.busy_reads.get());
+                }
+                (UART_IER, false) => self.ier.set(value),
+                (UART_FCR, _) => self.fcr.set(value),
+                (UART_LCR, _) => self.lcr.set(value),
+                (UART_MCR, _) => self.mcr.set(value),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn init_programs_the_line() {
+        let model = memory_uart::default();
+        let uart = uart::new(&model);
+        uart.init(9600);
+        assert_eq!(model.divisor.get(), 12);
+        assert_eq!(model.lcr.get(), LCR_8N1);
+        assert_eq!(model.ier.get(), 0);
+        assert_eq!(model.fcr.get(), FCR_ENABLE_CLEAR_14);
+        assert_eq!(model.mcr.get(), MCR_DTR_RTS_OUT2);
+
+        uart.init(115200);
+        assert_eq!(model.divisor.get(), 1);
+        // faster than the clock is as fast as it gets
+        uart.init(1_000_000);
+        assert_eq!(model.divisor.get(), 1);
+        // slower than the divisor reaches is as slow as it gets
+        uart.init(2);
+        assert_eq!(model.divisor.get(), 57600);
+        uart.init(1);
+        assert_eq!(model.divisor.get(), u16::MAX);
+        uart.init(0);
+        assert_eq!(model.divisor.get(), u16::MAX);
+    }
+
+    #[test]
+    fn waits_for_the_transmitter() {
+        let model = memory_uart {
+            busy_reads: Cell::new(3),
+            ..Default::default()
+        };
+        let uart = uart::new(&model);
+        uart.write_bytes(b"abc");
+        assert_eq!(model.sent.borrow().as_slice(), b"abc");
+        // each byte after the first found the previous one still going out
+        assert_eq!(model.busy.get(), 3);
+    }
+
+    #[test]
+    fn stuck_transmitter_is_given_up_on() {
+        let model = memory_uart {
+            busy_reads: Cell::new(u32::MAX),
+            ..Default::default()
+        };
+        let uart = uart::new(&model);
+        uart.write_byte(b'x');
+        uart.write_byte(b'y');
+        // y went out after TX_SPIN_LIMIT polls instead of hanging
+        assert_eq!(model.sent.borrow().as_slice(), b"xy");
+    }
+
+    #[test]
+    fn text_gets_carriage_returns() {
+        let model = memory_uart::default();
+        let uart = uart::new(&model);
+        writeln!(&uart, "a\nb").unwrap();
+        assert_eq!(model.sent.borrow().as_slice(), b"a\r\nb\r\n");
+    }
+
+    #[test]
+    fn reads_only_what_arrived() {
+        let model = memory_uart::default();
+        let uart = uart::new(&model);
+        assert_eq!(uart.read_byte(), None);
+        model.received.borrow_mut().extend(b"$?");
+        assert_eq!(uart.read_byte(), Some(b'$'));
+        assert_eq!(uart.read_byte(), Some(b'?'));
+        assert_eq!(uart.read_byte(), None);
+    }
+}
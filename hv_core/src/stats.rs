@@ -0,0 +1,325 @@
+// per vcpu exit counters and handler latency, all times are in tsc cycles
+use crate::guest_mem::pod;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// exit codes 0x00-0x9f, then 0x400-0x403 (npf and avic), then everything else
+pub const EXIT_BUCKETS: usize = 0xa0 + 4 + 1;
+pub const HISTOGRAM_BUCKETS: usize = 64;
+
+pub fn exit_bucket(exit_code: u64) -> usize {
+    match exit_code {
+        0x00..=0x9f => exit_code as usize,
+        0x400..=0x403 => 0xa0 + (exit_code - 0x400) as usize,
+        _ => EXIT_BUCKETS - 1,
+    }
+}
+
+// inverse of exit_bucket, the catch-all bucket reports as VMEXIT_INVALID
+pub fn bucket_exit_code(bucket: usize) -> u64 {
+    match bucket {
+        0x00..=0x9f => bucket as u64,
+        0xa0..=0xa3 => 0x400 + (bucket - 0xa0) as u64,
+        _ => u64::MAX,
+    }
+}
+
+// bucket n holds latencies in [2^(n-1), 2^n)
+pub fn histogram_bucket(cycles: u64) -> usize {
+    core::cmp::min(
+        (u64::BITS - cycles.leading_zeros()) as usize,
+        HISTOGRAM_BUCKETS - 1,
+    )
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct exit_counter {
+    pub count: u64,
+    pub total_cycles: u64,
+    pub max_cycles: u64,
+}
+
+impl exit_counter {
+    pub fn average_cycles(&self) -> u64 {
+        self.total_cycles.checked_div(self.count).unwrap_or(0)
+    }
+}
+unsafe impl pod for exit_counter {}
+
+// layout is shared with user mode through the get stats hypercall
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct exit_stats {
+    pub total: exit_counter,
+    pub exits: [exit_counter; EXIT_BUCKETS],
+    pub histogram: [u64; HISTOGRAM_BUCKETS],
+    // exits that had to load the host's hidden state, see hidden.rs
+    pub host_state_loads: u64,
+}
+unsafe impl pod for exit_stats {}
+
+impl exit_stats {
+    pub const fn new() -> Self {
+        Self {
+            total: exit_counter {
+                count: 0,
+                total_cycles: 0,
+                max_cycles: 0,
+            },
+            exits: [exit_counter {
+                count: 0,
+                total_cycles: 0,
+                max_cycles: 0,
+            }; EXIT_BUCKETS],
+            histogram: [0; HISTOGRAM_BUCKETS],
+            host_state_loads: 0,
+        }
+    }
+
+    pub fn record(&mut self, exit_code: u64, cycles: u64) {
+        for counter in [&mut self.total, &mut self.exits[exit_bucket(exit_code)]] {
+            counter.count += 1;
+            counter.total_cycles += cycles;
+            counter.max_cycles = core::cmp::max(counter.max_cycles, cycles);
+        }
+        self.histogram[histogram_bucket(cycles)] += 1;
+    }
+
+    pub fn counter(&self, exit_code: u64) -> &exit_counter {
+        &self.exits[exit_bucket(exit_code)]
+    }
+
+    pub fn merge(&mut self, other: &exit_stats) {
+        let pairs = core::iter::once((&mut self.total, &other.total))
+            .chain(self.exits.iter_mut().zip(other.exits.iter()));
+        for (counter, other) in pairs {
+            counter.count += other.count;
+            counter.total_cycles += other.total_cycles;
+            counter.max_cycles = core::cmp::max(counter.max_cycles, other.max_cycles);
+        }
+        for (bucket, other) in self.histogram.iter_mut().zip(other.histogram.iter()) {
+            *bucket += other;
+        }
+        self.host_state_loads += other.host_state_loads;
+    }
+
+    // (exit code, counter) for every exit that happened at least once
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &exit_counter)> {
+        self.exits
+            .iter()
+            .enumerate()
+            .filter(|(_, counter)| counter.count != 0)
+            .map(|(bucket, counter)| (bucket_exit_code(bucket), counter))
+    }
+}
+
+struct shared_counter {
+    count: AtomicU64,
+    total_cycles: AtomicU64,
+    max_cycles: AtomicU64,
+}
+
+impl shared_counter {
+    const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total_cycles: AtomicU64::new(0),
+            max_cycles: AtomicU64::new(0),
+        }
+    }
+
+    // the owner is the only writer, no need for locked read-modify-writes
+    fn record(&self, cycles: u64) {
+        bump(&self.count, 1);
+        bump(&self.total_cycles, cycles);
+        if cycles > self.max_cycles.load(Ordering::Relaxed) {
+            self.max_cycles.store(cycles, Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> exit_counter {
+        exit_counter {
+            count: self.count.load(Ordering::Relaxed),
+            total_cycles: self.total_cycles.load(Ordering::Relaxed),
+            max_cycles: self.max_cycles.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn bump(value: &AtomicU64, by: u64) {
+    let bumped = value.load(Ordering::Relaxed).wrapping_add(by);
+    value.store(bumped, Ordering::Relaxed);
+}
+
+// exit_stats as one vcpu records them, readable from other cpus meanwhile.
+// a snapshot can be an exit behind in some values, no value is ever torn
+pub struct shared_stats {
+    total: shared_counter,
+    exits: [shared_counter; EXIT_BUCKETS],
+    histogram: [AtomicU64; HISTOGRAM_BUCKETS],
+    host_state_loads: AtomicU64,
+}
+
+impl shared_stats {
+    pub const fn new() -> Self {
+        Self {
+            total: shared_counter::new(),
+            exits: [const { shared_counter::new() }; EXIT_BUCKETS],
+            histogram: [const { AtomicU64::new(0) }; HISTOGRAM_BUCKETS],
+            host_state_loads: AtomicU64::new(0),
+        }
+    }
+
+    // only the vcpu's own exit handler records
+    pub fn record(&self, exit_code: u64, cycles: u64) {
+        self.total.record(cycles);
+        self.exits[exit_bucket(exit_code)].record(cycles);
+        bump(&self.histogram[histogram_bucket(cycles)], 1);
+    }
+
+    pub fn host_state_loaded(&self) {
+        bump(&self.host_state_loads, 1);
+    }
+
+    pub fn snapshot(&self) -> exit_stats {
+        let mut stats = exit_stats::new();
+        stats.total = self.total.snapshot();
+        for (counter, shared) in stats.exits.iter_mut().zip(self.exits.iter()) {
+            *counter = shared.snapshot();
+        }
+        for (bucket, shared) in stats.histogram.iter_mut().zip(self.histogram.iter()) {
+            *bucket = shared.load(Ordering::Relaxed);
+        }
+        stats.host_state_loads = self.host_state_loads.load(Ordering::Relaxed);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn exit_buckets_round_trip() {
+        for exit_code in (0x00..=0x9f).chain(0x400..=0x403) {
+            assert_eq!(bucket_exit_code(exit_bucket(exit_code)), exit_code);
+        }
+        // everything else shares the last bucket
+        assert_eq!(exit_bucket(0xa0), EXIT_BUCKETS - 1);
+        assert_eq!(exit_bucket(u64::MAX), EXIT_BUCKETS - 1);
+        assert_eq!(bucket_exit_code(EXIT_BUCKETS - 1), u64::MAX);
+    }
+
+    #[test]
+    fn histogram_buckets_are_powers_of_two() {
+        assert_eq!(histogram_bucket(0), 0);
+        assert_eq!(histogram_bucket(1), 1);
+        assert_eq!(histogram_bucket(2), 2);
+        assert_eq!(histogram_bucket(3), 2);
+        assert_eq!(histogram_bucket(4), 3);
+        assert_eq!(histogram_bucket(1023), 10);
+        assert_eq!(histogram_bucket(1024), 11);
+        for bucket in 1..HISTOGRAM_BUCKETS - 1 {
+            assert_eq!(histogram_bucket(1 << (bucket - 1)), bucket);
+            assert_eq!(histogram_bucket((1 << bucket) - 1), bucket);
+        }
+        // the last bucket takes everything above
+        assert_eq!(histogram_bucket(1 << 62), HISTOGRAM_BUCKETS - 1);
+        assert_eq!(histogram_bucket(u64::MAX), HISTOGRAM_BUCKETS - 1);
+    }
+
+    #[test]
+    fn record_counts_exits() {
+        let mut stats = exit_stats::new();
+        stats.record(0x72, 100);
+        stats.record(0x72, 300);
+        stats.record(0x400, 5000);
+
+        let cpuid = stats.counter(0x72);
+        assert_eq!(cpuid.count, 2);
+        assert_eq!(cpuid.total_cycles, 400);
+        assert_eq!(cpuid.max_cycles, 300);
+        assert_eq!(cpuid.average_cycles(), 200);
+        assert_eq!(stats.total.count, 3);
+        assert_eq!(stats.total.max_cycles, 5000);
+        assert_eq!(stats.counter(0x7b).average_cycles(), 0);
+
+        assert_eq!(stats.histogram[histogram_bucket(100)], 1);
+        assert_eq!(stats.histogram[histogram_bucket(300)], 1);
+        assert_eq!(stats.histogram[histogram_bucket(5000)], 1);
+        assert_eq!(stats.histogram.iter().sum::<u64>(), 3);
+
+        let exits: Vec<_> = stats.iter().map(|(code, c)| (code, c.count)).collect();
+        assert_eq!(exits, [(0x72, 2), (0x400, 1)]);
+    }
+
+    #[test]
+    fn merge_adds_counts_and_keeps_maxima() {
+        let mut a = exit_stats::new();
+        a.record(0x72, 100);
+        a.host_state_loads = 2;
+        let mut b = exit_stats::new();
+        b.record(0x72, 700);
+        b.record(0x81, 50);
+        b.host_state_loads = 3;
+
+        a.merge(&b);
+        assert_eq!(a.counter(0x72).count, 2);
+        assert_eq!(a.counter(0x72).total_cycles, 800);
+        assert_eq!(a.counter(0x72).max_cycles, 700);
+        assert_eq!(a.counter(0x81).count, 1);
+        assert_eq!(a.total.count, 3);
+        assert_eq!(a.total.max_cycles, 700);
+        assert_eq!(a.histogram.iter().sum::<u64>(), 3);
+        assert_eq!(a.host_state_loads, 5);
+    }
+
+    #[test]
+    fn shared_stats_match_plain_ones() {
+        let shared = shared_stats::new();
+        let mut plain = exit_stats::new();
+        for (exit_code, cycles) in [(0x72, 100), (0x72, 300), (0x400, 5000), (0x7b, 0)] {
+            shared.record(exit_code, cycles);
+            plain.record(exit_code, cycles);
+        }
+        shared.host_state_loaded();
+
+        let snapshot = shared.snapshot();
+        assert_eq!(snapshot.total, plain.total);
+        assert_eq!(snapshot.exits, plain.exits);
+        assert_eq!(snapshot.histogram, plain.histogram);
+        assert_eq!(snapshot.host_state_loads, 1);
+    }
+
+    #[test]
+    fn snapshots_while_recording() {
+        let shared = shared_stats::new();
+        let done = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                for cycles in 1..20000 {
+                    shared.record(0x72, cycles);
+                }
+                done.store(true, Ordering::Release);
+            });
+            let mut last = exit_stats::new();
+            loop {
+                let finished = done.load(Ordering::Acquire);
+                let snapshot = shared.snapshot();
+                let cpuid = snapshot.counter(0x72);
+                assert!(cpuid.count >= last.counter(0x72).count);
+                assert!(cpuid.max_cycles >= last.counter(0x72).max_cycles);
+                last = snapshot;
+                if finished {
+                    break;
+                }
+                thread::yield_now();
+            }
+            assert_eq!(last.counter(0x72).count, 19999);
+            assert_eq!(last.total.max_cycles, 19999);
+        });
+    }
+}
@@ -0,0 +1,264 @@
+// syscall and sysret emulation over the state they read and write, the
+// driver's syscall.rs feeds it from the vmcb when sce is hidden
+const EFER_LMA: u64 = 1 << 10;
+const RFLAGS_IF: u64 = 1 << 9;
+const RFLAGS_RF: u64 = 1 << 16;
+const RFLAGS_VM: u64 = 1 << 17;
+const RFLAGS_RESERVED: u64 = 1 << 1;
+// rflags bits sysret restores from r11
+const SYSRET_RFLAGS: u64 = 0x3c_7fd7;
+
+// vmcb segment attributes: type, s, dpl, p, l, d, g
+const CODE64_DPL0: u16 = 0xa9b;
+const CODE32_DPL0: u16 = 0xc9b;
+const DATA_DPL0: u16 = 0xc93;
+const CODE64_DPL3: u16 = 0xafb;
+const CODE32_DPL3: u16 = 0xcfb;
+const DATA_DPL3: u16 = 0xcf3;
+const ATTRIB_L: u16 = 1 << 9;
+const FLAT_LIMIT: u32 = 0xffff_ffff;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct segment_state {
+    pub selector: u16,
+    pub attrib: u16,
+    pub limit: u32,
+    pub base: u64,
+}
+
+// what syscall and sysret read and write, rip is the instruction's own
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct syscall_state {
+    pub rip: u64,
+    pub rflags: u64,
+    pub rcx: u64,
+    pub r11: u64,
+    pub cs: segment_state,
+    pub ss: segment_state,
+    pub cpl: u8,
+    pub efer: u64,
+    pub star: u64,
+    pub lstar: u64,
+    pub cstar: u64,
+    pub sf_mask: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum syscall_fault {
+    Gp,
+}
+
+fn flat(selector: u16, attrib: u16) -> segment_state {
+    segment_state {
+        selector,
+        attrib,
+        limit: FLAT_LIMIT,
+        base: 0,
+    }
+}
+
+// syscall, 2 bytes long. the caller checked that sce is enabled
+pub fn emulate_syscall(state: &mut syscall_state) -> Result<(), syscall_fault> {
+    let next = state.rip.wrapping_add(2);
+    let selector = (state.star >> 32) as u16 & !3;
+
+    if state.efer & EFER_LMA != 0 {
+        state.rcx = next;
+        state.r11 = state.rflags & !RFLAGS_RF;
+        state.rip = match state.cs.attrib & ATTRIB_L != 0 {
+            true => state.lstar,
+            false => state.cstar,
+        };
+        state.rflags = state.rflags & !state.sf_mask & !RFLAGS_RF | RFLAGS_RESERVED;
+        state.cs = flat(selector, CODE64_DPL0);
+    } else {
+        state.rcx = next as u32 as u64;
+        state.rip = state.star as u32 as u64;
+        state.rflags &= !(RFLAGS_VM | RFLAGS_IF | RFLAGS_RF);
+        state.cs = flat(selector, CODE32_DPL0);
+    }
+    state.ss = flat(selector.wrapping_add(8), DATA_DPL0);
+    state.cpl = 0;
+    Ok(())
+}
+
+// sysret, quad is the rex.w form returning to 64 bit code
+pub fn emulate_sysret(state: &mut syscall_state, quad: bool) -> Result<(), syscall_fault> {
+    if state.cpl != 0 {
+        return Err(syscall_fault::Gp);
+    }
+    let selector = (state.star >> 48) as u16;
+
+    if state.efer & EFER_LMA != 0 {
+        (state.rip, state.cs) = match quad {
+            true => (state.rcx, flat(selector.wrapping_add(16) | 3, CODE64_DPL3)),
+            false => (state.rcx as u32 as u64, flat(selector | 3, CODE32_DPL3)),
+        };
+        state.rflags = state.r11 & SYSRET_RFLAGS | RFLAGS_RESERVED;
+    } else {
+        state.rip = state.rcx as u32 as u64;
+        state.cs = flat(selector | 3, CODE32_DPL3);
+        state.rflags |= RFLAGS_IF;
+    }
+    state.ss = flat(selector.wrapping_add(8) | 3, DATA_DPL3);
+    state.cpl = 3;
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum syscall_instruction {
+    Syscall,
+    Sysret { quad: bool },
+}
+
+// the instruction behind a #UD, if it's one of ours
+pub fn decode(bytes: &[u8; 3]) -> Option<syscall_instruction> {
+    match bytes {
+        [0x0f, 0x05, _] => Some(syscall_instruction::Syscall),
+        [0x0f, 0x07, _] => Some(syscall_instruction::Sysret { quad: false }),
+        [rex @ 0x40..=0x4f, 0x0f, 0x07] => Some(syscall_instruction::Sysret {
+            quad: rex & 0x8 != 0,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EFER_LME: u64 = 1 << 8;
+    const EFER_SCE: u64 = 1 << 0;
+    // the kernel's code and stack at 0x10 / 0x18, sysret based at 0x20
+    const STAR: u64 = 0x0023_0010 << 32;
+    const LSTAR: u64 = 0xffff_f800_0040_0000;
+    const CSTAR: u64 = 0xffff_f800_0040_1000;
+    const SF_MASK: u64 = 0x4700;
+
+    fn user64() -> syscall_state {
+        syscall_state {
+            rip: 0x7ff8_0000_1000,
+            rflags: 0x246 | 0x100,
+            rcx: 0,
+            r11: 0,
+            cs: flat(0x33, CODE64_DPL3),
+            ss: flat(0x2b, DATA_DPL3),
+            cpl: 3,
+            efer: EFER_LMA | EFER_LME | EFER_SCE,
+            star: STAR,
+            lstar: LSTAR,
+            cstar: CSTAR,
+            sf_mask: SF_MASK,
+        }
+    }
+
+    #[test]
+    fn syscall_from_64_bit_code() {
+        let mut state = user64();
+        emulate_syscall(&mut state).unwrap();
+        assert_eq!(state.rip, LSTAR);
+        assert_eq!(state.rcx, 0x7ff8_0000_1002);
+        assert_eq!(state.r11, 0x346);
+        // tf and if are in the mask
+        assert_eq!(state.rflags, 0x046);
+        assert_eq!(state.cs, flat(0x10, CODE64_DPL0));
+        assert_eq!(state.ss, flat(0x18, DATA_DPL0));
+        assert_eq!(state.cpl, 0);
+    }
+
+    #[test]
+    fn syscall_from_compatibility_mode() {
+        let mut state = user64();
+        state.cs = flat(0x23, CODE32_DPL3);
+        state.rip = 0x7700_1000;
+        emulate_syscall(&mut state).unwrap();
+        assert_eq!(state.rip, CSTAR);
+        assert_eq!(state.rcx, 0x7700_1002);
+        assert_eq!(state.cs, flat(0x10, CODE64_DPL0));
+    }
+
+    #[test]
+    fn syscall_in_legacy_mode() {
+        let mut state = user64();
+        state.efer = EFER_SCE;
+        state.star = STAR | 0x8040_0000;
+        state.cs = flat(0x23, CODE32_DPL3);
+        state.rip = 0xffff_fffe;
+        state.rflags = 0x2_0246 | RFLAGS_RF;
+        emulate_syscall(&mut state).unwrap();
+        assert_eq!(state.rip, 0x8040_0000);
+        // the return address wraps within 32 bits
+        assert_eq!(state.rcx, 0);
+        assert_eq!(state.rflags, 0x046);
+        assert_eq!(state.cs, flat(0x10, CODE32_DPL0));
+        assert_eq!(state.ss, flat(0x18, DATA_DPL0));
+    }
+
+    #[test]
+    fn sysret_to_64_bit_code() {
+        let mut state = user64();
+        emulate_syscall(&mut state).unwrap();
+        state.r11 |= RFLAGS_RF | RFLAGS_VM | 1 << 22;
+        emulate_sysret(&mut state, true).unwrap();
+        assert_eq!(state.rip, 0x7ff8_0000_1002);
+        assert_eq!(state.rflags, 0x346);
+        assert_eq!(state.cs, flat(0x33, CODE64_DPL3));
+        assert_eq!(state.ss, flat(0x2b, DATA_DPL3));
+        assert_eq!(state.cpl, 3);
+    }
+
+    #[test]
+    fn sysret_to_compatibility_mode() {
+        let mut state = user64();
+        emulate_syscall(&mut state).unwrap();
+        state.rcx = 0xdead_0000_7700_1002;
+        emulate_sysret(&mut state, false).unwrap();
+        assert_eq!(state.rip, 0x7700_1002);
+        assert_eq!(state.cs, flat(0x23, CODE32_DPL3));
+        assert_eq!(state.ss, flat(0x2b, DATA_DPL3));
+    }
+
+    #[test]
+    fn sysret_in_legacy_mode_sets_if() {
+        let mut state = user64();
+        state.efer = EFER_SCE;
+        state.cpl = 0;
+        state.rcx = 0x1_0040_1000;
+        state.rflags = 0x46;
+        emulate_sysret(&mut state, false).unwrap();
+        assert_eq!(state.rip, 0x40_1000);
+        assert_eq!(state.rflags, 0x246);
+        assert_eq!(state.cs, flat(0x23, CODE32_DPL3));
+    }
+
+    #[test]
+    fn sysret_from_user_mode_faults() {
+        let mut state = user64();
+        let before = state;
+        assert_eq!(emulate_sysret(&mut state, true), Err(syscall_fault::Gp));
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    fn decodes_only_syscall_and_sysret() {
+        assert_eq!(
+            decode(&[0x0f, 0x05, 0xc3]),
+            Some(syscall_instruction::Syscall)
+        );
+        assert_eq!(
+            decode(&[0x0f, 0x07, 0x90]),
+            Some(syscall_instruction::Sysret { quad: false })
+        );
+        assert_eq!(
+            decode(&[0x48, 0x0f, 0x07]),
+            Some(syscall_instruction::Sysret { quad: true })
+        );
+        assert_eq!(
+            decode(&[0x41, 0x0f, 0x07]),
+            Some(syscall_instruction::Sysret { quad: false })
+        );
+        // ud2 and sysenter
+        assert_eq!(decode(&[0x0f, 0x0b, 0x00]), None);
+        assert_eq!(decode(&[0x0f, 0x34, 0x00]), None);
+    }
+}
@@ -0,0 +1,235 @@
+// the bookkeeping behind tsc compensation, the driver's tsc.rs applies it
+// through tsc_offset and emulated rdtsc
+
+// one vcpu's view, tsc_offset is the negation of applied
+#[derive(Clone, Copy, Debug, Default)]
+pub struct tsc_clock {
+    // cycles taken out of the guest's tsc so far
+    pub applied: u64,
+    // last emulated rdtsc result
+    pub last: u64,
+    // cycles of the current exit the guest still sees pass, e.g. waiting for
+    // a sipi
+    pub visible: u64,
+}
+
+impl tsc_clock {
+    pub const fn new() -> Self {
+        Self {
+            applied: 0,
+            last: 0,
+            visible: 0,
+        }
+    }
+
+    // hides cycles more of this vcpu's time
+    pub fn hide(&mut self, cycles: u64) {
+        self.applied = self.applied.wrapping_add(cycles);
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.applied.wrapping_neg()
+    }
+
+    // what rdtsc returns when the hardware counter reads hw
+    pub fn read(&mut self, hw: u64) -> u64 {
+        self.last = hw.wrapping_sub(self.applied).max(self.last);
+        self.last
+    }
+}
+
+// one compensation for every vcpu. it only grows by time during which no
+// vcpu ran the guest, so a guest reading hw - compensation anywhere never
+// sees its tsc go back, not even when moving between vcpus
+#[derive(Clone, Copy, Debug)]
+pub struct shared_clock {
+    in_guest: u32,
+    // when the last vcpu left the guest
+    since: u64,
+    compensation: u64,
+}
+
+impl shared_clock {
+    pub const fn new() -> Self {
+        Self {
+            in_guest: 0,
+            since: 0,
+            compensation: 0,
+        }
+    }
+
+    pub fn compensation(&self) -> u64 {
+        self.compensation
+    }
+
+    // a vcpu starts running the guest, without an exit before. the time
+    // before it isn't hidden
+    pub fn join(&mut self) -> u64 {
+        self.in_guest += 1;
+        self.compensation
+    }
+
+    // a vcpu left the guest at now, it stays out until it resumes
+    pub fn exit(&mut self, now: u64) {
+        self.in_guest = self.in_guest.saturating_sub(1);
+        if self.in_guest == 0 {
+            self.since = now;
+        }
+    }
+
+    // a vcpu goes back into the guest at now, returns the compensation to
+    // run it with
+    pub fn resume(&mut self, now: u64) -> u64 {
+        if self.in_guest == 0 {
+            self.compensation += now.saturating_sub(self.since);
+        }
+        self.in_guest += 1;
+        self.compensation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    // deterministic timelines without pulling in a rng
+    struct lcg(u64);
+
+    impl lcg {
+        fn next(&mut self, bound: u64) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % bound
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    struct simulated_vcpu {
+        in_guest: bool,
+        // time of its next exit or resume
+        next: u64,
+        clock: tsc_clock,
+    }
+
+    // runs cpus vcpus through random exits, every tick each vcpu in the
+    // guest reads its tsc. returns the reads in real time order and the
+    // compensation at the end
+    fn run(cpus: usize, seed: u64) -> (Vec<(u64, usize, u64)>, u64) {
+        let mut rng = lcg(seed);
+        let mut clock = shared_clock::new();
+        let mut vcpus: Vec<_> = (0..cpus)
+            .map(|_| {
+                let mut vcpu = simulated_vcpu {
+                    in_guest: true,
+                    next: 1 + rng.next(300),
+                    clock: tsc_clock::new(),
+                };
+                vcpu.clock.applied = clock.join();
+                vcpu
+            })
+            .collect();
+        let mut reads = Vec::new();
+        for now in 0..200_000 {
+            for (index, vcpu) in vcpus.iter_mut().enumerate() {
+                if vcpu.in_guest {
+                    reads.push((now, index, vcpu.clock.read(now)));
+                }
+                if vcpu.next != now {
+                    continue;
+                }
+                if vcpu.in_guest {
+                    clock.exit(now);
+                    // exits vary from short ones to waiting on a lock
+                    let longest = rng.next(500) + 1;
+                    vcpu.next = now + 1 + rng.next(longest);
+                } else {
+                    vcpu.clock.applied = clock.resume(now);
+                    vcpu.next = now + 1 + rng.next(300);
+                }
+                vcpu.in_guest = !vcpu.in_guest;
+            }
+        }
+        (reads, clock.compensation())
+    }
+
+    #[test]
+    fn shared_tsc_never_goes_back() {
+        for (cpus, seed) in [(1, 1), (2, 2), (3, 3), (4, 4), (8, 5)] {
+            let (reads, compensation) = run(cpus, seed);
+            // something has to be hidden for this to mean anything
+            assert!(compensation > 0, "{} cpus hid nothing", cpus);
+            let mut highest = 0;
+            for &(now, cpu, value) in &reads {
+                assert!(
+                    value >= highest,
+                    "cpu {} at {} read {} after {}",
+                    cpu,
+                    now,
+                    value,
+                    highest
+                );
+                highest = value;
+            }
+        }
+    }
+
+    #[test]
+    fn shared_tsc_hides_time_nobody_ran() {
+        // one vcpu hides every exit exactly
+        let mut clock = shared_clock::new();
+        assert_eq!(clock.join(), 0);
+        clock.exit(100);
+        assert_eq!(clock.resume(150), 50);
+        clock.exit(200);
+        assert_eq!(clock.resume(230), 80);
+
+        // with two only the overlap of their exits is hidden
+        let mut clock = shared_clock::new();
+        clock.join();
+        clock.join();
+        clock.exit(100);
+        clock.exit(120);
+        assert_eq!(clock.resume(150), 30);
+        assert_eq!(clock.resume(180), 30);
+        clock.exit(200);
+        assert_eq!(clock.resume(300), 30);
+        assert_eq!(clock.compensation(), 30);
+    }
+
+    #[test]
+    fn joining_hides_nothing() {
+        let mut clock = shared_clock::new();
+        clock.join();
+        clock.exit(1_000_000);
+        assert_eq!(clock.join(), 0);
+        // the first vcpu comes back while the second runs
+        assert_eq!(clock.resume(2_000_000), 0);
+    }
+
+    #[test]
+    fn devirtualized_vcpus_stop_counting() {
+        let mut clock = shared_clock::new();
+        clock.join();
+        clock.join();
+        // the second never resumes
+        clock.exit(50);
+        clock.exit(100);
+        assert_eq!(clock.resume(120), 20);
+        clock.exit(200);
+        assert_eq!(clock.resume(260), 80);
+    }
+
+    #[test]
+    fn per_cpu_reads_never_go_back() {
+        let mut clock = tsc_clock::new();
+        assert_eq!(clock.read(1000), 1000);
+        clock.hide(300);
+        assert_eq!(clock.offset(), 300u64.wrapping_neg());
+        // the hardware moved less than was hidden
+        assert_eq!(clock.read(1200), 1000);
+        assert_eq!(clock.read(1400), 1100);
+    }
+}
@@ -0,0 +1,101 @@
+// just enough of the local apic to send nmis to other processors, through
+// the x2apic msrs or the xapic mmio page, and to read startup ipis off the
+// x2apic icr and send inits through it
+use crate::log_error;
+use crate::utils::{rdmsr, wrmsr};
+use core::arch::x86_64::__cpuid;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+use wdk_sys::{_MEMORY_CACHING_TYPE::MmNonCached, PHYSICAL_ADDRESS, ntddk::*};
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_MASK: u64 = 0xf_ffff_f000;
+const X2APIC_ID: u32 = 0x802;
+pub const X2APIC_ICR: u32 = 0x830;
+// xapic register offsets
+const XAPIC_ICR_LOW: usize = 0x300;
+const XAPIC_ICR_HIGH: usize = 0x310;
+const XAPIC_PAGE: u64 = 0x1000;
+
+pub const ICR_VECTOR: u64 = 0xff;
+pub const ICR_DELIVERY: u64 = 0b111 << 8;
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+pub const ICR_DELIVERY_STARTUP: u64 = 0b110 << 8;
+pub const ICR_DESTINATION_LOGICAL: u64 = 1 << 11;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_SEND_PENDING: u32 = 1 << 12;
+pub const ICR_SHORTHAND: u64 = 0b11 << 18;
+pub const ICR_SHORTHAND_SELF: u64 = 0b01 << 18;
+pub const ICR_SHORTHAND_ALL: u64 = 0b10 << 18;
+pub const ICR_SHORTHAND_OTHERS: u64 = 0b11 << 18;
+// x2apic destination of every cpu
+pub const X2APIC_BROADCAST: u32 = u32::MAX;
+
+static XAPIC: AtomicPtr<u32> = AtomicPtr::new(null_mut());
+
+pub fn x2apic() -> bool {
+    let base = unsafe { rdmsr(IA32_APIC_BASE) };
+    base & APIC_BASE_X2APIC != 0
+}
+
+// passive level, the xapic page is mapped once and never unmapped
+pub fn init() -> bool {
+    if x2apic() || !XAPIC.load(Ordering::Acquire).is_null() {
+        return true;
+    }
+    let base = PHYSICAL_ADDRESS {
+        QuadPart: (unsafe { rdmsr(IA32_APIC_BASE) } & APIC_BASE_MASK) as i64,
+    };
+    let page = unsafe { MmMapIoSpace(base, XAPIC_PAGE, MmNonCached) } as *mut u32;
+    if page.is_null() {
+        log_error!("failed to map the local apic");
+        return false;
+    }
+    XAPIC.store(page, Ordering::Release);
+    true
+}
+
+// id of the current processor's apic
+pub fn id() -> u32 {
+    match x2apic() {
+        true => unsafe { rdmsr(X2APIC_ID) as u32 },
+        false => unsafe { __cpuid(1) }.ebx >> 24,
+    }
+}
+
+fn xapic_register(offset: usize) -> *mut u32 {
+    unsafe { XAPIC.load(Ordering::Acquire).byte_add(offset) }
+}
+
+// x2apic only, the destination may be the sending cpu itself
+pub fn send_init(apic_id: u32) {
+    let command = ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT;
+    unsafe { wrmsr(X2APIC_ICR, (apic_id as u64) << 32 | command as u64) };
+}
+
+// host context only, the guest may be halfway through writing the icr when
+// it exited so the destination is put back
+pub fn send_nmi(apic_id: u32) {
+    let command = ICR_DELIVERY_NMI | ICR_LEVEL_ASSERT;
+    if x2apic() {
+        return unsafe { wrmsr(X2APIC_ICR, (apic_id as u64) << 32 | command as u64) };
+    }
+    if XAPIC.load(Ordering::Acquire).is_null() {
+        return;
+    }
+    let (low, high) = (
+        xapic_register(XAPIC_ICR_LOW),
+        xapic_register(XAPIC_ICR_HIGH),
+    );
+    unsafe {
+        let destination = high.read_volatile();
+        high.write_volatile(apic_id << 24);
+        low.write_volatile(command);
+        while low.read_volatile() & ICR_SEND_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+        high.write_volatile(destination);
+    }
+}
@@ -0,0 +1,62 @@
+// asids and tlb flushes. every cpu hands out its own asids. when all of a
+// vcpu's translations have to go its asid can be retired instead of flushed,
+// the guest resumes in a fresh one. once a cpu runs out, every asid of it is
+// reused under a new generation after a full flush.
+//
+// the guest's invlpg and unintercepted cr3 writes only reach the asid it
+// runs in, so a vcpu keeps a single one for all npt views and switching
+// views retires it.
+//
+// exit handlers only ask for a flush with request_tlb_flush, what ends up in
+// tlb_control is decided when the guest resumes
+use crate::hv::vcpu;
+use crate::vmcb::*;
+pub use hv_core::asid::*;
+
+fn tlb_control(flush: tlb_flush, flush_by_asid: bool) -> u32 {
+    match flush {
+        tlb_flush::None => TLB_CONTROL_DO_NOTHING,
+        tlb_flush::All => TLB_CONTROL_FLUSH_ALL,
+        _ if !flush_by_asid => TLB_CONTROL_FLUSH_ALL,
+        tlb_flush::NonGlobal => TLB_CONTROL_FLUSH_GUEST_NON_GLOBAL,
+        tlb_flush::Asid => TLB_CONTROL_FLUSH_GUEST,
+    }
+}
+
+// without flush by asid anything but a full flush is done with a new asid
+pub fn request_tlb_flush(vcpu_ctx: &mut vcpu, flush: tlb_flush) {
+    match flush {
+        tlb_flush::None => {}
+        tlb_flush::NonGlobal | tlb_flush::Asid
+            if !vcpu_ctx.svm_features.has(SVM_FEATURE_FLUSH_BY_ASID) =>
+        {
+            vcpu_ctx.asid.retire()
+        }
+        _ => vcpu_ctx.tlb_flush = vcpu_ctx.tlb_flush.max(flush),
+    }
+}
+
+// the guest resumes with nothing cached from before
+pub fn retire_asid(vcpu_ctx: &mut vcpu) {
+    vcpu_ctx.asid.retire();
+}
+
+pub fn setup_asid(vcpu_ctx: &mut vcpu) {
+    vcpu_ctx.asids = asid_allocator::new(vcpu_ctx.svm_features.asid_count);
+    // whatever the cpu cached for these asids before
+    vcpu_ctx.tlb_flush = tlb_flush::All;
+    apply(vcpu_ctx);
+}
+
+// right before the guest resumes
+pub fn apply(vcpu_ctx: &mut vcpu) {
+    let (asid, flush) = vcpu_ctx.asids.assign(&mut vcpu_ctx.asid);
+    let flush = vcpu_ctx.tlb_flush.max(flush);
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    if vmcb.control_area.guest_asid != asid {
+        *vmcb.guest_asid_mut() = asid;
+    }
+    vmcb.control_area.tlb_control =
+        tlb_control(flush, vcpu_ctx.svm_features.has(SVM_FEATURE_FLUSH_BY_ASID));
+    vcpu_ctx.tlb_flush = tlb_flush::None;
+}
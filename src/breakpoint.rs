@@ -0,0 +1,244 @@
+// software breakpoints the guest can't see. the int3 only exists in the
+// shadow copy of the page that the hook npt view executes, and the shadow
+// only ever executes the int3, the rest of the page is single-stepped from
+// the original so reads never see it. after the callback the original
+// instruction is single-stepped in the identity view
+use crate::cr3_tracker::address_space;
+use crate::event::*;
+use crate::guest_mem::*;
+use crate::handler::dr::{DR6_BD, DR6_BS, DR6_BT};
+use crate::handler::exception::*;
+use crate::hv::{bump_state_generation, vcpu};
+use crate::log_info;
+use crate::npt::{self, npt_error, npt_view};
+use crate::structs::*;
+use crate::sync::spin_mutex;
+
+pub const MAX_SW_BREAKPOINTS: usize = 64;
+const INT3: u8 = 0xcc;
+pub const RFLAGS_TF: u64 = 1 << 8;
+
+// called in host context with the breakpoint's address. changing rip skips
+// the original instruction
+pub type sw_breakpoint_callback = fn(&mut vcpu, &mut guest_regs, u64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum bp_error {
+    Npt(npt_error),
+    Walk(walk_error),
+    Exists,
+    NotFound,
+    TooMany,
+}
+
+#[derive(Clone, Copy)]
+struct sw_breakpoint {
+    gpa: u64,
+    gva: u64,
+    cr3: u64,
+    original: u8,
+    callback: Option<sw_breakpoint_callback>,
+    hits: u64,
+}
+
+struct breakpoints {
+    entries: [Option<sw_breakpoint>; MAX_SW_BREAKPOINTS],
+    // #BP and #DB are claimed while there are breakpoints
+    handlers: Option<[handler_slot; 2]>,
+}
+
+static BREAKPOINTS: spin_mutex<breakpoints> = spin_mutex::new(breakpoints {
+    entries: [None; MAX_SW_BREAKPOINTS],
+    handlers: None,
+});
+
+impl breakpoints {
+    fn find(&mut self, gpa: u64) -> Option<&mut sw_breakpoint> {
+        self.entries.iter_mut().flatten().find(|bp| bp.gpa == gpa)
+    }
+
+    // gives #BP and #DB back once the last breakpoint is gone
+    fn release_handlers(&mut self) {
+        if self.entries.iter().any(|bp| bp.is_some()) {
+            return;
+        }
+        if let Some([bp, db]) = self.handlers.take() {
+            unregister_handler(bp);
+            unregister_handler(db);
+        }
+    }
+}
+
+impl sw_breakpoint {
+    // the page can be mapped into other address spaces, possibly at another
+    // address. kernel addresses are the same in every one of them
+    fn matches(&self, cr3: u64, rip: u64) -> bool {
+        let kernel = (self.gva as i64) < 0;
+        self.gva == rip && (kernel || address_space(self.cr3) == address_space(cr3))
+    }
+}
+
+// the gva is resolved through ctx, user breakpoints only fire in that address
+// space and kernel breakpoints in every one. other cpus see the breakpoint
+// once their tlb is flushed
+pub fn set_breakpoint<M: phys_mem + ?Sized>(
+    mem: &M,
+    ctx: &paging_ctx,
+    gva: u64,
+    callback: Option<sw_breakpoint_callback>,
+) -> Result<(), bp_error> {
+    if !npt::enabled() {
+        return Err(bp_error::Npt(npt_error::Disabled));
+    }
+    let gpa = translate(mem, ctx, gva, access::EXECUTE).map_err(bp_error::Walk)?;
+
+    let mut bps = BREAKPOINTS.lock();
+    if bps.find(gpa).is_some() {
+        return Err(bp_error::Exists);
+    }
+    let slot = bps
+        .entries
+        .iter()
+        .position(|bp| bp.is_none())
+        .ok_or(bp_error::TooMany)?;
+    if bps.handlers.is_none() {
+        let bp = register_handler(EXCEPTION_BP, bp_handler);
+        let db = register_handler(EXCEPTION_DB, db_handler);
+        if let (Some(bp), Some(db)) = (bp, db) {
+            bps.handlers = Some([bp, db]);
+        } else {
+            for handler in [bp, db].into_iter().flatten() {
+                unregister_handler(handler);
+            }
+            return Err(bp_error::TooMany);
+        }
+    }
+    if let Err(error) = npt::hook_page(gpa, mem) {
+        bps.release_handlers();
+        return Err(bp_error::Npt(error));
+    }
+    let original = npt::write_shadow(gpa, INT3).unwrap();
+    bps.entries[slot] = Some(sw_breakpoint {
+        gpa,
+        gva,
+        cr3: ctx.cr3,
+        original,
+        callback,
+        hits: 0,
+    });
+    bump_state_generation();
+    Ok(())
+}
+
+// whether the int3 of a breakpoint is at gpa, in any address space
+pub fn is_breakpoint(gpa: u64) -> bool {
+    BREAKPOINTS.lock().find(gpa).is_some()
+}
+
+pub fn clear_breakpoint<M: phys_mem + ?Sized>(
+    mem: &M,
+    ctx: &paging_ctx,
+    gva: u64,
+) -> Result<(), bp_error> {
+    let gpa = translate(mem, ctx, gva, access::EXECUTE).map_err(bp_error::Walk)?;
+
+    let mut bps = BREAKPOINTS.lock();
+    let bp = *bps.find(gpa).ok_or(bp_error::NotFound)?;
+    npt::write_shadow(gpa, bp.original);
+    npt::unhook_page(gpa);
+    for entry in bps.entries.iter_mut() {
+        if entry.is_some_and(|entry| entry.gpa == gpa) {
+            *entry = None;
+        }
+    }
+
+    bps.release_handlers();
+    bump_state_generation();
+    Ok(())
+}
+
+fn bp_handler(
+    vcpu_ctx: &mut vcpu,
+    guest_regs: &mut guest_regs,
+    _info: &exception_info,
+) -> exception_action {
+    let rip = vcpu_ctx.guest_vmcb.state_save_area.rip;
+    let Ok(gpa) = gva_to_gpa(vcpu_ctx, rip, access::EXECUTE) else {
+        return exception_action::Pass;
+    };
+    let cr3 = vcpu_ctx.guest_vmcb.state_save_area.cr3;
+    let hit = BREAKPOINTS.lock().find(gpa).map(|bp| {
+        if bp.matches(cr3, rip) {
+            bp.hits += 1;
+        }
+        *bp
+    });
+
+    let Some(bp) = hit else {
+        // a breakpoint cleared since can still be in this cpu's tlb, the
+        // exit flushed it so just retry
+        let mut byte = [0u8];
+        let stale = vcpu_ctx.npt_view == npt_view::Hook
+            && vcpu_ctx.phys_window.read(gpa, &mut byte).is_some()
+            && byte[0] != INT3;
+        return match stale {
+            true => exception_action::Consume,
+            false => exception_action::Pass,
+        };
+    };
+    // someone else's breakpoint on a shared page, run the original
+    // instruction as if nothing was there
+    if !bp.matches(cr3, rip) {
+        step_original(vcpu_ctx);
+        return exception_action::Consume;
+    }
+
+    match bp.callback {
+        Some(callback) => callback(vcpu_ctx, guest_regs, bp.gva),
+        None => log_info!(
+            "breakpoint at {:#x} (cr3 {:#x}) hit {} times",
+            bp.gva,
+            bp.cr3,
+            bp.hits
+        ),
+    }
+    match vcpu_ctx.guest_vmcb.state_save_area.rip == rip {
+        true => step_original(vcpu_ctx),
+        // the callback moved on, the shadow is left behind
+        false => npt::set_view(vcpu_ctx, npt_view::Normal),
+    }
+    exception_action::Consume
+}
+
+// runs the instruction at rip from the identity view with interrupts held
+// off, db_handler switches back
+pub fn step_original(vcpu_ctx: &mut vcpu) {
+    let state = &mut vcpu_ctx.guest_vmcb.state_save_area;
+    vcpu_ctx.bp_step_tf = Some(state.rflags & RFLAGS_TF != 0);
+    state.rflags |= RFLAGS_TF;
+    vcpu_ctx.guest_vmcb.control_area.interrupt_shadow |= 1;
+    npt::set_view(vcpu_ctx, npt_view::Identity);
+}
+
+fn db_handler(
+    vcpu_ctx: &mut vcpu,
+    _guest_regs: &mut guest_regs,
+    _info: &exception_info,
+) -> exception_action {
+    let Some(guest_tf) = vcpu_ctx.bp_step_tf.take() else {
+        return exception_action::Pass;
+    };
+    npt::set_view(vcpu_ctx, npt_view::Normal);
+
+    if !guest_tf {
+        vcpu_ctx.guest_vmcb.state_save_area.rflags &= !RFLAGS_TF;
+        *vcpu_ctx.guest_vmcb.dr6_mut() &= !DR6_BS;
+    }
+    let state = &mut vcpu_ctx.guest_vmcb.state_save_area;
+    // tf was set before, or the instruction hit a data breakpoint
+    match state.dr6 & (0xf | DR6_BD | DR6_BS | DR6_BT) {
+        0 => exception_action::Consume,
+        DR6_BS => exception_action::Pass,
+        _ => exception_action::Reflect,
+    }
+}
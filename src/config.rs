@@ -0,0 +1,149 @@
+// compile time configuration of the hypervisor
+use crate::log::log_level;
+use crate::xstate::extended_state;
+
+pub const COM1: u16 = 0x3f8;
+pub const COM2: u16 = 0x2f8;
+
+pub struct hv_config {
+    pub log: log_config,
+    // dump every vcpu's exit statistics when the driver unloads
+    pub print_stats_on_unload: bool,
+    pub cr: cr_config,
+    // debug registers the hypervisor may take over for its own breakpoints,
+    // bit n is drn
+    pub owned_dr_slots: u8,
+    // physical nmis exit and are reinjected, needed for hypervisor nmis
+    pub nmi_intercept: bool,
+    // gdb remote protocol stub, on a uart of its own
+    pub gdb: Option<serial_config>,
+    // hide the hypervisor from the guest, see stealth.rs
+    pub stealth: Option<stealth_config>,
+    pub tsc: tsc_config,
+    // the host's fs / gs / tr and syscall msrs are only loaded at exits that
+    // need them, see hidden.rs. off loads them at every exit
+    pub lazy_hidden_state: bool,
+    // how much of the guest's vector state survives the exit handler, see
+    // xstate.rs. falls back to what the cpu supports
+    pub extended_state: extended_state,
+    pub xsetbv: xsetbv_config,
+    pub idle: idle_config,
+    // inits are emulated and sipis relayed so cpus can go offline and come
+    // back, see sipi.rs. needs the x2apic, every icr write exits
+    pub init_intercept: bool,
+}
+
+// the guest going idle, see idle.rs. the guest still runs every hlt, monitor
+// and mwait itself
+pub struct idle_config {
+    // hlt exits to the idle hooks
+    pub hlt: bool,
+    // monitor and mwait exit to the idle hooks
+    pub mwait: bool,
+    // pause loop exiting, a guest running this many pauses exits. 0 turns it
+    // off, needs the pause filter
+    pub pause_filter_count: u16,
+    // cycles between two pauses above which the count starts over, needs the
+    // pause filter threshold. 0 never starts over
+    pub pause_filter_threshold: u16,
+}
+
+pub struct xsetbv_config {
+    // xsetbv exits and the new xcr0 is validated before it's loaded
+    pub intercept: bool,
+    // xcr0 bits the guest can't enable, e.g. xstate::XCR0_AVX512. implies
+    // the intercept and hides them from cpuid leaf 0xd
+    pub hidden: u64,
+}
+
+pub struct stealth_config {
+    // turns on tsc.compensate
+    pub compensate_tsc: bool,
+    // hypercalls are only answered from the guest's kernel with this in r10
+    pub hypercall_key: u64,
+}
+
+pub struct tsc_config {
+    // rdtsc / rdtscp exit and return the compensated counter
+    pub intercept: bool,
+    // take the cycles spent in the hypervisor out of the guest's tsc
+    pub compensate: bool,
+    // cycles added per exit for the world switch itself, which the exit
+    // handler can't measure
+    pub exit_overhead: u64,
+    pub policy: tsc_policy,
+}
+
+// how compensation is kept consistent across vcpus, see tsc.rs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum tsc_policy {
+    // every vcpu hides exactly its own exits
+    PerCpu,
+    // one compensation for every vcpu, only the time all of them spent in the
+    // hypervisor at once is hidden. the guest's tsc doesn't go back when
+    // moving between vcpus
+    Shared,
+}
+
+pub struct log_config {
+    pub level: log_level,
+    // DbgPrint, buffered through the log ring while in the exit handler
+    pub dbgprint: bool,
+    // written directly to the uart from any context
+    pub serial: Option<serial_config>,
+}
+
+pub struct serial_config {
+    pub port: u16,
+    pub baud: u32,
+}
+
+// control register intercepts need decode assists, only cr0, cr3, cr4 and
+// cr8 are handled
+pub struct cr_config {
+    // bit n intercepts reads / writes of crn
+    pub read_intercepts: u16,
+    pub write_intercepts: u16,
+    // bits kept set in hardware while the guest sees the value it wrote,
+    // implies read and write intercepts of that register
+    pub cr0_pinned: u64,
+    pub cr4_pinned: u64,
+}
+
+pub const CONFIG: hv_config = hv_config {
+    log: log_config {
+        level: log_level::Info,
+        dbgprint: true,
+        serial: None,
+    },
+    print_stats_on_unload: true,
+    cr: cr_config {
+        read_intercepts: 0,
+        write_intercepts: 0,
+        cr0_pinned: 0,
+        cr4_pinned: 0,
+    },
+    owned_dr_slots: 0b1100,
+    nmi_intercept: true,
+    gdb: None,
+    stealth: None,
+    tsc: tsc_config {
+        intercept: false,
+        compensate: false,
+        exit_overhead: 0,
+        policy: tsc_policy::Shared,
+    },
+    lazy_hidden_state: true,
+    extended_state: extended_state::Xsave,
+    xsetbv: xsetbv_config {
+        intercept: false,
+        hidden: 0,
+    },
+    idle: idle_config {
+        hlt: false,
+        mwait: false,
+        pause_filter_count: 0,
+        pause_filter_threshold: 0,
+    },
+    init_intercept: false,
+};
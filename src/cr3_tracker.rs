@@ -0,0 +1,189 @@
+// tracks which address space every cpu runs in through cr3 write exits. the
+// cr3 write intercept is only enabled while tracking is on, so it costs
+// nothing otherwise
+use crate::config::CONFIG;
+use crate::hooks::hook_table;
+use crate::hv::{bump_state_generation, sync_vcpus, vcpu};
+use crate::log_warn;
+use crate::sync::spin_mutex;
+use crate::vmcb::*;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+
+const CR3_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+const CR3_WRITE_INTERCEPT: u16 = 1 << 3;
+
+pub const MAX_CR3_CALLBACKS: usize = 8;
+pub const MAX_FILTER_ENTRIES: usize = 16;
+
+// the page directory base, without pcid and no flush bits
+pub fn address_space(cr3: u64) -> u64 {
+    cr3 & CR3_ADDR_MASK
+}
+
+// called on a switch that passes the filter with the old and new address space
+pub type cr3_callback = fn(&mut vcpu, u64, u64);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum filter_mode {
+    // every switch is reported
+    All = 0,
+    // only switches from or to an address space in the list
+    Allow = 1,
+    // every switch except those between address spaces in the list
+    Deny = 2,
+}
+
+// list of address spaces, 0 marks a free entry. lookups from the exit
+// handler are lock-free, changes take turns so an address space can't end
+// up in the list twice
+pub struct cr3_filter {
+    mode: AtomicU8,
+    entries: [AtomicU64; MAX_FILTER_ENTRIES],
+    writer: spin_mutex<()>,
+}
+
+impl cr3_filter {
+    pub const fn new() -> Self {
+        Self {
+            mode: AtomicU8::new(filter_mode::All as u8),
+            entries: [const { AtomicU64::new(0) }; MAX_FILTER_ENTRIES],
+            writer: spin_mutex::new(()),
+        }
+    }
+
+    pub fn mode(&self) -> filter_mode {
+        match self.mode.load(Ordering::Relaxed) {
+            1 => filter_mode::Allow,
+            2 => filter_mode::Deny,
+            _ => filter_mode::All,
+        }
+    }
+
+    pub fn set_mode(&self, mode: filter_mode) {
+        self.mode.store(mode as u8, Ordering::Relaxed);
+    }
+
+    pub fn contains(&self, cr3: u64) -> bool {
+        let cr3 = address_space(cr3);
+        cr3 != 0
+            && self
+                .entries
+                .iter()
+                .any(|entry| entry.load(Ordering::Relaxed) == cr3)
+    }
+
+    // false if the list is full
+    pub fn add(&self, cr3: u64) -> bool {
+        let cr3 = address_space(cr3);
+        if cr3 == 0 {
+            return false;
+        }
+        let _writer = self.writer.lock();
+        if self.contains(cr3) {
+            return true;
+        }
+        let Some(free) = self
+            .entries
+            .iter()
+            .find(|entry| entry.load(Ordering::Relaxed) == 0)
+        else {
+            return false;
+        };
+        free.store(cr3, Ordering::Relaxed);
+        true
+    }
+
+    // false if it wasn't in the list, 0 never is
+    pub fn remove(&self, cr3: u64) -> bool {
+        let cr3 = address_space(cr3);
+        if cr3 == 0 {
+            return false;
+        }
+        let _writer = self.writer.lock();
+        let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| entry.load(Ordering::Relaxed) == cr3)
+        else {
+            return false;
+        };
+        entry.store(0, Ordering::Relaxed);
+        true
+    }
+
+    pub fn clear(&self) {
+        let _writer = self.writer.lock();
+        for entry in &self.entries {
+            entry.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub fn matches(&self, old: u64, new: u64) -> bool {
+        match self.mode() {
+            filter_mode::All => true,
+            filter_mode::Allow => self.contains(old) || self.contains(new),
+            filter_mode::Deny => !(self.contains(old) && self.contains(new)),
+        }
+    }
+}
+
+pub static FILTER: cr3_filter = cr3_filter::new();
+
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static CALLBACKS: hook_table<cr3_callback, MAX_CR3_CALLBACKS> = hook_table::new();
+
+pub fn tracking() -> bool {
+    TRACKING.load(Ordering::Relaxed)
+}
+
+// returns the slot to unregister with, None if all slots are taken
+pub fn register_callback(callback: cr3_callback) -> Option<usize> {
+    CALLBACKS.register(callback)
+}
+
+pub fn unregister_callback(slot: usize) {
+    CALLBACKS.unregister(slot);
+}
+
+// other vcpus pick the change up at their next exit, use set_tracking when
+// it has to apply everywhere before returning
+pub fn set_tracking_lazy(enabled: bool) {
+    TRACKING.store(enabled, Ordering::Relaxed);
+    bump_state_generation();
+}
+
+// passive level only, visits every processor
+pub fn set_tracking(enabled: bool) {
+    set_tracking_lazy(enabled);
+    sync_vcpus();
+}
+
+// mirrors the tracking state into this vcpu's intercepts
+pub fn sync(vcpu_ctx: &mut vcpu) {
+    let forced = CONFIG.cr.write_intercepts & CR3_WRITE_INTERCEPT != 0;
+    let decode_assists = vcpu_ctx.svm_features.has(SVM_FEATURE_DECODE_ASSISTS);
+    if tracking() && !decode_assists {
+        log_warn!("cr3 tracking needs decode assists");
+    }
+    if (tracking() && decode_assists) || forced {
+        *vcpu_ctx.guest_vmcb.intercept_cr_write_mut() |= CR3_WRITE_INTERCEPT;
+        let cr3 = address_space(vcpu_ctx.guest_vmcb.state_save_area.cr3);
+        vcpu_ctx.shared.current_cr3.store(cr3, Ordering::Relaxed);
+    } else {
+        *vcpu_ctx.guest_vmcb.intercept_cr_write_mut() &= !CR3_WRITE_INTERCEPT;
+        vcpu_ctx.shared.current_cr3.store(0, Ordering::Relaxed);
+    }
+}
+
+pub fn on_cr3_write(vcpu_ctx: &mut vcpu, old: u64, new: u64) {
+    let old = address_space(old);
+    let new = address_space(new);
+    vcpu_ctx.shared.current_cr3.store(new, Ordering::Relaxed);
+    if old == new || !tracking() || !FILTER.matches(old, new) {
+        return;
+    }
+    for callback in CALLBACKS.iter() {
+        callback(vcpu_ctx, old, new);
+    }
+}
@@ -0,0 +1,2 @@
+pub use hv_core::gdb::{commands, packet};
+pub mod stub;
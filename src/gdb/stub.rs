@@ -0,0 +1,432 @@
+// the stub runs in the exit handler of the vcpu that stopped and polls its
+// uart until gdb resumes the guest. other vcpus are kicked and park at the
+// exit that follows, they show up as threads once they did. the boot processor watches the uart for
+// gdb connecting or breaking in at each of its exits
+use super::commands::*;
+use super::packet::*;
+use crate::breakpoint::{self, MAX_SW_BREAKPOINTS};
+use crate::config::CONFIG;
+use crate::guest_mem::*;
+use crate::handler::dr::{self, breakpoint_kind};
+use crate::hidden;
+use crate::hv::{MAX_PROCESSORS, state_generation, vcpu};
+use crate::ipi;
+use crate::log_warn;
+use crate::serial::{port_io, uart};
+use crate::single_step;
+use crate::structs::*;
+use crate::sync::spin_mutex;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+// gdb order of the gprs by instruction encoding
+const GPR_ORDER: [u8; 16] = [0, 3, 1, 2, 6, 7, 5, 4, 8, 9, 10, 11, 12, 13, 14, 15];
+const RFLAGS_RESERVED: u64 = 1 << 1;
+// supervisor access without a write check, the debugger patches code
+const DEBUGGER_ACCESS: access = access::READ;
+
+static UART: uart<port_io> = uart::new(port_io {
+    base: match CONFIG.gdb {
+        Some(ref gdb) => gdb.port,
+        None => 0,
+    },
+});
+
+// processor + 1 of the vcpu talking to gdb in the low half, 0 while the
+// guest runs. the high half counts stops, a vcpu parked in an earlier one is
+// not stopped anymore
+static OWNER: AtomicU64 = AtomicU64::new(0);
+
+struct parked_vcpu {
+    vcpu: AtomicPtr<vcpu>,
+    regs: AtomicPtr<guest_regs>,
+    stop: AtomicU64,
+}
+
+static PARKED: [parked_vcpu; MAX_PROCESSORS] = [const {
+    parked_vcpu {
+        vcpu: AtomicPtr::new(null_mut()),
+        regs: AtomicPtr::new(null_mut()),
+        stop: AtomicU64::new(0),
+    }
+}; MAX_PROCESSORS];
+
+#[derive(Clone, Copy)]
+struct hw_breakpoint {
+    kind: gdb_breakpoint,
+    address: u64,
+    len: u64,
+}
+
+#[derive(Clone, Copy)]
+struct sw_breakpoint {
+    address: u64,
+    ctx: paging_ctx,
+}
+
+struct stub {
+    reader: packet_reader,
+    session: gdb_session,
+    reply: reply_buffer,
+    // gdb waits for a stop reply after c / s
+    running: bool,
+    // by owned debug register slot
+    hw: [Option<hw_breakpoint>; 4],
+    sw: [Option<sw_breakpoint>; MAX_SW_BREAKPOINTS],
+}
+
+static STUB: spin_mutex<stub> = spin_mutex::new(stub {
+    reader: packet_reader::new(),
+    session: gdb_session::new(),
+    reply: reply_buffer::new(),
+    running: false,
+    hw: [None; 4],
+    sw: [None; MAX_SW_BREAKPOINTS],
+});
+
+pub fn init() {
+    if let Some(ref gdb) = CONFIG.gdb {
+        UART.init(gdb.baud);
+    }
+}
+
+fn try_acquire(thread: u32) -> bool {
+    let owner = OWNER.load(Ordering::Acquire);
+    owner as u32 == 0
+        && OWNER
+            .compare_exchange(
+                owner,
+                ((owner >> 32) + 1) << 32 | thread as u64,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+}
+
+fn release() {
+    OWNER.fetch_and(!0xffff_ffff, Ordering::Release);
+}
+
+// called early in every exit while the stub is configured
+pub fn poll(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    if OWNER.load(Ordering::Acquire) as u32 != 0 {
+        return park(vcpu_ctx, guest_regs);
+    }
+    if vcpu_ctx.processor != 0 || !try_acquire(vcpu_ctx.processor + 1) {
+        return;
+    }
+    match UART.read_byte() {
+        Some(byte) => run(vcpu_ctx, guest_regs, SIGINT, Some(byte)),
+        None => release(),
+    }
+}
+
+// stops the guest on this vcpu and hands it to gdb
+pub fn enter(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, signal: u8) {
+    // another vcpu stopped first, this stop is reported once it resumes
+    while !try_acquire(vcpu_ctx.processor + 1) {
+        park(vcpu_ctx, guest_regs);
+    }
+    run(vcpu_ctx, guest_regs, signal, None);
+}
+
+fn park(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let stop = OWNER.load(Ordering::Acquire);
+    if stop as u32 == 0 {
+        return;
+    }
+    // the owner reads the selectors from the vmcb
+    hidden::save_guest(vcpu_ctx);
+    let slot = &PARKED[vcpu_ctx.processor as usize];
+    slot.vcpu.store(vcpu_ctx, Ordering::Relaxed);
+    slot.regs.store(guest_regs, Ordering::Relaxed);
+    slot.stop.store(stop, Ordering::Release);
+    while OWNER.load(Ordering::Acquire) == stop {
+        ipi::serve(vcpu_ctx);
+        core::hint::spin_loop();
+    }
+    // breakpoints set while stopped
+    if vcpu_ctx.state_generation != state_generation() {
+        vcpu_ctx.sync_state();
+    }
+}
+
+fn send(payload: &[u8]) {
+    write_packet(payload, |byte| UART.write_byte(byte));
+}
+
+fn run(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, signal: u8, first: Option<u8>) {
+    hidden::save_guest(vcpu_ctx);
+    let mut guard = STUB.lock();
+    let stub = &mut *guard;
+    stub.session.stopped(vcpu_ctx.processor + 1, signal);
+    ipi::kick(vcpu_ctx);
+    if stub.running {
+        stub.running = false;
+        stub.reply.clear();
+        stop_reply(&stub.session, &mut stub.reply);
+        send(stub.reply.as_bytes());
+    }
+
+    let mut target = hv_target {
+        vcpu_ctx: &mut *vcpu_ctx,
+        guest_regs: &mut *guest_regs,
+        hw: &mut stub.hw,
+        sw: &mut stub.sw,
+    };
+    let mut pending = first;
+    loop {
+        let Some(byte) = pending.take().or_else(|| UART.read_byte()) else {
+            ipi::serve(target.vcpu_ctx);
+            core::hint::spin_loop();
+            continue;
+        };
+        match stub.reader.push(byte) {
+            Some(packet_event::Packet) => UART.write_byte(b'+'),
+            Some(packet_event::BadChecksum) | Some(packet_event::Overflow) => {
+                UART.write_byte(b'-');
+                continue;
+            }
+            Some(packet_event::Nack) => {
+                send(stub.reply.as_bytes());
+                continue;
+            }
+            // already stopped
+            Some(packet_event::Interrupt) => {
+                stub.reply.clear();
+                stop_reply(&stub.session, &mut stub.reply);
+                send(stub.reply.as_bytes());
+                continue;
+            }
+            _ => continue,
+        }
+
+        let payload = stub.reader.payload();
+        match dispatch(&mut stub.session, &mut target, payload, &mut stub.reply) {
+            gdb_action::Reply => send(stub.reply.as_bytes()),
+            gdb_action::Continue => {
+                stub.running = true;
+                break;
+            }
+            gdb_action::Step => {
+                if single_step::start_current(target.vcpu_ctx, 1, Some(step_done)) {
+                    stub.running = true;
+                    break;
+                }
+                stub.reply.error(0x01);
+                send(stub.reply.as_bytes());
+            }
+            gdb_action::Detach => {
+                if !stub.reply.as_bytes().is_empty() {
+                    send(stub.reply.as_bytes());
+                }
+                target.clear_breakpoints();
+                break;
+            }
+        }
+    }
+
+    drop(guard);
+    release();
+    if vcpu_ctx.state_generation != state_generation() {
+        vcpu_ctx.sync_state();
+    }
+}
+
+fn step_done(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    enter(vcpu_ctx, guest_regs, SIGTRAP);
+}
+
+fn sw_hit(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, _address: u64) {
+    enter(vcpu_ctx, guest_regs, SIGTRAP);
+}
+
+fn hw_hit(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, _slot: usize) {
+    enter(vcpu_ctx, guest_regs, SIGTRAP);
+}
+
+struct hv_target<'a> {
+    vcpu_ctx: &'a mut vcpu,
+    guest_regs: &'a mut guest_regs,
+    hw: &'a mut [Option<hw_breakpoint>; 4],
+    sw: &'a mut [Option<sw_breakpoint>; MAX_SW_BREAKPOINTS],
+}
+
+// a vcpu parked during the current stop
+fn parked(thread: u32) -> Option<(*mut vcpu, *mut guest_regs)> {
+    let slot = PARKED.get(thread.checked_sub(1)? as usize)?;
+    if slot.stop.load(Ordering::Acquire) != OWNER.load(Ordering::Relaxed) {
+        return None;
+    }
+    Some((
+        slot.vcpu.load(Ordering::Relaxed),
+        slot.regs.load(Ordering::Relaxed),
+    ))
+}
+
+impl hv_target<'_> {
+    fn thread(&mut self, thread: u32) -> Option<(&mut vcpu, &mut guest_regs)> {
+        if thread == self.vcpu_ctx.processor + 1 {
+            return Some((&mut *self.vcpu_ctx, &mut *self.guest_regs));
+        }
+        // the parked vcpu spins until the stop ends
+        let (vcpu_ctx, guest_regs) = parked(thread)?;
+        unsafe { Some((&mut *vcpu_ctx, &mut *guest_regs)) }
+    }
+
+    fn paging_ctx(&mut self, thread: u32) -> Option<paging_ctx> {
+        let (vcpu_ctx, _) = self.thread(thread)?;
+        Some(paging_ctx::from(&vcpu_ctx.guest_vmcb.state_save_area))
+    }
+
+    fn clear_breakpoints(&mut self) {
+        for slot in 0..self.hw.len() {
+            if self.hw[slot].take().is_some() {
+                dr::clear_breakpoint_lazy(slot);
+            }
+        }
+        for entry in self.sw.iter_mut() {
+            if let Some(bp) = entry.take() {
+                let _ =
+                    breakpoint::clear_breakpoint(&self.vcpu_ctx.phys_window, &bp.ctx, bp.address);
+            }
+        }
+    }
+}
+
+impl gdb_target for hv_target<'_> {
+    fn thread_alive(&self, thread: u32) -> bool {
+        thread == self.vcpu_ctx.processor + 1 || parked(thread).is_some()
+    }
+
+    fn read_registers(&mut self, thread: u32) -> Option<gdb_regs> {
+        let (vcpu_ctx, guest_regs) = self.thread(thread)?;
+        let state = &vcpu_ctx.guest_vmcb.state_save_area;
+        let mut regs = gdb_regs::default();
+        for (value, &gpr) in regs.values.iter_mut().zip(GPR_ORDER.iter()) {
+            *value = guest_regs.gpr(gpr);
+        }
+        regs.values[GDB_RIP] = state.rip;
+        regs.values[GDB_EFLAGS] = state.rflags;
+        let selectors = [
+            state.cs_selector,
+            state.ss_selector,
+            state.ds_selector,
+            state.es_selector,
+            state.fs_selector,
+            state.gs_selector,
+        ];
+        for (value, selector) in regs.values[GDB_CS..].iter_mut().zip(selectors) {
+            *value = selector as u64;
+        }
+        Some(regs)
+    }
+
+    // selectors are read only, loading them would need the descriptors
+    fn write_registers(&mut self, thread: u32, regs: &gdb_regs) -> bool {
+        let Some((vcpu_ctx, guest_regs)) = self.thread(thread) else {
+            return false;
+        };
+        for (&value, &gpr) in regs.values.iter().zip(GPR_ORDER.iter()) {
+            guest_regs.set_gpr(gpr, value);
+        }
+        let state = &mut vcpu_ctx.guest_vmcb.state_save_area;
+        if state.rip != regs.values[GDB_RIP] {
+            state.rip = regs.values[GDB_RIP];
+            vcpu_ctx.advance_rip = false;
+        }
+        state.rflags = regs.values[GDB_EFLAGS] & 0xffff_ffff | RFLAGS_RESERVED;
+        true
+    }
+
+    fn read_memory(&mut self, thread: u32, address: u64, buf: &mut [u8]) -> bool {
+        let Some(ctx) = self.paging_ctx(thread) else {
+            return false;
+        };
+        read_virt(
+            &self.vcpu_ctx.phys_window,
+            &ctx,
+            address,
+            buf,
+            DEBUGGER_ACCESS,
+        )
+        .is_ok()
+    }
+
+    fn write_memory(&mut self, thread: u32, address: u64, data: &[u8]) -> bool {
+        let Some(ctx) = self.paging_ctx(thread) else {
+            return false;
+        };
+        write_virt(
+            &self.vcpu_ctx.phys_window,
+            &ctx,
+            address,
+            data,
+            DEBUGGER_ACCESS,
+        )
+        .is_ok()
+    }
+
+    fn set_breakpoint(&mut self, kind: gdb_breakpoint, address: u64, len: u64) -> bool {
+        if kind == gdb_breakpoint::Software {
+            // in the address space of the vcpu that stopped
+            let ctx = paging_ctx::from(&self.vcpu_ctx.guest_vmcb.state_save_area);
+            let Some(entry) = self.sw.iter_mut().find(|entry| entry.is_none()) else {
+                return false;
+            };
+            let set =
+                breakpoint::set_breakpoint(&self.vcpu_ctx.phys_window, &ctx, address, Some(sw_hit));
+            if set.is_ok() {
+                *entry = Some(sw_breakpoint { address, ctx });
+            }
+            return set.is_ok();
+        }
+
+        // x86 has no read only watchpoints
+        let (dr_kind, len) = match kind {
+            gdb_breakpoint::Hardware => (breakpoint_kind::Execute, 1),
+            gdb_breakpoint::Write => (breakpoint_kind::Write, len),
+            _ => (breakpoint_kind::ReadWrite, len),
+        };
+        let free =
+            (0..4).find(|slot| CONFIG.owned_dr_slots & 1 << slot != 0 && self.hw[*slot].is_none());
+        let Some(slot) = free else {
+            return false;
+        };
+        match dr::set_breakpoint_lazy(slot, address, dr_kind, len, Some(hw_hit)) {
+            Ok(()) => {
+                self.hw[slot] = Some(hw_breakpoint { kind, address, len });
+                true
+            }
+            Err(error) => {
+                log_warn!("gdb breakpoint at {:#x} rejected: {:?}", address, error);
+                false
+            }
+        }
+    }
+
+    fn clear_breakpoint(&mut self, kind: gdb_breakpoint, address: u64, len: u64) -> bool {
+        if kind == gdb_breakpoint::Software {
+            let Some(entry) = self
+                .sw
+                .iter_mut()
+                .find(|entry| entry.is_some_and(|bp| bp.address == address))
+            else {
+                return false;
+            };
+            let bp = entry.take().unwrap();
+            return breakpoint::clear_breakpoint(&self.vcpu_ctx.phys_window, &bp.ctx, address)
+                .is_ok();
+        }
+
+        let slot = self.hw.iter().position(|entry| {
+            entry.is_some_and(|bp| bp.kind == kind && bp.address == address && bp.len == len)
+        });
+        let Some(slot) = slot else {
+            return false;
+        };
+        self.hw[slot] = None;
+        dr::clear_breakpoint_lazy(slot);
+        true
+    }
+}
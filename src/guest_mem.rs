@@ -0,0 +1,121 @@
+// guest memory as seen by the vcpu whose exit is being handled, on top of the
+// page table walker in hv_core
+use crate::event::*;
+use crate::hv::vcpu;
+use crate::vmcb::*;
+use core::mem::MaybeUninit;
+pub use hv_core::guest_mem::*;
+use wdk_sys::{PHYSICAL_ADDRESS, ntddk::*};
+
+impl From<&state_save> for paging_ctx {
+    fn from(state: &state_save) -> Self {
+        Self {
+            cr0: state.cr0,
+            cr3: state.cr3,
+            cr4: state.cr4,
+            efer: state.efer,
+        }
+    }
+}
+
+// raise the fault in the guest as if its own access had failed
+pub fn inject_page_fault(guest_vmcb: &mut vmcb, fault: &page_fault) {
+    *guest_vmcb.cr2_mut() = fault.address;
+    guest_vmcb.control_area.event_inj = exception(EXCEPTION_PF, Some(fault.error_code));
+}
+
+// accesses guest physical memory through the kernel's own mappings, guest
+// physical memory is identical to host physical memory. only usable for pages
+// with a system address and in normal context, the exit handler uses the
+// vcpu's phys_window instead
+pub struct kernel_phys_mem;
+
+impl kernel_phys_mem {
+    fn va(pa: u64) -> Option<*mut u8> {
+        let va = unsafe {
+            MmGetVirtualForPhysical(PHYSICAL_ADDRESS {
+                QuadPart: pa as i64,
+            })
+        };
+        (!va.is_null()).then_some(va as *mut u8)
+    }
+}
+
+impl phys_mem for kernel_phys_mem {
+    fn read(&self, pa: u64, buf: &mut [u8]) -> Option<()> {
+        let src = Self::va(pa)?;
+        unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Some(())
+    }
+
+    fn write(&self, pa: u64, data: &[u8]) -> Option<()> {
+        let dst = Self::va(pa)?;
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Some(())
+    }
+}
+
+// the guest's view of memory for buffers handed over in a hypercall, user mode
+// callers only get to touch user pages
+fn guest_access(vcpu_ctx: &vcpu, write: bool) -> (paging_ctx, access) {
+    let state = &vcpu_ctx.guest_vmcb.state_save_area;
+    let access = access {
+        write,
+        user: state.cpl == 3,
+        execute: false,
+    };
+    (paging_ctx::from(state), access)
+}
+
+pub fn gva_to_gpa(vcpu_ctx: &vcpu, gva: u64, access: access) -> Result<u64, walk_error> {
+    let ctx = paging_ctx::from(&vcpu_ctx.guest_vmcb.state_save_area);
+    translate(&vcpu_ctx.phys_window, &ctx, gva, access)
+}
+
+pub fn read_guest<T: pod>(vcpu_ctx: &vcpu, gva: u64) -> Result<T, guest_mem_error> {
+    let (ctx, access) = guest_access(vcpu_ctx, false);
+    let mut value = MaybeUninit::<T>::zeroed();
+    let bytes =
+        unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    read_virt(&vcpu_ctx.phys_window, &ctx, gva, bytes, access)?;
+    Ok(unsafe { value.assume_init() })
+}
+
+// checks that [gva, gva + len) is accessible without touching it
+pub fn probe_guest(
+    vcpu_ctx: &vcpu,
+    gva: u64,
+    len: usize,
+    write: bool,
+) -> Result<(), guest_mem_error> {
+    let (ctx, access) = guest_access(vcpu_ctx, write);
+    for_each_page(&vcpu_ctx.phys_window, &ctx, gva, len, access, |_, _, _| {
+        Ok(true)
+    })?;
+    Ok(())
+}
+
+pub fn write_guest(vcpu_ctx: &vcpu, gva: u64, data: &[u8]) -> Result<(), guest_mem_error> {
+    let (ctx, access) = guest_access(vcpu_ctx, true);
+    write_virt(&vcpu_ctx.phys_window, &ctx, gva, data, access)?;
+    Ok(())
+}
+
+pub fn write_guest_value<T: pod>(
+    vcpu_ctx: &vcpu,
+    gva: u64,
+    value: &T,
+) -> Result<(), guest_mem_error> {
+    let bytes =
+        unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) };
+    write_guest(vcpu_ctx, gva, bytes)
+}
+
+pub fn copy_from_guest_string(
+    vcpu_ctx: &vcpu,
+    gva: u64,
+    buf: &mut [u8],
+) -> Result<usize, guest_mem_error> {
+    let (ctx, access) = guest_access(vcpu_ctx, false);
+    read_virt_string(&vcpu_ctx.phys_window, &ctx, gva, buf, access)
+}
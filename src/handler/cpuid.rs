@@ -0,0 +1,21 @@
+// cpuid is only intercepted by the stealth profile and to hide xcr0 features
+use crate::hv::vcpu;
+use crate::stealth;
+use crate::structs::*;
+use crate::xstate;
+use core::arch::x86_64::__cpuid_count;
+
+pub fn cpuid_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let leaf = guest_regs.rax as u32;
+    let subleaf = guest_regs.rcx as u32;
+    let result = unsafe { __cpuid_count(leaf, subleaf) };
+    let mut regs = [result.eax, result.ebx, result.ecx, result.edx];
+    if stealth::enabled() {
+        stealth::filter_cpuid(leaf, &mut regs);
+    }
+    xstate::filter_cpuid(leaf, subleaf, &mut regs);
+    guest_regs.rax = regs[0] as u64;
+    guest_regs.rbx = regs[1] as u64;
+    guest_regs.rcx = regs[2] as u64;
+    guest_regs.rdx = regs[3] as u64;
+}
@@ -0,0 +1,333 @@
+// control register access intercepts. cr0 and cr4 can have bits pinned in
+// hardware while the guest keeps seeing the value it wrote
+use crate::asid::{request_tlb_flush, tlb_flush};
+use crate::config::CONFIG;
+use crate::cr3_tracker;
+use crate::event::*;
+use crate::guest_mem::*;
+use crate::hidden;
+use crate::hv::vcpu;
+use crate::log_warn;
+use crate::structs::*;
+use crate::vmcb::*;
+use core::arch::asm;
+use hv_core::msw::*;
+
+pub const CR0_PE: u64 = 1 << 0;
+pub const CR0_TS: u64 = 1 << 3;
+pub const CR0_NW: u64 = 1 << 29;
+pub const CR0_CD: u64 = 1 << 30;
+pub const CR4_PSE: u64 = 1 << 4;
+pub const CR4_PGE: u64 = 1 << 7;
+pub const CR4_PCIDE: u64 = 1 << 17;
+pub const CR4_SMEP: u64 = 1 << 20;
+pub const CR4_SMAP: u64 = 1 << 21;
+pub const CR4_PKE: u64 = 1 << 22;
+pub const CR3_NO_FLUSH: u64 = 1 << 63;
+
+// VME PVI TSD DE PSE PAE MCE PGE PCE OSFXSR OSXMMEXCPT UMIP LA57 FSGSBASE
+// PCIDE OSXSAVE SMEP SMAP PKE CET
+const CR4_VALID: u64 = 0x0000_0000_00f7_1fff;
+const CR3_RESERVED: u64 = 0x7ff0_0000_0000_0000;
+const CR0_TLB_BITS: u64 = CR0_PG | CR0_WP | CR0_CD | CR0_NW;
+const CR4_TLB_BITS: u64 =
+    CR4_PSE | CR4_PAE | CR4_PGE | CR4_PCIDE | CR4_SMEP | CR4_SMAP | CR4_PKE | CR4_LA57;
+
+// cr0, cr3, cr4 and cr8 are the only ones with handlers
+const HANDLED_CRS: u16 = 1 << 0 | 1 << 3 | 1 << 4 | 1 << 8;
+
+pub fn setup_cr_intercepts(vcpu_ctx: &mut vcpu) {
+    let config = &CONFIG.cr;
+    let state = &vcpu_ctx.guest_vmcb.state_save_area;
+    vcpu_ctx.cr0_shadow = state.cr0;
+    vcpu_ctx.cr4_shadow = state.cr4;
+
+    let mut read = config.read_intercepts;
+    let mut write = config.write_intercepts;
+    if config.cr0_pinned != 0 {
+        read |= 1 << 0;
+        write |= 1 << 0;
+    }
+    if config.cr4_pinned != 0 {
+        read |= 1 << 4;
+        write |= 1 << 4;
+    }
+    read &= HANDLED_CRS;
+    write &= HANDLED_CRS;
+    if read | write == 0 {
+        return;
+    }
+    if !vcpu_ctx.svm_features.has(SVM_FEATURE_DECODE_ASSISTS) {
+        return log_warn!("decode assists are not supported, cr intercepts disabled");
+    }
+
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    *vmcb.cr0_mut() |= config.cr0_pinned;
+    *vmcb.cr4_mut() |= config.cr4_pinned;
+    *vmcb.intercept_cr_read_mut() |= read;
+    *vmcb.intercept_cr_write_mut() |= write;
+}
+
+// cr0 and cr4 as an init leaves them, pinned bits stay set in hardware
+pub fn reset(vcpu_ctx: &mut vcpu, cr0: u64, cr4: u64) {
+    let config = &CONFIG.cr;
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    let pinned = |cr: u8| vmcb.control_area.intercept_cr_write & 1 << cr != 0;
+    let (cr0_pinned, cr4_pinned) = (pinned(0), pinned(4));
+    *vmcb.cr0_mut() = cr0 | if cr0_pinned { config.cr0_pinned } else { 0 };
+    *vmcb.cr4_mut() = cr4 | if cr4_pinned { config.cr4_pinned } else { 0 };
+    vcpu_ctx.cr0_shadow = cr0;
+    vcpu_ctx.cr4_shadow = cr4;
+}
+
+// the value the guest expects to read, pinned bits come from the shadow
+pub fn guest_cr0(vcpu_ctx: &vcpu) -> u64 {
+    let pinned = CONFIG.cr.cr0_pinned;
+    (vcpu_ctx.guest_vmcb.state_save_area.cr0 & !pinned) | (vcpu_ctx.cr0_shadow & pinned)
+}
+
+pub fn guest_cr4(vcpu_ctx: &vcpu) -> u64 {
+    let pinned = CONFIG.cr.cr4_pinned;
+    (vcpu_ctx.guest_vmcb.state_save_area.cr4 & !pinned) | (vcpu_ctx.cr4_shadow & pinned)
+}
+
+fn read_cr8() -> u64 {
+    let value: u64;
+    unsafe { asm!("mov {}, cr8", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+// with V_INTR_MASKING off the guest's tpr is the physical one
+fn write_cr8(value: u64) {
+    unsafe { asm!("mov cr8, {}", in(reg) value, options(nomem, nostack)) };
+}
+
+pub fn cr_read_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, cr: u8) {
+    let value = match cr {
+        0 => guest_cr0(vcpu_ctx),
+        3 => vcpu_ctx.guest_vmcb.state_save_area.cr3,
+        4 => guest_cr4(vcpu_ctx),
+        _ => read_cr8(),
+    };
+
+    let exit_info1 = vcpu_ctx.guest_vmcb.control_area.exit_info1;
+    if exit_info1 & EXIT_INFO1_MOV_CR != 0 {
+        guest_regs.set_gpr((exit_info1 & EXIT_INFO1_GPR_MASK) as u8, value);
+        return;
+    }
+
+    // SMSW
+    match decode_msw(vcpu_ctx) {
+        Some(msw_instruction {
+            op: msw_op::Smsw(msw_operand::Reg(reg)),
+            operand_size,
+            ..
+        }) => {
+            let value = match operand_size {
+                2 => (guest_regs.gpr(reg) & !0xffff) | (value & 0xffff),
+                4 => value & 0xffff_ffff,
+                _ => value,
+            };
+            guest_regs.set_gpr(reg, value);
+        }
+        Some(msw_instruction {
+            op: msw_op::Smsw(msw_operand::Mem(mem)),
+            len,
+            ..
+        }) => {
+            let address = operand_address(vcpu_ctx, guest_regs, &mem, len);
+            if let Err(error) = write_guest_value(vcpu_ctx, address, &(value as u16)) {
+                operand_fault(vcpu_ctx, error);
+            }
+        }
+        _ => unsupported(vcpu_ctx),
+    }
+}
+
+pub fn cr_write_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, cr: u8) {
+    let exit_info1 = vcpu_ctx.guest_vmcb.control_area.exit_info1;
+    let value = if exit_info1 & EXIT_INFO1_MOV_CR != 0 {
+        guest_regs.gpr((exit_info1 & EXIT_INFO1_GPR_MASK) as u8)
+    } else {
+        // CLTS and LMSW, only ever cr0
+        let cr0 = guest_cr0(vcpu_ctx);
+        match decode_msw(vcpu_ctx) {
+            Some(msw_instruction {
+                op: msw_op::Clts, ..
+            }) => cr0 & !CR0_TS,
+            Some(msw_instruction {
+                op: msw_op::Lmsw(operand),
+                len,
+                ..
+            }) => {
+                let msw = match operand {
+                    msw_operand::Reg(reg) => guest_regs.gpr(reg),
+                    msw_operand::Mem(mem) => {
+                        let address = operand_address(vcpu_ctx, guest_regs, &mem, len);
+                        match read_guest::<u16>(vcpu_ctx, address) {
+                            Ok(msw) => msw as u64,
+                            Err(error) => return operand_fault(vcpu_ctx, error),
+                        }
+                    }
+                };
+                // loads PE MP EM TS, PE can be set but not cleared
+                (cr0 & !0xf) | (msw & 0xf) | (cr0 & CR0_PE)
+            }
+            _ => return unsupported(vcpu_ctx),
+        }
+    };
+
+    let valid = match cr {
+        0 => set_cr0(vcpu_ctx, value),
+        3 => set_cr3(vcpu_ctx, value),
+        4 => set_cr4(vcpu_ctx, value),
+        _ if value & !0xf == 0 => {
+            write_cr8(value);
+            true
+        }
+        _ => false,
+    };
+    if !valid {
+        vcpu_ctx.inject_fault(EXCEPTION_GP, Some(0));
+    }
+}
+
+fn set_cr0(vcpu_ctx: &mut vcpu, value: u64) -> bool {
+    let long_mode = vcpu_ctx.guest_vmcb.state_save_area.efer & EFER_LMA != 0;
+    if value >> 32 != 0
+        || (value & CR0_PG != 0 && value & CR0_PE == 0)
+        || (value & CR0_NW != 0 && value & CR0_CD == 0)
+        || (long_mode && value & CR0_PG == 0)
+    {
+        return false;
+    }
+
+    let old = guest_cr0(vcpu_ctx);
+    vcpu_ctx.cr0_shadow = value;
+    *vcpu_ctx.guest_vmcb.cr0_mut() = value | CONFIG.cr.cr0_pinned;
+    if (old ^ value) & CR0_TLB_BITS != 0 {
+        request_tlb_flush(vcpu_ctx, tlb_flush::Asid);
+    }
+    true
+}
+
+fn set_cr3(vcpu_ctx: &mut vcpu, value: u64) -> bool {
+    if value & CR3_RESERVED != 0 {
+        return false;
+    }
+    let pcide = guest_cr4(vcpu_ctx) & CR4_PCIDE != 0;
+    let no_flush = pcide && value & CR3_NO_FLUSH != 0;
+    let value = value & !CR3_NO_FLUSH;
+
+    let old = vcpu_ctx.guest_vmcb.state_save_area.cr3;
+    *vcpu_ctx.guest_vmcb.cr3_mut() = value;
+    if !no_flush {
+        request_tlb_flush(vcpu_ctx, tlb_flush::NonGlobal);
+    }
+    cr3_tracker::on_cr3_write(vcpu_ctx, old, value);
+    true
+}
+
+fn set_cr4(vcpu_ctx: &mut vcpu, value: u64) -> bool {
+    let old = guest_cr4(vcpu_ctx);
+    let long_mode = vcpu_ctx.guest_vmcb.state_save_area.efer & EFER_LMA != 0;
+    if value & !CR4_VALID != 0
+        || (long_mode && value & CR4_PAE == 0)
+        || (long_mode && (old ^ value) & CR4_LA57 != 0)
+        || (old & CR4_PCIDE == 0
+            && value & CR4_PCIDE != 0
+            && vcpu_ctx.guest_vmcb.state_save_area.cr3 & 0xfff != 0)
+    {
+        return false;
+    }
+
+    vcpu_ctx.cr4_shadow = value;
+    *vcpu_ctx.guest_vmcb.cr4_mut() = value | CONFIG.cr.cr4_pinned;
+    if (old ^ value) & CR4_TLB_BITS != 0 {
+        request_tlb_flush(vcpu_ctx, tlb_flush::Asid);
+    }
+    true
+}
+
+fn unsupported(vcpu_ctx: &mut vcpu) {
+    log_warn!(
+        "unsupported cr access at {:#x}",
+        vcpu_ctx.guest_vmcb.state_save_area.rip
+    );
+    vcpu_ctx.inject_fault(EXCEPTION_UD, None);
+}
+
+// the guest's own fault for an smsw / lmsw memory operand it can't access
+fn operand_fault(vcpu_ctx: &mut vcpu, error: guest_mem_error) {
+    match error {
+        guest_mem_error::Walk(walk_error::PageFault(fault)) => {
+            inject_page_fault(&mut vcpu_ctx.guest_vmcb, &fault);
+            vcpu_ctx.advance_rip = false;
+        }
+        _ => vcpu_ctx.inject_fault(EXCEPTION_GP, Some(0)),
+    }
+}
+
+// default operand and address size of the guest's code segment in bytes
+fn code_size(vcpu_ctx: &vcpu) -> u8 {
+    let state = &vcpu_ctx.guest_vmcb.state_save_area;
+    if state.efer & EFER_LMA != 0 && state.cs_attrib & CS_L != 0 {
+        8
+    } else if state.cs_attrib & CS_D != 0 {
+        4
+    } else {
+        2
+    }
+}
+
+// linear address of a memory operand, segment limits aren't checked
+fn operand_address(
+    vcpu_ctx: &mut vcpu,
+    guest_regs: &guest_regs,
+    mem: &mem_operand,
+    len: u8,
+) -> u64 {
+    let long_mode = code_size(vcpu_ctx) == 8;
+    let next_rip = vcpu_ctx
+        .guest_vmcb
+        .state_save_area
+        .rip
+        .wrapping_add(len as u64);
+    let offset = mem.offset(|reg| guest_regs.gpr(reg), next_rip);
+    if matches!(mem.segment, segment::Fs | segment::Gs) {
+        hidden::save_guest(vcpu_ctx);
+    }
+    let state = &vcpu_ctx.guest_vmcb.state_save_area;
+    let base = match mem.segment {
+        segment::Fs => state.fs_base,
+        segment::Gs => state.gs_base,
+        // flat in 64-bit mode
+        _ if long_mode => 0,
+        segment::Es => state.es_base,
+        segment::Cs => state.cs_base,
+        segment::Ss => state.ss_base,
+        segment::Ds => state.ds_base,
+    };
+    match long_mode {
+        true => base.wrapping_add(offset),
+        false => base.wrapping_add(offset) & 0xffff_ffff,
+    }
+}
+
+// decodes the cr0 accesses that come without decode assist info
+fn decode_msw(vcpu_ctx: &vcpu) -> Option<msw_instruction> {
+    let state = &vcpu_ctx.guest_vmcb.state_save_area;
+    let rip = state.cs_base.wrapping_add(state.rip);
+    let ctx = paging_ctx::from(state);
+    let mut bytes = [0u8; MAX_INSTRUCTION_LEN];
+    let fetch = access {
+        write: false,
+        user: state.cpl == 3,
+        execute: true,
+    };
+    // the instruction may end right before an unmapped page
+    let len = (2..=bytes.len()).rev().find(|&len| {
+        read_virt(&vcpu_ctx.phys_window, &ctx, rip, &mut bytes[..len], fetch).is_ok()
+    })?;
+    decode(&bytes[..len], code_size(vcpu_ctx))
+}
@@ -0,0 +1,335 @@
+// debug register intercepts. the hypervisor can take over some of dr0-dr3
+// for breakpoints the guest never sees, the guest keeps working with shadow
+// copies of those slots and of dr7. intercepts are only on while a slot is
+// owned
+use crate::config::CONFIG;
+use crate::event::*;
+use crate::handler::cr::guest_cr4;
+use crate::handler::exception::*;
+use crate::hooks::hook_table;
+use crate::hv::{bump_state_generation, sync_vcpus, vcpu};
+use crate::structs::*;
+use crate::vmcb::*;
+use crate::{log_info, log_warn};
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, AtomicU64, AtomicUsize, Ordering};
+
+pub const DR6_B0: u64 = 1 << 0;
+pub const DR6_BD: u64 = 1 << 13;
+pub const DR6_BS: u64 = 1 << 14;
+pub const DR6_BT: u64 = 1 << 15;
+pub const DR7_GD: u64 = 1 << 13;
+pub const RFLAGS_RF: u64 = 1 << 16;
+const CR4_DE: u64 = 1 << 3;
+
+// dr0-dr7, reads and writes of dr4 / dr5 alias dr6 / dr7 unless cr4.de
+const DR_INTERCEPTS: u16 = 0xff;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum breakpoint_kind {
+    Execute = 0,
+    Write = 1,
+    Io = 2,
+    ReadWrite = 3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum dr_error {
+    // the slot is not in CONFIG.owned_dr_slots
+    NotOwnable,
+    // execute breakpoints are 1 byte, data ones 1, 2, 4 or 8 and aligned
+    InvalidLength,
+    Unaligned,
+}
+
+// called in host context when an owned breakpoint hits, with the slot
+pub type breakpoint_callback = fn(&mut vcpu, &mut guest_regs, usize);
+
+struct owned_slot {
+    address: AtomicU64,
+    // this slot's enable, r/w and len bits of dr7
+    dr7: AtomicU64,
+    callback: hook_table<breakpoint_callback, 1>,
+}
+
+static SLOTS: [owned_slot; 4] = [const {
+    owned_slot {
+        address: AtomicU64::new(0),
+        dr7: AtomicU64::new(0),
+        callback: hook_table::new(),
+    }
+}; 4];
+// slots with an armed hypervisor breakpoint
+static OWNED: AtomicU8 = AtomicU8::new(0);
+// #DB is claimed while any slot is owned, holds the handler index + 1
+static DB_HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+fn slot_dr7_mask(slot: usize) -> u64 {
+    0b11 << (slot * 2) | 0xf << (16 + slot * 4)
+}
+
+fn owned_dr7_mask(owned: u8) -> u64 {
+    (0..4)
+        .filter(|slot| owned & 1 << slot != 0)
+        .fold(0, |mask, slot| mask | slot_dr7_mask(slot))
+}
+
+// dr7 bits for a global breakpoint in slot
+pub fn encode_dr7(slot: usize, kind: breakpoint_kind, len: u64) -> Option<u64> {
+    let len = match (kind, len) {
+        (breakpoint_kind::Execute, 1) => 0b00,
+        (breakpoint_kind::Execute, _) => return None,
+        (_, 1) => 0b00,
+        (_, 2) => 0b01,
+        (_, 8) => 0b10,
+        (_, 4) => 0b11,
+        _ => return None,
+    };
+    let control = kind as u64 | len << 2;
+    Some(0b10 << (slot * 2) | control << (16 + slot * 4))
+}
+
+// other vcpus pick the breakpoint up at their next exit, use set_breakpoint
+// when it has to be armed everywhere before returning
+pub fn set_breakpoint_lazy(
+    slot: usize,
+    address: u64,
+    kind: breakpoint_kind,
+    len: u64,
+    callback: Option<breakpoint_callback>,
+) -> Result<(), dr_error> {
+    if slot >= 4 || CONFIG.owned_dr_slots & 1 << slot == 0 {
+        return Err(dr_error::NotOwnable);
+    }
+    let dr7 = encode_dr7(slot, kind, len).ok_or(dr_error::InvalidLength)?;
+    if address & (len - 1) != 0 {
+        return Err(dr_error::Unaligned);
+    }
+
+    let entry = &SLOTS[slot];
+    entry.dr7.store(0, Ordering::Release);
+    entry.address.store(address, Ordering::Relaxed);
+    entry.callback.unregister(0);
+    if let Some(callback) = callback {
+        entry.callback.register(callback);
+    }
+    entry.dr7.store(dr7, Ordering::Release);
+    if OWNED.fetch_or(1 << slot, Ordering::AcqRel) == 0 {
+        if let Some(handler) = register_handler(EXCEPTION_DB, db_handler) {
+            DB_HANDLER.store(handler.index + 1, Ordering::Release);
+        }
+    }
+    bump_state_generation();
+    Ok(())
+}
+
+// passive level only, visits every processor
+pub fn set_breakpoint(
+    slot: usize,
+    address: u64,
+    kind: breakpoint_kind,
+    len: u64,
+    callback: Option<breakpoint_callback>,
+) -> Result<(), dr_error> {
+    set_breakpoint_lazy(slot, address, kind, len, callback)?;
+    sync_vcpus();
+    Ok(())
+}
+
+pub fn clear_breakpoint_lazy(slot: usize) {
+    if slot < 4 {
+        let owned = OWNED.fetch_and(!(1 << slot), Ordering::AcqRel);
+        SLOTS[slot].dr7.store(0, Ordering::Release);
+        if owned == 1 << slot {
+            let index = DB_HANDLER.swap(0, Ordering::AcqRel);
+            if index != 0 {
+                unregister_handler(handler_slot {
+                    vector: EXCEPTION_DB,
+                    index: index - 1,
+                });
+            }
+        }
+        bump_state_generation();
+    }
+}
+
+pub fn clear_breakpoint(slot: usize) {
+    clear_breakpoint_lazy(slot);
+    sync_vcpus();
+}
+
+fn read_dr(dr: u8) -> u64 {
+    let value: u64;
+    unsafe {
+        match dr {
+            0 => asm!("mov {}, dr0", out(reg) value, options(nomem, nostack)),
+            1 => asm!("mov {}, dr1", out(reg) value, options(nomem, nostack)),
+            2 => asm!("mov {}, dr2", out(reg) value, options(nomem, nostack)),
+            _ => asm!("mov {}, dr3", out(reg) value, options(nomem, nostack)),
+        }
+    }
+    value
+}
+
+// dr0-dr3 are not part of the vmcb, the hardware registers are the guest's
+fn write_dr(dr: u8, value: u64) {
+    unsafe {
+        match dr {
+            0 => asm!("mov dr0, {}", in(reg) value, options(nomem, nostack)),
+            1 => asm!("mov dr1, {}", in(reg) value, options(nomem, nostack)),
+            2 => asm!("mov dr2, {}", in(reg) value, options(nomem, nostack)),
+            _ => asm!("mov dr3, {}", in(reg) value, options(nomem, nostack)),
+        }
+    }
+}
+
+// hardware dr7: the guest's slots, the owned ones and never general detect
+fn hardware_dr7(vcpu_ctx: &vcpu) -> u64 {
+    let owned = vcpu_ctx.dr_owned;
+    let hypervisor = (0..4)
+        .filter(|slot| owned & 1 << slot != 0)
+        .fold(0, |dr7, slot| dr7 | SLOTS[slot].dr7.load(Ordering::Acquire));
+    (vcpu_ctx.dr7_shadow & !owned_dr7_mask(owned) & !DR7_GD) | hypervisor
+}
+
+// dr7 as an init leaves it, owned slots stay armed
+pub fn reset(vcpu_ctx: &mut vcpu, dr7: u64) {
+    vcpu_ctx.dr7_shadow = dr7;
+    *vcpu_ctx.guest_vmcb.dr7_mut() = match vcpu_ctx.dr_owned {
+        0 => dr7,
+        _ => hardware_dr7(vcpu_ctx),
+    };
+}
+
+// mirrors the owned breakpoints into this vcpu's debug registers
+pub fn sync(vcpu_ctx: &mut vcpu) {
+    let mut owned = OWNED.load(Ordering::Acquire);
+    if owned != 0 && !vcpu_ctx.svm_features.has(SVM_FEATURE_DECODE_ASSISTS) {
+        log_warn!("hypervisor breakpoints need decode assists");
+        owned = 0;
+    }
+    let previous = vcpu_ctx.dr_owned;
+    if previous == 0 && owned != 0 {
+        vcpu_ctx.dr7_shadow = vcpu_ctx.guest_vmcb.state_save_area.dr7;
+    }
+
+    for slot in 0..4u8 {
+        let bit = 1 << slot;
+        if owned & bit != 0 {
+            if previous & bit == 0 {
+                vcpu_ctx.dr_shadow[slot as usize] = read_dr(slot);
+            }
+            write_dr(slot, SLOTS[slot as usize].address.load(Ordering::Relaxed));
+        } else if previous & bit != 0 {
+            write_dr(slot, vcpu_ctx.dr_shadow[slot as usize]);
+        }
+    }
+    vcpu_ctx.dr_owned = owned;
+
+    if owned != 0 {
+        *vcpu_ctx.guest_vmcb.dr7_mut() = hardware_dr7(vcpu_ctx);
+    } else if previous != 0 {
+        *vcpu_ctx.guest_vmcb.dr7_mut() = vcpu_ctx.dr7_shadow;
+    }
+    let intercepts = if owned != 0 { DR_INTERCEPTS } else { 0 };
+    if vcpu_ctx.guest_vmcb.control_area.intercept_dr_read != intercepts {
+        *vcpu_ctx.guest_vmcb.intercept_dr_read_mut() = intercepts;
+        *vcpu_ctx.guest_vmcb.intercept_dr_write_mut() = intercepts;
+    }
+}
+
+// dr4 / dr5 are dr6 / dr7 unless cr4.de makes them undefined. a set dr7.gd
+// turns the access into a #DB instead
+fn check_access(vcpu_ctx: &mut vcpu, dr: u8) -> Option<u8> {
+    let dr = match dr {
+        4 | 5 if guest_cr4(vcpu_ctx) & CR4_DE != 0 => {
+            vcpu_ctx.inject_fault(EXCEPTION_UD, None);
+            return None;
+        }
+        4 | 5 => dr + 2,
+        _ => dr,
+    };
+    if vcpu_ctx.dr7_shadow & DR7_GD != 0 {
+        vcpu_ctx.dr7_shadow &= !DR7_GD;
+        *vcpu_ctx.guest_vmcb.dr6_mut() |= DR6_BD;
+        vcpu_ctx.inject_fault(EXCEPTION_DB, None);
+        return None;
+    }
+    Some(dr)
+}
+
+fn gpr(vcpu_ctx: &vcpu) -> u8 {
+    (vcpu_ctx.guest_vmcb.control_area.exit_info1 & EXIT_INFO1_GPR_MASK) as u8
+}
+
+pub fn dr_read_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, dr: u8) {
+    let Some(dr) = check_access(vcpu_ctx, dr) else {
+        return;
+    };
+    let owned = vcpu_ctx.dr_owned;
+    let value = match dr {
+        0..=3 if owned & 1 << dr != 0 => vcpu_ctx.dr_shadow[dr as usize],
+        0..=3 => read_dr(dr),
+        6 => vcpu_ctx.guest_vmcb.state_save_area.dr6 & !(owned as u64),
+        _ => vcpu_ctx.dr7_shadow,
+    };
+    guest_regs.set_gpr(gpr(vcpu_ctx), value);
+}
+
+pub fn dr_write_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, dr: u8) {
+    let Some(dr) = check_access(vcpu_ctx, dr) else {
+        return;
+    };
+    let value = guest_regs.gpr(gpr(vcpu_ctx));
+    match dr {
+        0..=3 if vcpu_ctx.dr_owned & 1 << dr != 0 => vcpu_ctx.dr_shadow[dr as usize] = value,
+        0..=3 => write_dr(dr, value),
+        _ if value >> 32 != 0 => vcpu_ctx.inject_fault(EXCEPTION_GP, Some(0)),
+        6 => *vcpu_ctx.guest_vmcb.dr6_mut() = value,
+        _ => {
+            vcpu_ctx.dr7_shadow = value;
+            *vcpu_ctx.guest_vmcb.dr7_mut() = hardware_dr7(vcpu_ctx);
+        }
+    }
+}
+
+// owned breakpoints are consumed here, anything left in dr6 for the guest is
+// reflected
+fn db_handler(
+    vcpu_ctx: &mut vcpu,
+    guest_regs: &mut guest_regs,
+    _info: &exception_info,
+) -> exception_action {
+    let dr6 = vcpu_ctx.guest_vmcb.state_save_area.dr6;
+    let hits = dr6 as u8 & vcpu_ctx.dr_owned & 0xf;
+    if hits == 0 {
+        return exception_action::Pass;
+    }
+
+    for slot in (0..4).filter(|slot| hits & 1 << slot != 0) {
+        let entry = &SLOTS[slot];
+        // execute breakpoints fault before the instruction, let it run
+        if entry.dr7.load(Ordering::Relaxed) >> (16 + slot * 4) & 0b11 == 0 {
+            vcpu_ctx.guest_vmcb.state_save_area.rflags |= RFLAGS_RF;
+        }
+        match entry.callback.iter().next() {
+            Some(callback) => callback(vcpu_ctx, guest_regs, slot),
+            None => log_info!(
+                "hardware breakpoint {} hit at {:#x}",
+                slot,
+                vcpu_ctx.guest_vmcb.state_save_area.rip
+            ),
+        }
+    }
+
+    let dr6 = dr6 & !(hits as u64);
+    *vcpu_ctx.guest_vmcb.dr6_mut() = dr6;
+    let guest_bits = (dr6 & 0xf & !(vcpu_ctx.dr_owned as u64)) | (dr6 & (DR6_BD | DR6_BS | DR6_BT));
+    match guest_bits {
+        0 => exception_action::Consume,
+        // a single step, up to whoever set tf
+        DR6_BS => exception_action::Pass,
+        _ => exception_action::Reflect,
+    }
+}
@@ -0,0 +1,146 @@
+// exception intercepts. modules claim vectors by registering handlers, a
+// vector is only intercepted while it has at least one. every handler sees
+// the exception in registration order, the first one that doesn't pass
+// decides what happens to it
+use crate::event::*;
+use crate::hooks::hook_table;
+use crate::hv::{bump_state_generation, vcpu};
+use crate::structs::*;
+use crate::vmcb::*;
+use crate::{log_debug, log_error};
+use core::sync::atomic::{AtomicU32, Ordering};
+pub use hv_core::exception::*;
+
+pub const MAX_HANDLERS_PER_VECTOR: usize = 4;
+
+pub type exception_handler = fn(&mut vcpu, &mut guest_regs, &exception_info) -> exception_action;
+
+// identifies a registered handler for unregister
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct handler_slot {
+    pub vector: u8,
+    pub index: usize,
+}
+
+static HANDLERS: [hook_table<exception_handler, MAX_HANDLERS_PER_VECTOR>; 32] =
+    [const { hook_table::new() }; 32];
+// vectors with at least one handler, mirrored into intercept_exception
+static CLAIMED: AtomicU32 = AtomicU32::new(0);
+
+fn update_claimed(vector: u8) {
+    let claimed = HANDLERS[vector as usize].iter().next().is_some();
+    if claimed {
+        CLAIMED.fetch_or(1 << vector, Ordering::AcqRel);
+    } else {
+        CLAIMED.fetch_and(!(1 << vector), Ordering::AcqRel);
+    }
+    bump_state_generation();
+}
+
+// vcpus start intercepting the vector at their next exit. None if the vector
+// is invalid or already has MAX_HANDLERS_PER_VECTOR handlers
+pub fn register_handler(vector: u8, handler: exception_handler) -> Option<handler_slot> {
+    let index = HANDLERS.get(vector as usize)?.register(handler)?;
+    update_claimed(vector);
+    Some(handler_slot { vector, index })
+}
+
+pub fn unregister_handler(slot: handler_slot) {
+    HANDLERS[slot.vector as usize].unregister(slot.index);
+    update_claimed(slot.vector);
+}
+
+pub fn sync(vcpu_ctx: &mut vcpu) {
+    *vcpu_ctx.guest_vmcb.intercept_exception_mut() = CLAIMED.load(Ordering::Acquire);
+}
+
+pub fn exception_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, vector: u8) {
+    let control = &vcpu_ctx.guest_vmcb.control_area;
+    let info = exception_info {
+        vector,
+        error_code: has_error_code(vector).then_some(control.exit_info1 as u32),
+        address: control.exit_info2,
+    };
+    // an event whose delivery caused this exception
+    let interrupted = control.exit_int_info;
+    vcpu_ctx.advance_rip = false;
+
+    let action = decide(
+        HANDLERS[vector as usize]
+            .iter()
+            .map(|handler| handler(vcpu_ctx, guest_regs, &info)),
+    );
+
+    match delivery_for(&info, action) {
+        Some(mut delivery) => {
+            match nest(interrupted, &mut delivery) {
+                nested_event::None => {}
+                nested_event::Requeue(event) => requeue(vcpu_ctx, event),
+                nested_event::DoubleFault => {
+                    log_debug!(
+                        "exception {} while delivering {:#x}, #DF",
+                        vector,
+                        interrupted
+                    )
+                }
+                nested_event::TripleFault => {
+                    log_error!("exception {} while delivering #DF", vector)
+                }
+            }
+            vcpu_ctx.guest_vmcb.control_area.event_inj = delivery.event_inj;
+            if let Some(cr2) = delivery.cr2 {
+                *vcpu_ctx.guest_vmcb.cr2_mut() = cr2;
+            }
+            vcpu_ctx.advance_rip = delivery.skip_instruction;
+        }
+        // the interrupted event still has to reach the guest
+        None if interrupted & EVENT_VALID != 0 => {
+            vcpu_ctx.guest_vmcb.control_area.event_inj = interrupted;
+        }
+        None => {}
+    }
+}
+
+fn set_virq(vintr: &mut u64, vector: u8) {
+    let vector = vector as u64;
+    *vintr &= !(0xf << V_INTR_PRIO_SHIFT | 0xff << V_INTR_VECTOR_SHIFT);
+    *vintr |=
+        V_IRQ | V_IGN_TPR | (vector >> 4) << V_INTR_PRIO_SHIFT | vector << V_INTR_VECTOR_SHIFT;
+}
+
+// event_inj holds the exception, the interrupt or nmi it interrupted is
+// taken once the guest handles that
+fn requeue(vcpu_ctx: &mut vcpu, event: u64) {
+    if event_type(event) == EVENT_TYPE_NMI {
+        return vcpu_ctx.nmi.requeue();
+    }
+    let vector = event_vector(event);
+    if vcpu_ctx.guest_vmcb.control_area.vintr & V_IRQ == 0 {
+        return set_virq(vcpu_ctx.guest_vmcb.vintr_mut(), vector);
+    }
+    // follows the pending one, see vintr_handler
+    if !vcpu_ctx.requeued.push(vector) {
+        return log_error!("interrupt {:#x} lost, too many are requeued", vector);
+    }
+    *vcpu_ctx.guest_vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_VINTR;
+}
+
+// only intercepted while interrupts are requeued. the guest is about to take
+// the one in v_irq, it goes in through event_inj instead and the next
+// requeued one takes its place
+pub fn vintr_handler(vcpu_ctx: &mut vcpu) {
+    vcpu_ctx.advance_rip = false;
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    let vintr = vmcb.control_area.vintr;
+    if vintr & V_IRQ != 0 {
+        let vector = (vintr >> V_INTR_VECTOR_SHIFT) as u8;
+        vmcb.control_area.event_inj = event(vector, EVENT_TYPE_INTR, None);
+        *vmcb.vintr_mut() &= !V_IRQ;
+    }
+    if let Some(vector) = vcpu_ctx.requeued.pop() {
+        set_virq(vmcb.vintr_mut(), vector);
+    }
+    if vcpu_ctx.requeued.is_empty() {
+        *vmcb.intercept_misc1_mut() &= !SVM_INTERCEPT_MISC1_VINTR;
+    }
+}
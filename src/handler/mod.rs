@@ -1 +1,7 @@
+pub mod cpuid;
+pub mod cr;
+pub mod dr;
+pub mod exception;
+pub mod msr;
+pub mod npf;
 pub mod vmmcall;
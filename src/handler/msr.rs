@@ -0,0 +1,119 @@
+// rdmsr / wrmsr of msrs intercepted in the permission map. efer is shadowed
+// per vcpu and the msrs in msr_shadow's table follow their policy, other msrs
+// are passed through, msrs outside of the map's ranges always exit. the guest
+// gets the #GP the hardware raises for msrs the cpu doesn't have
+use crate::apic::X2APIC_ICR;
+use crate::event::*;
+use crate::hidden;
+use crate::hv::vcpu;
+use crate::log_info;
+use crate::msr_shadow::{self, write_outcome};
+use crate::sipi;
+use crate::stealth;
+use crate::structs::*;
+use crate::syscall;
+use crate::utils::{rdmsr_checked, wrmsr_checked};
+use crate::vmcb::*;
+use x86::msr::IA32_EFER;
+
+const EFER_RESERVED: u64 = !(EFER_SCE
+    | EFER_LME
+    | EFER_LMA
+    | EFER_NXE
+    | EFER_SVME
+    | EFER_LMSLE
+    | EFER_FFXSR
+    | EFER_TCE
+    | 1 << 17
+    | 1 << 18
+    | 1 << 20
+    | 1 << 21);
+
+// the vmcb's efer is what the guest wrote, with sce hidden while syscalls are
+// traced. svme stays set, vmrun refuses a guest without it
+pub fn sync(vcpu_ctx: &mut vcpu) {
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    let current = vmcb.state_save_area.efer;
+    let mut efer = vcpu_ctx.efer_shadow & !EFER_LMA | current & EFER_LMA | EFER_SVME;
+    if syscall::tracing() {
+        efer &= !EFER_SCE;
+    }
+    if efer != current {
+        *vmcb.efer_mut() = efer;
+    }
+}
+
+// the guest's hidden state may not be on the cpu, see hidden.rs
+fn read_hardware(vcpu_ctx: &mut vcpu, msr: u32) -> Option<u64> {
+    hidden::read_msr(vcpu_ctx, msr).or_else(|| rdmsr_checked(msr))
+}
+
+fn write_hardware(vcpu_ctx: &mut vcpu, msr: u32, value: u64) -> Option<()> {
+    match hidden::write_msr(vcpu_ctx, msr, value) {
+        true => Some(()),
+        false => wrmsr_checked(msr, value),
+    }
+}
+
+fn read_msr(vcpu_ctx: &mut vcpu, msr: u32) -> Option<u64> {
+    match msr {
+        _ if stealth::hidden_msr(msr) => None,
+        // lma is maintained by the cpu
+        IA32_EFER => {
+            let lma = vcpu_ctx.guest_vmcb.state_save_area.efer & EFER_LMA;
+            Some(vcpu_ctx.efer_shadow & !EFER_LMA | lma)
+        }
+        _ => msr_shadow::read_shadow(msr).or_else(|| read_hardware(vcpu_ctx, msr)),
+    }
+}
+
+fn write_msr(vcpu_ctx: &mut vcpu, msr: u32, value: u64) -> Option<()> {
+    match msr {
+        _ if stealth::hidden_msr(msr) => return None,
+        IA32_EFER => {
+            if value & (EFER_RESERVED | stealth::efer_reserved()) != 0 {
+                return None;
+            }
+            vcpu_ctx.efer_shadow = value;
+            sync(vcpu_ctx);
+        }
+        X2APIC_ICR if sipi::intercepting() => {
+            sipi::icr_write(vcpu_ctx, value);
+            write_hardware(vcpu_ctx, msr, value)?;
+        }
+        _ => match msr_shadow::write_shadow(msr, value) {
+            Some(write_outcome::Fault) => return None,
+            Some(write_outcome::Shadowed) => {}
+            Some(write_outcome::Logged) => {
+                log_info!(
+                    "wrmsr {:#x} = {:#x} at {:#x}",
+                    msr,
+                    value,
+                    vcpu_ctx.guest_vmcb.state_save_area.rip
+                );
+                write_hardware(vcpu_ctx, msr, value)?;
+            }
+            Some(write_outcome::Hardware) | None => write_hardware(vcpu_ctx, msr, value)?,
+        },
+    }
+    Some(())
+}
+
+pub fn msr_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let msr = guest_regs.rcx as u32;
+    let write = vcpu_ctx.guest_vmcb.control_area.exit_info1 & 1 != 0;
+    let result = match write {
+        true => write_msr(
+            vcpu_ctx,
+            msr,
+            guest_regs.rdx << 32 | guest_regs.rax & 0xffff_ffff,
+        ),
+        false => read_msr(vcpu_ctx, msr).map(|value| {
+            guest_regs.rax = value & 0xffff_ffff;
+            guest_regs.rdx = value >> 32;
+        }),
+    };
+    if result.is_none() {
+        vcpu_ctx.inject_fault(EXCEPTION_GP, Some(0));
+    }
+}
@@ -0,0 +1,48 @@
+// nested page faults happen on npt view switches and on the first access to
+// mmio above what init mapped, see npt.rs
+use crate::breakpoint::{self, step_original};
+use crate::guest_mem::*;
+use crate::hv::vcpu;
+use crate::log_error;
+use crate::npt::{self, NPF_EXECUTE, NPF_PRESENT, NPF_WRITE, npt_view};
+use wdk::dbg_break;
+
+pub fn npf_handler(vcpu_ctx: &mut vcpu) {
+    let error_code = vcpu_ctx.guest_vmcb.control_area.exit_info1;
+    let gpa = vcpu_ctx.guest_vmcb.control_area.exit_info2;
+    vcpu_ctx.advance_rip = false;
+
+    if error_code & NPF_PRESENT == 0 {
+        if let Err(error) = npt::map(gpa) {
+            log_error!("failed to map {:#x} on demand: {:?}", gpa, error);
+            dbg_break();
+        }
+        return;
+    }
+
+    let view = match vcpu_ctx.npt_view {
+        // the shadow only executes the int3 itself, everything else on the
+        // page is stepped from the original so its reads don't see the int3s
+        npt_view::Normal if error_code & NPF_EXECUTE != 0 && npt::is_hooked(gpa) => {
+            let rip = vcpu_ctx.guest_vmcb.state_save_area.rip;
+            match gva_to_gpa(vcpu_ctx, rip, access::EXECUTE) {
+                Ok(rip_gpa) if breakpoint::is_breakpoint(rip_gpa) => npt_view::Hook,
+                _ => return step_original(vcpu_ctx),
+            }
+        }
+        // a page that was unhooked since can still be in the tlb as not
+        // executable, the switch flushes it
+        npt_view::Normal if error_code & NPF_EXECUTE != 0 => npt_view::Normal,
+        // left the hooked page or wrote to it
+        npt_view::Hook if error_code & (NPF_EXECUTE | NPF_WRITE) != 0 => npt_view::Normal,
+        _ => {
+            log_error!(
+                "unexpected nested page fault at {:#x}: {:#x}",
+                gpa,
+                error_code
+            );
+            return dbg_break();
+        }
+    };
+    npt::set_view(vcpu_ctx, view);
+}
@@ -1,25 +1,382 @@
-use crate::hv::vcpu;
+use crate::breakpoint::{self, bp_error};
+use crate::cr3_tracker::{self, filter_mode};
+use crate::event::EXCEPTION_UD;
+use crate::guest_mem::*;
+use crate::handler::dr::{self, breakpoint_kind};
+use crate::hv::{MAX_PROCESSORS, shared_for, vcpu};
+use crate::ipi;
+use crate::log::{self, log_record};
+use crate::log_debug;
+use crate::msr_shadow::{self, write_policy};
+use crate::nmi;
+use crate::npt::npt_error;
+use crate::single_step::{self, step_record};
+use crate::stats::exit_stats;
+use crate::stealth;
+use crate::syscall;
 use crate::{structs::*, utils::*, vmcb::*};
+use core::sync::atomic::Ordering;
 use core::{arch::asm, ptr::addr_of};
-use wdk::*;
 use x86::msr::*;
 
-const VMMCALL_UNLOAD: u64 = 0x10;
+pub const VMMCALL_UNLOAD: u64 = 0x10;
 const VMMCALL_MAGIC: u64 = 1;
+const VMMCALL_GET_INFO: u64 = 2;
+const VMMCALL_READ_LOG: u64 = 3;
+const VMMCALL_GET_STATS: u64 = 4;
+pub const VMMCALL_SYNC: u64 = 5;
+const VMMCALL_CR3_TRACKING: u64 = 6;
+const VMMCALL_GET_CR3: u64 = 7;
+const VMMCALL_SET_HW_BREAKPOINT: u64 = 8;
+const VMMCALL_CLEAR_HW_BREAKPOINT: u64 = 9;
+const VMMCALL_SET_SW_BREAKPOINT: u64 = 0xa;
+const VMMCALL_CLEAR_SW_BREAKPOINT: u64 = 0xb;
+const VMMCALL_STEP: u64 = 0xc;
+const VMMCALL_READ_STEP_TRACE: u64 = 0xd;
+pub const VMMCALL_SYSCALL_TRACING: u64 = 0xe;
+const VMMCALL_SHADOW_MSR: u64 = 0xf;
+
+// r9 of VMMCALL_SHADOW_MSR, the policy or SHADOW_MSR_REMOVE in the low byte
+const SHADOW_MSR_REMOVE: u64 = 0xff;
+const SHADOW_MSR_READS: u64 = 1 << 8;
+
+// operations of VMMCALL_CR3_TRACKING, selected by rdx
+const CR3_TRACKING_DISABLE: u64 = 0;
+const CR3_TRACKING_ENABLE: u64 = 1;
+const CR3_TRACKING_ADD: u64 = 2;
+const CR3_TRACKING_REMOVE: u64 = 3;
+const CR3_TRACKING_CLEAR: u64 = 4;
+const CR3_TRACKING_SET_MODE: u64 = 5;
+
+// returned in rax by hypercalls that take buffers
+pub const HV_STATUS_SUCCESS: u64 = 0;
+pub const HV_STATUS_INVALID_PARAMETER: u64 = 1;
+pub const HV_STATUS_ACCESS_VIOLATION: u64 = 2;
+pub const HV_STATUS_BUSY: u64 = 3;
+pub const HV_STATUS_NO_RESOURCES: u64 = 4;
+pub const HV_STATUS_UNSUPPORTED: u64 = 5;
+pub const HV_STATUS_ACCESS_DENIED: u64 = 6;
+
+// response of VMMCALL_GET_INFO, written to the buffer in rdx
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct hv_info {
+    pub magic: u64,
+    pub processor: u32,
+    pub reserved: u32,
+}
+unsafe impl pod for hv_info {}
+
+// hypercalls that change hypervisor state or look into other address spaces,
+// only the guest's kernel may make them
+fn privileged(code: u64) -> bool {
+    matches!(
+        code,
+        VMMCALL_UNLOAD
+            | VMMCALL_READ_LOG
+            | VMMCALL_GET_STATS
+            | VMMCALL_SYNC
+            | VMMCALL_CR3_TRACKING
+            | VMMCALL_GET_CR3
+            | VMMCALL_SET_HW_BREAKPOINT
+            | VMMCALL_CLEAR_HW_BREAKPOINT
+            | VMMCALL_SET_SW_BREAKPOINT
+            | VMMCALL_CLEAR_SW_BREAKPOINT
+            | VMMCALL_STEP
+            | VMMCALL_READ_STEP_TRACE
+            | VMMCALL_SYSCALL_TRACING
+            | VMMCALL_SHADOW_MSR
+    )
+}
+
+fn status<T>(result: Result<T, guest_mem_error>) -> u64 {
+    match result {
+        Ok(_) => HV_STATUS_SUCCESS,
+        Err(_) => HV_STATUS_ACCESS_VIOLATION,
+    }
+}
 
 pub fn vmmcall_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
-    println!("vmmcall called with rcx: {}", guest_regs.rcx);
+    log_debug!("vmmcall called with rcx: {}", guest_regs.rcx);
 
+    // not even the magic answers a probe
+    if stealth::hides_hypercall(vcpu_ctx.guest_vmcb.state_save_area.cpl, guest_regs.r10) {
+        vcpu_ctx.inject_fault(EXCEPTION_UD, None);
+        return;
+    }
+    if privileged(guest_regs.rcx) && vcpu_ctx.guest_vmcb.state_save_area.cpl != 0 {
+        guest_regs.rax = HV_STATUS_ACCESS_DENIED;
+        return;
+    }
     match guest_regs.rcx {
         VMMCALL_MAGIC => {
             guest_regs.rax = 0x1337;
         }
+        VMMCALL_GET_INFO => {
+            let info = hv_info {
+                magic: 0x1337,
+                processor: vcpu_ctx.processor,
+                reserved: 0,
+            };
+            guest_regs.rax = status(write_guest_value(vcpu_ctx, guest_regs.rdx, &info));
+        }
+        VMMCALL_READ_LOG => read_log(vcpu_ctx, guest_regs),
+        VMMCALL_GET_STATS => get_stats(vcpu_ctx, guest_regs),
+        // the exit itself applied the current global state
+        VMMCALL_SYNC => {}
+        VMMCALL_CR3_TRACKING => cr3_tracking(vcpu_ctx, guest_regs),
+        VMMCALL_GET_CR3 => {
+            let Some(other) = u32::try_from(guest_regs.r8).ok().and_then(shared_for) else {
+                guest_regs.rax = HV_STATUS_INVALID_PARAMETER;
+                return;
+            };
+            guest_regs.rax = HV_STATUS_SUCCESS;
+            guest_regs.rdx = other.current_cr3.load(Ordering::Relaxed);
+        }
+        VMMCALL_SET_HW_BREAKPOINT => set_hw_breakpoint(vcpu_ctx, guest_regs),
+        VMMCALL_CLEAR_HW_BREAKPOINT => {
+            dr::clear_breakpoint_lazy(guest_regs.r8 as usize);
+            vcpu_ctx.sync_state();
+            guest_regs.rax = HV_STATUS_SUCCESS;
+        }
+        VMMCALL_SHADOW_MSR => shadow_msr(guest_regs),
+        VMMCALL_SET_SW_BREAKPOINT => sw_breakpoint(vcpu_ctx, guest_regs, true),
+        VMMCALL_CLEAR_SW_BREAKPOINT => sw_breakpoint(vcpu_ctx, guest_regs, false),
+        VMMCALL_STEP => {
+            if !single_step::start_lazy(guest_regs.r8 as u32, guest_regs.rdx) {
+                guest_regs.rax = HV_STATUS_INVALID_PARAMETER;
+                return;
+            }
+            vcpu_ctx.sync_state();
+            guest_regs.rax = HV_STATUS_SUCCESS;
+        }
+        VMMCALL_READ_STEP_TRACE => read_step_trace(vcpu_ctx, guest_regs),
+        VMMCALL_SYSCALL_TRACING => {
+            if !syscall::set_tracing_lazy(guest_regs.rdx != 0) {
+                guest_regs.rax = HV_STATUS_NO_RESOURCES;
+                return;
+            }
+            vcpu_ctx.sync_state();
+            guest_regs.rax = HV_STATUS_SUCCESS;
+        }
+        // an nmi the guest is still owed can't be injected once devirtualized,
+        // the vmmcall runs again after the guest took it
+        VMMCALL_UNLOAD if nmi::owed(vcpu_ctx) => vcpu_ctx.advance_rip = false,
         VMMCALL_UNLOAD => {
             vcpu_ctx.unload = true;
         }
+        // vmmcall without svm
+        _ if stealth::enabled() => vcpu_ctx.inject_fault(EXCEPTION_UD, None),
+        // any process can get here, it's not worth a break
+        _ => {
+            log_debug!("invalid vmmcall_code: {}", guest_regs.rcx);
+            guest_regs.rax = HV_STATUS_UNSUPPORTED;
+        }
+    }
+}
+
+// rdx = buffer, r8 = capacity in records. returns the number of records in rdx
+fn read_log(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let buffer = guest_regs.rdx;
+    let capacity = guest_regs.r8 as usize;
+    guest_regs.rdx = 0;
+
+    let Some(size) = capacity.checked_mul(size_of::<log_record>()) else {
+        guest_regs.rax = HV_STATUS_INVALID_PARAMETER;
+        return;
+    };
+    if let Err(_) = probe_guest(vcpu_ctx, buffer, size, true) {
+        guest_regs.rax = HV_STATUS_ACCESS_VIOLATION;
+        return;
+    }
+
+    let mut status = HV_STATUS_SUCCESS;
+    let mut written = 0u64;
+    let drained = log::try_drain(capacity, |record| {
+        let gva = buffer + written * size_of::<log_record>() as u64;
+        if write_guest_value(vcpu_ctx, gva, record).is_err() {
+            status = HV_STATUS_ACCESS_VIOLATION;
+            return false;
+        }
+        written += 1;
+        true
+    });
+    if drained.is_none() {
+        status = HV_STATUS_BUSY;
+    }
+    guest_regs.rax = status;
+    guest_regs.rdx = written;
+}
+
+// rdx = buffer, r8 = capacity in records, r9 = processor. returns the number
+// of records in rdx
+fn read_step_trace(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let buffer = guest_regs.rdx;
+    let capacity = guest_regs.r8 as usize;
+    guest_regs.rdx = 0;
+
+    let Some(processor) = u32::try_from(guest_regs.r9)
+        .ok()
+        .filter(|p| shared_for(*p).is_some())
+    else {
+        guest_regs.rax = HV_STATUS_INVALID_PARAMETER;
+        return;
+    };
+    let Some(size) = capacity.checked_mul(size_of::<step_record>()) else {
+        guest_regs.rax = HV_STATUS_INVALID_PARAMETER;
+        return;
+    };
+    if let Err(_) = probe_guest(vcpu_ctx, buffer, size, true) {
+        guest_regs.rax = HV_STATUS_ACCESS_VIOLATION;
+        return;
+    }
+
+    let mut status = HV_STATUS_SUCCESS;
+    let mut written = 0u64;
+    let drained = single_step::try_drain(processor, capacity, |record| {
+        let gva = buffer + written * size_of::<step_record>() as u64;
+        if write_guest_value(vcpu_ctx, gva, record).is_err() {
+            status = HV_STATUS_ACCESS_VIOLATION;
+            return false;
+        }
+        written += 1;
+        true
+    });
+    if drained.is_none() {
+        status = HV_STATUS_BUSY;
+    }
+    guest_regs.rax = status;
+    guest_regs.rdx = written;
+}
+
+// rdx = buffer, r8 = processor index or u64::MAX for the sum over all cpus
+fn get_stats(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let stats = if guest_regs.r8 == u64::MAX {
+        let mut total = exit_stats::new();
+        for processor in 0..MAX_PROCESSORS as u32 {
+            if let Some(other) = shared_for(processor) {
+                total.merge(&other.stats.snapshot());
+            }
+        }
+        total
+    } else {
+        let Some(other) = u32::try_from(guest_regs.r8).ok().and_then(shared_for) else {
+            guest_regs.rax = HV_STATUS_INVALID_PARAMETER;
+            return;
+        };
+        other.stats.snapshot()
+    };
+    guest_regs.rax = status(write_guest_value(vcpu_ctx, guest_regs.rdx, &stats));
+}
+
+// rdx = operation, r8 = address space or filter mode
+fn cr3_tracking(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let filter = &cr3_tracker::FILTER;
+    let ok = match guest_regs.rdx {
+        CR3_TRACKING_DISABLE | CR3_TRACKING_ENABLE => {
+            cr3_tracker::set_tracking_lazy(guest_regs.rdx == CR3_TRACKING_ENABLE);
+            vcpu_ctx.sync_state();
+            true
+        }
+        CR3_TRACKING_ADD => filter.add(guest_regs.r8),
+        CR3_TRACKING_REMOVE => filter.remove(guest_regs.r8),
+        CR3_TRACKING_CLEAR => {
+            filter.clear();
+            true
+        }
+        CR3_TRACKING_SET_MODE => match guest_regs.r8 {
+            0 => Some(filter_mode::All),
+            1 => Some(filter_mode::Allow),
+            2 => Some(filter_mode::Deny),
+            _ => None,
+        }
+        .map(|mode| filter.set_mode(mode))
+        .is_some(),
+        _ => false,
+    };
+    guest_regs.rax = if ok {
+        HV_STATUS_SUCCESS
+    } else {
+        HV_STATUS_INVALID_PARAMETER
+    };
+}
+
+// rdx = address, r8 = slot, r9 = kind (0 execute, 1 write, 3 read/write) | length << 8
+fn set_hw_breakpoint(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let kind = match guest_regs.r9 & 0xff {
+        0 => breakpoint_kind::Execute,
+        1 => breakpoint_kind::Write,
+        3 => breakpoint_kind::ReadWrite,
+        _ => {
+            guest_regs.rax = HV_STATUS_INVALID_PARAMETER;
+            return;
+        }
+    };
+    let len = guest_regs.r9 >> 8;
+    let result = dr::set_breakpoint_lazy(guest_regs.r8 as usize, guest_regs.rdx, kind, len, None);
+    vcpu_ctx.sync_state();
+    guest_regs.rax = match result {
+        Ok(()) => HV_STATUS_SUCCESS,
+        Err(_) => HV_STATUS_INVALID_PARAMETER,
+    };
+}
+
+// rdx = address, r8 = cr3 of the address space it's in or 0 for the caller's.
+// other address spaces have to be in the cr3 filter list, an arbitrary r8
+// would have the walker treat any physical page as a page table
+fn sw_breakpoint(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, set: bool) {
+    let state = &vcpu_ctx.guest_vmcb.state_save_area;
+    let current = cr3_tracker::address_space(state.cr3);
+    let cr3 = match cr3_tracker::address_space(guest_regs.r8) {
+        0 => state.cr3,
+        cr3 if cr3 == current || cr3_tracker::FILTER.contains(cr3) => cr3,
+        _ => {
+            guest_regs.rax = HV_STATUS_INVALID_PARAMETER;
+            return;
+        }
+    };
+    let ctx = paging_ctx::from(state).with_cr3(cr3);
+    let result = match set {
+        true => breakpoint::set_breakpoint(&vcpu_ctx.phys_window, &ctx, guest_regs.rdx, None),
+        false => breakpoint::clear_breakpoint(&vcpu_ctx.phys_window, &ctx, guest_regs.rdx),
+    };
+    ipi::flush_npt_all_cpus(vcpu_ctx);
+    guest_regs.rax = match result {
+        Ok(()) => HV_STATUS_SUCCESS,
+        Err(bp_error::Npt(npt_error::Disabled)) => HV_STATUS_UNSUPPORTED,
+        Err(bp_error::TooMany)
+        | Err(bp_error::Npt(npt_error::OutOfPages))
+        | Err(bp_error::Npt(npt_error::TooManyHooks)) => HV_STATUS_NO_RESOURCES,
+        Err(_) => HV_STATUS_INVALID_PARAMETER,
+    };
+}
+
+fn shadow_msr(guest_regs: &mut guest_regs) {
+    let msr = guest_regs.r8 as u32;
+    let policy = match guest_regs.r9 & 0xff {
+        0 => write_policy::Allow,
+        1 => write_policy::Deny,
+        2 => write_policy::Log,
+        3 => write_policy::Redirect,
+        SHADOW_MSR_REMOVE => {
+            guest_regs.rax = match msr_shadow::unshadow_msr(msr) {
+                true => HV_STATUS_SUCCESS,
+                false => HV_STATUS_INVALID_PARAMETER,
+            };
+            return;
+        }
         _ => {
-            println!("invalid vmmcall_code");
-            dbg_break();
+            guest_regs.rax = HV_STATUS_INVALID_PARAMETER;
+            return;
         }
+    };
+    if msr == IA32_EFER {
+        guest_regs.rax = HV_STATUS_INVALID_PARAMETER;
+        return;
     }
+    let shadow = (guest_regs.r9 & SHADOW_MSR_READS != 0).then_some(guest_regs.rdx);
+    guest_regs.rax = match msr_shadow::shadow_msr(msr, shadow, policy) {
+        true => HV_STATUS_SUCCESS,
+        false => HV_STATUS_NO_RESOURCES,
+    };
 }
@@ -0,0 +1,105 @@
+// the state vmrun and #vmexit don't switch: fs, gs, tr and ldtr with their
+// hidden parts, kernel_gs_base and the syscall / sysenter msrs. after an exit
+// the cpu still runs with the guest's, which the host gets along with as long
+// as gs points at the same kpcr, the guest is the same kernel on the same
+// cpu. the host's is only loaded when gs differs, e.g. the guest exited from
+// user mode, or before running the kernel's nmi handler.
+//
+// the guest's is copied to the vmcb only when a handler reads or changes it
+// there. launch_vm loads it back before vmrun when the host's was loaded or
+// the vmcb copy changed
+use crate::config::CONFIG;
+use crate::hv::vcpu;
+use crate::utils::rdmsr;
+use crate::vmcb::state_save;
+use core::arch::asm;
+use x86::msr::*;
+
+// flags of the current exit
+#[derive(Clone, Copy, Debug, Default)]
+pub struct hidden_state {
+    // the guest vmcb holds the guest's current hidden state
+    pub guest_saved: bool,
+    // the cpu runs with the host's
+    pub host_loaded: bool,
+}
+
+impl hidden_state {
+    pub const fn new() -> Self {
+        Self {
+            guest_saved: false,
+            host_loaded: false,
+        }
+    }
+}
+
+// first thing in the exit handler, before anything that may need the kpcr
+pub fn enter(vcpu_ctx: &mut vcpu) {
+    vcpu_ctx.hidden = hidden_state::new();
+    let gs_base = unsafe { rdmsr(IA32_GS_BASE) };
+    if !CONFIG.lazy_hidden_state || gs_base != vcpu_ctx.host_vmcb.state_save_area.gs_base {
+        load_host(vcpu_ctx);
+    }
+}
+
+// for reads of the guest's hidden state from the vmcb
+pub fn save_guest(vcpu_ctx: &mut vcpu) {
+    if vcpu_ctx.hidden.guest_saved {
+        return;
+    }
+    unsafe { asm!("vmsave rax", in("rax") vcpu_ctx.host_stack_layout.guest_vmcb_pa) };
+    vcpu_ctx.hidden.guest_saved = true;
+}
+
+pub fn load_host(vcpu_ctx: &mut vcpu) {
+    if vcpu_ctx.hidden.host_loaded {
+        return;
+    }
+    save_guest(vcpu_ctx);
+    unsafe { asm!("vmload rax", in("rax") vcpu_ctx.host_stack_layout.host_vmcb_pa) };
+    vcpu_ctx.hidden.host_loaded = true;
+    vcpu_ctx.host_stack_layout.reload_guest_hidden = 1;
+    vcpu_ctx.shared.stats.host_state_loaded();
+}
+
+// for changes to the guest's hidden state, they reach the cpu at vmrun
+pub fn guest_mut(vcpu_ctx: &mut vcpu) -> &mut state_save {
+    save_guest(vcpu_ctx);
+    vcpu_ctx.host_stack_layout.reload_guest_hidden = 1;
+    &mut vcpu_ctx.guest_vmcb.state_save_area
+}
+
+// the vmcb field holding an msr that is part of the hidden state
+fn msr_field(state: &mut state_save, msr: u32) -> Option<&mut u64> {
+    match msr {
+        IA32_FS_BASE => Some(&mut state.fs_base),
+        IA32_GS_BASE => Some(&mut state.gs_base),
+        IA32_KERNEL_GSBASE => Some(&mut state.kernel_gs_base),
+        IA32_STAR => Some(&mut state.star),
+        IA32_LSTAR => Some(&mut state.lstar),
+        IA32_CSTAR => Some(&mut state.cstar),
+        IA32_FMASK => Some(&mut state.sf_mask),
+        IA32_SYSENTER_CS => Some(&mut state.sysenter_cs),
+        IA32_SYSENTER_ESP => Some(&mut state.sysenter_esp),
+        IA32_SYSENTER_EIP => Some(&mut state.sysenter_eip),
+        _ => None,
+    }
+}
+
+// the guest's value of a hidden state msr, None for every other msr
+pub fn read_msr(vcpu_ctx: &mut vcpu, msr: u32) -> Option<u64> {
+    msr_field(&mut vcpu_ctx.guest_vmcb.state_save_area, msr)?;
+    save_guest(vcpu_ctx);
+    msr_field(&mut vcpu_ctx.guest_vmcb.state_save_area, msr).map(|field| *field)
+}
+
+// false if msr isn't part of the hidden state
+pub fn write_msr(vcpu_ctx: &mut vcpu, msr: u32, value: u64) -> bool {
+    if msr_field(&mut vcpu_ctx.guest_vmcb.state_save_area, msr).is_none() {
+        return false;
+    }
+    if let Some(field) = msr_field(guest_mut(vcpu_ctx), msr) {
+        *field = value;
+    }
+    true
+}
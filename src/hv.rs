@@ -1,14 +1,37 @@
 extern crate alloc;
+use crate::asid::{asid_allocator, asid_slot, setup_asid, tlb_flush};
+use crate::config::CONFIG;
+use crate::cr3_tracker;
+use crate::event::exception;
+use crate::handler::cr::setup_cr_intercepts;
+use crate::handler::exception::requeued_interrupts;
+use crate::handler::vmmcall::{VMMCALL_SYNC, VMMCALL_UNLOAD};
+use crate::handler::{dr, msr};
+use crate::hidden::{self, hidden_state};
+use crate::idle::{idle_state, setup_idle};
+use crate::ipi;
+use crate::log::log_ring;
+use crate::msrpm::{self, setup_msrpm};
+use crate::nmi::{self, nmi_state, setup_nmi};
+use crate::npt::{self, npt_view, setup_npt};
+use crate::phys_window::phys_window;
 use crate::segments::*;
+use crate::single_step::{self, step_state, step_trace};
+use crate::sipi::{self, setup_sipi, sipi_state};
+use crate::stats::{HISTOGRAM_BUCKETS, exit_stats, shared_stats};
+use crate::stealth::{self, setup_stealth};
 use crate::structs::*;
+use crate::tsc::{setup_tsc, tsc_clock};
 use crate::utils::*;
 use crate::vmcb::*;
+use crate::xstate::{extended_state, setup_xstate, xsave_sizing};
+use crate::{log_error, log_info};
 use alloc::boxed::Box;
 use core::arch::{asm, global_asm};
 use core::ptr::*;
-use core::sync::atomic::{AtomicU64, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
 use static_assertions::*;
-use wdk::{dbg_break, println};
+use wdk::dbg_break;
 use wdk_sys::{
     ALL_PROCESSOR_GROUPS, PAGE_SIZE, CONTEXT, KAFFINITY, NT_SUCCESS, PAGED_CODE,
     POOL_FLAG_NON_PAGED, PROCESSOR_NUMBER, ntddk::*,
@@ -36,6 +59,97 @@ fn set_virtualized(processor: u32) {
     VIRTUALIZED_BITSET.fetch_or(bit, Ordering::Relaxed);
 }
 
+// one bit per cpu in VIRTUALIZED_BITSET
+pub use hv_core::MAX_PROCESSORS;
+
+// the part of a vcpu that other cpus and the drain thread look at while it
+// runs. the exit handler holds its vcpu mutably, so this lives in its own
+// allocation and only has atomics and lock-free rings
+pub struct vcpu_shared {
+    pub in_host: AtomicBool,     // set while the exit handler runs
+    pub virtualized: AtomicBool, // from launch until devirtualized
+    pub log_ring: log_ring,
+    pub stats: shared_stats,
+    pub current_cr3: AtomicU64, // address space while cr3 writes are tracked
+    pub step_trace: step_trace,
+}
+
+impl vcpu_shared {
+    pub const fn new() -> Self {
+        Self {
+            in_host: AtomicBool::new(false),
+            virtualized: AtomicBool::new(false),
+            log_ring: log_ring::new(),
+            stats: shared_stats::new(),
+            current_cr3: AtomicU64::new(0),
+            step_trace: step_trace::new(),
+        }
+    }
+}
+
+// by processor index, never freed once virtualized
+static SHARED: [AtomicPtr<vcpu_shared>; MAX_PROCESSORS] =
+    [const { AtomicPtr::new(null_mut()) }; MAX_PROCESSORS];
+
+pub fn shared_for(processor: u32) -> Option<&'static vcpu_shared> {
+    let shared = SHARED.get(processor as usize)?.load(Ordering::Acquire);
+    unsafe { shared.as_ref() }
+}
+
+// from the guest, on the current processor. returns rax
+pub fn hypercall(code: u64, rdx: u64) -> u64 {
+    let key = stealth::hypercall_key();
+    let status: u64;
+    unsafe {
+        asm!(
+            "vmmcall",
+            in("rcx") code,
+            inout("rdx") rdx => _,
+            in("r10") key,
+            lateout("rax") status,
+            options(nostack),
+        )
+    };
+    status
+}
+
+// running as the guest of a virtualized processor, an exit can come at any
+// instruction
+pub fn in_guest() -> bool {
+    let processor = unsafe { KeGetCurrentProcessorNumberEx(null_mut()) };
+    shared_for(processor).is_some_and(|shared| {
+        shared.virtualized.load(Ordering::Relaxed) && !shared.in_host.load(Ordering::Relaxed)
+    })
+}
+
+// bumped whenever global state that every vcpu mirrors into its vmcb changes,
+// each vcpu catches up at its next exit
+static STATE_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+pub fn state_generation() -> u64 {
+    STATE_GENERATION.load(Ordering::Acquire)
+}
+
+pub fn bump_state_generation() {
+    STATE_GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+// forces an exit on every virtualized cpu so the current state is applied
+// before returning, passive level only
+pub fn sync_vcpus() {
+    for processor in 0..processor_count() {
+        if !is_virtualized(processor) {
+            continue;
+        }
+        let Some(executor) = ProcessorExecutor::switch_to_processor(processor) else {
+            return log_error!("failed to switch to #cpu: {}", processor);
+        };
+
+        hypercall(VMMCALL_SYNC, 0);
+        core::mem::drop(executor);
+    }
+}
+
 #[repr(C, align(4096))]
 pub struct host_stack_layout {
     pub stack_contents: [u8; STACK_CONTENTS_SIZE],
@@ -43,8 +157,10 @@ pub struct host_stack_layout {
     pub guest_vmcb_pa: u64,
     pub host_vmcb_pa: u64,
     pub self_data: *mut u64, // self reference that will point to a vcpu struct
-    pub shared_data: *mut u64, // shared_data will be used for msr bitmap in future
-    pub padding_1: u64,
+    pub shared_data: *mut u64, // msr permission map shared by all vcpus
+    pub reload_guest_hidden: u64, // launch_vm vmloads the guest before vmrun, see hidden.rs
+    pub xsave_area: *mut u8, // the guest's vector state during an exit, see xstate.rs
+    pub extended_state: u64, // how launch_vm saves it, an xstate::extended_state
     pub reserved_1: u64,
 }
 const_assert_eq!(core::mem::size_of::<host_stack_layout>(), KERNEL_STACK_SIZE);
@@ -57,6 +173,31 @@ pub struct vcpu {
     pub host_state_area: [u8; PAGE_SIZE as usize],
     pub prev_vmexit: u64,
     pub unload: bool,
+    pub processor: u32,
+    pub phys_window: phys_window,
+    pub shared: &'static vcpu_shared,
+    pub svm_features: svm_features,
+    pub advance_rip: bool, // skip the intercepted instruction on resume
+    pub cr0_shadow: u64,   // guest view of pinned cr0 / cr4 bits
+    pub cr4_shadow: u64,
+    pub state_generation: u64, // last global state generation applied
+    pub dr_owned: u8,          // debug register slots holding hypervisor breakpoints
+    pub dr_shadow: [u64; 4],   // guest values of the owned slots
+    pub dr7_shadow: u64,       // guest dr7 while any slot is owned
+    pub npt_view: npt_view,
+    pub bp_step_tf: Option<bool>, // stepping over a breakpoint, guest's own tf
+    pub step: step_state,
+    pub efer_shadow: u64, // guest view of efer
+    pub tsc: tsc_clock,
+    pub nmi: nmi_state,
+    pub requeued: requeued_interrupts, // waiting for v_irq
+    pub asids: asid_allocator,
+    pub asid: asid_slot,
+    pub tlb_flush: tlb_flush, // requested during this exit
+    pub hidden: hidden_state,
+    pub xsave: xsave_sizing, // only tracked with extended_state::Xsave
+    pub idle: idle_state,
+    pub sipi: sipi_state,
 }
 
 impl vcpu {
@@ -68,15 +209,13 @@ impl vcpu {
         self.host_stack_layout.host_vmcb_pa = pa(addr_of!(self.host_vmcb) as _);
         self.host_stack_layout.self_data = self as *mut vcpu as *mut u64;
 
-        println!("guest_vmcb_pa: {}", self.host_stack_layout.guest_vmcb_pa);
-        println!("host_area_pa: {}", self.host_stack_layout.host_vmcb_pa);
+        log_info!("guest_vmcb_pa: {}", self.host_stack_layout.guest_vmcb_pa);
+        log_info!("host_area_pa: {}", self.host_stack_layout.host_vmcb_pa);
 
         //self.guest_vmcb.control_area.intercept_misc1 |= SVM_INTERCEPT_MISC1_CPUID;
         self.guest_vmcb.control_area.intercept_misc2 |= SVM_INTERCEPT_MISC2_VMRUN;
         self.guest_vmcb.control_area.intercept_misc2 |= SVM_INTERCEPT_MISC2_VMMCALL; //intercept vmmcall here
 
-        self.guest_vmcb.control_area.guest_asid = 1;
-
         self.guest_vmcb.state_save_area.gdtr_base = gdtr.base.as_u64();
         self.guest_vmcb.state_save_area.gdtr_limit = gdtr.limit as _;
         self.guest_vmcb.state_save_area.idtr_base = idtr.base.as_u64();
@@ -114,6 +253,18 @@ impl vcpu {
         self.guest_vmcb.state_save_area.rsp = context.Rsp;
         self.guest_vmcb.state_save_area.rip = context.Rip;
 
+        setup_cr_intercepts(self);
+        setup_npt(self);
+        setup_msrpm(self);
+        self.efer_shadow = self.guest_vmcb.state_save_area.efer;
+        setup_stealth(self);
+        setup_tsc(self);
+        setup_nmi(self);
+        setup_asid(self);
+        setup_xstate(self);
+        setup_idle(self);
+        setup_sipi(self);
+
         unsafe { asm!("vmsave rax", in("rax") self.host_stack_layout.guest_vmcb_pa) };
 
         let host_state_area_pa = pa(self.host_state_area.as_ptr() as *const _);
@@ -122,7 +273,27 @@ impl vcpu {
         unsafe { asm!("vmsave rax", in("rax") self.host_stack_layout.host_vmcb_pa) };
     }
 
-    pub fn new(context: &mut CONTEXT) -> Box<Self> {
+    pub fn sync_state(&mut self) {
+        // first exit, the guest runs on this cpu now
+        if self.state_generation == u64::MAX {
+            ipi::online(self);
+        }
+        self.state_generation = state_generation();
+        cr3_tracker::sync(self);
+        dr::sync(self);
+        single_step::sync(self);
+        msr::sync(self);
+        crate::handler::exception::sync(self);
+        npt::sync(self);
+    }
+
+    // raise a fault in the guest, the faulting instruction is not skipped
+    pub fn inject_fault(&mut self, vector: u8, error_code: Option<u32>) {
+        self.guest_vmcb.control_area.event_inj = exception(vector, error_code);
+        self.advance_rip = false;
+    }
+
+    pub fn new(context: &mut CONTEXT, processor: u32, phys_window: phys_window) -> Box<Self> {
         let instance = Self {
             host_stack_layout: host_stack_layout {
                 stack_contents: [0u8; STACK_CONTENTS_SIZE],
@@ -131,7 +302,9 @@ impl vcpu {
                 host_vmcb_pa: 0,
                 self_data: core::ptr::null_mut(),
                 shared_data: core::ptr::null_mut(),
-                padding_1: u64::MAX,
+                reload_guest_hidden: 1,
+                xsave_area: core::ptr::null_mut(),
+                extended_state: extended_state::Volatile as u64,
                 reserved_1: u64::MAX,
             },
             guest_vmcb: unsafe { core::mem::zeroed() },
@@ -139,6 +312,31 @@ impl vcpu {
             host_state_area: [0u8; PAGE_SIZE as usize],
             prev_vmexit: 0,
             unload: false,
+            processor,
+            phys_window,
+            shared: Box::leak(Box::new(vcpu_shared::new())),
+            svm_features: svm_features::read(),
+            advance_rip: true,
+            cr0_shadow: 0,
+            cr4_shadow: 0,
+            state_generation: u64::MAX,
+            dr_owned: 0,
+            dr_shadow: [0; 4],
+            dr7_shadow: 0,
+            npt_view: npt_view::Normal,
+            bp_step_tf: None,
+            step: step_state::new(),
+            efer_shadow: 0,
+            tsc: tsc_clock::new(),
+            nmi: nmi_state::new(),
+            requeued: requeued_interrupts::new(),
+            asids: asid_allocator::new(0),
+            asid: asid_slot::new(),
+            tlb_flush: tlb_flush::None,
+            hidden: hidden_state::new(),
+            xsave: xsave_sizing::new(),
+            idle: idle_state::new(),
+            sipi: sipi_state::new(),
         };
         let mut instance = Box::new(instance);
         instance.setup_vmcb(context);
@@ -151,27 +349,56 @@ fn virtualize_cpu(processor: u32) {
     unsafe { RtlCaptureContext(&mut context as *mut CONTEXT) };
 
     if !is_virtualized(processor) {
+        let Some(phys_window) = phys_window::new() else {
+            return log_error!("failed to reserve mapping window for #cpu: {}", processor);
+        };
         set_virtualized(processor);
         enable_svm();
-        let mut vcpu = vcpu::new(&mut context);
+        let mut vcpu = vcpu::new(&mut context, processor, phys_window);
+        SHARED[processor as usize].store(vcpu.shared as *const _ as *mut _, Ordering::Release);
+        vcpu.shared.virtualized.store(true, Ordering::Relaxed);
         let host_rsp = &vcpu.host_stack_layout.guest_vmcb_pa as *const u64 as *mut u64;
         unsafe { launch_vm(host_rsp) };
+        // back in the guest, an exit right away brings the vcpu online
+        hypercall(VMMCALL_SYNC, 0);
     }
-    println!("virtualized #cpu: {}", processor)
+    log_info!("virtualized #cpu: {}", processor)
 }
 
-pub fn virtualize() {
+// false when nothing was virtualized because the configuration can't be
+// honoured
+pub fn virtualize() -> bool {
+    if !npt::enabled() && !npt::init(&svm_features::read()) {
+        log_info!("running without nested paging");
+    }
+    if !msrpm::init() {
+        return false;
+    }
+    nmi::init();
+    ipi::init();
+    if !sipi::init() {
+        nmi::shutdown();
+        return false;
+    }
     for processor in 0..processor_count() {
         let Some(executor) = ProcessorExecutor::switch_to_processor(processor) else {
-            return println!("failed to switch to #cpu: {}", processor);
+            log_error!("failed to switch to #cpu: {}", processor);
+            // the cpus before it are virtualized, unload takes care of them
+            return true;
         };
 
         virtualize_cpu(processor);
         core::mem::drop(executor);
     }
+    true
 }
 
 pub fn devirtualize_cpu(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) -> u8 {
+    ipi::offline(vcpu_ctx);
+    sipi::teardown(vcpu_ctx);
+    vcpu_ctx.shared.virtualized.store(false, Ordering::Relaxed);
+    vcpu_ctx.shared.in_host.store(false, Ordering::Relaxed);
+
     guest_regs.rax = vcpu_ctx as *mut _ as u32 as u64; // storing addr of vcpu_ctx
     guest_regs.rdx = vcpu_ctx as *mut _ as u64 >> 32; // in these two registers
 
@@ -179,32 +406,87 @@ pub fn devirtualize_cpu(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) -> u8
     guest_regs.rcx = vcpu_ctx.guest_vmcb.state_save_area.rsp;
 
     let guest_vmcb_pa = pa(addr_of!(vcpu_ctx.guest_vmcb) as _);
+    hidden::save_guest(vcpu_ctx);
 
     unsafe {
         asm!("vmload rax", in("rax") guest_vmcb_pa);
-        asm!("sti");
+        // nmis held off since the exit arrive here, maskable interrupts stay
+        // off until the guest's rflags are back. a hypervisor ipi still on
+        // its way is claimed by drain
+        asm!("cli");
+        nmi::drain(vcpu_ctx.processor);
         asm!("stgi");
 
         // Disable svm.
         let msr = rdmsr(IA32_EFER) & !EFER_SVME;
         wrmsr(IA32_EFER, msr);
-
+    }
+    sipi::park(vcpu_ctx);
+    unsafe {
         // Restore guest eflags.
         asm!("push {}; popfq", in(reg) (*vcpu_ctx).guest_vmcb.state_save_area.rflags);
     }
     return 1;
 }
 
+// cpus spinning in the exit handler give up once this is set
+static UNLOADING: AtomicBool = AtomicBool::new(false);
+
+pub fn unloading() -> bool {
+    UNLOADING.load(Ordering::Acquire)
+}
+
 pub fn devirtualize() {
+    UNLOADING.store(true, Ordering::Release);
     for processor in 0..processor_count() {
+        // devirtualized itself, it waits for a sipi
+        if sipi::parked(processor) {
+            continue;
+        }
         let Some(executor) = ProcessorExecutor::switch_to_processor(processor) else {
-            return println!("failed to switch to #cpu: {}", processor);
+            return log_error!("failed to switch to #cpu: {}", processor);
         };
 
-        unsafe {
-            core::arch::asm!("vmmcall", in("rcx") 0x10, options(nostack, nomem));
+        hypercall(VMMCALL_UNLOAD, 0);
+        log_info!("devirtualized #cpu: {}", processor);
+        if CONFIG.print_stats_on_unload {
+            if let Some(shared) = shared_for(processor) {
+                print_stats(processor, &shared.stats.snapshot());
+            }
         }
-        println!("devirtualized #cpu: {}", processor);
         core::mem::drop(executor);
     }
-}
\ No newline at end of file
+    sipi::shutdown();
+    nmi::shutdown();
+}
+
+fn print_stats(processor: u32, stats: &exit_stats) {
+    log_info!(
+        "#cpu {}: {} exits, avg {} max {} cycles",
+        processor,
+        stats.total.count,
+        stats.total.average_cycles(),
+        stats.total.max_cycles
+    );
+    log_info!(
+        "#cpu {}: host state loaded {} times",
+        processor,
+        stats.host_state_loads
+    );
+    for (exit_code, counter) in stats.iter() {
+        log_info!(
+            "#cpu {}: exit {:#x}: {} exits, avg {} max {} cycles",
+            processor,
+            exit_code,
+            counter.count,
+            counter.average_cycles(),
+            counter.max_cycles
+        );
+    }
+    for bucket in 0..HISTOGRAM_BUCKETS {
+        let count = stats.histogram[bucket];
+        if count != 0 {
+            log_info!("#cpu {}: < 2^{} cycles: {}", processor, bucket, count);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+// guest idle, CONFIG.idle. hlt, monitor and mwait exit so hooks see the guest
+// going idle, then the guest runs the instruction itself: its intercept is
+// dropped and rip stays put. the intercept is armed again at the next exit,
+// physical interrupts exit while it's down so an interrupt ending the wait
+// is one. a wait ended otherwise, e.g. a store to the monitored line, is
+// only noticed at whatever exit comes next.
+//
+// pause loop exiting reports spinning guests, the pause itself is a hint and
+// skipped
+use crate::config::CONFIG;
+use crate::hooks::hook_table;
+use crate::hv::vcpu;
+use crate::log_warn;
+use crate::vmcb::*;
+use core::arch::x86_64::_rdtsc;
+
+pub const MAX_IDLE_HOOKS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum idle_event {
+    Hlt,
+    Monitor,
+    Mwait,
+    // the pause filter caught a spin loop
+    Pause,
+    // a hlt / mwait ended, cycles since the guest started it
+    Wake(u64),
+}
+
+// called in host context. for hlt, monitor, mwait and pause the guest's rip
+// still points at the instruction
+pub type idle_hook = fn(&mut vcpu, idle_event);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct idle_state {
+    // intercepts dropped to let the guest run an instruction once
+    pub misc1: u32,
+    pub misc2: u32,
+    // tsc when the guest went into hlt / mwait
+    pub since: Option<u64>,
+}
+
+impl idle_state {
+    pub const fn new() -> Self {
+        Self {
+            misc1: 0,
+            misc2: 0,
+            since: None,
+        }
+    }
+
+    // lets the guest run the intercepted instruction once, the intercepts
+    // to drop are recorded to put them back later
+    pub fn pass(&mut self, misc1: u32, misc2: u32, wait: bool, now: u64) {
+        self.misc1 |= misc1;
+        self.misc2 |= misc2;
+        if wait {
+            self.since = Some(now);
+        }
+    }
+
+    // at the next exit, the intercepts to put back and how long the guest
+    // waited if it did
+    pub fn rearm(&mut self, now: u64) -> (u32, u32, Option<u64>) {
+        let waited = self.since.take().map(|since| now.wrapping_sub(since));
+        let rearm = (self.misc1, self.misc2, waited);
+        self.misc1 = 0;
+        self.misc2 = 0;
+        rearm
+    }
+}
+
+static HOOKS: hook_table<idle_hook, MAX_IDLE_HOOKS> = hook_table::new();
+
+pub fn register_hook(hook: idle_hook) -> Option<usize> {
+    HOOKS.register(hook)
+}
+
+pub fn unregister_hook(slot: usize) {
+    HOOKS.unregister(slot);
+}
+
+fn call_hooks(vcpu_ctx: &mut vcpu, event: idle_event) {
+    for hook in HOOKS.iter() {
+        hook(vcpu_ctx, event);
+    }
+}
+
+pub fn setup_idle(vcpu_ctx: &mut vcpu) {
+    let config = &CONFIG.idle;
+    let features = vcpu_ctx.svm_features;
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    if config.hlt {
+        *vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_HLT;
+    }
+    if config.mwait {
+        *vmcb.intercept_misc2_mut() |= SVM_INTERCEPT_MISC2_MONITOR
+            | SVM_INTERCEPT_MISC2_MWAIT
+            | SVM_INTERCEPT_MISC2_MWAIT_CONDITIONAL;
+    }
+    if config.pause_filter_count == 0 {
+        return;
+    }
+    // every pause exiting would be far too slow
+    if !features.has(SVM_FEATURE_PAUSE_FILTER) {
+        return log_warn!("no pause filter, pause loops aren't intercepted");
+    }
+    *vmcb.pause_filter_count_mut() = config.pause_filter_count;
+    if features.has(SVM_FEATURE_PAUSE_FILTER_THRESHOLD) {
+        *vmcb.pause_filter_threshold_mut() = config.pause_filter_threshold;
+    }
+    *vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_PAUSE;
+}
+
+// early in every exit. the intr intercept was only ever borrowed by a wait
+pub fn rearm(vcpu_ctx: &mut vcpu) {
+    if vcpu_ctx.idle == idle_state::new() {
+        return;
+    }
+    let (misc1, misc2, waited) = vcpu_ctx.idle.rearm(unsafe { _rdtsc() });
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    if waited.is_some() {
+        *vmcb.intercept_misc1_mut() &= !SVM_INTERCEPT_MISC1_INTR;
+    }
+    if misc1 != 0 {
+        *vmcb.intercept_misc1_mut() |= misc1;
+    }
+    if misc2 != 0 {
+        *vmcb.intercept_misc2_mut() |= misc2;
+    }
+    if let Some(cycles) = waited {
+        call_hooks(vcpu_ctx, idle_event::Wake(cycles));
+    }
+}
+
+fn pass(vcpu_ctx: &mut vcpu, misc1: u32, misc2: u32, event: idle_event) {
+    call_hooks(vcpu_ctx, event);
+    let wait = event != idle_event::Monitor;
+    vcpu_ctx.idle.pass(misc1, misc2, wait, unsafe { _rdtsc() });
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    if misc1 != 0 {
+        *vmcb.intercept_misc1_mut() &= !misc1;
+    }
+    if misc2 != 0 {
+        *vmcb.intercept_misc2_mut() &= !misc2;
+    }
+    if wait {
+        *vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_INTR;
+    }
+    vcpu_ctx.advance_rip = false;
+}
+
+pub fn hlt_handler(vcpu_ctx: &mut vcpu) {
+    pass(vcpu_ctx, SVM_INTERCEPT_MISC1_HLT, 0, idle_event::Hlt);
+}
+
+pub fn monitor_handler(vcpu_ctx: &mut vcpu) {
+    let misc2 = SVM_INTERCEPT_MISC2_MONITOR;
+    pass(vcpu_ctx, 0, misc2, idle_event::Monitor);
+}
+
+pub fn mwait_handler(vcpu_ctx: &mut vcpu) {
+    let misc2 = SVM_INTERCEPT_MISC2_MWAIT | SVM_INTERCEPT_MISC2_MWAIT_CONDITIONAL;
+    pass(vcpu_ctx, 0, misc2, idle_event::Mwait);
+}
+
+pub fn pause_handler(vcpu_ctx: &mut vcpu) {
+    call_hooks(vcpu_ctx, idle_event::Pause);
+}
+
+// an interrupt ended a wait, rearm already ran. the guest takes it once it
+// resumes, rip is past the hlt / mwait
+pub fn intr_handler(vcpu_ctx: &mut vcpu) {
+    vcpu_ctx.advance_rip = false;
+}
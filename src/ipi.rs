@@ -0,0 +1,97 @@
+// hypervisor ipis: a callback runs in host context on every virtualized cpu
+// and the sender waits until all of them ran it. targets are signalled with
+// an nmi, which exits whether the cpu runs the guest or sits in the exit
+// handler with gif clear, see nmi.rs.
+//
+// one broadcast runs at a time. a cpu waiting for its turn, or spinning in
+// the exit handler for another reason, serves requests meanwhile, so two
+// senders never wait on each other. at most one nmi per target is in flight,
+// a later broadcast finds the target still owing the earlier nmi and doesn't
+// send another, nmis sent while gif is clear would merge into one
+use crate::apic;
+use crate::hv::{MAX_PROCESSORS, state_generation, vcpu};
+use crate::log_error;
+use crate::nmi;
+use crate::npt;
+use crate::structs::*;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+pub use hv_core::ipi::*;
+
+// runs in host context on every cpu, context is the broadcast's argument
+pub type ipi_callback = fn(&mut vcpu, u64);
+
+static RENDEZVOUS: rendezvous<ipi_callback> = rendezvous::new();
+static APIC_IDS: [AtomicU32; MAX_PROCESSORS] = [const { AtomicU32::new(0) }; MAX_PROCESSORS];
+static HOOK: AtomicBool = AtomicBool::new(false);
+
+fn run_on(vcpu_ctx: &mut vcpu) -> impl FnMut(ipi_callback, u64) + '_ {
+    move |callback, context| callback(vcpu_ctx, context)
+}
+
+fn send(target: usize) {
+    apic::send_nmi(APIC_IDS[target].load(Ordering::Relaxed));
+}
+
+fn ipi_nmi(vcpu_ctx: &mut vcpu, _guest_regs: &mut guest_regs) -> bool {
+    let processor = vcpu_ctx.processor as usize;
+    let ours = RENDEZVOUS.signalled(processor);
+    RENDEZVOUS.serve(processor, run_on(vcpu_ctx));
+    ours
+}
+
+// passive level, before any cpu is virtualized. without the nmi intercept
+// broadcasts only run on the sender
+pub fn init() {
+    if !nmi::intercepting() || HOOK.load(Ordering::Acquire) || !apic::init() {
+        return;
+    }
+    if nmi::register_hook(ipi_nmi).is_none() {
+        return log_error!("no nmi hook slot left for hypervisor ipis");
+    }
+    HOOK.store(true, Ordering::Release);
+}
+
+// on the cpu itself, once it runs the guest
+pub fn online(vcpu_ctx: &mut vcpu) {
+    if !HOOK.load(Ordering::Acquire) {
+        return;
+    }
+    let processor = vcpu_ctx.processor as usize;
+    APIC_IDS[processor].store(apic::id(), Ordering::Relaxed);
+    RENDEZVOUS.set_online(processor, true, run_on(vcpu_ctx));
+}
+
+pub fn offline(vcpu_ctx: &mut vcpu) {
+    let processor = vcpu_ctx.processor as usize;
+    RENDEZVOUS.set_online(processor, false, run_on(vcpu_ctx));
+}
+
+// for loops spinning in the exit handler
+pub fn serve(vcpu_ctx: &mut vcpu) {
+    let processor = vcpu_ctx.processor as usize;
+    RENDEZVOUS.serve(processor, run_on(vcpu_ctx));
+}
+
+// host context only
+pub fn broadcast(vcpu_ctx: &mut vcpu, callback: ipi_callback, context: u64) {
+    let processor = vcpu_ctx.processor as usize;
+    RENDEZVOUS.broadcast(processor, callback, context, send, run_on(vcpu_ctx));
+}
+
+// makes every other cpu exit soon, without waiting for it
+pub fn kick(vcpu_ctx: &vcpu) {
+    RENDEZVOUS.kick(vcpu_ctx.processor as usize, send);
+}
+
+fn flush_npt(vcpu_ctx: &mut vcpu, _context: u64) {
+    match vcpu_ctx.state_generation != state_generation() {
+        true => vcpu_ctx.sync_state(),
+        false => npt::sync(vcpu_ctx),
+    }
+}
+
+// nested page table changes are seen everywhere once this returns, every cpu
+// also applied pending global state on the way
+pub fn flush_npt_all_cpus(vcpu_ctx: &mut vcpu) {
+    broadcast(vcpu_ctx, flush_npt, 0);
+}
@@ -5,18 +5,43 @@
 #![allow(non_upper_case_globals)]
 
 use core::panic::PanicInfo;
-use wdk::println;
 use wdk_alloc::WdkAllocator;
-use wdk_sys::{DRIVER_OBJECT, NTSTATUS, PUNICODE_STRING, STATUS_SUCCESS};
+use wdk_sys::{DRIVER_OBJECT, NTSTATUS, PUNICODE_STRING, STATUS_NOT_SUPPORTED, STATUS_SUCCESS};
 extern crate wdk_panic;
 
+mod apic;
+mod asid;
+mod breakpoint;
+mod config;
+mod cr3_tracker;
+mod gdb;
+mod guest_mem;
 mod handler;
+mod hidden;
 mod hv;
+mod idle;
+mod ipi;
+mod log;
+mod msr_shadow;
+mod msrpm;
+mod nmi;
+mod npt;
+mod phys_window;
 mod segments;
+mod serial;
+mod single_step;
+mod sipi;
+mod stealth;
 mod structs;
+mod sync;
+mod syscall;
+mod tsc;
 mod utils;
 mod vmcb;
 mod vmexit;
+mod xstate;
+
+use hv_core::{event, hooks, ring, stats};
 
 #[global_allocator]
 static GLOBAL_ALLOCATOR: WdkAllocator = WdkAllocator;
@@ -26,9 +51,12 @@ pub unsafe extern "system" fn driver_entry(
     driver: &mut DRIVER_OBJECT,
     registry_path: PUNICODE_STRING,
 ) -> NTSTATUS {
-    println!("DriverEntry from Rust!");
-    if utils::is_svm_supported() == true {
-        hv::virtualize();
+    log::init();
+    gdb::stub::init();
+    log_info!("DriverEntry from Rust!");
+    if utils::is_svm_supported() == true && !hv::virtualize() {
+        log::shutdown();
+        return STATUS_NOT_SUPPORTED;
     }
     driver.DriverUnload = Some(driver_unload);
     STATUS_SUCCESS
@@ -36,5 +64,6 @@ pub unsafe extern "system" fn driver_entry(
 
 unsafe extern "C" fn driver_unload(driver: *mut DRIVER_OBJECT) {
     hv::devirtualize();
-    println!("bye bye from driver!");
+    log_info!("bye bye from driver!");
+    log::shutdown();
 }
@@ -0,0 +1,334 @@
+// logging that is safe to use from the exit handler. records are formatted
+// once into a fixed size entry and handed to the configured sinks:
+// - DbgPrint: in the exit handler records are pushed onto the ring of the
+//   current vcpu and a worker thread in normal context drains them, or they
+//   can be pulled out with a hypercall. outside of it they are printed directly
+// - serial: written straight to the uart from any context
+use crate::config::CONFIG;
+use crate::guest_mem::pod;
+use crate::hv::{MAX_PROCESSORS, shared_for};
+use crate::ring::spsc_ring;
+use crate::serial::{port_io, uart};
+use core::arch::x86_64::_rdtsc;
+use core::ffi::c_void;
+use core::fmt::{self, Write};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, Ordering};
+use core::writeln;
+use wdk::println;
+use wdk_sys::{
+    _KWAIT_REASON, _MODE, GENERIC_ALL, LARGE_INTEGER, NT_SUCCESS, PVOID, STATUS_SUCCESS, ntddk::*,
+};
+
+pub const LOG_MSG_SIZE: usize = 112;
+pub const LOG_RING_ENTRIES: usize = 256;
+
+pub type log_ring = spsc_ring<log_record, LOG_RING_ENTRIES>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum log_level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl log_level {
+    pub fn name(self) -> &'static str {
+        match self {
+            log_level::Error => "ERROR",
+            log_level::Warn => "WARN",
+            log_level::Info => "INFO",
+            log_level::Debug => "DEBUG",
+            log_level::Trace => "TRACE",
+        }
+    }
+
+    fn from_u8(level: u8) -> Self {
+        match level {
+            1 => log_level::Error,
+            2 => log_level::Warn,
+            3 => log_level::Info,
+            4 => log_level::Debug,
+            _ => log_level::Trace,
+        }
+    }
+}
+
+// layout is shared with user mode through the read log hypercall
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct log_record {
+    pub tsc: u64,
+    pub processor: u32,
+    pub level: u8,
+    pub len: u8,
+    pub reserved: u16,
+    pub msg: [u8; LOG_MSG_SIZE],
+}
+static_assertions::const_assert_eq!(core::mem::size_of::<log_record>(), 128);
+unsafe impl pod for log_record {}
+
+impl log_record {
+    pub fn new(level: log_level, processor: u32, args: fmt::Arguments) -> Self {
+        let mut record = Self {
+            tsc: unsafe { _rdtsc() },
+            processor,
+            level: level as u8,
+            len: 0,
+            reserved: 0,
+            msg: [0u8; LOG_MSG_SIZE],
+        };
+        let _ = record.write_fmt(args);
+        record
+    }
+
+    pub fn level(&self) -> log_level {
+        log_level::from_u8(self.level)
+    }
+
+    pub fn message(&self) -> &str {
+        let bytes = &self.msg[..self.len as usize];
+        // truncation only ever happens on a char boundary
+        core::str::from_utf8(bytes).unwrap_or("<invalid utf8>")
+    }
+}
+
+impl fmt::Display for log_record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[#{}][{}][{}] {}",
+            self.processor,
+            self.tsc,
+            self.level().name(),
+            self.message()
+        )
+    }
+}
+
+// appends to the message, silently truncating what doesn't fit
+impl Write for log_record {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = self.len as usize;
+        let mut take = core::cmp::min(s.len(), LOG_MSG_SIZE - len);
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.msg[len..len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len = (len + take) as u8;
+        Ok(())
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(log_level::Info as u8);
+
+pub fn set_max_level(level: log_level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: log_level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+static SERIAL: uart<port_io> = uart::new(port_io {
+    base: match CONFIG.log.serial {
+        Some(ref serial) => serial.port,
+        None => 0,
+    },
+});
+
+// keeps lines from different cpus apart. the wait is bounded because the
+// holder may be the guest side of this very cpu, interrupted by an exit
+static SERIAL_LOCK: AtomicBool = AtomicBool::new(false);
+const SERIAL_LOCK_SPINS: u32 = 1_000_000;
+
+fn write_serial(record: &log_record) {
+    let mut locked = false;
+    for _ in 0..SERIAL_LOCK_SPINS {
+        if !SERIAL_LOCK.swap(true, Ordering::Acquire) {
+            locked = true;
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    let _ = writeln!(&SERIAL, "{}", record);
+    if locked {
+        SERIAL_LOCK.store(false, Ordering::Release);
+    }
+}
+
+pub fn init() {
+    MAX_LEVEL.store(CONFIG.log.level as u8, Ordering::Relaxed);
+    if let Some(ref serial) = CONFIG.log.serial {
+        SERIAL.init(serial.baud);
+    }
+    if CONFIG.log.dbgprint {
+        start_drain_thread();
+    }
+}
+
+pub fn shutdown() {
+    stop_drain_thread();
+}
+
+pub fn write(level: log_level, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let processor = unsafe { KeGetCurrentProcessorNumberEx(null_mut()) };
+    let record = log_record::new(level, processor, args);
+
+    if CONFIG.log.serial.is_some() {
+        write_serial(&record);
+    }
+    if CONFIG.log.dbgprint {
+        match shared_for(processor) {
+            Some(shared) if shared.in_host.load(Ordering::Relaxed) => {
+                shared.log_ring.push(record);
+            }
+            _ => println!("{}", record),
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        $crate::log::write($level, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::log!($crate::log::log_level::Error, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::log!($crate::log::log_level::Warn, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => { $crate::log!($crate::log::log_level::Info, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::log!($crate::log::log_level::Debug, $($arg)*) };
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)*) => { $crate::log!($crate::log::log_level::Trace, $($arg)*) };
+}
+
+// the rings have a single consumer, the drain thread and the hypercall take
+// turns through this flag. the hypercall never waits on it since the drain
+// thread could be preempted while holding it
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+// pops up to max records across all cpus, returns None if someone else is draining
+pub fn try_drain(max: usize, mut f: impl FnMut(&log_record) -> bool) -> Option<usize> {
+    if DRAINING.swap(true, Ordering::Acquire) {
+        return None;
+    }
+    let mut count = 0;
+    'cpus: for processor in 0..MAX_PROCESSORS as u32 {
+        let Some(shared) = shared_for(processor) else {
+            continue;
+        };
+        while count < max {
+            let Some(record) = shared.log_ring.pop() else {
+                continue 'cpus;
+            };
+            count += 1;
+            if !f(&record) {
+                break 'cpus;
+            }
+        }
+        break;
+    }
+    DRAINING.store(false, Ordering::Release);
+    Some(count)
+}
+
+fn drain_to_debugger() {
+    while let Some(count) = try_drain(64, |record| {
+        println!("{}", record);
+        true
+    }) {
+        if count == 0 {
+            break;
+        }
+    }
+}
+
+static DRAIN_THREAD: AtomicPtr<c_void> = AtomicPtr::new(null_mut());
+static DRAIN_STOP: AtomicBool = AtomicBool::new(false);
+
+unsafe extern "C" fn drain_thread(_context: PVOID) {
+    // relative 10ms
+    let mut interval = LARGE_INTEGER {
+        QuadPart: -10 * 1000 * 10,
+    };
+    while !DRAIN_STOP.load(Ordering::Relaxed) {
+        drain_to_debugger();
+        unsafe { KeDelayExecutionThread(_MODE::KernelMode as _, 0, &mut interval) };
+    }
+    drain_to_debugger();
+    unsafe { PsTerminateSystemThread(STATUS_SUCCESS) };
+}
+
+fn start_drain_thread() {
+    let mut handle = null_mut();
+    let status = unsafe {
+        PsCreateSystemThread(
+            &mut handle,
+            GENERIC_ALL,
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            Some(drain_thread),
+            null_mut(),
+        )
+    };
+    if !NT_SUCCESS(status) {
+        return println!("failed to create log drain thread: {:#x}", status);
+    }
+
+    let mut thread = null_mut();
+    unsafe {
+        ObReferenceObjectByHandle(
+            handle,
+            GENERIC_ALL,
+            null_mut(),
+            _MODE::KernelMode as _,
+            &mut thread,
+            null_mut(),
+        );
+        ZwClose(handle);
+    }
+    DRAIN_THREAD.store(thread, Ordering::Relaxed);
+}
+
+fn stop_drain_thread() {
+    DRAIN_STOP.store(true, Ordering::Relaxed);
+    let thread = DRAIN_THREAD.swap(null_mut(), Ordering::Relaxed);
+    if !thread.is_null() {
+        unsafe {
+            KeWaitForSingleObject(
+                thread,
+                _KWAIT_REASON::Executive,
+                _MODE::KernelMode as _,
+                0,
+                null_mut(),
+            );
+            ObfDereferenceObject(thread);
+        }
+    }
+    drain_to_debugger();
+}
@@ -0,0 +1,60 @@
+// shadow msrs: the hardware keeps one value while rdmsr in the guest returns
+// another, e.g. a relocated lstar. every shadowed msr has a write policy that
+// decides what a guest wrmsr does. the table itself is plain data in
+// hv_core, the global instance and the permission map bits are handled here.
+// efer is always shadowed per vcpu, see handler/msr.rs
+use crate::msrpm;
+use crate::sync::spin_mutex;
+pub use hv_core::msr_shadow::*;
+use x86::msr::IA32_EFER;
+
+static SHADOWS: spin_mutex<shadow_table> = spin_mutex::new(shadow_table::new());
+
+fn update_intercept(msr: u32, entry: Option<&shadow_entry>) -> bool {
+    let read = entry.is_some_and(|entry| entry.shadow.is_some());
+    let write = entry.is_some_and(|entry| entry.policy != write_policy::Allow);
+    // msrs outside of the map's ranges always exit
+    msrpm::set_intercept(msr, read, write) || msrpm::position(msr).is_none()
+}
+
+// applies to every vcpu at its next rdmsr / wrmsr, the map isn't cached
+pub fn shadow_msr(msr: u32, shadow: Option<u64>, policy: write_policy) -> bool {
+    if msr == IA32_EFER {
+        return false;
+    }
+    let entry = shadow_entry {
+        msr,
+        shadow,
+        policy,
+    };
+    let mut table = SHADOWS.lock();
+    if !table.insert(entry) {
+        return false;
+    }
+    if !update_intercept(msr, Some(&entry)) {
+        table.remove(msr);
+        return false;
+    }
+    true
+}
+
+pub fn unshadow_msr(msr: u32) -> bool {
+    let mut table = SHADOWS.lock();
+    if table.remove(msr).is_none() {
+        return false;
+    }
+    update_intercept(msr, None);
+    true
+}
+
+pub fn read_shadow(msr: u32) -> Option<u64> {
+    SHADOWS.lock().read(msr)
+}
+
+pub fn write_shadow(msr: u32, value: u64) -> Option<write_outcome> {
+    SHADOWS.lock().write(msr, value)
+}
+
+pub fn shadowed(msr: u32) -> bool {
+    SHADOWS.lock().lookup(msr).is_some()
+}
@@ -0,0 +1,89 @@
+// msr permission map: two bits per msr, read then write, for the three msr
+// ranges svm can intercept. msrs outside of them always exit while the map
+// is enabled. one map is shared by every vcpu and reachable from the host
+// stack through host_stack_layout::shared_data
+use crate::hv::vcpu;
+use crate::log_error;
+use crate::utils::pa;
+use crate::vmcb::*;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU8, AtomicU64, Ordering};
+use wdk_sys::{PHYSICAL_ADDRESS, ntddk::*};
+
+pub const MSRPM_SIZE: usize = 0x2000;
+// first msr and byte offset of every range, 0x2000 msrs each
+const RANGES: [(u32, usize); 3] = [(0, 0), (0xc000_0000, 0x800), (0xc001_0000, 0x1000)];
+const RANGE_MSRS: u32 = 0x2000;
+
+#[repr(C, align(4096))]
+pub struct msrpm {
+    bits: [AtomicU8; MSRPM_SIZE],
+}
+
+static MAP: AtomicPtr<msrpm> = AtomicPtr::new(null_mut());
+static MAP_PA: AtomicU64 = AtomicU64::new(0);
+
+// byte offset and bit of msr's read intercept, the write bit follows it
+pub fn position(msr: u32) -> Option<(usize, u8)> {
+    let (first, offset) = RANGES
+        .iter()
+        .find(|(first, _)| msr.wrapping_sub(*first) < RANGE_MSRS)?;
+    let bit = (msr - first) as usize * 2;
+    Some((offset + bit / 8, (bit % 8) as u8))
+}
+
+// passive level, before any cpu is virtualized. the map has to be physically
+// contiguous and is never freed
+pub fn init() -> bool {
+    if !MAP.load(Ordering::Acquire).is_null() {
+        return true;
+    }
+    let highest = PHYSICAL_ADDRESS { QuadPart: i64::MAX };
+    let map = unsafe { MmAllocateContiguousMemory(MSRPM_SIZE as _, highest) } as *mut msrpm;
+    if map.is_null() {
+        log_error!("failed to allocate the msr permission map");
+        return false;
+    }
+    unsafe { map.write_bytes(0, 1) };
+    MAP_PA.store(pa(map as _), Ordering::Relaxed);
+    MAP.store(map, Ordering::Release);
+    true
+}
+
+fn map() -> Option<&'static msrpm> {
+    unsafe { MAP.load(Ordering::Acquire).as_ref() }
+}
+
+// takes effect on the next access of every vcpu, the map isn't cached.
+// false for msrs the map can't describe
+pub fn set_intercept(msr: u32, read: bool, write: bool) -> bool {
+    let (Some(map), Some((offset, bit))) = (map(), position(msr)) else {
+        return false;
+    };
+    let mask = 0b11 << bit;
+    let value = (read as u8 | (write as u8) << 1) << bit;
+    let byte = &map.bits[offset];
+    let _ = byte.fetch_update(Ordering::AcqRel, Ordering::Relaxed, |bits| {
+        Some(bits & !mask | value)
+    });
+    true
+}
+
+// (read, write) intercepted
+pub fn intercepted(msr: u32) -> (bool, bool) {
+    let (Some(map), Some((offset, bit))) = (map(), position(msr)) else {
+        return (true, true);
+    };
+    let bits = map.bits[offset].load(Ordering::Relaxed) >> bit;
+    (bits & 1 != 0, bits & 2 != 0)
+}
+
+pub fn setup_msrpm(vcpu_ctx: &mut vcpu) {
+    let map = MAP.load(Ordering::Acquire);
+    if map.is_null() {
+        return;
+    }
+    vcpu_ctx.host_stack_layout.shared_data = map as *mut u64;
+    *vcpu_ctx.guest_vmcb.msrpm_base_pa_mut() = MAP_PA.load(Ordering::Relaxed);
+    *vcpu_ctx.guest_vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_MSR_PROT;
+}
@@ -0,0 +1,217 @@
+// nmi virtualization. with the nmi intercept on, a physical nmi exits and
+// stays pending while gif is clear. the exit handler takes it right away by
+// opening gif for a moment, our nmi callback claims it so the kernel's
+// handler doesn't act on it in host context, and it's owed to the guest
+// unless a hook says it was the hypervisor's own. owed nmis are injected as
+// soon as the guest can take one: vnmi tracks the guest's nmi masking in
+// hardware, without it the iret ending the guest's handler is intercepted
+// and the next nmi goes in once the iret retired.
+//
+// the kernel collapses nmis as the cpu does, one pending while one is being
+// handled. an nmi sent to signal the hypervisor that coincides with a real
+// one is taken as a signal, the real one is lost
+use crate::config::CONFIG;
+use crate::event::*;
+use crate::hidden;
+use crate::hooks::hook_table;
+use crate::hv::{MAX_PROCESSORS, vcpu};
+use crate::structs::*;
+use crate::vmcb::*;
+use crate::{log_debug, log_error};
+use core::arch::asm;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use wdk_sys::{BOOLEAN, PVOID, ntddk::*};
+
+pub const MAX_NMI_HOOKS: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct nmi_state {
+    // an nmi is owed to the guest
+    pub pending: bool,
+    // the guest is handling an nmi we injected, only tracked without vnmi
+    pub blocked: bool,
+    // the iret ending the guest's handler, the window opens once rip moved on
+    pub iret_rip: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum nmi_step {
+    Nothing,
+    Inject,
+    // intercept iret to learn when the guest's handler ends
+    WaitIret,
+}
+
+impl nmi_state {
+    pub const fn new() -> Self {
+        Self {
+            pending: false,
+            blocked: false,
+            iret_rip: None,
+        }
+    }
+
+    // an injected nmi whose delivery didn't complete, the guest never got
+    // into its handler
+    pub fn requeue(&mut self) {
+        self.pending = true;
+        self.blocked = false;
+        self.iret_rip = None;
+    }
+
+    pub fn iret(&mut self, rip: u64) {
+        if self.blocked {
+            self.iret_rip = Some(rip);
+        }
+    }
+
+    // at the end of an exit, rip is where the guest resumes. busy when
+    // event_inj is already taken by something else
+    pub fn next(&mut self, rip: u64, busy: bool) -> nmi_step {
+        if self.iret_rip.is_some_and(|iret| iret != rip) {
+            self.blocked = false;
+            self.iret_rip = None;
+        }
+        match (self.pending, self.blocked) {
+            (false, _) => nmi_step::Nothing,
+            (true, true) => nmi_step::WaitIret,
+            (true, false) if busy => nmi_step::Nothing,
+            (true, false) => {
+                self.pending = false;
+                self.blocked = true;
+                nmi_step::Inject
+            }
+        }
+    }
+}
+
+// called in host context for every physical nmi, true if it was meant for
+// the hypervisor and the guest must not see it
+pub type nmi_hook = fn(&mut vcpu, &mut guest_regs) -> bool;
+
+static HOOKS: hook_table<nmi_hook, MAX_NMI_HOOKS> = hook_table::new();
+static CALLBACK: AtomicPtr<core::ffi::c_void> = AtomicPtr::new(null_mut());
+// the processor is taking an intercepted nmi in host context
+static DRAINING: [AtomicBool; MAX_PROCESSORS] = [const { AtomicBool::new(false) }; MAX_PROCESSORS];
+
+pub fn register_hook(hook: nmi_hook) -> Option<usize> {
+    HOOKS.register(hook)
+}
+
+pub fn unregister_hook(slot: usize) {
+    HOOKS.unregister(slot);
+}
+
+unsafe extern "C" fn nmi_callback(_context: PVOID, _handled: BOOLEAN) -> BOOLEAN {
+    let processor = unsafe { KeGetCurrentProcessorNumberEx(null_mut()) } as usize;
+    DRAINING
+        .get(processor)
+        .is_some_and(|draining| draining.load(Ordering::Relaxed)) as BOOLEAN
+}
+
+// passive level, before any cpu is virtualized. nmis aren't intercepted
+// without the callback, they reach the guest directly then
+pub fn init() {
+    if !CONFIG.nmi_intercept || !CALLBACK.load(Ordering::Acquire).is_null() {
+        return;
+    }
+    let handle = unsafe { KeRegisterNmiCallback(Some(nmi_callback), null_mut()) };
+    if handle.is_null() {
+        return log_error!("failed to register the nmi callback");
+    }
+    CALLBACK.store(handle, Ordering::Release);
+}
+
+// passive level, after every cpu is devirtualized
+pub fn shutdown() {
+    let handle = CALLBACK.swap(null_mut(), Ordering::AcqRel);
+    if !handle.is_null() {
+        unsafe { KeDeregisterNmiCallback(handle) };
+    }
+}
+
+pub fn intercepting() -> bool {
+    !CALLBACK.load(Ordering::Acquire).is_null()
+}
+
+pub fn setup_nmi(vcpu_ctx: &mut vcpu) {
+    if !intercepting() {
+        return;
+    }
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    *vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_NMI;
+    if vcpu_ctx.svm_features.has(SVM_FEATURE_VNMI) {
+        *vmcb.vintr_mut() |= V_NMI_ENABLE;
+    }
+}
+
+// lets the pending physical nmi through the host idt, where nmi_callback
+// claims it. interrupts stay off, only the nmi can come in
+pub fn drain(processor: u32) {
+    DRAINING[processor as usize].store(true, Ordering::Relaxed);
+    unsafe { asm!("pushfq", "cli", "stgi", "nop", "clgi", "popfq") };
+    DRAINING[processor as usize].store(false, Ordering::Relaxed);
+}
+
+pub fn nmi_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    vcpu_ctx.advance_rip = false;
+    // the kernel's nmi handler runs on the host's gs
+    hidden::load_host(vcpu_ctx);
+    drain(vcpu_ctx.processor);
+
+    let mut consumed = false;
+    for hook in HOOKS.iter() {
+        consumed |= hook(vcpu_ctx, guest_regs);
+    }
+    if !consumed {
+        vcpu_ctx.nmi.pending = true;
+    }
+}
+
+// the iret hasn't executed yet, it does once the guest resumes
+pub fn iret_handler(vcpu_ctx: &mut vcpu) {
+    vcpu_ctx.advance_rip = false;
+    let rip = vcpu_ctx.guest_vmcb.state_save_area.rip;
+    vcpu_ctx.nmi.iret(rip);
+    *vcpu_ctx.guest_vmcb.intercept_misc1_mut() &= !SVM_INTERCEPT_MISC1_IRET;
+}
+
+// an nmi the guest is owed and can't take yet, unload waits for it
+pub fn owed(vcpu_ctx: &vcpu) -> bool {
+    vcpu_ctx.nmi.pending
+}
+
+// called at the end of every exit, rip is already where the guest resumes
+pub fn deliver(vcpu_ctx: &mut vcpu) {
+    // an injected nmi whose delivery was cut short by this exit
+    let control = &mut vcpu_ctx.guest_vmcb.control_area;
+    let interrupted = control.exit_int_info;
+    if interrupted & EVENT_VALID != 0
+        && (interrupted >> 8) & 7 == EVENT_TYPE_NMI
+        && control.event_inj & EVENT_VALID == 0
+    {
+        control.event_inj = interrupted;
+    }
+    if !vcpu_ctx.nmi.pending && vcpu_ctx.nmi.iret_rip.is_none() {
+        return;
+    }
+    if vcpu_ctx.svm_features.has(SVM_FEATURE_VNMI) {
+        // a second one collapses into the one still pending
+        *vcpu_ctx.guest_vmcb.vintr_mut() |= V_NMI_PENDING;
+        vcpu_ctx.nmi.pending = false;
+        return;
+    }
+    let rip = vcpu_ctx.guest_vmcb.state_save_area.rip;
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    let busy = vmcb.control_area.event_inj & EVENT_VALID != 0;
+    match vcpu_ctx.nmi.next(rip, busy) {
+        nmi_step::Nothing => {}
+        nmi_step::Inject => {
+            log_debug!("injecting nmi at {:#x}", rip);
+            vmcb.control_area.event_inj = event(EXCEPTION_NMI, EVENT_TYPE_NMI, None);
+            *vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_IRET;
+        }
+        nmi_step::WaitIret => *vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_IRET,
+    }
+}
@@ -0,0 +1,433 @@
+// nested page tables. guest physical memory is identity mapped in three views
+// that only differ for hooked pages:
+//   identity: no hooks, the original code everywhere
+//   normal: hooked pages are not executable
+//   hook: only hooked pages are executable, they map a read-only shadow copy
+// vcpus run in normal and only switch to hook for an int3 in the shadow, see
+// npf.rs. mmio above what init maps is mapped when it first faults. tables
+// for splitting large pages, those mappings and shadow pages come from a pool
+// allocated up front since both happen in the exit handler
+extern crate alloc;
+use crate::asid::{request_tlb_flush, retire_asid, tlb_flush};
+use crate::guest_mem::phys_mem;
+use crate::hv::vcpu;
+use crate::sync::spin_mutex;
+use crate::utils::pa;
+use crate::vmcb::*;
+use crate::{log_error, log_info};
+use alloc::alloc::{Layout, alloc_zeroed};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use wdk_sys::ntddk::*;
+
+pub const NPT_PRESENT: u64 = 1 << 0;
+pub const NPT_WRITE: u64 = 1 << 1;
+pub const NPT_USER: u64 = 1 << 2;
+pub const NPT_PWT: u64 = 1 << 3;
+pub const NPT_PCD: u64 = 1 << 4;
+pub const NPT_LARGE: u64 = 1 << 7;
+pub const NPT_NX: u64 = 1 << 63;
+const NPT_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+// nested walks are user accesses. pat is always 0, it moves between 4k and
+// large entries
+const NPT_TABLE: u64 = NPT_PRESENT | NPT_WRITE | NPT_USER;
+const NPT_LEAF_FLAGS: u64 = NPT_PRESENT | NPT_WRITE | NPT_USER | NPT_PWT | NPT_PCD | NPT_NX;
+
+// exit_info1 of nested page faults
+pub const NPF_PRESENT: u64 = 1 << 0;
+pub const NPF_WRITE: u64 = 1 << 1;
+pub const NPF_EXECUTE: u64 = 1 << 4;
+
+const PAGE: u64 = 0x1000;
+const GB: u64 = 1 << 30;
+// at least one pml4 entry so mmio above ram is covered
+const MIN_COVERAGE: u64 = 512 * GB;
+// without 1gb pages every gb needs a page directory, only ram is covered then
+const MIN_COVERAGE_2MB: u64 = 4 * GB;
+
+pub const VIEWS: usize = 3;
+pub const POOL_PAGES: usize = 192;
+pub const MAX_HOOKED_PAGES: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(usize)]
+pub enum npt_view {
+    Identity = 0,
+    Normal = 1,
+    Hook = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum npt_error {
+    // nested paging is unsupported or failed to initialize
+    Disabled,
+    OutOfPages,
+    TooManyHooks,
+    // outside of the mapped range
+    NotMapped,
+    // the original page could not be copied
+    PhysRead,
+}
+
+#[repr(C, align(4096))]
+pub struct npt_page {
+    pub entries: [u64; 512],
+}
+
+#[derive(Clone, Copy)]
+struct hooked_page {
+    gpa: u64,
+    // index of the shadow copy in pages
+    shadow: usize,
+    refs: u32,
+}
+
+pub struct npt {
+    pages: &'static mut [npt_page],
+    gb_pages: bool,
+    // the cpu's physical address width
+    top: u64,
+    pas: &'static [u64],
+    // pages[pool_start..] are handed out on demand
+    pool_start: usize,
+    pool_used: [u64; POOL_PAGES / 64],
+    hooked: [Option<hooked_page>; MAX_HOOKED_PAGES],
+}
+
+static NPT: AtomicPtr<spin_mutex<npt>> = AtomicPtr::new(null_mut());
+// pml4 physical address of every view, fixed after init
+static ROOTS: [AtomicU64; VIEWS] = [const { AtomicU64::new(0) }; VIEWS];
+
+fn get() -> Option<&'static spin_mutex<npt>> {
+    unsafe { NPT.load(Ordering::Acquire).as_ref() }
+}
+
+pub fn enabled() -> bool {
+    !NPT.load(Ordering::Acquire).is_null()
+}
+
+fn has_1gb_pages() -> bool {
+    unsafe { __cpuid(0x8000_0001).edx & (1 << 26) != 0 }
+}
+
+fn physical_address_bits() -> u32 {
+    unsafe { __cpuid(0x8000_0008).eax & 0xff }
+}
+
+fn leaf_flags(view: npt_view) -> u64 {
+    match view {
+        npt_view::Hook => NPT_TABLE | NPT_NX,
+        _ => NPT_TABLE,
+    }
+}
+
+fn ram_top() -> u64 {
+    let ranges = unsafe { MmGetPhysicalMemoryRanges() };
+    if ranges.is_null() {
+        return 0;
+    }
+    let mut top = 0;
+    let mut range = ranges;
+    unsafe {
+        while (*range).NumberOfBytes.QuadPart != 0 {
+            let end = (*range).BaseAddress.QuadPart + (*range).NumberOfBytes.QuadPart;
+            top = core::cmp::max(top, end as u64);
+            range = range.add(1);
+        }
+        ExFreePool(ranges as _);
+    }
+    top
+}
+
+fn alloc_pages(count: usize) -> Option<&'static mut [npt_page]> {
+    let layout = Layout::from_size_align(count * PAGE as usize, PAGE as usize).ok()?;
+    let pages = unsafe { alloc_zeroed(layout) } as *mut npt_page;
+    if pages.is_null() {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts_mut(pages, count) })
+}
+
+// passive level, before any cpu is virtualized. the tables are never freed
+pub fn init(svm_features: &crate::utils::svm_features) -> bool {
+    if !svm_features.has(SVM_FEATURE_NP) {
+        log_info!("nested paging is not supported");
+        return false;
+    }
+    let gb_pages = has_1gb_pages();
+    let ram_top = ram_top().next_multiple_of(GB);
+    let coverage = if gb_pages {
+        core::cmp::max(ram_top, MIN_COVERAGE).next_multiple_of(512 * GB)
+    } else {
+        core::cmp::max(ram_top, MIN_COVERAGE_2MB)
+    };
+    let gigabytes = (coverage / GB) as usize;
+    let pdpts = gigabytes.div_ceil(512);
+    let directories = if gb_pages { 0 } else { gigabytes };
+    let pool_start = VIEWS * (1 + pdpts + directories);
+
+    let Some(pages) = alloc_pages(pool_start + POOL_PAGES) else {
+        log_error!("failed to allocate nested page tables");
+        return false;
+    };
+    let pas: Vec<u64> = pages.iter().map(|page| pa(page as *const _ as _)).collect();
+    let mut npt = npt {
+        pages,
+        gb_pages,
+        top: 1 << physical_address_bits(),
+        pas: Box::leak(pas.into_boxed_slice()),
+        pool_start,
+        pool_used: [0; POOL_PAGES / 64],
+        hooked: [None; MAX_HOOKED_PAGES],
+    };
+
+    let mut next = VIEWS;
+    for view in [npt_view::Identity, npt_view::Normal, npt_view::Hook] {
+        npt.build(view, coverage, gb_pages, &mut next);
+        ROOTS[view as usize].store(npt.pas[view as usize], Ordering::Relaxed);
+    }
+    log_info!(
+        "nested paging: {} gb mapped with {} pages",
+        gigabytes,
+        if gb_pages { "1gb" } else { "2mb" }
+    );
+
+    let npt = Box::leak(Box::new(spin_mutex::new(npt)));
+    NPT.store(npt, Ordering::Release);
+    true
+}
+
+impl npt {
+    fn build(&mut self, view: npt_view, coverage: u64, gb_pages: bool, next: &mut usize) {
+        let leaf = leaf_flags(view);
+        let root = view as usize;
+        for gpa in (0..coverage).step_by(GB as usize) {
+            let pml4e = (gpa >> 39) as usize & 511;
+            if self.pages[root].entries[pml4e] == 0 {
+                self.pages[root].entries[pml4e] = self.pas[*next] | NPT_TABLE;
+                *next += 1;
+            }
+            let pdpt = self.index_of(self.pages[root].entries[pml4e]);
+            let pdpte = (gpa >> 30) as usize & 511;
+            if gb_pages {
+                self.pages[pdpt].entries[pdpte] = gpa | leaf | NPT_LARGE;
+                continue;
+            }
+            let pd = *next;
+            *next += 1;
+            self.pages[pdpt].entries[pdpte] = self.pas[pd] | NPT_TABLE;
+            for (i, entry) in self.pages[pd].entries.iter_mut().enumerate() {
+                *entry = (gpa + ((i as u64) << 21)) | leaf | NPT_LARGE;
+            }
+        }
+    }
+
+    // page holding the table an entry points to
+    fn index_of(&self, entry: u64) -> usize {
+        let pa = entry & NPT_ADDR_MASK;
+        self.pas.iter().position(|&page| page == pa).unwrap()
+    }
+
+    fn alloc(&mut self) -> Option<usize> {
+        for (word, used) in self.pool_used.iter_mut().enumerate() {
+            if *used != u64::MAX {
+                let bit = used.trailing_ones() as usize;
+                *used |= 1 << bit;
+                let index = self.pool_start + word * 64 + bit;
+                self.pages[index].entries.fill(0);
+                return Some(index);
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, index: usize) {
+        let slot = index - self.pool_start;
+        self.pool_used[slot / 64] &= !(1 << (slot % 64));
+    }
+
+    // the 4k entry mapping gpa in view, large pages on the way are split
+    fn pte(&mut self, view: npt_view, gpa: u64) -> Result<&mut u64, npt_error> {
+        let mut table = view as usize;
+        for shift in [39, 30, 21] {
+            let index = (gpa >> shift) as usize & 511;
+            let entry = self.pages[table].entries[index];
+            if entry & NPT_PRESENT == 0 {
+                return Err(npt_error::NotMapped);
+            }
+            if entry & NPT_LARGE != 0 {
+                let child = self.alloc().ok_or(npt_error::OutOfPages)?;
+                let size = 1u64 << (shift - 9);
+                let base = entry & NPT_ADDR_MASK & !((1u64 << shift) - 1);
+                let flags = entry & NPT_LEAF_FLAGS | if size > PAGE { NPT_LARGE } else { 0 };
+                for (i, child_entry) in self.pages[child].entries.iter_mut().enumerate() {
+                    *child_entry = (base + i as u64 * size) | flags;
+                }
+                self.pages[table].entries[index] = self.pas[child] | NPT_TABLE;
+                table = child;
+            } else {
+                table = self.index_of(entry);
+            }
+        }
+        Ok(&mut self.pages[table].entries[(gpa >> 12) as usize & 511])
+    }
+
+    // identity maps the large page holding gpa in view if nothing does yet
+    fn map_view(&mut self, view: npt_view, gpa: u64) -> Result<(), npt_error> {
+        let leaf_shift = if self.gb_pages { 30 } else { 21 };
+        let mut table = view as usize;
+        for shift in (leaf_shift + 9..=39).rev().step_by(9) {
+            let index = (gpa >> shift) as usize & 511;
+            let entry = self.pages[table].entries[index];
+            table = match entry {
+                _ if entry & NPT_PRESENT == 0 => {
+                    let child = self.alloc().ok_or(npt_error::OutOfPages)?;
+                    self.pages[table].entries[index] = self.pas[child] | NPT_TABLE;
+                    child
+                }
+                _ if entry & NPT_LARGE != 0 => return Ok(()),
+                _ => self.index_of(entry),
+            };
+        }
+        let entry = &mut self.pages[table].entries[(gpa >> leaf_shift) as usize & 511];
+        if *entry & NPT_PRESENT == 0 {
+            *entry = gpa & !((1 << leaf_shift) - 1) | leaf_flags(view) | NPT_LARGE;
+        }
+        Ok(())
+    }
+
+    fn map(&mut self, gpa: u64) -> Result<(), npt_error> {
+        if gpa >= self.top {
+            return Err(npt_error::NotMapped);
+        }
+        for view in [npt_view::Identity, npt_view::Normal, npt_view::Hook] {
+            self.map_view(view, gpa)?;
+        }
+        Ok(())
+    }
+
+    fn find(&self, gpa: u64) -> Option<usize> {
+        let gpa = gpa & !(PAGE - 1);
+        self.hooked
+            .iter()
+            .position(|hook| hook.is_some_and(|hook| hook.gpa == gpa))
+    }
+
+    fn hook_page<M: phys_mem + ?Sized>(&mut self, gpa: u64, mem: &M) -> Result<(), npt_error> {
+        let gpa = gpa & !(PAGE - 1);
+        if let Some(index) = self.find(gpa) {
+            self.hooked[index].as_mut().unwrap().refs += 1;
+            return Ok(());
+        }
+        let slot = self
+            .hooked
+            .iter()
+            .position(|hook| hook.is_none())
+            .ok_or(npt_error::TooManyHooks)?;
+        let shadow = self.alloc().ok_or(npt_error::OutOfPages)?;
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(
+                self.pages[shadow].entries.as_mut_ptr() as *mut u8,
+                PAGE as usize,
+            )
+        };
+        if mem.read(gpa, bytes).is_none() {
+            self.free(shadow);
+            return Err(npt_error::PhysRead);
+        }
+
+        // split both views before changing either so a failure leaves no trace
+        let shadow_pa = self.pas[shadow];
+        let split = self.pte(npt_view::Normal, gpa).map(|_| ());
+        if let Err(error) = split.and_then(|()| self.pte(npt_view::Hook, gpa).map(|_| ())) {
+            self.free(shadow);
+            return Err(error);
+        }
+        *self.pte(npt_view::Normal, gpa)? = gpa | NPT_TABLE | NPT_NX;
+        *self.pte(npt_view::Hook, gpa)? = shadow_pa | NPT_PRESENT | NPT_USER;
+        self.hooked[slot] = Some(hooked_page {
+            gpa,
+            shadow,
+            refs: 1,
+        });
+        Ok(())
+    }
+
+    // split tables stay, they are reused when the region is hooked again
+    fn unhook_page(&mut self, gpa: u64) {
+        let Some(index) = self.find(gpa) else {
+            return;
+        };
+        let hook = self.hooked[index].as_mut().unwrap();
+        hook.refs -= 1;
+        if hook.refs != 0 {
+            return;
+        }
+        let hook = self.hooked[index].take().unwrap();
+        if let Ok(pte) = self.pte(npt_view::Normal, hook.gpa) {
+            *pte = hook.gpa | NPT_TABLE;
+        }
+        if let Ok(pte) = self.pte(npt_view::Hook, hook.gpa) {
+            *pte = hook.gpa | NPT_TABLE | NPT_NX;
+        }
+        self.free(hook.shadow);
+    }
+
+    fn shadow_bytes(&mut self, gpa: u64) -> Option<&mut [u8]> {
+        let shadow = self.hooked[self.find(gpa)?]?.shadow;
+        let page = self.pages[shadow].entries.as_mut_ptr() as *mut u8;
+        Some(unsafe { core::slice::from_raw_parts_mut(page, PAGE as usize) })
+    }
+}
+
+// shadows the page holding gpa, hooks of the same page are counted. the
+// change is seen by other cpus once their tlb is flushed
+pub fn hook_page<M: phys_mem + ?Sized>(gpa: u64, mem: &M) -> Result<(), npt_error> {
+    get().ok_or(npt_error::Disabled)?.lock().hook_page(gpa, mem)
+}
+
+pub fn unhook_page(gpa: u64) {
+    if let Some(npt) = get() {
+        npt.lock().unhook_page(gpa);
+    }
+}
+
+// gpa faulted as not present, it's past what init mapped
+pub fn map(gpa: u64) -> Result<(), npt_error> {
+    get().ok_or(npt_error::Disabled)?.lock().map(gpa)
+}
+
+pub fn is_hooked(gpa: u64) -> bool {
+    get().is_some_and(|npt| npt.lock().find(gpa).is_some())
+}
+
+// replaces a byte of the shadow copy, returns the previous one
+pub fn write_shadow(gpa: u64, value: u8) -> Option<u8> {
+    let mut npt = get()?.lock();
+    let byte = &mut npt.shadow_bytes(gpa)?[(gpa & (PAGE - 1)) as usize];
+    Some(core::mem::replace(byte, value))
+}
+
+pub fn set_view(vcpu_ctx: &mut vcpu, view: npt_view) {
+    vcpu_ctx.npt_view = view;
+    *vcpu_ctx.guest_vmcb.n_cr3_mut() = ROOTS[view as usize].load(Ordering::Relaxed);
+    // translations are tagged by asid, not by root
+    retire_asid(vcpu_ctx);
+}
+
+pub fn setup_npt(vcpu_ctx: &mut vcpu) {
+    if enabled() {
+        *vcpu_ctx.guest_vmcb.np_enable_mut() = 1;
+        set_view(vcpu_ctx, npt_view::Normal);
+    }
+}
+
+// hooks changed somewhere
+pub fn sync(vcpu_ctx: &mut vcpu) {
+    if vcpu_ctx.guest_vmcb.control_area.np_enable != 0 {
+        request_tlb_flush(vcpu_ctx, tlb_flush::Asid);
+    }
+}
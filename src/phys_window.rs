@@ -0,0 +1,95 @@
+// a reserved single page of kernel address space whose pte is rewritten to map
+// arbitrary physical pages. every vcpu owns one and only uses it from the exit
+// handler, so there is no locking, no pool allocation and no irql dependency
+use crate::guest_mem::{CR4_LA57, phys_mem};
+use crate::utils::readcr3;
+use core::arch::asm;
+use wdk_sys::{PAGE_SIZE, PHYSICAL_ADDRESS, ntddk::*};
+use x86_64::registers::control::Cr4;
+
+const WINDOW_TAG: u32 = u32::from_le_bytes(*b"hvpw");
+
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITE: u64 = 1 << 1;
+const PTE_ACCESSED: u64 = 1 << 5;
+const PTE_DIRTY: u64 = 1 << 6;
+const PTE_LARGE: u64 = 1 << 7;
+const PTE_NX: u64 = 1 << 63;
+const PTE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+pub struct phys_window {
+    va: *mut u8,
+    pte: *mut u64,
+}
+
+impl phys_window {
+    // must be called at passive level, before the cpu is virtualized
+    pub fn new() -> Option<Self> {
+        let va = unsafe { MmAllocateMappingAddress(PAGE_SIZE as _, WINDOW_TAG) } as *mut u8;
+        if va.is_null() {
+            return None;
+        }
+        let Some(pte) = pte_address(va as u64) else {
+            unsafe { MmFreeMappingAddress(va as _, WINDOW_TAG) };
+            return None;
+        };
+        Some(Self { va, pte })
+    }
+
+    // maps the page containing pa and returns a pointer to pa inside the window.
+    // the pointer is only valid until the next call
+    pub fn map(&self, pa: u64) -> *mut u8 {
+        unsafe {
+            self.pte.write_volatile(
+                (pa & PTE_ADDR_MASK) | PTE_PRESENT | PTE_WRITE | PTE_ACCESSED | PTE_DIRTY | PTE_NX,
+            );
+            asm!("invlpg [{}]", in(reg) self.va, options(nostack));
+            self.va.add((pa & (PAGE_SIZE as u64 - 1)) as usize)
+        }
+    }
+}
+
+impl phys_mem for phys_window {
+    fn read(&self, pa: u64, buf: &mut [u8]) -> Option<()> {
+        let src = self.map(pa);
+        unsafe { core::ptr::copy_nonoverlapping(src, buf.as_mut_ptr(), buf.len()) };
+        Some(())
+    }
+
+    fn write(&self, pa: u64, data: &[u8]) -> Option<()> {
+        let dst = self.map(pa);
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), dst, data.len()) };
+        Some(())
+    }
+}
+
+// walk the current page tables to find the pte that maps va, page tables are
+// reachable through MmGetVirtualForPhysical in normal context
+fn pte_address(va: u64) -> Option<*mut u64> {
+    let levels = if Cr4::read_raw() & CR4_LA57 != 0 {
+        5
+    } else {
+        4
+    };
+    let mut table = readcr3() & PTE_ADDR_MASK;
+    for level in (1..=levels).rev() {
+        let index = (va >> (12 + 9 * (level - 1))) & 0x1ff;
+        let entry_va = unsafe {
+            MmGetVirtualForPhysical(PHYSICAL_ADDRESS {
+                QuadPart: (table + index * 8) as i64,
+            })
+        } as *mut u64;
+        if entry_va.is_null() {
+            return None;
+        }
+        if level == 1 {
+            return Some(entry_va);
+        }
+        let entry = unsafe { entry_va.read_volatile() };
+        if entry & PTE_PRESENT == 0 || entry & PTE_LARGE != 0 {
+            return None;
+        }
+        table = entry & PTE_ADDR_MASK;
+    }
+    None
+}
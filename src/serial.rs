@@ -0,0 +1,23 @@
+// polling 16550 uart, usable from the exit handler since it only needs port i/o
+use core::arch::asm;
+pub use hv_core::serial::*;
+
+pub struct port_io {
+    pub base: u16,
+}
+
+impl uart_io for port_io {
+    fn read(&self, reg: u16) -> u8 {
+        let value: u8;
+        unsafe {
+            asm!("in al, dx", out("al") value, in("dx") self.base + reg, options(nomem, nostack))
+        };
+        value
+    }
+
+    fn write(&self, reg: u16, value: u8) {
+        unsafe {
+            asm!("out dx, al", in("dx") self.base + reg, in("al") value, options(nomem, nostack))
+        };
+    }
+}
@@ -0,0 +1,291 @@
+// steps one vcpu through a number of instructions with rflags.tf, recording
+// rip and the registers each instruction changed. the guest keeps seeing its
+// own tf: pushf is emulated while stepping and tf the guest clears itself
+// (popf, iret) is put back after noting it
+use crate::breakpoint::RFLAGS_TF;
+use crate::event::*;
+use crate::guest_mem::*;
+use crate::handler::dr::{DR6_BS, RFLAGS_RF};
+use crate::handler::exception::*;
+use crate::hv::{MAX_PROCESSORS, bump_state_generation, shared_for, sync_vcpus, vcpu};
+use crate::log_warn;
+use crate::ring::spsc_ring;
+use crate::structs::*;
+use crate::sync::spin_mutex;
+use crate::vmcb::*;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const RFLAGS_VM: u64 = 1 << 17;
+// index of rflags in step_record::values, after the 16 gprs
+pub const STEP_RFLAGS: usize = 16;
+pub const STEP_TRACE_ENTRIES: usize = 128;
+const NO_REQUEST: u64 = u64::MAX;
+
+// one instruction. values holds the new value of every register whose bit is
+// set in changed, the first record of a run has all of them
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct step_record {
+    // rip after the instruction
+    pub rip: u64,
+    pub changed: u32,
+    pub reserved: u32,
+    pub values: [u64; 17],
+}
+unsafe impl pod for step_record {}
+
+pub type step_trace = spsc_ring<step_record, STEP_TRACE_ENTRIES>;
+
+// called in host context when a run started by start_current finishes
+pub type step_callback = fn(&mut vcpu, &mut guest_regs);
+
+pub struct step_state {
+    pub remaining: u64,
+    // whether the guest itself has tf set
+    pub guest_tf: bool,
+    pub recorded: u64,
+    pub previous: [u64; 17],
+    pub notify: Option<step_callback>,
+}
+
+impl step_state {
+    pub const fn new() -> Self {
+        Self {
+            remaining: 0,
+            guest_tf: false,
+            recorded: 0,
+            previous: [0; 17],
+            notify: None,
+        }
+    }
+}
+
+// steps asked for per processor, 0 stops a run
+static REQUESTS: [AtomicU64; MAX_PROCESSORS] =
+    [const { AtomicU64::new(NO_REQUEST) }; MAX_PROCESSORS];
+// #DB is claimed while any vcpu is stepping
+static DB_HANDLER: spin_mutex<(Option<handler_slot>, u32)> = spin_mutex::new((None, 0));
+// one reader at a time for the traces
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+// picked up at the processor's next exit, use start when the run has to
+// begin before returning
+pub fn start_lazy(processor: u32, count: u64) -> bool {
+    let Some(request) = REQUESTS.get(processor as usize) else {
+        return false;
+    };
+    request.store(count, Ordering::Release);
+    bump_state_generation();
+    true
+}
+
+// passive level only, visits every processor
+pub fn start(processor: u32, count: u64) -> bool {
+    let started = start_lazy(processor, count);
+    sync_vcpus();
+    started
+}
+
+pub fn stop(processor: u32) {
+    start(processor, 0);
+}
+
+// steps the vcpu whose exit is being handled, notify runs after the last
+// instruction
+pub fn start_current(vcpu_ctx: &mut vcpu, count: u64, notify: Option<step_callback>) -> bool {
+    if count == 0 || (vcpu_ctx.step.remaining == 0 && !begin(vcpu_ctx)) {
+        return false;
+    }
+    vcpu_ctx.step.remaining = count;
+    vcpu_ctx.step.notify = notify;
+    true
+}
+
+// pops up to max records of a processor's trace, stops early when f returns
+// false. None if someone else is reading
+pub fn try_drain(
+    processor: u32,
+    max: usize,
+    mut f: impl FnMut(&step_record) -> bool,
+) -> Option<usize> {
+    let shared = shared_for(processor)?;
+    if DRAINING.swap(true, Ordering::Acquire) {
+        return None;
+    }
+    let mut count = 0;
+    while count < max {
+        let Some(record) = shared.step_trace.pop() else {
+            break;
+        };
+        count += 1;
+        if !f(&record) {
+            break;
+        }
+    }
+    DRAINING.store(false, Ordering::Release);
+    Some(count)
+}
+
+fn claim_db() -> bool {
+    let mut handler = DB_HANDLER.lock();
+    if handler.0.is_none() {
+        handler.0 = register_handler(EXCEPTION_DB, db_handler);
+    }
+    if handler.0.is_some() {
+        handler.1 += 1;
+    }
+    handler.0.is_some()
+}
+
+fn release_db() {
+    let mut handler = DB_HANDLER.lock();
+    handler.1 -= 1;
+    if handler.1 == 0 {
+        if let Some(slot) = handler.0.take() {
+            unregister_handler(slot);
+        }
+    }
+}
+
+pub fn sync(vcpu_ctx: &mut vcpu) {
+    let request = REQUESTS[vcpu_ctx.processor as usize].swap(NO_REQUEST, Ordering::AcqRel);
+    match request {
+        NO_REQUEST => {}
+        0 if vcpu_ctx.step.remaining != 0 => finish(vcpu_ctx),
+        0 => {}
+        count => {
+            if vcpu_ctx.step.remaining != 0 || begin(vcpu_ctx) {
+                vcpu_ctx.step.remaining = count;
+                vcpu_ctx.step.notify = None;
+            }
+        }
+    }
+}
+
+fn begin(vcpu_ctx: &mut vcpu) -> bool {
+    if !claim_db() {
+        log_warn!("no #DB handler slot left for single stepping");
+        return false;
+    }
+    let rflags = &mut vcpu_ctx.guest_vmcb.state_save_area.rflags;
+    vcpu_ctx.step.guest_tf = *rflags & RFLAGS_TF != 0;
+    vcpu_ctx.step.recorded = 0;
+    *rflags |= RFLAGS_TF;
+    *vcpu_ctx.guest_vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_PUSHF;
+    true
+}
+
+fn finish(vcpu_ctx: &mut vcpu) {
+    vcpu_ctx.step.remaining = 0;
+    vcpu_ctx.step.notify = None;
+    let rflags = &mut vcpu_ctx.guest_vmcb.state_save_area.rflags;
+    *rflags &= !RFLAGS_TF;
+    if vcpu_ctx.step.guest_tf {
+        *rflags |= RFLAGS_TF;
+    }
+    *vcpu_ctx.guest_vmcb.intercept_misc1_mut() &= !SVM_INTERCEPT_MISC1_PUSHF;
+    release_db();
+}
+
+// rflags as the guest expects it
+fn guest_rflags(vcpu_ctx: &vcpu) -> u64 {
+    let rflags = vcpu_ctx.guest_vmcb.state_save_area.rflags & !RFLAGS_TF;
+    match vcpu_ctx.step.guest_tf {
+        true => rflags | RFLAGS_TF,
+        false => rflags,
+    }
+}
+
+fn record(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, rip: u64) {
+    let mut values = [0; 17];
+    for (index, value) in values[..16].iter_mut().enumerate() {
+        *value = guest_regs.gpr(index as u8);
+    }
+    values[STEP_RFLAGS] = guest_rflags(vcpu_ctx);
+
+    let step = &mut vcpu_ctx.step;
+    let mut changed = 0;
+    for (index, value) in values.iter_mut().enumerate() {
+        if step.recorded == 0 || *value != step.previous[index] {
+            changed |= 1 << index;
+            step.previous[index] = *value;
+        } else {
+            *value = 0;
+        }
+    }
+    step.recorded += 1;
+    vcpu_ctx.shared.step_trace.push(step_record {
+        rip,
+        changed,
+        reserved: 0,
+        values,
+    });
+
+    step.remaining -= 1;
+    if step.remaining == 0 {
+        let notify = step.notify;
+        finish(vcpu_ctx);
+        if let Some(notify) = notify {
+            notify(vcpu_ctx, guest_regs);
+        }
+    }
+}
+
+fn db_handler(
+    vcpu_ctx: &mut vcpu,
+    guest_regs: &mut guest_regs,
+    _info: &exception_info,
+) -> exception_action {
+    let state = &mut vcpu_ctx.guest_vmcb.state_save_area;
+    if vcpu_ctx.step.remaining == 0 || state.dr6 & DR6_BS == 0 {
+        return exception_action::Pass;
+    }
+    // popf or iret cleared it, keep stepping and remember for later
+    if state.rflags & RFLAGS_TF == 0 {
+        vcpu_ctx.step.guest_tf = false;
+        state.rflags |= RFLAGS_TF;
+    }
+    let guest_tf = vcpu_ctx.step.guest_tf;
+    if !guest_tf {
+        *vcpu_ctx.guest_vmcb.dr6_mut() &= !DR6_BS;
+    }
+    let rip = vcpu_ctx.guest_vmcb.state_save_area.rip;
+    record(vcpu_ctx, guest_regs, rip);
+
+    match guest_tf {
+        true => exception_action::Reflect,
+        false => exception_action::Consume,
+    }
+}
+
+// only intercepted while stepping, pushes the guest's own tf
+pub fn pushf_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let state = &vcpu_ctx.guest_vmcb.state_save_area;
+    let prefix: u8 = read_guest(vcpu_ctx, state.cs_base.wrapping_add(state.rip)).unwrap_or(0);
+    let long_mode = state.cs_attrib & CS_L != 0;
+    let default_32 = state.cs_attrib & CS_D != 0;
+    let size = match (long_mode, default_32, prefix == 0x66) {
+        (true, _, false) => 8,
+        (false, true, false) | (false, false, true) => 4,
+        _ => 2,
+    };
+    let value = guest_rflags(vcpu_ctx) & !(RFLAGS_RF | RFLAGS_VM);
+    let rsp = guest_regs.rsp.wrapping_sub(size);
+
+    match write_guest(vcpu_ctx, rsp, &value.to_le_bytes()[..size as usize]) {
+        Ok(()) => {}
+        Err(guest_mem_error::Walk(walk_error::PageFault(fault))) => {
+            inject_page_fault(&mut vcpu_ctx.guest_vmcb, &fault);
+            vcpu_ctx.advance_rip = false;
+            return;
+        }
+        Err(_) => return vcpu_ctx.inject_fault(EXCEPTION_SS, Some(0)),
+    }
+    guest_regs.rsp = rsp;
+    // emulated, so no #DB for it. rip moves first so a notify callback sees
+    // the state after the instruction
+    let rip = vcpu_ctx.guest_vmcb.control_area.n_rip;
+    vcpu_ctx.guest_vmcb.state_save_area.rip = rip;
+    vcpu_ctx.advance_rip = false;
+    record(vcpu_ctx, guest_regs, rip);
+}
@@ -0,0 +1,325 @@
+// init and startup ipis, CONFIG.init_intercept. an init the guest gets would
+// reset the cpu under the hypervisor, VM_CR.R_INIT turns it into a #SX which
+// exits and is emulated instead: the guest's state goes back to what an init
+// leaves and the vcpu waits in the exit handler for its startup ipi, serving
+// hypervisor ipis meanwhile. svm has no wait-for-sipi state, a sipi reaching
+// a cpu that isn't in it is dropped, so the sender's icr write is intercepted
+// and the vector handed to the target. only the x2apic icr is an msr, the
+// hypervisor doesn't load with the xapic and init_intercept set. a vcpu
+// still waiting when the hypervisor unloads goes back to waiting natively.
+//
+// the init intercept leaves the init pending, it's dropped for one vmrun so
+// the init reaches the guest as the #SX. every icr write exits while this is
+// on, ipis get noticeably slower. xcr0 and the vector registers are left as
+// they are, the guest sets them up again
+use crate::apic::{self, *};
+use crate::asid::{request_tlb_flush, tlb_flush};
+use crate::config::CONFIG;
+use crate::event::*;
+use crate::handler::exception::*;
+use crate::handler::{cr, dr, msr};
+use crate::hidden;
+use crate::hv::{MAX_PROCESSORS, unloading, vcpu};
+use crate::ipi;
+use crate::msrpm;
+use crate::nmi::nmi_state;
+use crate::structs::*;
+use crate::utils::{rdmsr, wrmsr};
+use crate::vmcb::*;
+use crate::{log_debug, log_error, log_warn};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+// #SX error code of a redirected init
+const SX_INIT: u32 = 1;
+const SIPI_RECEIVED: u32 = 1 << 8;
+
+// what an init leaves, see AMD manual '14.1.3 Processor Initialization State'
+const CR0_RESET: u64 = cr::CR0_CD | cr::CR0_NW | 1 << 4;
+const DR6_RESET: u64 = 0xffff_0ff0;
+const DR7_RESET: u64 = 0x400;
+const RFLAGS_RESET: u64 = 1 << 1;
+const RIP_RESET: u64 = 0xfff0;
+const LIMIT_RESET: u32 = 0xffff;
+// selector, attributes, limit and base. present and accessed code / data /
+// ldt / busy tss
+const CODE_SEGMENT: (u16, u16, u32, u64) = (0xf000, 0x9b, LIMIT_RESET, 0xffff_0000);
+const DATA_SEGMENT: (u16, u16, u32, u64) = (0, 0x93, LIMIT_RESET, 0);
+const LDT_SEGMENT: (u16, u16, u32, u64) = (0, 0x82, LIMIT_RESET, 0);
+const TSS_SEGMENT: (u16, u16, u32, u64) = (0, 0x8b, LIMIT_RESET, 0);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct sipi_state {
+    // an init arrived during this exit
+    pub init: bool,
+    // VM_CR before init got redirected
+    pub vm_cr: Option<u64>,
+}
+
+impl sipi_state {
+    pub const fn new() -> Self {
+        Self {
+            init: false,
+            vm_cr: None,
+        }
+    }
+}
+
+// apic id of every virtualized cpu, u32::MAX for the others
+static APIC_IDS: [AtomicU32; MAX_PROCESSORS] = [const { AtomicU32::new(u32::MAX) }; MAX_PROCESSORS];
+// the last sipi sent to every cpu since its init, SIPI_RECEIVED | vector
+static SIPIS: [AtomicU32; MAX_PROCESSORS] = [const { AtomicU32::new(0) }; MAX_PROCESSORS];
+// slot of the #SX handler, usize::MAX while not intercepting
+static HANDLER: AtomicUsize = AtomicUsize::new(usize::MAX);
+// devirtualized while waiting for a sipi, the cpu sent itself an init
+static PARKED: [AtomicBool; MAX_PROCESSORS] = [const { AtomicBool::new(false) }; MAX_PROCESSORS];
+
+// vector of a startup ipi written to the x2apic icr by the cpu sender, if it
+// reaches the cpu target. logical destinations aren't decoded
+pub fn startup_vector(icr: u64, sender: u32, target: u32) -> Option<u8> {
+    if icr & ICR_DELIVERY != ICR_DELIVERY_STARTUP {
+        return None;
+    }
+    let destination = (icr >> 32) as u32;
+    let reached = match icr & ICR_SHORTHAND {
+        ICR_SHORTHAND_SELF => target == sender,
+        ICR_SHORTHAND_ALL => true,
+        ICR_SHORTHAND_OTHERS => target != sender,
+        _ if icr & ICR_DESTINATION_LOGICAL != 0 => false,
+        _ => destination == target || destination == X2APIC_BROADCAST,
+    };
+    reached.then_some((icr & ICR_VECTOR) as u8)
+}
+
+macro_rules! set_segment {
+    ($state:ident.{$selector:ident, $attrib:ident, $limit:ident, $base:ident} = $value:expr) => {
+        (
+            $state.$selector,
+            $state.$attrib,
+            $state.$limit,
+            $state.$base,
+        ) = $value
+    };
+}
+
+// the state an init leaves, except for control / debug registers and efer
+// which have shadows of their own
+pub fn reset_state(state: &mut state_save) {
+    set_segment!(state.{cs_selector, cs_attrib, cs_limit, cs_base} = CODE_SEGMENT);
+    set_segment!(state.{es_selector, es_attrib, es_limit, es_base} = DATA_SEGMENT);
+    set_segment!(state.{ss_selector, ss_attrib, ss_limit, ss_base} = DATA_SEGMENT);
+    set_segment!(state.{ds_selector, ds_attrib, ds_limit, ds_base} = DATA_SEGMENT);
+    set_segment!(state.{fs_selector, fs_attrib, fs_limit, fs_base} = DATA_SEGMENT);
+    set_segment!(state.{gs_selector, gs_attrib, gs_limit, gs_base} = DATA_SEGMENT);
+    set_segment!(state.{ldtr_selector, ldtr_attrib, ldtr_limit, ldtr_base} = LDT_SEGMENT);
+    set_segment!(state.{tr_selector, tr_attrib, tr_limit, tr_base} = TSS_SEGMENT);
+    (state.gdtr_limit, state.gdtr_base) = (LIMIT_RESET, 0);
+    (state.idtr_limit, state.idtr_base) = (LIMIT_RESET, 0);
+    state.cpl = 0;
+    state.rflags = RFLAGS_RESET;
+    state.rip = RIP_RESET;
+    state.rsp = 0;
+    state.rax = 0;
+    state.cr2 = 0;
+}
+
+// real mode at vector * 0x1000, where a sipi starts the cpu
+pub fn start(state: &mut state_save, vector: u8) {
+    state.cs_selector = (vector as u16) << 8;
+    state.cs_base = (vector as u64) << 12;
+    state.rip = 0;
+}
+
+pub fn intercepting() -> bool {
+    HANDLER.load(Ordering::Acquire) != usize::MAX
+}
+
+fn sx_handler(
+    vcpu_ctx: &mut vcpu,
+    _guest_regs: &mut guest_regs,
+    info: &exception_info,
+) -> exception_action {
+    if info.error_code != Some(SX_INIT) {
+        return exception_action::Pass;
+    }
+    // sipis from before the init don't count
+    SIPIS[vcpu_ctx.processor as usize].store(0, Ordering::Release);
+    vcpu_ctx.sipi.init = true;
+    exception_action::Consume
+}
+
+// passive level, after the msr permission map exists and before any cpu is
+// virtualized. false if init_intercept is set and can't be honoured
+pub fn init() -> bool {
+    if !CONFIG.init_intercept || intercepting() {
+        return true;
+    }
+    if !apic::x2apic() {
+        log_error!("sipis are only seen with the x2apic, init can't be intercepted");
+        return false;
+    }
+    if !msrpm::set_intercept(X2APIC_ICR, false, true) {
+        log_error!("icr writes can't be intercepted, init can't be either");
+        return false;
+    }
+    let Some(slot) = register_handler(EXCEPTION_SX, sx_handler) else {
+        msrpm::set_intercept(X2APIC_ICR, false, false);
+        log_error!("no #SX handler slot left for init");
+        return false;
+    };
+    HANDLER.store(slot.index, Ordering::Release);
+    true
+}
+
+// passive level, after every cpu is devirtualized
+pub fn shutdown() {
+    let index = HANDLER.swap(usize::MAX, Ordering::AcqRel);
+    if index == usize::MAX {
+        return;
+    }
+    unregister_handler(handler_slot {
+        vector: EXCEPTION_SX,
+        index,
+    });
+    msrpm::set_intercept(X2APIC_ICR, false, false);
+}
+
+// on the cpu itself. #SX is intercepted right away, the handler's claim only
+// reaches the vmcb at the first exit
+pub fn setup_sipi(vcpu_ctx: &mut vcpu) {
+    if !intercepting() {
+        return;
+    }
+    APIC_IDS[vcpu_ctx.processor as usize].store(apic::id(), Ordering::Relaxed);
+    let vm_cr = unsafe { rdmsr(SVM_MSR_VM_CR) };
+    unsafe { wrmsr(SVM_MSR_VM_CR, vm_cr | VM_CR_R_INIT) };
+    vcpu_ctx.sipi.vm_cr = Some(vm_cr);
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    *vmcb.intercept_exception_mut() |= 1 << EXCEPTION_SX;
+    *vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_INIT;
+}
+
+// inits are the cpu's own again once it's devirtualized
+pub fn teardown(vcpu_ctx: &mut vcpu) {
+    APIC_IDS[vcpu_ctx.processor as usize].store(u32::MAX, Ordering::Relaxed);
+    if let Some(vm_cr) = vcpu_ctx.sipi.vm_cr.take() {
+        unsafe { wrmsr(SVM_MSR_VM_CR, vm_cr) };
+    }
+}
+
+// an intercepted write of the x2apic icr, it still reaches the apic after
+pub fn icr_write(vcpu_ctx: &vcpu, icr: u64) {
+    if icr & ICR_DELIVERY != ICR_DELIVERY_STARTUP {
+        return;
+    }
+    if icr & (ICR_SHORTHAND | ICR_DESTINATION_LOGICAL) == ICR_DESTINATION_LOGICAL {
+        log_warn!("sipi to logical destination {:#x} isn't relayed", icr >> 32);
+    }
+    let sender = APIC_IDS[vcpu_ctx.processor as usize].load(Ordering::Relaxed);
+    for (target, sipi) in APIC_IDS.iter().zip(&SIPIS) {
+        let target = target.load(Ordering::Relaxed);
+        if target == u32::MAX {
+            continue;
+        }
+        if let Some(vector) = startup_vector(icr, sender, target) {
+            sipi.store(SIPI_RECEIVED | vector as u32, Ordering::Release);
+        }
+    }
+}
+
+// the init stays pending, without the intercept it reaches the guest as #SX
+// at the next vmrun
+pub fn init_handler(vcpu_ctx: &mut vcpu) {
+    vcpu_ctx.advance_rip = false;
+    *vcpu_ctx.guest_vmcb.intercept_misc1_mut() &= !SVM_INTERCEPT_MISC1_INIT;
+}
+
+fn emulate_init(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    *guest_regs = guest_regs::default();
+    guest_regs.rdx = unsafe { __cpuid(1) }.eax as u64;
+    reset_state(hidden::guest_mut(vcpu_ctx));
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    vmcb.mark_dirty(VMCB_CLEAN_SEG | VMCB_CLEAN_DT | VMCB_CLEAN_CR2);
+    *vmcb.cr3_mut() = 0;
+    *vmcb.dr6_mut() = DR6_RESET;
+    // whatever was being delivered is gone
+    vmcb.control_area.event_inj = 0;
+    vmcb.control_area.exit_int_info = 0;
+    vmcb.control_area.interrupt_shadow = 0;
+    if vmcb.control_area.vintr & (V_NMI_PENDING | V_NMI_BLOCKING) != 0 {
+        *vmcb.vintr_mut() &= !(V_NMI_PENDING | V_NMI_BLOCKING);
+    }
+    *vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_INIT;
+    cr::reset(vcpu_ctx, CR0_RESET, 0);
+    dr::reset(vcpu_ctx, DR7_RESET);
+    vcpu_ctx.efer_shadow = 0;
+    msr::sync(vcpu_ctx);
+    vcpu_ctx.nmi = nmi_state::new();
+    vcpu_ctx.advance_rip = false;
+    request_tlb_flush(vcpu_ctx, tlb_flush::Asid);
+}
+
+// None when the hypervisor unloads first
+fn wait_for_sipi(vcpu_ctx: &mut vcpu) -> Option<u8> {
+    let processor = vcpu_ctx.processor as usize;
+    loop {
+        let sipi = SIPIS[processor].swap(0, Ordering::AcqRel);
+        if sipi & SIPI_RECEIVED != 0 {
+            return Some(sipi as u8);
+        }
+        if unloading() {
+            return None;
+        }
+        ipi::serve(vcpu_ctx);
+        spin_loop();
+    }
+}
+
+// the cpu devirtualized while waiting for a sipi, it can't go back to the
+// guest's reset state. an init now that svm is off puts it back into
+// waiting for the sipi natively
+pub fn park(vcpu_ctx: &vcpu) {
+    if !PARKED[vcpu_ctx.processor as usize].load(Ordering::Acquire) {
+        return;
+    }
+    apic::send_init(apic::id());
+    loop {
+        spin_loop();
+    }
+}
+
+pub fn parked(processor: u32) -> bool {
+    PARKED
+        .get(processor as usize)
+        .is_some_and(|parked| parked.load(Ordering::Acquire))
+}
+
+// after the exit's handler, an init taken during the exit resets the guest
+// and the vcpu waits for its sipi. the guest's tsc keeps counting meanwhile
+pub fn startup(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    if !vcpu_ctx.sipi.init {
+        return;
+    }
+    vcpu_ctx.sipi.init = false;
+    emulate_init(vcpu_ctx, guest_regs);
+
+    log_debug!("#cpu {} waits for a sipi", vcpu_ctx.processor);
+    let waiting = unsafe { _rdtsc() };
+    let Some(vector) = wait_for_sipi(vcpu_ctx) else {
+        log_debug!(
+            "#cpu {} unloads while waiting for a sipi",
+            vcpu_ctx.processor
+        );
+        PARKED[vcpu_ctx.processor as usize].store(true, Ordering::Release);
+        vcpu_ctx.unload = true;
+        return;
+    };
+    vcpu_ctx.tsc.visible += unsafe { _rdtsc() }.wrapping_sub(waiting);
+
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    start(&mut vmcb.state_save_area, vector);
+    vmcb.mark_dirty(VMCB_CLEAN_SEG);
+    let address = (vector as u64) << 12;
+    log_debug!("#cpu {} starts at {:#x}", vcpu_ctx.processor, address);
+}
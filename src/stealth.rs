@@ -0,0 +1,91 @@
+// stealth profile, CONFIG.stealth. the guest sees a cpu without svm and
+// without a hypervisor: no hypervisor bit or leaves in cpuid, no svm feature,
+// efer.svme and the svm msrs are hidden and svm instructions raise #UD.
+// compensate_tsc hides the time spent in the exit handler, see tsc.rs
+use crate::config::CONFIG;
+use crate::event::*;
+use crate::hv::vcpu;
+use crate::msrpm;
+use crate::vmcb::*;
+use x86::msr::IA32_EFER;
+
+const CPUID_FEATURES: u32 = 1;
+const CPUID_HYPERVISOR_FIRST: u32 = 0x4000_0000;
+const CPUID_HYPERVISOR_LAST: u32 = 0x4000_00ff;
+const CPUID_EXT_FEATURES: u32 = 0x8000_0001;
+const CPUID_SVM: u32 = 0x8000_000a;
+// leaf 1 ecx
+const CPUID_HYPERVISOR_PRESENT: u32 = 1 << 31;
+// leaf 0x8000_0001 ecx
+const CPUID_EXT_SVM: u32 = 1 << 2;
+
+// msrs that raise #GP on a cpu without svm
+const HIDDEN_MSRS: [u32; 2] = [SVM_MSR_VM_CR, SVM_MSR_VM_HSAVE_PA];
+
+pub fn enabled() -> bool {
+    CONFIG.stealth.is_some()
+}
+
+// what the driver's own hypercalls pass in r10
+pub fn hypercall_key() -> u64 {
+    CONFIG
+        .stealth
+        .as_ref()
+        .map_or(0, |stealth| stealth.hypercall_key)
+}
+
+// the hypercall is answered like vmmcall without svm, with #UD
+pub fn hides_hypercall(cpl: u8, key: u64) -> bool {
+    CONFIG
+        .stealth
+        .as_ref()
+        .is_some_and(|stealth| cpl != 0 || key != stealth.hypercall_key)
+}
+
+// eax, ebx, ecx, edx of cpuid leaf as the guest should see them
+pub fn filter_cpuid(leaf: u32, regs: &mut [u32; 4]) {
+    match leaf {
+        CPUID_FEATURES => regs[2] &= !CPUID_HYPERVISOR_PRESENT,
+        CPUID_EXT_FEATURES => regs[2] &= !CPUID_EXT_SVM,
+        // unsupported leaves read as zero on amd
+        CPUID_SVM | CPUID_HYPERVISOR_FIRST..=CPUID_HYPERVISOR_LAST => *regs = [0; 4],
+        _ => {}
+    }
+}
+
+pub fn hidden_msr(msr: u32) -> bool {
+    enabled() && HIDDEN_MSRS.contains(&msr)
+}
+
+// efer bits the guest can't set
+pub fn efer_reserved() -> u64 {
+    match enabled() {
+        true => EFER_SVME,
+        false => 0,
+    }
+}
+
+pub fn setup_stealth(vcpu_ctx: &mut vcpu) {
+    if !enabled() {
+        return;
+    }
+    let vmcb = &mut vcpu_ctx.guest_vmcb;
+    *vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_CPUID | SVM_INTERCEPT_MISC1_INVLPGA;
+    *vmcb.intercept_misc2_mut() |= SVM_INTERCEPT_MISC2_VMLOAD
+        | SVM_INTERCEPT_MISC2_VMSAVE
+        | SVM_INTERCEPT_MISC2_STGI
+        | SVM_INTERCEPT_MISC2_CLGI
+        | SVM_INTERCEPT_MISC2_SKINIT;
+
+    // svme was set by enable_svm, the guest never wrote it
+    vcpu_ctx.efer_shadow &= !EFER_SVME;
+    msrpm::set_intercept(IA32_EFER, true, true);
+    for msr in HIDDEN_MSRS {
+        msrpm::set_intercept(msr, true, true);
+    }
+}
+
+// svm instructions other than vmmcall, which is the hypercall interface
+pub fn svm_instruction_handler(vcpu_ctx: &mut vcpu) {
+    vcpu_ctx.inject_fault(EXCEPTION_UD, None);
+}
@@ -24,6 +24,54 @@ pub struct guest_regs {
 }
 const_assert_eq!(core::mem::size_of::<guest_regs>(), 0x80 /* 16 * 0x8 */);
 
+impl guest_regs {
+    // registers by their instruction encoding (rax = 0 ... r15 = 15), as found in
+    // modrm and in decode assist exit info. rsp is synced with the vmcb around
+    // every exit
+    pub fn gpr(&self, index: u8) -> u64 {
+        match index & 0xf {
+            0 => self.rax,
+            1 => self.rcx,
+            2 => self.rdx,
+            3 => self.rbx,
+            4 => self.rsp,
+            5 => self.rbp,
+            6 => self.rsi,
+            7 => self.rdi,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            _ => self.r15,
+        }
+    }
+
+    pub fn set_gpr(&mut self, index: u8, value: u64) {
+        let reg = match index & 0xf {
+            0 => &mut self.rax,
+            1 => &mut self.rcx,
+            2 => &mut self.rdx,
+            3 => &mut self.rbx,
+            4 => &mut self.rsp,
+            5 => &mut self.rbp,
+            6 => &mut self.rsi,
+            7 => &mut self.rdi,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            _ => &mut self.r15,
+        };
+        *reg = value;
+    }
+}
+
 // credits to https://github.com/not-matthias/amd_hypervisor
 #[repr(C)]
 pub struct KTRAP_FRAME {
@@ -164,5 +212,5 @@ pub struct KTRAP_FRAME {
 
 pub const KERNEL_STACK_SIZE: usize = 0x6000;
 pub const STACK_CONTENTS_SIZE: usize = KERNEL_STACK_SIZE
-    - (core::mem::size_of::<*mut u64>() * 6)
+    - (core::mem::size_of::<*mut u64>() * 8)
     - core::mem::size_of::<KTRAP_FRAME>();
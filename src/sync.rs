@@ -0,0 +1,74 @@
+// spin lock for host context, and for normal context on a processor that
+// isn't virtualized. a guest holding it can exit at any instruction, nmis
+// included, and an exit handler spinning on it would never let the guest
+// release it, so the guest takes it through a hypercall instead. interrupts
+// are off while it is held so a holder in normal context can't be preempted
+// by something that spins on it
+use crate::hv::in_guest;
+use core::arch::asm;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const RFLAGS_IF: u64 = 1 << 9;
+
+pub struct spin_mutex<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for spin_mutex<T> {}
+
+pub struct spin_guard<'a, T> {
+    mutex: &'a spin_mutex<T>,
+    rflags: u64,
+}
+
+impl<T> spin_mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> spin_guard<'_, T> {
+        debug_assert!(!in_guest(), "spin_mutex taken in guest context");
+        let rflags: u64;
+        unsafe { asm!("pushfq; pop {}; cli", out(reg) rflags) };
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        spin_guard {
+            mutex: self,
+            rflags,
+        }
+    }
+}
+
+impl<T> Deref for spin_guard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for spin_guard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for spin_guard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        if self.rflags & RFLAGS_IF != 0 {
+            unsafe { asm!("sti") };
+        }
+    }
+}
@@ -0,0 +1,195 @@
+// syscall tracing without touching the kernel. efer.sce is cleared in the
+// vmcb so syscall and sysret raise #UD, both are emulated here and every
+// syscall is handed to the registered hooks first. the guest keeps seeing
+// sce set, see handler/msr.rs
+use crate::event::*;
+use crate::guest_mem::*;
+use crate::handler::exception::*;
+use crate::handler::vmmcall::{HV_STATUS_SUCCESS, VMMCALL_SYSCALL_TRACING};
+use crate::hidden;
+use crate::hooks::hook_table;
+use crate::hv::{bump_state_generation, hypercall, in_guest, sync_vcpus, vcpu};
+use crate::log_debug;
+use crate::msrpm;
+use crate::structs::*;
+use crate::sync::spin_mutex;
+use crate::vmcb::*;
+use core::sync::atomic::{AtomicBool, Ordering};
+pub use hv_core::syscall::*;
+use x86::msr::IA32_EFER;
+
+pub const MAX_SYSCALL_HOOKS: usize = 8;
+
+// a syscall as seen at its entry, arguments in the windows x64 order
+#[derive(Clone, Copy, Debug)]
+pub struct syscall_event {
+    pub number: u64,
+    pub args: [u64; 4],
+    // where sysret returns to
+    pub return_address: u64,
+    pub cr3: u64,
+}
+
+// called in host context before the syscall is emulated
+pub type syscall_hook = fn(&mut vcpu, &mut guest_regs, &syscall_event);
+
+static TRACING: AtomicBool = AtomicBool::new(false);
+static HOOKS: hook_table<syscall_hook, MAX_SYSCALL_HOOKS> = hook_table::new();
+// #UD is claimed while tracing
+static UD_HANDLER: spin_mutex<Option<handler_slot>> = spin_mutex::new(None);
+
+pub fn tracing() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+// returns the slot to unregister with, None if all slots are taken
+pub fn register_hook(hook: syscall_hook) -> Option<usize> {
+    HOOKS.register(hook)
+}
+
+pub fn unregister_hook(slot: usize) {
+    HOOKS.unregister(slot);
+}
+
+// other vcpus pick the change up at their next exit, use set_tracing when
+// it has to apply everywhere before returning. efer stays intercepted once
+// tracing was on, a vcpu that hasn't synced yet still needs its shadow
+pub fn set_tracing_lazy(enabled: bool) -> bool {
+    let mut handler = UD_HANDLER.lock();
+    match (enabled, handler.is_some()) {
+        (true, false) => {
+            if !msrpm::set_intercept(IA32_EFER, true, true) {
+                return false;
+            }
+            *handler = register_handler(EXCEPTION_UD, ud_handler);
+            if handler.is_none() {
+                return false;
+            }
+        }
+        (false, true) => unregister_handler(handler.take().unwrap()),
+        _ => {}
+    }
+    TRACING.store(enabled, Ordering::Relaxed);
+    bump_state_generation();
+    true
+}
+
+// passive level only, visits every processor. a virtualized processor makes
+// the change in host context, see sync.rs
+pub fn set_tracing(enabled: bool) -> bool {
+    let done = match in_guest() {
+        true => hypercall(VMMCALL_SYSCALL_TRACING, enabled as u64) == HV_STATUS_SUCCESS,
+        false => set_tracing_lazy(enabled),
+    };
+    sync_vcpus();
+    done
+}
+
+fn load(vcpu_ctx: &vcpu, guest_regs: &guest_regs) -> syscall_state {
+    let state = &vcpu_ctx.guest_vmcb.state_save_area;
+    syscall_state {
+        rip: state.rip,
+        rflags: state.rflags,
+        rcx: guest_regs.rcx,
+        r11: guest_regs.r11,
+        cs: segment_state {
+            selector: state.cs_selector,
+            attrib: state.cs_attrib,
+            limit: state.cs_limit,
+            base: state.cs_base,
+        },
+        ss: segment_state {
+            selector: state.ss_selector,
+            attrib: state.ss_attrib,
+            limit: state.ss_limit,
+            base: state.ss_base,
+        },
+        cpl: state.cpl,
+        efer: state.efer,
+        star: state.star,
+        lstar: state.lstar,
+        cstar: state.cstar,
+        sf_mask: state.sf_mask,
+    }
+}
+
+fn store(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs, emulated: &syscall_state) {
+    let state = &mut vcpu_ctx.guest_vmcb.state_save_area;
+    state.rip = emulated.rip;
+    state.rflags = emulated.rflags;
+    guest_regs.rcx = emulated.rcx;
+    guest_regs.r11 = emulated.r11;
+    state.cs_selector = emulated.cs.selector;
+    state.cs_attrib = emulated.cs.attrib;
+    state.cs_limit = emulated.cs.limit;
+    state.cs_base = emulated.cs.base;
+    state.ss_selector = emulated.ss.selector;
+    state.ss_attrib = emulated.ss.attrib;
+    state.ss_limit = emulated.ss.limit;
+    state.ss_base = emulated.ss.base;
+    state.cpl = emulated.cpl;
+    vcpu_ctx.guest_vmcb.mark_dirty(VMCB_CLEAN_SEG);
+}
+
+fn call_hooks(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let event = syscall_event {
+        number: guest_regs.rax,
+        args: [guest_regs.r10, guest_regs.rdx, guest_regs.r8, guest_regs.r9],
+        return_address: vcpu_ctx.guest_vmcb.state_save_area.rip.wrapping_add(2),
+        cr3: vcpu_ctx.guest_vmcb.state_save_area.cr3,
+    };
+    let mut hooked = false;
+    for hook in HOOKS.iter() {
+        hook(vcpu_ctx, guest_regs, &event);
+        hooked = true;
+    }
+    if !hooked {
+        log_debug!(
+            "syscall {:#x} returning to {:#x}",
+            event.number,
+            event.return_address
+        );
+    }
+}
+
+// only #UD raised because sce is hidden is ours, the rest goes to the guest
+fn ud_handler(
+    vcpu_ctx: &mut vcpu,
+    guest_regs: &mut guest_regs,
+    _info: &exception_info,
+) -> exception_action {
+    if vcpu_ctx.efer_shadow & EFER_SCE == 0 {
+        return exception_action::Pass;
+    }
+    let state = &vcpu_ctx.guest_vmcb.state_save_area;
+    let address = state.cs_base.wrapping_add(state.rip);
+    let Ok(bytes) = read_guest::<[u8; 3]>(vcpu_ctx, address)
+        .or_else(|_| read_guest::<[u8; 2]>(vcpu_ctx, address).map(|b| [b[0], b[1], 0]))
+    else {
+        return exception_action::Pass;
+    };
+    let Some(instruction) = decode(&bytes) else {
+        return exception_action::Pass;
+    };
+
+    if instruction == syscall_instruction::Syscall {
+        call_hooks(vcpu_ctx, guest_regs);
+    }
+    hidden::save_guest(vcpu_ctx);
+    let mut emulated = load(vcpu_ctx, guest_regs);
+    let result = match instruction {
+        syscall_instruction::Syscall => emulate_syscall(&mut emulated),
+        syscall_instruction::Sysret { quad } => emulate_sysret(&mut emulated, quad),
+    };
+    match result {
+        Ok(()) => {
+            store(vcpu_ctx, guest_regs, &emulated);
+            exception_action::Consume
+        }
+        Err(syscall_fault::Gp) => exception_action::Inject(exception_info {
+            vector: EXCEPTION_GP,
+            error_code: Some(0),
+            address: 0,
+        }),
+    }
+}
@@ -0,0 +1,101 @@
+// time stamp counter compensation, CONFIG.tsc. the cycles a vcpu spends in
+// the exit handler are hidden from the guest through tsc_offset and, with
+// the intercept on, through emulated rdtsc / rdtscp results.
+//
+// hiding every vcpu's own exits exactly (tsc_policy::PerCpu) lets offsets
+// drift apart, a thread moving to a vcpu that hid more sees its tsc go back.
+// tsc_policy::Shared applies one compensation to every vcpu at each vmrun,
+// it only hides the time no vcpu ran the guest. emulated reads are
+// additionally kept above everything handed out on any vcpu
+use crate::config::{CONFIG, tsc_policy};
+use crate::hv::vcpu;
+use crate::structs::*;
+use crate::sync::spin_mutex;
+use crate::utils::rdmsr;
+use crate::vmcb::*;
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+pub use hv_core::tsc::*;
+use x86::msr::IA32_TSC_AUX;
+
+static SHARED: spin_mutex<shared_clock> = spin_mutex::new(shared_clock::new());
+// highest emulated result on any vcpu
+static WATERMARK: AtomicU64 = AtomicU64::new(0);
+
+pub fn compensating() -> bool {
+    CONFIG.tsc.compensate || CONFIG.stealth.as_ref().is_some_and(|s| s.compensate_tsc)
+}
+
+fn shared() -> bool {
+    compensating() && CONFIG.tsc.policy == tsc_policy::Shared
+}
+
+fn apply(vcpu_ctx: &mut vcpu) {
+    let offset = vcpu_ctx.tsc.offset();
+    if vcpu_ctx.guest_vmcb.control_area.tsc_offset != offset {
+        *vcpu_ctx.guest_vmcb.tsc_offset_mut() = offset;
+    }
+}
+
+pub fn setup_tsc(vcpu_ctx: &mut vcpu) {
+    if shared() {
+        vcpu_ctx.tsc.applied = SHARED.lock().join();
+        apply(vcpu_ctx);
+    }
+    if CONFIG.tsc.intercept {
+        let vmcb = &mut vcpu_ctx.guest_vmcb;
+        *vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_RDTSC;
+        *vmcb.intercept_misc2_mut() |= SVM_INTERCEPT_MISC2_RDTSCP;
+    }
+}
+
+// called first in the exit handler, exit_start is the tsc at its entry. a
+// vcpu that devirtualizes never resumes and stops holding compensation back
+pub fn exit(exit_start: u64) {
+    if shared() {
+        SHARED
+            .lock()
+            .exit(exit_start.saturating_sub(CONFIG.tsc.exit_overhead));
+    }
+}
+
+// called last in the exit handler, exit_start is the tsc at its entry
+pub fn compensate(vcpu_ctx: &mut vcpu, exit_start: u64) {
+    let visible = core::mem::take(&mut vcpu_ctx.tsc.visible);
+    if !compensating() {
+        return;
+    }
+    match CONFIG.tsc.policy {
+        tsc_policy::PerCpu => {
+            let spent = unsafe { _rdtsc() }.wrapping_sub(exit_start);
+            vcpu_ctx
+                .tsc
+                .hide(spent.saturating_sub(visible) + CONFIG.tsc.exit_overhead);
+        }
+        tsc_policy::Shared => vcpu_ctx.tsc.applied = SHARED.lock().resume(unsafe { _rdtsc() }),
+    }
+    apply(vcpu_ctx);
+}
+
+fn emulated_tsc(vcpu_ctx: &mut vcpu) -> u64 {
+    let value = vcpu_ctx.tsc.read(unsafe { _rdtsc() });
+    match CONFIG.tsc.policy {
+        tsc_policy::PerCpu => value,
+        tsc_policy::Shared => {
+            let highest = WATERMARK.fetch_max(value, Ordering::Relaxed).max(value);
+            vcpu_ctx.tsc.last = highest;
+            highest
+        }
+    }
+}
+
+pub fn rdtsc_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let value = emulated_tsc(vcpu_ctx);
+    guest_regs.rax = value & 0xffff_ffff;
+    guest_regs.rdx = value >> 32;
+}
+
+pub fn rdtscp_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    rdtsc_handler(vcpu_ctx, guest_regs);
+    guest_regs.rcx = unsafe { rdmsr(IA32_TSC_AUX) } & 0xffff_ffff;
+}
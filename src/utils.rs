@@ -1,5 +1,7 @@
 use crate::vmcb::EFER_SVME;
-use core::arch::asm;
+use crate::{log_error, log_info};
+use core::arch::x86_64::__cpuid;
+use core::arch::{asm, naked_asm};
 use core::ffi::c_void;
 use wdk::*;
 use wdk_sys::ntddk::*;
@@ -16,7 +18,7 @@ pub fn is_svm_supported() -> bool {
         return false;
     };
     if !result.has_svm() {
-        println!("Processor does not support SVM");
+        log_error!("Processor does not support SVM");
         return false;
     }
     // Check `VM_CR.SVMDIS == 0`
@@ -38,17 +40,40 @@ pub fn is_svm_supported() -> bool {
         .map(|svm_info| svm_info.has_svm_lock())
         .unwrap_or_default()
     {
-        println!("the user must change a platform firmware setting to enable SVM");
+        log_error!("the user must change a platform firmware setting to enable SVM");
     } else {
-        println!("SVMLock may be unlockable; consult platform firmware or TPM to obtain the key.");
+        log_error!(
+            "SVMLock may be unlockable; consult platform firmware or TPM to obtain the key."
+        );
     }
 
     false
 }
 
+// CPUID Fn8000_000A, read once per vcpu so hot exit paths don't execute cpuid
+#[derive(Clone, Copy, Debug, Default)]
+pub struct svm_features {
+    pub asid_count: u32,
+    pub edx: u32,
+}
+
+impl svm_features {
+    pub fn read() -> Self {
+        let result = unsafe { __cpuid(0x8000_000a) };
+        Self {
+            asid_count: result.ebx,
+            edx: result.edx,
+        }
+    }
+
+    pub fn has(&self, feature: u32) -> bool {
+        self.edx & feature == feature
+    }
+}
+
 pub fn enable_svm() {
     unsafe { wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_SVME) }
-    println!("enabled svm!");
+    log_info!("enabled svm!");
 }
 
 pub fn pa(va: *const core::ffi::c_void) -> u64 {
@@ -75,6 +100,90 @@ pub unsafe fn wrmsr(msr: u32, value: u64){
     Msr::new(msr).write(value);
 }
 
+#[repr(C, packed)]
+struct descriptor_table {
+    limit: u16,
+    base: u64,
+}
+
+// #GP while fault_idt is loaded: drops the error code, steps over the two
+// byte rdmsr / wrmsr and reports the fault in r8
+#[unsafe(naked)]
+unsafe extern "C" fn msr_fault() {
+    naked_asm!(
+        "add rsp, 8",
+        "add qword ptr [rsp], 2",
+        "mov r8d, 1",
+        "iretq"
+    );
+}
+
+// an idt up to #GP, which is the only gate
+fn fault_idt() -> [u64; 28] {
+    let handler = msr_fault as *const () as u64;
+    let cs: u16;
+    unsafe { asm!("mov {:x}, cs", out(reg) cs, options(nomem, nostack)) };
+    let mut idt = [0u64; 28];
+    // present 64-bit interrupt gate
+    idt[26] = handler & 0xffff | (cs as u64) << 16 | 0x8e00 << 32 | (handler >> 16 & 0xffff) << 48;
+    idt[27] = handler >> 32;
+    idt
+}
+
+// rdmsr / wrmsr of an msr the cpu may not have, None where it raises #GP.
+// the fault is taken through an idt of its own, so only with interrupts and
+// nmis held, e.g. in the exit handler with gif clear
+pub fn rdmsr_checked(msr: u32) -> Option<u64> {
+    let idt = fault_idt();
+    let table = descriptor_table {
+        limit: size_of_val(&idt) as u16 - 1,
+        base: idt.as_ptr() as u64,
+    };
+    let mut saved = descriptor_table { limit: 0, base: 0 };
+    let (low, high): (u32, u32);
+    let faulted: u64;
+    unsafe {
+        asm!(
+            "sidt [{saved}]",
+            "lidt [{table}]",
+            "rdmsr",
+            "lidt [{saved}]",
+            saved = in(reg) &mut saved,
+            table = in(reg) &table,
+            in("ecx") msr,
+            inout("r8") 0u64 => faulted,
+            out("eax") low,
+            out("edx") high,
+        )
+    };
+    (faulted == 0).then_some((high as u64) << 32 | low as u64)
+}
+
+pub fn wrmsr_checked(msr: u32, value: u64) -> Option<()> {
+    let idt = fault_idt();
+    let table = descriptor_table {
+        limit: size_of_val(&idt) as u16 - 1,
+        base: idt.as_ptr() as u64,
+    };
+    let mut saved = descriptor_table { limit: 0, base: 0 };
+    let faulted: u64;
+    unsafe {
+        asm!(
+            "sidt [{saved}]",
+            "lidt [{table}]",
+            "wrmsr",
+            "lidt [{saved}]",
+            saved = in(reg) &mut saved,
+            table = in(reg) &table,
+            in("ecx") msr,
+            in("eax") value as u32,
+            in("edx") (value >> 32) as u32,
+            inout("r8") 0u64 => faulted,
+        )
+    };
+    (faulted == 0).then_some(())
+}
+
 //there was a bug in KeRevertToUserGroupAffinityThread
 pub fn processor_count() -> u32 {
     unsafe { KeQueryActiveProcessorCount(core::ptr::null_mut()) }
@@ -87,7 +196,7 @@ pub struct ProcessorExecutor {
 impl ProcessorExecutor {
     pub fn switch_to_processor(i: u32) -> Option<Self> {
         if i > processor_count() {
-            println!("Invalid processor index: {}", i);
+            log_error!("Invalid processor index: {}", i);
             return None;
         }
         let old_affinity = unsafe { KeSetSystemAffinityThreadEx(1u64 << i) };
@@ -97,7 +206,7 @@ impl ProcessorExecutor {
 
 impl Drop for ProcessorExecutor {
     fn drop(&mut self) {
-        println!("Switching execution back to previous processor");
+        log_info!("Switching execution back to previous processor");
         unsafe {
             KeRevertToUserAffinityThreadEx(self.old_affinity);
         }
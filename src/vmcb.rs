@@ -1,13 +1,128 @@
+use core::mem::offset_of;
 use static_assertions::const_assert_eq;
 
 pub const SVM_INTERCEPT_MISC2_VMRUN: u32 = 1 << 0;
 pub const SVM_INTERCEPT_MISC2_VMMCALL: u32 = 1 << 1;
+pub const SVM_INTERCEPT_MISC2_VMLOAD: u32 = 1 << 2;
+pub const SVM_INTERCEPT_MISC2_VMSAVE: u32 = 1 << 3;
+pub const SVM_INTERCEPT_MISC2_STGI: u32 = 1 << 4;
+pub const SVM_INTERCEPT_MISC2_CLGI: u32 = 1 << 5;
+pub const SVM_INTERCEPT_MISC2_SKINIT: u32 = 1 << 6;
+pub const SVM_INTERCEPT_MISC2_RDTSCP: u32 = 1 << 7;
+pub const SVM_INTERCEPT_MISC2_MONITOR: u32 = 1 << 10;
+pub const SVM_INTERCEPT_MISC2_MWAIT: u32 = 1 << 11;
+pub const SVM_INTERCEPT_MISC2_MWAIT_CONDITIONAL: u32 = 1 << 12;
+pub const SVM_INTERCEPT_MISC2_XSETBV: u32 = 1 << 13;
+pub const SVM_INTERCEPT_MISC1_INTR: u32 = 1 << 0;
+pub const SVM_INTERCEPT_MISC1_NMI: u32 = 1 << 1;
+pub const SVM_INTERCEPT_MISC1_INIT: u32 = 1 << 3;
+pub const SVM_INTERCEPT_MISC1_VINTR: u32 = 1 << 4;
+pub const SVM_INTERCEPT_MISC1_RDTSC: u32 = 1 << 14;
+pub const SVM_INTERCEPT_MISC1_PUSHF: u32 = 1 << 16;
 pub const SVM_INTERCEPT_MISC1_CPUID: u32 = 1 << 18;
+pub const SVM_INTERCEPT_MISC1_IRET: u32 = 1 << 20;
+pub const SVM_INTERCEPT_MISC1_PAUSE: u32 = 1 << 23;
+pub const SVM_INTERCEPT_MISC1_HLT: u32 = 1 << 24;
+pub const SVM_INTERCEPT_MISC1_INVLPGA: u32 = 1 << 26;
+pub const SVM_INTERCEPT_MISC1_MSR_PROT: u32 = 1 << 28;
+pub const SVM_MSR_VM_CR: u32 = 0xc001_0114;
+pub const VM_CR_R_INIT: u64 = 1 << 1;
 pub const SVM_MSR_VM_HSAVE_PA: u32 = 0xc001_0117;
+pub const EFER_SCE: u64 = 1 << 0;
+pub const EFER_LME: u64 = 1 << 8;
+pub const EFER_LMA: u64 = 1 << 10;
+pub const EFER_NXE: u64 = 1 << 11;
 pub const EFER_SVME: u64 = 1 << 12;
+pub const EFER_LMSLE: u64 = 1 << 13;
+pub const EFER_FFXSR: u64 = 1 << 14;
+pub const EFER_TCE: u64 = 1 << 15;
+// cs attribute bits in the vmcb's compressed format
+pub const CS_L: u16 = 1 << 9;
+pub const CS_D: u16 = 1 << 10;
 pub const VMEXIT_VMMCALL: u64 = 0x81;
+pub const VMEXIT_INTR: u64 = 0x0060;
+pub const VMEXIT_NMI: u64 = 0x0061;
+pub const VMEXIT_INIT: u64 = 0x0063;
+pub const VMEXIT_VINTR: u64 = 0x0064;
+pub const VMEXIT_RDTSC: u64 = 0x006e;
+pub const VMEXIT_PUSHF: u64 = 0x0070;
 pub const VMEXIT_CPUID: u64 = 0x0072;
+pub const VMEXIT_IRET: u64 = 0x0074;
+pub const VMEXIT_PAUSE: u64 = 0x0077;
+pub const VMEXIT_HLT: u64 = 0x0078;
+pub const VMEXIT_INVLPGA: u64 = 0x007a;
+pub const VMEXIT_MSR: u64 = 0x007c;
 pub const VMEXIT_VMRUN: u64 = 0x0080;
+pub const VMEXIT_VMLOAD: u64 = 0x0082;
+pub const VMEXIT_VMSAVE: u64 = 0x0083;
+pub const VMEXIT_STGI: u64 = 0x0084;
+pub const VMEXIT_CLGI: u64 = 0x0085;
+pub const VMEXIT_SKINIT: u64 = 0x0086;
+pub const VMEXIT_RDTSCP: u64 = 0x0087;
+pub const VMEXIT_MONITOR: u64 = 0x008a;
+pub const VMEXIT_MWAIT: u64 = 0x008b;
+pub const VMEXIT_MWAIT_CONDITIONAL: u64 = 0x008c;
+pub const VMEXIT_XSETBV: u64 = 0x008d;
+pub const VMEXIT_CR0_READ: u64 = 0x0000;
+pub const VMEXIT_CR15_READ: u64 = 0x000f;
+pub const VMEXIT_CR0_WRITE: u64 = 0x0010;
+pub const VMEXIT_CR15_WRITE: u64 = 0x001f;
+pub const VMEXIT_DR0_READ: u64 = 0x0020;
+pub const VMEXIT_DR15_READ: u64 = 0x002f;
+pub const VMEXIT_DR0_WRITE: u64 = 0x0030;
+pub const VMEXIT_DR15_WRITE: u64 = 0x003f;
+// exception intercepts, 0x40 + vector
+pub const VMEXIT_EXCEPTION_DE: u64 = 0x0040;
+pub const VMEXIT_EXCEPTION_DB: u64 = 0x0041;
+pub const VMEXIT_EXCEPTION_31: u64 = 0x005f;
+pub const VMEXIT_NPF: u64 = 0x0400;
+
+// exit_info1 of MOV CRx/DRx intercepts with decode assists
+pub const EXIT_INFO1_MOV_CR: u64 = 1 << 63;
+pub const EXIT_INFO1_GPR_MASK: u64 = 0xf;
+
+// control_area::vintr
+pub const V_IRQ: u64 = 1 << 8;
+pub const V_NMI_PENDING: u64 = 1 << 11;
+pub const V_NMI_BLOCKING: u64 = 1 << 12;
+pub const V_INTR_PRIO_SHIFT: u64 = 16;
+pub const V_IGN_TPR: u64 = 1 << 20;
+pub const V_NMI_ENABLE: u64 = 1 << 26;
+pub const V_INTR_VECTOR_SHIFT: u64 = 32;
+
+// control_area::vmcb_clean, a set bit lets vmrun keep the group's fields
+// cached from the last vmrun of this vmcb on this cpu
+pub const VMCB_CLEAN_INTERCEPTS: u64 = 1 << 0;
+pub const VMCB_CLEAN_IOPM: u64 = 1 << 1;
+pub const VMCB_CLEAN_ASID: u64 = 1 << 2;
+pub const VMCB_CLEAN_TPR: u64 = 1 << 3;
+pub const VMCB_CLEAN_NP: u64 = 1 << 4;
+pub const VMCB_CLEAN_CR: u64 = 1 << 5;
+pub const VMCB_CLEAN_DR: u64 = 1 << 6;
+pub const VMCB_CLEAN_DT: u64 = 1 << 7;
+pub const VMCB_CLEAN_SEG: u64 = 1 << 8;
+pub const VMCB_CLEAN_CR2: u64 = 1 << 9;
+pub const VMCB_CLEAN_LBR: u64 = 1 << 10;
+pub const VMCB_CLEAN_AVIC: u64 = 1 << 11;
+pub const VMCB_CLEAN_ALL: u64 = (1 << 12) - 1;
+
+// tlb_control
+pub const TLB_CONTROL_DO_NOTHING: u32 = 0;
+pub const TLB_CONTROL_FLUSH_ALL: u32 = 1;
+pub const TLB_CONTROL_FLUSH_GUEST: u32 = 3;
+pub const TLB_CONTROL_FLUSH_GUEST_NON_GLOBAL: u32 = 7;
+
+// CPUID Fn8000_000A_EDX
+pub const SVM_FEATURE_NP: u32 = 1 << 0;
+pub const SVM_FEATURE_LBR_VIRT: u32 = 1 << 1;
+pub const SVM_FEATURE_NRIPS: u32 = 1 << 3;
+pub const SVM_FEATURE_VMCB_CLEAN: u32 = 1 << 5;
+pub const SVM_FEATURE_FLUSH_BY_ASID: u32 = 1 << 6;
+pub const SVM_FEATURE_DECODE_ASSISTS: u32 = 1 << 7;
+pub const SVM_FEATURE_PAUSE_FILTER: u32 = 1 << 10;
+pub const SVM_FEATURE_PAUSE_FILTER_THRESHOLD: u32 = 1 << 12;
+pub const SVM_FEATURE_VGIF: u32 = 1 << 16;
+pub const SVM_FEATURE_VNMI: u32 = 1 << 25;
 
 #[repr(C)]
 pub struct control_area {
@@ -135,3 +250,146 @@ pub struct vmcb {
     pub state_save_area: state_save,
 }
 const_assert_eq!(core::mem::size_of::<vmcb>(), 0x1000);
+
+// the fields from first up to end belong to group
+macro_rules! clean_group {
+    ($area:ident.$first:ident..$end:ident => $group:expr) => {
+        (
+            offset_of!(vmcb, $area.$first),
+            offset_of!(vmcb, $area.$end),
+            $group,
+        )
+    };
+}
+
+const CLEAN_GROUPS: [(usize, usize, u64); 21] = [
+    clean_group!(control_area.intercept_cr_read..iopm_base_pa => VMCB_CLEAN_INTERCEPTS),
+    clean_group!(control_area.tsc_offset..guest_asid => VMCB_CLEAN_INTERCEPTS),
+    clean_group!(control_area.iopm_base_pa..tsc_offset => VMCB_CLEAN_IOPM),
+    clean_group!(control_area.guest_asid..tlb_control => VMCB_CLEAN_ASID),
+    clean_group!(control_area.vintr..interrupt_shadow => VMCB_CLEAN_TPR),
+    clean_group!(control_area.np_enable..avic_apic_bar => VMCB_CLEAN_NP),
+    clean_group!(control_area.n_cr3..lbr_virtualization_enable => VMCB_CLEAN_NP),
+    clean_group!(state_save_area.gpat..dbg_ctl => VMCB_CLEAN_NP),
+    clean_group!(state_save_area.efer..reserved3 => VMCB_CLEAN_CR),
+    clean_group!(state_save_area.cr4..dr7 => VMCB_CLEAN_CR),
+    clean_group!(state_save_area.dr7..rflags => VMCB_CLEAN_DR),
+    clean_group!(state_save_area.gdtr_selector..ldtr_selector => VMCB_CLEAN_DT),
+    clean_group!(state_save_area.idtr_selector..tr_selector => VMCB_CLEAN_DT),
+    clean_group!(state_save_area.es_selector..fs_selector => VMCB_CLEAN_SEG),
+    clean_group!(state_save_area.cpl..reserved2 => VMCB_CLEAN_SEG),
+    clean_group!(state_save_area.cr2..reserved6 => VMCB_CLEAN_CR2),
+    clean_group!(control_area.lbr_virtualization_enable..vmcb_clean => VMCB_CLEAN_LBR),
+    (
+        offset_of!(vmcb, state_save_area.dbg_ctl),
+        size_of::<control_area>() + size_of::<state_save>(),
+        VMCB_CLEAN_LBR,
+    ),
+    clean_group!(control_area.avic_apic_bar..guest_pa_of_ghcb => VMCB_CLEAN_AVIC),
+    clean_group!(control_area.avic_apic_backing_page_pointer..reserved2 => VMCB_CLEAN_AVIC),
+    clean_group!(control_area.avic_logical_table_pointer..reserved3 => VMCB_CLEAN_AVIC),
+];
+
+// clean bit group of the vmcb byte at offset, 0 for fields vmrun always loads
+pub const fn clean_bit(offset: usize) -> u64 {
+    let mut i = 0;
+    while i < CLEAN_GROUPS.len() {
+        let (first, end, group) = CLEAN_GROUPS[i];
+        if offset >= first && offset < end {
+            return group;
+        }
+        i += 1;
+    }
+    0
+}
+
+// the group each field is marked dirty in
+macro_rules! assert_clean_group {
+    ($area:ident.$field:ident => $group:expr) => {
+        const_assert_eq!(clean_bit(offset_of!(vmcb, $area.$field)), $group);
+    };
+}
+
+assert_clean_group!(control_area.intercept_misc2 => VMCB_CLEAN_INTERCEPTS);
+assert_clean_group!(control_area.pause_filter_count => VMCB_CLEAN_INTERCEPTS);
+assert_clean_group!(control_area.tsc_offset => VMCB_CLEAN_INTERCEPTS);
+assert_clean_group!(control_area.msrpm_base_pa => VMCB_CLEAN_IOPM);
+assert_clean_group!(control_area.guest_asid => VMCB_CLEAN_ASID);
+assert_clean_group!(control_area.tlb_control => 0);
+assert_clean_group!(control_area.vintr => VMCB_CLEAN_TPR);
+assert_clean_group!(control_area.event_inj => 0);
+assert_clean_group!(control_area.n_cr3 => VMCB_CLEAN_NP);
+assert_clean_group!(state_save_area.gpat => VMCB_CLEAN_NP);
+assert_clean_group!(control_area.vmcb_clean => 0);
+assert_clean_group!(control_area.avic_physical_table_pointer => VMCB_CLEAN_AVIC);
+assert_clean_group!(state_save_area.ss_base => VMCB_CLEAN_SEG);
+assert_clean_group!(state_save_area.fs_base => 0);
+assert_clean_group!(state_save_area.cpl => VMCB_CLEAN_SEG);
+assert_clean_group!(state_save_area.idtr_limit => VMCB_CLEAN_DT);
+assert_clean_group!(state_save_area.tr_base => 0);
+assert_clean_group!(state_save_area.efer => VMCB_CLEAN_CR);
+assert_clean_group!(state_save_area.cr0 => VMCB_CLEAN_CR);
+assert_clean_group!(state_save_area.dr6 => VMCB_CLEAN_DR);
+assert_clean_group!(state_save_area.rflags => 0);
+assert_clean_group!(state_save_area.cr2 => VMCB_CLEAN_CR2);
+assert_clean_group!(state_save_area.last_excep_to => VMCB_CLEAN_LBR);
+
+// fields in a clean bit group are written through these, the group is
+// reloaded on the next vmrun. vmcb_clean is set again at every exit
+macro_rules! cached_fields {
+    ($($area:ident.$field:ident: $ty:ty => $accessor:ident),* $(,)?) => {
+        impl vmcb {
+            $(
+                pub fn $accessor(&mut self) -> &mut $ty {
+                    self.mark_dirty(clean_bit(offset_of!(vmcb, $area.$field)));
+                    &mut self.$area.$field
+                }
+            )*
+        }
+    };
+}
+
+cached_fields! {
+    control_area.intercept_cr_read: u16 => intercept_cr_read_mut,
+    control_area.intercept_cr_write: u16 => intercept_cr_write_mut,
+    control_area.intercept_dr_read: u16 => intercept_dr_read_mut,
+    control_area.intercept_dr_write: u16 => intercept_dr_write_mut,
+    control_area.intercept_exception: u32 => intercept_exception_mut,
+    control_area.intercept_misc1: u32 => intercept_misc1_mut,
+    control_area.intercept_misc2: u32 => intercept_misc2_mut,
+    control_area.pause_filter_threshold: u16 => pause_filter_threshold_mut,
+    control_area.pause_filter_count: u16 => pause_filter_count_mut,
+    control_area.iopm_base_pa: u64 => iopm_base_pa_mut,
+    control_area.msrpm_base_pa: u64 => msrpm_base_pa_mut,
+    control_area.tsc_offset: u64 => tsc_offset_mut,
+    control_area.guest_asid: u32 => guest_asid_mut,
+    control_area.vintr: u64 => vintr_mut,
+    control_area.np_enable: u64 => np_enable_mut,
+    control_area.n_cr3: u64 => n_cr3_mut,
+    control_area.lbr_virtualization_enable: u64 => lbr_virtualization_enable_mut,
+    state_save_area.cpl: u8 => cpl_mut,
+    state_save_area.efer: u64 => efer_mut,
+    state_save_area.cr0: u64 => cr0_mut,
+    state_save_area.cr2: u64 => cr2_mut,
+    state_save_area.cr3: u64 => cr3_mut,
+    state_save_area.cr4: u64 => cr4_mut,
+    state_save_area.dr6: u64 => dr6_mut,
+    state_save_area.dr7: u64 => dr7_mut,
+    state_save_area.gpat: u64 => gpat_mut,
+    state_save_area.dbg_ctl: u64 => dbg_ctl_mut,
+}
+
+impl vmcb {
+    // for writes to several fields of a group at once, e.g. a whole segment
+    pub fn mark_dirty(&mut self, groups: u64) {
+        self.control_area.vmcb_clean &= !groups;
+    }
+
+    // right after an exit, nothing was written since the last vmrun. without
+    // the feature vmcb_clean stays 0 and everything is reloaded
+    pub fn mark_clean(&mut self, supported: bool) {
+        if supported {
+            self.control_area.vmcb_clean = VMCB_CLEAN_ALL;
+        }
+    }
+}
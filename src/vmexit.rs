@@ -1,9 +1,30 @@
+use crate::asid;
+use crate::config::CONFIG;
+use crate::event::*;
+use crate::gdb;
+use crate::handler::cpuid::cpuid_handler;
+use crate::handler::cr::{cr_read_handler, cr_write_handler};
+use crate::handler::dr::{dr_read_handler, dr_write_handler};
+use crate::handler::exception::{exception_handler, vintr_handler};
+use crate::handler::msr::msr_handler;
+use crate::handler::npf::npf_handler;
 use crate::handler::vmmcall::vmmcall_handler;
+use crate::hidden;
 use crate::hv::*;
+use crate::idle;
+use crate::log_error;
+use crate::nmi::{self, iret_handler, nmi_handler};
+use crate::single_step::pushf_handler;
+use crate::sipi;
+use crate::stealth::{self, svm_instruction_handler};
 use crate::structs::*;
+use crate::tsc::{self, rdtsc_handler, rdtscp_handler};
 use crate::vmcb::*;
+use crate::xstate::xsetbv_handler;
 use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
 use core::ptr::NonNull;
+use core::sync::atomic::Ordering;
 use wdk::*;
 
 #[unsafe(no_mangle)]
@@ -11,37 +32,103 @@ unsafe extern "win64" fn vmexit_handler(
     mut vcpu: NonNull<vcpu>,
     mut guest_regs: NonNull<guest_regs>,
 ) -> u8 {
+    let exit_start = unsafe { _rdtsc() };
     let vcpu_ctx = unsafe { vcpu.as_mut() };
     let guest_regs = unsafe { guest_regs.as_mut() };
 
-    unsafe { asm!("vmload rax", in("rax") vcpu_ctx.host_stack_layout.host_vmcb_pa) };
+    hidden::enter(vcpu_ctx);
+    vcpu_ctx.shared.in_host.store(true, Ordering::Relaxed);
+    tsc::exit(exit_start);
+    let vmcb_clean = vcpu_ctx.svm_features.has(SVM_FEATURE_VMCB_CLEAN);
+    vcpu_ctx.guest_vmcb.mark_clean(vmcb_clean);
+    idle::rearm(vcpu_ctx);
 
     guest_regs.rax = vcpu_ctx.guest_vmcb.state_save_area.rax;
+    guest_regs.rsp = vcpu_ctx.guest_vmcb.state_save_area.rsp;
+    vcpu_ctx.advance_rip = true;
+    if vcpu_ctx.state_generation != state_generation() {
+        vcpu_ctx.sync_state();
+    }
+    if CONFIG.gdb.is_some() {
+        gdb::stub::poll(vcpu_ctx, guest_regs);
+    }
 
     vcpu_ctx.host_stack_layout.trap_frame.rsp = vcpu_ctx.guest_vmcb.state_save_area.rsp;
     vcpu_ctx.host_stack_layout.trap_frame.rip = vcpu_ctx.guest_vmcb.control_area.n_rip;
 
-    match vcpu_ctx.guest_vmcb.control_area.exit_code {
+    let exit_code = vcpu_ctx.guest_vmcb.control_area.exit_code;
+    match exit_code {
+        VMEXIT_CR0_READ..=VMEXIT_CR15_READ => {
+            cr_read_handler(vcpu_ctx, guest_regs, (exit_code - VMEXIT_CR0_READ) as u8)
+        }
+        VMEXIT_CR0_WRITE..=VMEXIT_CR15_WRITE => {
+            cr_write_handler(vcpu_ctx, guest_regs, (exit_code - VMEXIT_CR0_WRITE) as u8)
+        }
+        VMEXIT_DR0_READ..=VMEXIT_DR15_READ => {
+            dr_read_handler(vcpu_ctx, guest_regs, (exit_code - VMEXIT_DR0_READ) as u8)
+        }
+        VMEXIT_DR0_WRITE..=VMEXIT_DR15_WRITE => {
+            dr_write_handler(vcpu_ctx, guest_regs, (exit_code - VMEXIT_DR0_WRITE) as u8)
+        }
+        VMEXIT_EXCEPTION_DE..=VMEXIT_EXCEPTION_31 => exception_handler(
+            vcpu_ctx,
+            guest_regs,
+            (exit_code - VMEXIT_EXCEPTION_DE) as u8,
+        ),
+        VMEXIT_INTR => idle::intr_handler(vcpu_ctx),
+        VMEXIT_NMI => nmi_handler(vcpu_ctx, guest_regs),
+        VMEXIT_INIT => sipi::init_handler(vcpu_ctx),
+        VMEXIT_VINTR => vintr_handler(vcpu_ctx),
+        VMEXIT_IRET => iret_handler(vcpu_ctx),
+        VMEXIT_NPF => npf_handler(vcpu_ctx),
+        VMEXIT_RDTSC => rdtsc_handler(vcpu_ctx, guest_regs),
+        VMEXIT_RDTSCP => rdtscp_handler(vcpu_ctx, guest_regs),
+        VMEXIT_PUSHF => pushf_handler(vcpu_ctx, guest_regs),
+        VMEXIT_CPUID => cpuid_handler(vcpu_ctx, guest_regs),
+        VMEXIT_XSETBV => xsetbv_handler(vcpu_ctx, guest_regs),
+        VMEXIT_HLT => idle::hlt_handler(vcpu_ctx),
+        VMEXIT_PAUSE => idle::pause_handler(vcpu_ctx),
+        VMEXIT_MONITOR => idle::monitor_handler(vcpu_ctx),
+        VMEXIT_MWAIT | VMEXIT_MWAIT_CONDITIONAL => idle::mwait_handler(vcpu_ctx),
+        VMEXIT_MSR => msr_handler(vcpu_ctx, guest_regs),
+        VMEXIT_INVLPGA | VMEXIT_VMLOAD..=VMEXIT_SKINIT => svm_instruction_handler(vcpu_ctx),
         VMEXIT_VMRUN => vmrun_handler(vcpu_ctx),
         VMEXIT_VMMCALL => {
             vmmcall_handler(vcpu_ctx, guest_regs);
         }
         _ => {
-            println!("if this prints, its over");
+            log_error!("unhandled vmexit: {:#x}", exit_code);
             dbg_break();
         }
     }
+    sipi::startup(vcpu_ctx, guest_regs);
 
     if vcpu_ctx.unload {
         return devirtualize_cpu(vcpu_ctx, guest_regs);
     }
     // reflect changed regs to guest
     vcpu_ctx.guest_vmcb.state_save_area.rax = guest_regs.rax;
-    vcpu_ctx.guest_vmcb.state_save_area.rip = vcpu_ctx.guest_vmcb.control_area.n_rip;
+    vcpu_ctx.guest_vmcb.state_save_area.rsp = guest_regs.rsp;
+    if vcpu_ctx.advance_rip {
+        vcpu_ctx.guest_vmcb.state_save_area.rip = vcpu_ctx.guest_vmcb.control_area.n_rip;
+    }
+    nmi::deliver(vcpu_ctx);
+    asid::apply(vcpu_ctx);
 
+    vcpu_ctx.prev_vmexit = exit_code;
+    vcpu_ctx
+        .shared
+        .stats
+        .record(exit_code, unsafe { _rdtsc() } - exit_start);
+    tsc::compensate(vcpu_ctx, exit_start);
+
+    vcpu_ctx.shared.in_host.store(false, Ordering::Relaxed);
     return 0;
 }
 
 fn vmrun_handler(vcpu_ctx: &mut vcpu) {
-    vcpu_ctx.guest_vmcb.control_area.event_inj = 2147486477;
+    match stealth::enabled() {
+        true => svm_instruction_handler(vcpu_ctx),
+        false => vcpu_ctx.inject_fault(EXCEPTION_GP, Some(0)),
+    }
 }
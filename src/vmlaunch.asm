@@ -2,6 +2,14 @@
 
 .equ KTRAP_FRAME_SIZE, 0x190
 .equ GUEST_REGS_SIZE, 0x80
+// host_stack_layout fields from guest_vmcb_pa
+.equ SELF_DATA, 0x10
+.equ RELOAD_GUEST_HIDDEN, 0x20
+.equ XSAVE_AREA, 0x28
+.equ EXTENDED_STATE, 0x30
+// xstate::extended_state
+.equ EXTENDED_STATE_FXSAVE, 1
+.equ EXTENDED_STATE_XSAVE, 2
 
 .macro pushaq
     push    rax
@@ -50,38 +58,70 @@ guest_loop:
 
     mov rax, [rsp]          // rax = vcpu.host_stack_layout.guest_vmcb_pa
 
-    vmload rax              // load previous saved guest state from vmcb
-
+    cmp qword ptr [rsp + RELOAD_GUEST_HIDDEN], 0
+    je 1f
+    vmload rax              // the exit handler loaded the host's state or changed the guest's
+    mov qword ptr [rsp + RELOAD_GUEST_HIDDEN], 0
+1:
     vmrun rax               // switch to guest until #VMEXIT
 
-    vmsave rax              // save current guest state to vmcb
-
+                            // the guest's fs / gs / tr / syscall msrs stay loaded, see hidden.rs
     sub rsp, KTRAP_FRAME_SIZE
 
     pushaq
 
-    mov rdx, rsp                                                // rdx = guest_registers
-    mov rcx, [rsp + GUEST_REGS_SIZE + KTRAP_FRAME_SIZE + 16]    // rcx = vcpu_ctx
-
+    // the guest's vector state, rbx / rsi survive the call and hold the
+    // save area and how it's saved
+    mov rbx, [rsp + GUEST_REGS_SIZE + KTRAP_FRAME_SIZE + XSAVE_AREA]
+    mov rsi, [rsp + GUEST_REGS_SIZE + KTRAP_FRAME_SIZE + EXTENDED_STATE]
     sub rsp, 0x20 + 0x60
+    cmp rsi, EXTENDED_STATE_XSAVE
+    je 2f
+    cmp rsi, EXTENDED_STATE_FXSAVE
+    je 3f
     movaps [rsp + 0x20], xmm0
     movaps [rsp + 0x20 + 0x10], xmm1
     movaps [rsp + 0x20 + 0x20], xmm2
     movaps [rsp + 0x20 + 0x30], xmm3
     movaps [rsp + 0x20 + 0x40], xmm4
     movaps [rsp + 0x20 + 0x50], xmm5
+    jmp 4f
+2:
+    mov eax, -1             // everything enabled in the guest's xcr0
+    mov edx, -1
+    xsave64 [rbx]
+    jmp 4f
+3:
+    fxsave64 [rbx]
+4:
+    lea rdx, [rsp + 0x20 + 0x60]                                        // rdx = guest_registers
+    mov rcx, [rdx + GUEST_REGS_SIZE + KTRAP_FRAME_SIZE + SELF_DATA]     // rcx = vcpu_ctx
 
     call vmexit_handler
 
-    movaps [rsp + 0x20 + 0x50], xmm5
-    movaps [rsp + 0x20 + 0x40], xmm4
-    movaps [rsp + 0x20 + 0x30], xmm3
-    movaps [rsp + 0x20 + 0x20], xmm2
-    movaps [rsp + 0x20 + 0x10], xmm1
-    movaps [rsp + 0x20], xmm0
+    mov edi, eax            // xrstor takes edx:eax
+    cmp rsi, EXTENDED_STATE_XSAVE
+    je 2f
+    cmp rsi, EXTENDED_STATE_FXSAVE
+    je 3f
+    movaps xmm5, [rsp + 0x20 + 0x50]
+    movaps xmm4, [rsp + 0x20 + 0x40]
+    movaps xmm3, [rsp + 0x20 + 0x30]
+    movaps xmm2, [rsp + 0x20 + 0x20]
+    movaps xmm1, [rsp + 0x20 + 0x10]
+    movaps xmm0, [rsp + 0x20]
+    jmp 4f
+2:
+    mov eax, -1
+    mov edx, -1
+    xrstor64 [rbx]
+    jmp 4f
+3:
+    fxrstor64 [rbx]
+4:
     add rsp, 0x20 + 0x60
 
-    test al, al
+    test dil, dil
 
     popaq
 
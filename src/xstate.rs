@@ -0,0 +1,204 @@
+// the guest's x87 / sse / avx state across the exit handler. vmrun doesn't
+// switch any of it, launch_vm saves it before calling the handler and puts it
+// back after, in one of three ways (CONFIG.extended_state):
+// - Volatile: xmm0-xmm5, all the win64 abi lets the handler clobber as long
+//   as nothing in the exit path touches x87, mxcsr or the upper ymm halves
+// - Fxsave: x87 and sse
+// - Xsave: everything enabled in the guest's xcr0, avx and avx-512 included
+//
+// with either save instruction handlers may use simd freely.
+//
+// with CONFIG.xsetbv the guest's xsetbv exits, the new xcr0 is checked
+// against what the cpu supports minus the hidden features and the xsave area
+// is checked to hold it before it's loaded. xcr0 as it was when the cpu got
+// virtualized is left alone, hidden features the guest already enabled stay
+extern crate alloc;
+use crate::config::CONFIG;
+use crate::event::*;
+use crate::hv::vcpu;
+use crate::log_warn;
+use crate::structs::*;
+use crate::vmcb::*;
+use alloc::alloc::{Layout, alloc_zeroed};
+use core::arch::asm;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::ptr::null_mut;
+use wdk_sys::PAGE_SIZE;
+
+// values launch_vm compares host_stack_layout::extended_state with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u64)]
+pub enum extended_state {
+    Volatile = 0,
+    Fxsave = 1,
+    Xsave = 2,
+}
+
+pub const XCR0_X87: u64 = 1 << 0;
+pub const XCR0_SSE: u64 = 1 << 1;
+pub const XCR0_AVX: u64 = 1 << 2;
+pub const XCR0_MPX: u64 = 0b11 << 3;
+pub const XCR0_AVX512: u64 = 0b111 << 5;
+pub const XCR0_AMX: u64 = 0b11 << 17;
+
+const CPUID_1_EDX_FXSR: u32 = 1 << 24;
+const CPUID_1_ECX_OSXSAVE: u32 = 1 << 27;
+const CPUID_XSAVE: u32 = 0xd;
+const FXSAVE_SIZE: usize = 512;
+// legacy region and xsave header
+const XSAVE_HEADER_END: usize = 576;
+const XSAVE_ALIGN: usize = 64;
+// the pool only aligns allocations of a page or more beyond 16 bytes
+const AREA_ALIGN: usize = PAGE_SIZE as usize;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct xsave_sizing {
+    // bytes allocated for the area
+    pub capacity: usize,
+    // bytes xsave writes with the current xcr0
+    pub size: usize,
+}
+
+impl xsave_sizing {
+    pub const fn new() -> Self {
+        Self {
+            capacity: 0,
+            size: 0,
+        }
+    }
+}
+
+// whether xsetbv may load value into xcr0, allowed are the features the cpu
+// supports and the guest may see
+pub fn valid_xcr0(value: u64, allowed: u64) -> bool {
+    let all_or_none = |bits: u64| value & bits == 0 || value & bits == bits;
+    value & !allowed == 0
+        && value & XCR0_X87 != 0
+        && (value & XCR0_AVX == 0 || value & XCR0_SSE != 0)
+        && (value & XCR0_AVX512 == 0 || value & XCR0_AVX != 0)
+        && all_or_none(XCR0_MPX)
+        && all_or_none(XCR0_AVX512)
+        && all_or_none(XCR0_AMX)
+}
+
+// size of the standard format area for xcr0, component(n) is the size and
+// offset of state component n
+pub fn xsave_size(xcr0: u64, component: impl Fn(u32) -> (u32, u32)) -> usize {
+    (2..64)
+        .filter(|n| xcr0 & 1 << n != 0)
+        .map(|n| {
+            let (size, offset) = component(n);
+            (offset + size) as usize
+        })
+        .fold(XSAVE_HEADER_END, usize::max)
+}
+
+fn cpu_component(n: u32) -> (u32, u32) {
+    let leaf = unsafe { __cpuid_count(CPUID_XSAVE, n) };
+    (leaf.eax, leaf.ebx)
+}
+
+fn supported_xcr0() -> u64 {
+    let leaf = unsafe { __cpuid_count(CPUID_XSAVE, 0) };
+    (leaf.edx as u64) << 32 | leaf.eax as u64
+}
+
+pub fn allowed_xcr0() -> u64 {
+    supported_xcr0() & !CONFIG.xsetbv.hidden
+}
+
+fn read_xcr0() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe { asm!("xgetbv", in("ecx") 0, out("eax") low, out("edx") high) };
+    (high as u64) << 32 | low as u64
+}
+
+// leaf 0xd of cpuid stops reporting hidden features, needs the cpuid intercept
+pub fn filter_cpuid(leaf: u32, subleaf: u32, regs: &mut [u32; 4]) {
+    if leaf == CPUID_XSAVE && subleaf == 0 {
+        regs[0] &= !(CONFIG.xsetbv.hidden as u32);
+        regs[3] &= !((CONFIG.xsetbv.hidden >> 32) as u32);
+    }
+}
+
+// what the cpu supports of the configured mode
+fn supported(mode: extended_state) -> extended_state {
+    let leaf1 = unsafe { __cpuid(1) };
+    match mode {
+        extended_state::Xsave if leaf1.ecx & CPUID_1_ECX_OSXSAVE != 0 => extended_state::Xsave,
+        extended_state::Xsave | extended_state::Fxsave if leaf1.edx & CPUID_1_EDX_FXSR != 0 => {
+            extended_state::Fxsave
+        }
+        _ => extended_state::Volatile,
+    }
+}
+
+// large enough for every feature xcr0 can enable, whatever the guest picks
+fn area_size(mode: extended_state) -> usize {
+    match mode {
+        extended_state::Xsave => unsafe { __cpuid_count(0xd, 0) }.ecx as usize,
+        extended_state::Fxsave => FXSAVE_SIZE,
+        extended_state::Volatile => 0,
+    }
+}
+
+// the area is never freed, like the vcpu
+pub fn setup_xstate(vcpu_ctx: &mut vcpu) {
+    let mut mode = supported(CONFIG.extended_state);
+    if mode != CONFIG.extended_state {
+        log_warn!("extended state falls back to {:?}", mode);
+    }
+    let mut area = null_mut();
+    if mode != extended_state::Volatile {
+        let size = area_size(mode).next_multiple_of(AREA_ALIGN);
+        area = Layout::from_size_align(size, AREA_ALIGN)
+            .map_or(null_mut(), |layout| unsafe { alloc_zeroed(layout) });
+        if area.is_null() {
+            log_warn!("no memory for the xsave area, only xmm0-xmm5 are kept");
+            mode = extended_state::Volatile;
+        }
+    }
+    // xsave64 / xrstor64 / fxsave64 fault on a misaligned area
+    assert!(area as usize % XSAVE_ALIGN == 0);
+    vcpu_ctx.host_stack_layout.xsave_area = area;
+    vcpu_ctx.host_stack_layout.extended_state = mode as u64;
+    if mode == extended_state::Xsave {
+        vcpu_ctx.xsave = xsave_sizing {
+            capacity: area_size(mode),
+            size: xsave_size(read_xcr0(), cpu_component),
+        };
+    }
+
+    if CONFIG.xsetbv.intercept || CONFIG.xsetbv.hidden != 0 {
+        *vcpu_ctx.guest_vmcb.intercept_misc2_mut() |= SVM_INTERCEPT_MISC2_XSETBV;
+    }
+    if CONFIG.xsetbv.hidden != 0 {
+        *vcpu_ctx.guest_vmcb.intercept_misc1_mut() |= SVM_INTERCEPT_MISC1_CPUID;
+    }
+}
+
+// xcr0 isn't switched by vmrun, loading it here loads it for the guest.
+// launch_vm restores the guest's vector state under the new xcr0, state of
+// newly enabled features starts out in its init state
+pub fn xsetbv_handler(vcpu_ctx: &mut vcpu, guest_regs: &mut guest_regs) {
+    let value = guest_regs.rdx << 32 | guest_regs.rax & 0xffff_ffff;
+    if guest_regs.rcx as u32 != 0
+        || vcpu_ctx.guest_vmcb.state_save_area.cpl != 0
+        || !valid_xcr0(value, allowed_xcr0())
+    {
+        return vcpu_ctx.inject_fault(EXCEPTION_GP, Some(0));
+    }
+    if vcpu_ctx.host_stack_layout.extended_state == extended_state::Xsave as u64 {
+        let size = xsave_size(value, cpu_component);
+        if size > vcpu_ctx.xsave.capacity {
+            log_warn!(
+                "xcr0 {:#x} needs {} bytes of xsave area, refused",
+                value,
+                size
+            );
+            return vcpu_ctx.inject_fault(EXCEPTION_GP, Some(0));
+        }
+        vcpu_ctx.xsave.size = size;
+    }
+    unsafe { asm!("xsetbv", in("ecx") 0, in("eax") value as u32, in("edx") (value >> 32) as u32) };
+}